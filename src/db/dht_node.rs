@@ -0,0 +1,17 @@
+use sea_orm::entity::prelude::*;
+
+/// A node from the DHT routing table, kept for bootstrapping the next run.
+#[derive(Clone, Debug, PartialEq, Eq, DeriveEntityModel)]
+#[sea_orm(table_name = "dht_node")]
+pub struct Model {
+    #[sea_orm(primary_key, auto_increment = false)]
+    pub addr: String,
+    pub id: Vec<u8>,
+    /// Seconds since the Unix epoch.
+    pub saved_at: i64,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {}
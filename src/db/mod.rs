@@ -0,0 +1,40 @@
+//! Persistent state, stored through sea-orm.
+//!
+//! Tables are created from the entity definitions on connect, so there is no
+//! separate migration step.
+
+pub(crate) mod dht_node;
+pub(crate) mod swarm;
+pub(crate) mod swarm_peer;
+pub(crate) mod tracker_health;
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use sea_orm::{ConnectionTrait, Database, DatabaseConnection, DbErr, EntityTrait, Schema};
+
+pub(crate) async fn connect(url: &str) -> Result<DatabaseConnection, DbErr> {
+    let db = Database::connect(url).await?;
+    create_table(&db, dht_node::Entity).await?;
+    create_table(&db, swarm::Entity).await?;
+    create_table(&db, swarm_peer::Entity).await?;
+    create_table(&db, tracker_health::Entity).await?;
+    Ok(db)
+}
+
+/// Timestamps are stored as seconds since the Unix epoch.
+pub(crate) fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64
+}
+
+async fn create_table<E: EntityTrait>(db: &DatabaseConnection, entity: E) -> Result<(), DbErr> {
+    let backend = db.get_database_backend();
+    let stmt = Schema::new(backend)
+        .create_table_from_entity(entity)
+        .if_not_exists()
+        .to_owned();
+    db.execute(backend.build(&stmt)).await?;
+    Ok(())
+}
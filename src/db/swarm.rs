@@ -0,0 +1,15 @@
+use sea_orm::entity::prelude::*;
+
+/// Per-torrent counters kept by the built-in tracker.
+#[derive(Clone, Debug, PartialEq, Eq, DeriveEntityModel)]
+#[sea_orm(table_name = "swarm")]
+pub struct Model {
+    #[sea_orm(primary_key, auto_increment = false)]
+    pub info_hash: Vec<u8>,
+    pub downloaded: i64,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {}
@@ -0,0 +1,21 @@
+use sea_orm::entity::prelude::*;
+
+/// A peer that has announced to the built-in tracker.
+#[derive(Clone, Debug, PartialEq, Eq, DeriveEntityModel)]
+#[sea_orm(table_name = "swarm_peer")]
+pub struct Model {
+    #[sea_orm(primary_key, auto_increment = false)]
+    pub info_hash: Vec<u8>,
+    #[sea_orm(primary_key, auto_increment = false)]
+    pub peer_id: Vec<u8>,
+    pub ip: String,
+    pub port: i32,
+    pub left: i64,
+    /// Seconds since the Unix epoch.
+    pub last_seen: i64,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {}
@@ -0,0 +1,22 @@
+use sea_orm::entity::prelude::*;
+
+/// How a tracker has been behaving, so backoff survives restarts.
+#[derive(Clone, Debug, PartialEq, Eq, DeriveEntityModel)]
+#[sea_orm(table_name = "tracker_health")]
+pub struct Model {
+    #[sea_orm(primary_key, auto_increment = false)]
+    pub url: String,
+    /// Seconds since the Unix epoch, as are the other timestamps.
+    pub last_success: Option<i64>,
+    pub last_error: Option<String>,
+    pub last_error_at: Option<i64>,
+    pub consecutive_failures: i32,
+    pub avg_latency_ms: Option<i64>,
+    pub last_peer_count: Option<i32>,
+    pub next_announce: i64,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {}
@@ -0,0 +1,23 @@
+//! Readers for the shapes deku doesn't handle on its own.
+
+use deku::bitvec::{BitSlice, Msb0};
+use deku::ctx::Endian;
+use deku::prelude::*;
+
+/// Reads elements until the input is exhausted, for the trailing arrays that
+/// make up the tail of most UDP tracker packets and peer wire messages.
+pub(crate) fn read_to_end<'a, T>(
+    mut rest: &'a BitSlice<u8, Msb0>,
+    endian: Endian,
+) -> Result<(&'a BitSlice<u8, Msb0>, Vec<T>), DekuError>
+where
+    T: DekuRead<'a, Endian>,
+{
+    let mut items = Vec::new();
+    while !rest.is_empty() {
+        let (new_rest, item) = T::read(rest, endian)?;
+        items.push(item);
+        rest = new_rest;
+    }
+    Ok((rest, items))
+}
@@ -0,0 +1,90 @@
+//! BEP 33: the bloom filters a DHT scrape returns, one of the IPs seeding a
+//! torrent and one of those still downloading it. Filters from several nodes
+//! are merged, and the number of bits still unset tells roughly how many
+//! distinct IPs went in.
+//! See: http://www.bittorrent.org/beps/bep_0033.html
+
+use std::net::IpAddr;
+
+use sha1::{Digest, Sha1};
+
+/// Bits in a filter.
+const M: usize = 256 * 8;
+/// Bits set by each IP.
+const K: usize = 2;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct BloomFilter([u8; M / 8]);
+
+impl Default for BloomFilter {
+    fn default() -> Self {
+        Self([0; M / 8])
+    }
+}
+
+impl BloomFilter {
+    pub(crate) fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        Some(Self(bytes.try_into().ok()?))
+    }
+
+    pub(crate) fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    pub(crate) fn insert(&mut self, ip: &IpAddr) {
+        let hash = match ip {
+            IpAddr::V4(ip) => Sha1::digest(ip.octets()),
+            IpAddr::V6(ip) => Sha1::digest(ip.octets()),
+        };
+        for index in [
+            usize::from(hash[0]) | usize::from(hash[1]) << 8,
+            usize::from(hash[2]) | usize::from(hash[3]) << 8,
+        ] {
+            let index = index % M;
+            self.0[index / 8] |= 1 << (index % 8);
+        }
+    }
+
+    /// Adds everything in `other`.
+    pub(crate) fn union(&mut self, other: &BloomFilter) {
+        for (ours, theirs) in self.0.iter_mut().zip(other.0) {
+            *ours |= theirs;
+        }
+    }
+
+    /// About how many IPs went into the filter.
+    pub(crate) fn estimate(&self) -> f64 {
+        let unset = M - self
+            .0
+            .iter()
+            .map(|b| b.count_ones() as usize)
+            .sum::<usize>();
+        // A full filter could hold any number; say as many as it can tell.
+        let unset = unset.max(1) as f64;
+        (unset / M as f64).ln() / (K as f64 * (1.0 - 1.0 / M as f64).ln())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+    use super::BloomFilter;
+
+    #[test]
+    fn matches_the_bep_vector() {
+        let mut filter = BloomFilter::default();
+        for i in 0..256 {
+            filter.insert(&IpAddr::V4(Ipv4Addr::new(192, 0, 2, i as u8)));
+        }
+        for i in 0..1000 {
+            filter.insert(&IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, i)));
+        }
+        assert!((filter.estimate() - 1224.93).abs() < 0.01);
+
+        let mut merged = BloomFilter::default();
+        assert_eq!(merged.estimate(), 0.0);
+        merged.union(&filter);
+        assert_eq!(BloomFilter::from_bytes(merged.as_bytes()), Some(filter));
+    }
+}
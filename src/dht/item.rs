@@ -0,0 +1,227 @@
+//! Arbitrary data stored in the DHT. Immutable items are found by the hash
+//! of their value; mutable ones by the hash of a public key and salt, and
+//! carry a signed sequence number so that only the key's owner can update
+//! them, and only forwards.
+//! See: http://www.bittorrent.org/beps/bep_0044.html
+
+use std::collections::HashMap;
+
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use serde_bencode::value::Value;
+use sha1::{Digest, Sha1};
+
+use super::routing::NodeId;
+use crate::torrent::InfoHash;
+
+/// The most a bencoded value may take up.
+pub(crate) const MAX_VALUE_SIZE: usize = 1000;
+pub(crate) const MAX_SALT_SIZE: usize = 64;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Item {
+    /// The bencoded value, which is what gets hashed and signed.
+    value: Vec<u8>,
+    mutable: Option<Mutable>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Mutable {
+    pub(crate) key: [u8; 32],
+    pub(crate) salt: Vec<u8>,
+    pub(crate) seq: i64,
+    pub(crate) signature: [u8; 64],
+}
+
+impl Item {
+    pub(crate) fn immutable(value: &Value) -> Self {
+        Self {
+            value: encode(value),
+            mutable: None,
+        }
+    }
+
+    /// `value` as version `seq` of the item `key` publishes under `salt`.
+    pub(crate) fn mutable(key: &SigningKey, salt: Vec<u8>, seq: i64, value: &Value) -> Self {
+        let value = encode(value);
+        let signature = key.sign(&signed(&salt, seq, &value)).to_bytes();
+        Self {
+            value,
+            mutable: Some(Mutable {
+                key: key.verifying_key().to_bytes(),
+                salt,
+                seq,
+                signature,
+            }),
+        }
+    }
+
+    /// An item as it arrived, which for mutable items means checking the
+    /// signature.
+    pub(crate) fn from_parts(value: &Value, mutable: Option<Mutable>) -> Result<Self, ItemError> {
+        let item = Self {
+            value: encode(value),
+            mutable,
+        };
+        if item.value.len() > MAX_VALUE_SIZE {
+            return Err(ItemError::TooBig);
+        }
+        if let Some(mutable) = &item.mutable {
+            if mutable.salt.len() > MAX_SALT_SIZE {
+                return Err(ItemError::SaltTooBig);
+            }
+            let key =
+                VerifyingKey::from_bytes(&mutable.key).map_err(|_| ItemError::BadSignature)?;
+            let signature = Signature::from_bytes(&mutable.signature);
+            key.verify(&signed(&mutable.salt, mutable.seq, &item.value), &signature)
+                .map_err(|_| ItemError::BadSignature)?;
+        }
+        Ok(item)
+    }
+
+    pub(crate) fn value(&self) -> Value {
+        serde_bencode::from_bytes(&self.value).expect("items hold valid bencode")
+    }
+
+    pub(crate) fn mutable_part(&self) -> Option<&Mutable> {
+        self.mutable.as_ref()
+    }
+
+    pub(crate) fn seq(&self) -> Option<i64> {
+        self.mutable.as_ref().map(|m| m.seq)
+    }
+
+    /// Where the item is stored.
+    pub(crate) fn target(&self) -> NodeId {
+        match &self.mutable {
+            None => immutable_target(&self.value),
+            Some(mutable) => mutable_target(&mutable.key, &mutable.salt),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ItemError {
+    TooBig,
+    SaltTooBig,
+    BadSignature,
+}
+
+fn encode(value: &Value) -> Vec<u8> {
+    serde_bencode::to_bytes(value).expect("values always encode")
+}
+
+fn immutable_target(value: &[u8]) -> NodeId {
+    NodeId::from(<[u8; 20]>::from(Sha1::digest(value)))
+}
+
+pub(crate) fn mutable_target(key: &[u8; 32], salt: &[u8]) -> NodeId {
+    let mut hasher = Sha1::new();
+    hasher.update(key);
+    hasher.update(salt);
+    NodeId::from(<[u8; 20]>::from(hasher.finalize()))
+}
+
+/// What a mutable item's signature covers: the salt if there is one, the
+/// sequence number and the value, as they'd appear in a bencoded dictionary.
+fn signed(salt: &[u8], seq: i64, value: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::new();
+    if !salt.is_empty() {
+        buf.extend(format!("4:salt{}:", salt.len()).as_bytes());
+        buf.extend(salt);
+    }
+    buf.extend(format!("3:seqi{seq}e1:v").as_bytes());
+    buf.extend(value);
+    buf
+}
+
+/// BEP 46: what a mutable torrent's item holds, its current info hash.
+/// See: http://www.bittorrent.org/beps/bep_0046.html
+pub(crate) fn torrent_value(info_hash: &InfoHash) -> Value {
+    Value::Dict(HashMap::from([(
+        b"ih".to_vec(),
+        Value::Bytes(info_hash.as_bytes().to_vec()),
+    )]))
+}
+
+pub(crate) fn torrent_info_hash(value: &Value) -> Option<InfoHash> {
+    let Value::Dict(dict) = value else {
+        return None;
+    };
+    let Some(Value::Bytes(ih)) = dict.get(&b"ih"[..]) else {
+        return None;
+    };
+    InfoHash::try_from(&ih[..]).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use ed25519_dalek::SigningKey;
+    use serde_bencode::value::Value;
+
+    use super::{Item, ItemError, Mutable};
+    use crate::torrent::InfoHash;
+
+    fn hex<const N: usize>(s: &str) -> [u8; N] {
+        let mut bytes = [0; N];
+        for (i, b) in bytes.iter_mut().enumerate() {
+            *b = u8::from_str_radix(&s[2 * i..2 * i + 2], 16).unwrap();
+        }
+        bytes
+    }
+
+    fn target(s: &str) -> String {
+        InfoHash::from_hex(s).unwrap().to_hex_string()
+    }
+
+    #[test]
+    fn checks_the_bep_vectors() {
+        let hello = Value::Bytes(b"Hello World!".to_vec());
+        assert_eq!(
+            format!("{:?}", Item::immutable(&hello).target()),
+            target("e5f96f6f38320f0f33959cb4d3d656452117aadb")
+        );
+
+        let key = hex("77ff84905a91936367c01360803104f92432fcd904a43511876df5cdf3e7e548");
+        let unsalted = Mutable {
+            key,
+            salt: Vec::new(),
+            seq: 1,
+            signature: hex("305ac8aeb6c9c151fa120f120ea2cfb923564e11552d06a5d856091e5e853cff1260d3f39e4999684aa92eb73ffd136e6f4f3ecbfda0ce53a1608ecd7ae21f01"),
+        };
+        let item = Item::from_parts(&hello, Some(unsalted.clone())).unwrap();
+        assert_eq!(
+            format!("{:?}", item.target()),
+            target("4a533d47ec9c7d95b1ad75f576cffc641853b750")
+        );
+        let salted = Mutable {
+            salt: b"foobar".to_vec(),
+            signature: hex("6834284b6b24c3204eb2fea824d82f88883a3d95e8b4a21b8c0ded553d17d17ddf9a8a7104b1258f30bed3787e6cb896fca78c58f8e03b5f18f14951a87d9a08"),
+            ..unsalted.clone()
+        };
+        let item = Item::from_parts(&hello, Some(salted)).unwrap();
+        assert_eq!(
+            format!("{:?}", item.target()),
+            target("411eba73b6f087ca51a3795d9c8c938d365e32c1")
+        );
+
+        let replayed = Mutable { seq: 2, ..unsalted };
+        assert_eq!(
+            Item::from_parts(&hello, Some(replayed)),
+            Err(ItemError::BadSignature)
+        );
+    }
+
+    #[test]
+    fn signs_what_it_verifies() {
+        let key = SigningKey::from_bytes(&[7; 32]);
+        let value = Value::List(vec![Value::Int(1), Value::Bytes(b"two".to_vec())]);
+        let item = Item::mutable(&key, b"salt".to_vec(), 5, &value);
+        let parts = item.mutable_part().cloned();
+        assert_eq!(Item::from_parts(&value, parts), Ok(item.clone()));
+        assert_eq!(item.value(), value);
+        assert_eq!(item.seq(), Some(5));
+
+        let big = Value::Bytes(vec![0; 1000]);
+        assert_eq!(Item::from_parts(&big, None), Err(ItemError::TooBig));
+    }
+}
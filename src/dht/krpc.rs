@@ -0,0 +1,594 @@
+//! KRPC, the DHT's protocol: bencoded dictionaries over UDP, each a query, a
+//! response or an error, matched up by transaction ID.
+//! See: http://www.bittorrent.org/beps/bep_0005.html#krpc-protocol
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use serde::{Deserialize, Serialize};
+use serde_bencode::value::Value;
+use serde_bytes::ByteBuf;
+
+use super::item::{Item, ItemError, Mutable};
+use super::routing::NodeId;
+use crate::peer::extension::pex::addr_to_bytes;
+use crate::torrent::InfoHash;
+
+/// Our client and version, sent as `v` in every message.
+const VERSION: &[u8] = b"CH\x00\x01";
+
+pub(crate) const GENERIC_ERROR: i64 = 201;
+pub(crate) const PROTOCOL_ERROR: i64 = 203;
+pub(crate) const METHOD_UNKNOWN: i64 = 204;
+pub(crate) const MESSAGE_TOO_BIG: i64 = 205;
+pub(crate) const INVALID_SIGNATURE: i64 = 206;
+pub(crate) const SALT_TOO_BIG: i64 = 207;
+pub(crate) const CAS_MISMATCH: i64 = 301;
+pub(crate) const SEQ_TOO_OLD: i64 = 302;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) enum Kind {
+    #[serde(rename = "q")]
+    Query,
+    #[serde(rename = "r")]
+    Response,
+    #[serde(rename = "e")]
+    Error,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct Message {
+    #[serde(rename = "t")]
+    pub(crate) transaction: ByteBuf,
+    #[serde(rename = "y")]
+    pub(crate) kind: Kind,
+    #[serde(rename = "q", default, skip_serializing_if = "Option::is_none")]
+    pub(crate) query: Option<String>,
+    #[serde(rename = "a", default, skip_serializing_if = "Option::is_none")]
+    pub(crate) args: Option<Args>,
+    #[serde(rename = "r", default, skip_serializing_if = "Option::is_none")]
+    pub(crate) response: Option<Response>,
+    /// A code and a message; read through [`Message::failure`], since
+    /// serde_bencode can't read a list into a tuple.
+    #[serde(rename = "e", default, skip_serializing_if = "Option::is_none")]
+    error: Option<Vec<Value>>,
+    #[serde(rename = "v", default, skip_serializing_if = "Option::is_none")]
+    pub(crate) version: Option<ByteBuf>,
+    /// BEP 42: in responses, the address the query came from.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) ip: Option<ByteBuf>,
+    /// BEP 43: set to 1 on queries from nodes that won't answer any.
+    #[serde(rename = "ro", default, skip_serializing_if = "Option::is_none")]
+    pub(crate) read_only: Option<i64>,
+}
+
+/// The arguments of every query we know, all optional but `id`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct Args {
+    pub(crate) id: ByteBuf,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) target: Option<ByteBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) info_hash: Option<ByteBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) port: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) token: Option<ByteBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) implied_port: Option<i64>,
+    #[serde(rename = "v", default, skip_serializing_if = "Option::is_none")]
+    pub(crate) value: Option<Value>,
+    #[serde(rename = "k", default, skip_serializing_if = "Option::is_none")]
+    pub(crate) key: Option<ByteBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) salt: Option<ByteBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) seq: Option<i64>,
+    #[serde(rename = "sig", default, skip_serializing_if = "Option::is_none")]
+    pub(crate) signature: Option<ByteBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) cas: Option<i64>,
+    /// BEP 32: which families of nodes to return, `n4` and `n6`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) want: Option<Vec<ByteBuf>>,
+    /// BEP 33: asks `get_peers` for bloom filters of the swarm.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) scrape: Option<i64>,
+    /// BEP 33: announces a seed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) seed: Option<i64>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct Response {
+    pub(crate) id: ByteBuf,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) nodes: Option<ByteBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) nodes6: Option<ByteBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) values: Option<Vec<ByteBuf>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) token: Option<ByteBuf>,
+    #[serde(rename = "v", default, skip_serializing_if = "Option::is_none")]
+    pub(crate) value: Option<Value>,
+    #[serde(rename = "k", default, skip_serializing_if = "Option::is_none")]
+    pub(crate) key: Option<ByteBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) seq: Option<i64>,
+    #[serde(rename = "sig", default, skip_serializing_if = "Option::is_none")]
+    pub(crate) signature: Option<ByteBuf>,
+    /// BEP 33: the IPs seeding, and the ones downloading.
+    #[serde(rename = "BFsd", default, skip_serializing_if = "Option::is_none")]
+    pub(crate) seeds: Option<ByteBuf>,
+    #[serde(rename = "BFpe", default, skip_serializing_if = "Option::is_none")]
+    pub(crate) downloaders: Option<ByteBuf>,
+    /// BEP 51: seconds until the samples change, how many info hashes the
+    /// node has, and some of them.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) interval: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) num: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) samples: Option<ByteBuf>,
+}
+
+impl Response {
+    pub(crate) fn new(id: &NodeId) -> Self {
+        Self {
+            id: ByteBuf::from(id.as_bytes().to_vec()),
+            ..Default::default()
+        }
+    }
+
+    pub(crate) fn node_id(&self) -> Option<NodeId> {
+        NodeId::from_bytes(&self.id)
+    }
+
+    /// The nodes in `nodes` and `nodes6`; trailing partial entries are
+    /// dropped.
+    pub(crate) fn nodes(&self) -> Vec<(NodeId, SocketAddr)> {
+        let nodes = self.nodes.iter().flat_map(|nodes| decode_nodes(nodes, 4));
+        let nodes6 = self.nodes6.iter().flat_map(|nodes| decode_nodes(nodes, 16));
+        nodes.chain(nodes6).collect()
+    }
+
+    /// Fills in `nodes` with the IPv4 ones of `closest` and `nodes6` with the
+    /// IPv6 ones.
+    pub(crate) fn set_nodes(&mut self, closest: &[(NodeId, SocketAddr)]) {
+        let (v4, v6): (Vec<_>, Vec<_>) = closest.iter().partition(|(_, addr)| addr.is_ipv4());
+        if !v4.is_empty() {
+            self.nodes = Some(ByteBuf::from(encode_nodes(v4.iter().copied())));
+        }
+        if !v6.is_empty() {
+            self.nodes6 = Some(ByteBuf::from(encode_nodes(v6.iter().copied())));
+        }
+    }
+
+    /// The peers in `values`, skipping entries of the wrong length.
+    pub(crate) fn peers(&self) -> Vec<SocketAddr> {
+        self.values
+            .iter()
+            .flatten()
+            .filter_map(|value| decode_addr(value))
+            .collect()
+    }
+
+    /// The info hashes in `samples`.
+    pub(crate) fn samples(&self) -> Vec<InfoHash> {
+        self.samples.as_ref().map_or_else(Vec::new, |samples| {
+            samples
+                .chunks_exact(20)
+                .filter_map(|chunk| InfoHash::try_from(chunk).ok())
+                .collect()
+        })
+    }
+
+    /// The item a `get` for `target` returned, if its signature checks out
+    /// and it really is stored there.
+    pub(crate) fn item(&self, target: &NodeId, salt: &[u8]) -> Option<Item> {
+        let mutable = match (&self.key, self.seq, &self.signature) {
+            (Some(key), Some(seq), Some(signature)) => Some(Mutable {
+                key: key.as_slice().try_into().ok()?,
+                salt: salt.to_vec(),
+                seq,
+                signature: signature.as_slice().try_into().ok()?,
+            }),
+            _ => None,
+        };
+        let item = Item::from_parts(self.value.as_ref()?, mutable).ok()?;
+        (item.target() == *target).then_some(item)
+    }
+
+    pub(crate) fn set_item(&mut self, item: &Item) {
+        self.value = Some(item.value());
+        if let Some(mutable) = item.mutable_part() {
+            self.key = Some(ByteBuf::from(mutable.key.to_vec()));
+            self.seq = Some(mutable.seq);
+            self.signature = Some(ByteBuf::from(mutable.signature.to_vec()));
+        }
+    }
+}
+
+/// A query we can send and answer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Query {
+    Ping,
+    FindNode {
+        target: NodeId,
+    },
+    GetPeers {
+        info_hash: InfoHash,
+        /// BEP 33: asks for bloom filters of the swarm as well.
+        scrape: bool,
+    },
+    AnnouncePeer {
+        info_hash: InfoHash,
+        /// None to have the node use the port the query came from.
+        port: Option<u16>,
+        token: Vec<u8>,
+        seed: bool,
+    },
+    /// BEP 44: the item stored at `target`, unless it's mutable and no newer
+    /// than `seq`.
+    Get {
+        target: NodeId,
+        seq: Option<i64>,
+    },
+    /// BEP 44: stores `item`, if it's mutable only when what's stored has
+    /// sequence number `cas`.
+    Put {
+        item: Item,
+        cas: Option<i64>,
+        token: Vec<u8>,
+    },
+    /// BEP 51: a sample of the info hashes the node has peers for, and the
+    /// nodes closest to `target`.
+    SampleInfohashes {
+        target: NodeId,
+    },
+}
+
+impl Query {
+    pub(crate) fn to_message(&self, id: &NodeId, transaction: &[u8]) -> Message {
+        let mut args = Args {
+            id: ByteBuf::from(id.as_bytes().to_vec()),
+            ..Default::default()
+        };
+        let name = match self {
+            Query::Ping => "ping",
+            Query::FindNode { target } => {
+                args.target = Some(ByteBuf::from(target.as_bytes().to_vec()));
+                "find_node"
+            }
+            Query::GetPeers { info_hash, scrape } => {
+                args.info_hash = Some(ByteBuf::from(info_hash.as_bytes()));
+                args.scrape = scrape.then_some(1);
+                "get_peers"
+            }
+            Query::AnnouncePeer {
+                info_hash,
+                port,
+                token,
+                seed,
+            } => {
+                args.info_hash = Some(ByteBuf::from(info_hash.as_bytes()));
+                args.port = Some(port.unwrap_or(0).into());
+                args.implied_port = Some(port.is_none().into());
+                args.token = Some(ByteBuf::from(token.clone()));
+                args.seed = seed.then_some(1);
+                "announce_peer"
+            }
+            Query::Get { target, seq } => {
+                args.target = Some(ByteBuf::from(target.as_bytes().to_vec()));
+                args.seq = *seq;
+                "get"
+            }
+            Query::Put { item, cas, token } => {
+                args.value = Some(item.value());
+                if let Some(mutable) = item.mutable_part() {
+                    args.key = Some(ByteBuf::from(mutable.key.to_vec()));
+                    args.seq = Some(mutable.seq);
+                    args.signature = Some(ByteBuf::from(mutable.signature.to_vec()));
+                    if !mutable.salt.is_empty() {
+                        args.salt = Some(ByteBuf::from(mutable.salt.clone()));
+                    }
+                }
+                args.cas = *cas;
+                args.token = Some(ByteBuf::from(token.clone()));
+                "put"
+            }
+            Query::SampleInfohashes { target } => {
+                args.target = Some(ByteBuf::from(target.as_bytes().to_vec()));
+                "sample_infohashes"
+            }
+        };
+        Message {
+            transaction: ByteBuf::from(transaction),
+            kind: Kind::Query,
+            query: Some(name.to_string()),
+            args: Some(args),
+            response: None,
+            error: None,
+            version: Some(ByteBuf::from(VERSION)),
+            ip: None,
+            read_only: None,
+        }
+    }
+
+    /// Reads the query in `message`, with the querying node's ID, or the
+    /// error to answer with.
+    pub(crate) fn from_message(message: &Message) -> Result<(NodeId, Query), (i64, String)> {
+        let protocol = |reason: &str| (PROTOCOL_ERROR, reason.to_string());
+        let args = message
+            .args
+            .as_ref()
+            .ok_or_else(|| protocol("no arguments"))?;
+        let id = NodeId::from_bytes(&args.id).ok_or_else(|| protocol("bad id"))?;
+        let info_hash = || {
+            let bytes = args.info_hash.as_ref().map_or(&[][..], |b| b.as_slice());
+            InfoHash::try_from(bytes).map_err(|_| protocol("bad info_hash"))
+        };
+        let target = || {
+            args.target
+                .as_ref()
+                .and_then(|target| NodeId::from_bytes(target))
+                .ok_or_else(|| protocol("bad target"))
+        };
+        let token = || {
+            args.token
+                .as_ref()
+                .map(|token| token.to_vec())
+                .ok_or_else(|| protocol("no token"))
+        };
+        let query = match message.query.as_deref() {
+            Some("ping") => Query::Ping,
+            Some("find_node") => Query::FindNode { target: target()? },
+            Some("get_peers") => Query::GetPeers {
+                info_hash: info_hash()?,
+                scrape: args.scrape == Some(1),
+            },
+            Some("announce_peer") => Query::AnnouncePeer {
+                info_hash: info_hash()?,
+                port: match (args.implied_port, args.port) {
+                    (Some(1), _) => None,
+                    (_, Some(port)) => Some(u16::try_from(port).map_err(|_| protocol("bad port"))?),
+                    (_, None) => return Err(protocol("no port")),
+                },
+                token: token()?,
+                seed: args.seed == Some(1),
+            },
+            Some("get") => Query::Get {
+                target: target()?,
+                seq: args.seq,
+            },
+            Some("put") => Query::Put {
+                item: read_item(args)?,
+                cas: args.cas,
+                token: token()?,
+            },
+            Some("sample_infohashes") => Query::SampleInfohashes { target: target()? },
+            _ => return Err((METHOD_UNKNOWN, "Method Unknown".to_string())),
+        };
+        Ok((id, query))
+    }
+}
+
+/// The item in a `put`, with the error to answer if it's no good.
+fn read_item(args: &Args) -> Result<Item, (i64, String)> {
+    let value = args
+        .value
+        .as_ref()
+        .ok_or_else(|| (PROTOCOL_ERROR, "no value".to_string()))?;
+    let mutable = match (&args.key, &args.signature) {
+        (Some(key), Some(signature)) => Some(Mutable {
+            key: key.as_slice().try_into().map_err(|_| bad_signature())?,
+            salt: args.salt.as_ref().map(|s| s.to_vec()).unwrap_or_default(),
+            seq: args
+                .seq
+                .ok_or_else(|| (PROTOCOL_ERROR, "no seq".to_string()))?,
+            signature: signature
+                .as_slice()
+                .try_into()
+                .map_err(|_| bad_signature())?,
+        }),
+        _ => None,
+    };
+    Item::from_parts(value, mutable).map_err(|e| match e {
+        ItemError::TooBig => (MESSAGE_TOO_BIG, "Message (v field) too big".to_string()),
+        ItemError::SaltTooBig => (SALT_TOO_BIG, "salt (salt field) too big".to_string()),
+        ItemError::BadSignature => bad_signature(),
+    })
+}
+
+fn bad_signature() -> (i64, String) {
+    (INVALID_SIGNATURE, "invalid signature".to_string())
+}
+
+impl Message {
+    pub(crate) fn response(transaction: &[u8], response: Response) -> Self {
+        Self {
+            transaction: ByteBuf::from(transaction),
+            kind: Kind::Response,
+            query: None,
+            args: None,
+            response: Some(response),
+            error: None,
+            version: Some(ByteBuf::from(VERSION)),
+            ip: None,
+            read_only: None,
+        }
+    }
+
+    pub(crate) fn error(transaction: &[u8], code: i64, message: String) -> Self {
+        Self {
+            transaction: ByteBuf::from(transaction),
+            kind: Kind::Error,
+            query: None,
+            args: None,
+            response: None,
+            error: Some(vec![Value::Int(code), Value::Bytes(message.into_bytes())]),
+            version: Some(ByteBuf::from(VERSION)),
+            ip: None,
+            read_only: None,
+        }
+    }
+
+    /// The code and message of an error.
+    pub(crate) fn failure(&self) -> Option<(i64, String)> {
+        match self.error.as_deref()? {
+            [Value::Int(code), Value::Bytes(message)] => {
+                Some((*code, String::from_utf8_lossy(message).into_owned()))
+            }
+            _ => None,
+        }
+    }
+
+    pub(crate) fn to_bytes(&self) -> Vec<u8> {
+        serde_bencode::to_bytes(self).expect("KRPC messages always encode")
+    }
+}
+
+/// Compact node info: each node's ID, then its address as in
+/// [`addr_to_bytes`].
+pub(crate) fn encode_nodes<'a>(
+    nodes: impl IntoIterator<Item = &'a (NodeId, SocketAddr)>,
+) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    for (id, addr) in nodes {
+        bytes.extend(id.as_bytes());
+        bytes.extend(addr_to_bytes(addr));
+    }
+    bytes
+}
+
+/// Nodes whose IPs take `ip_len` bytes: 4 in `nodes`, 16 in `nodes6`.
+pub(crate) fn decode_nodes(bytes: &[u8], ip_len: usize) -> Vec<(NodeId, SocketAddr)> {
+    bytes
+        .chunks_exact(20 + ip_len + 2)
+        .filter_map(|chunk| {
+            Some((
+                NodeId::from_bytes(&chunk[..20])?,
+                decode_addr(&chunk[20..])?,
+            ))
+        })
+        .collect()
+}
+
+/// A compact IPv4 or IPv6 address, told apart by length.
+pub(crate) fn decode_addr(bytes: &[u8]) -> Option<SocketAddr> {
+    let (ip, port) = bytes.split_at(bytes.len().checked_sub(2)?);
+    let ip = match ip.len() {
+        4 => IpAddr::V4(Ipv4Addr::new(ip[0], ip[1], ip[2], ip[3])),
+        16 => IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(ip).ok()?)),
+        _ => return None,
+    };
+    Some(SocketAddr::new(ip, u16::from_be_bytes([port[0], port[1]])))
+}
+
+#[cfg(test)]
+mod tests {
+    use ed25519_dalek::SigningKey;
+    use serde_bencode::value::Value;
+    use serde_bytes::ByteBuf;
+
+    use super::{decode_nodes, encode_nodes, Kind, Message, Query, Response, METHOD_UNKNOWN};
+    use crate::dht::item::Item;
+    use crate::dht::routing::NodeId;
+    use crate::torrent::InfoHash;
+
+    #[test]
+    fn reads_the_bep_examples() {
+        let ping = b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t2:aa1:y1:qe";
+        let message: Message = serde_bencode::from_bytes(ping).unwrap();
+        assert_eq!(message.kind, Kind::Query);
+        let (id, query) = Query::from_message(&message).unwrap();
+        assert_eq!(id.as_bytes(), b"abcdefghij0123456789");
+        assert_eq!(query, Query::Ping);
+
+        let announce = b"d1:ad2:id20:abcdefghij01234567899:info_hash20:mnopqrstuvwxyz1234564:porti6881e5:token8:aoeusnthe1:q13:announce_peer1:t2:aa1:y1:qe";
+        let message: Message = serde_bencode::from_bytes(announce).unwrap();
+        let (_, query) = Query::from_message(&message).unwrap();
+        assert_eq!(
+            query,
+            Query::AnnouncePeer {
+                info_hash: InfoHash::try_from(&b"mnopqrstuvwxyz123456"[..]).unwrap(),
+                port: Some(6881),
+                token: b"aoeusnth".to_vec(),
+                seed: false,
+            }
+        );
+
+        let error = b"d1:eli201e23:A Generic Error Ocurrede1:t2:aa1:y1:ee";
+        let message: Message = serde_bencode::from_bytes(error).unwrap();
+        assert_eq!(
+            message.failure(),
+            Some((201, "A Generic Error Ocurred".into()))
+        );
+    }
+
+    #[test]
+    fn round_trips_queries() {
+        let id = NodeId::random();
+        for query in [
+            Query::Ping,
+            Query::FindNode {
+                target: NodeId::random(),
+            },
+            Query::GetPeers {
+                info_hash: InfoHash::from([7; 20]),
+                scrape: true,
+            },
+            Query::AnnouncePeer {
+                info_hash: InfoHash::from([7; 20]),
+                port: None,
+                token: vec![1, 2, 3],
+                seed: true,
+            },
+            Query::SampleInfohashes {
+                target: NodeId::random(),
+            },
+            Query::Get {
+                target: NodeId::random(),
+                seq: Some(3),
+            },
+            Query::Put {
+                item: Item::mutable(
+                    &SigningKey::from_bytes(&[1; 32]),
+                    b"s".to_vec(),
+                    2,
+                    &Value::Int(4),
+                ),
+                cas: Some(1),
+                token: vec![1, 2, 3],
+            },
+        ] {
+            let bytes = query.to_message(&id, b"xy").to_bytes();
+            let message: Message = serde_bencode::from_bytes(&bytes).unwrap();
+            assert_eq!(message.transaction, ByteBuf::from(&b"xy"[..]));
+            assert_eq!(Query::from_message(&message), Ok((id, query)));
+        }
+
+        let mut unknown = Query::Ping.to_message(&id, b"xy");
+        unknown.query = Some("vote".into());
+        assert_eq!(
+            Query::from_message(&unknown).map_err(|e| e.0),
+            Err(METHOD_UNKNOWN)
+        );
+
+        let nodes = vec![
+            (NodeId::random(), "10.0.0.1:6881".parse().unwrap()),
+            (NodeId::random(), "10.0.0.2:6882".parse().unwrap()),
+        ];
+        assert_eq!(decode_nodes(&encode_nodes(&nodes), 4), nodes);
+
+        let mut response = Response::new(&id);
+        let both = vec![
+            nodes[0],
+            (NodeId::random(), "[2001:db8::1]:6881".parse().unwrap()),
+        ];
+        response.set_nodes(&both);
+        assert_eq!(response.nodes.as_ref().unwrap().len(), 26);
+        assert_eq!(response.nodes6.as_ref().unwrap().len(), 38);
+        assert_eq!(response.nodes(), both);
+    }
+}
@@ -0,0 +1,1215 @@
+//! The mainline DHT: a Kademlia network of nodes that store, for each info
+//! hash, the peers that announced themselves for it. That finds peers for
+//! torrents without a tracker, and for magnet links.
+//! See: http://www.bittorrent.org/beps/bep_0005.html
+//!
+//! IPv6 nodes live in a routing table of their own (BEP 32), and our IDs
+//! are derived from our external address (BEP 42). Crawlers can estimate
+//! swarm sizes (BEP 33) and sample the torrents nodes know of (BEP 51)
+//! without serving the network, as read-only nodes (BEP 43).
+//! See: http://www.bittorrent.org/beps/bep_0032.html
+
+mod bloom;
+pub(crate) mod item;
+pub(crate) mod krpc;
+pub(crate) mod routing;
+mod security;
+mod storage;
+
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use ed25519_dalek::SigningKey;
+use futures::stream::FuturesUnordered;
+use futures::StreamExt;
+use log::{debug, info};
+use serde::Deserialize;
+use serde_bencode::value::Value;
+use serde_bytes::ByteBuf;
+use snafu::prelude::*;
+use tokio::sync::{oneshot, watch};
+use tokio::time::Instant;
+
+use self::bloom::BloomFilter;
+use self::item::Item;
+use self::krpc::{Kind, Message, Query, Response, GENERIC_ERROR, PROTOCOL_ERROR};
+use self::routing::{NodeId, NodeStore, RoutingTable, K};
+use self::storage::{ItemStore, PeerStore, Tokens, SAMPLE_INTERVAL};
+use crate::external_ip::{ExternalIp, Voter};
+use crate::net::UdpMux;
+use crate::peer::extension::pex::addr_to_bytes;
+use crate::peer::manager::{ConnectionManager, PeerSource};
+use crate::torrent::InfoHash;
+
+/// How long a node gets to answer a query.
+const QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+/// Queries a lookup has in flight at once.
+const ALPHA: usize = 3;
+/// How often we announce each torrent, and ask for its peers again.
+const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(15 * 60);
+/// How often the routing table is checked for stale buckets, and for being
+/// empty, in which case we bootstrap again.
+const MAINTENANCE_INTERVAL: Duration = Duration::from_secs(60);
+const SAVE_INTERVAL: Duration = Duration::from_secs(10 * 60);
+/// How often a followed mutable torrent is checked for a new version.
+const FOLLOW_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Debug, Clone, Deserialize)]
+pub(crate) struct DhtConfig {
+    #[serde(default = "default_enabled")]
+    pub(crate) enabled: bool,
+    /// The UDP port the `dht` command listens on; 0 picks any free one.
+    /// Otherwise the node shares the port we listen for peers on.
+    #[serde(default)]
+    pub(crate) port: u16,
+    /// Well-known nodes to join the network through, as `host:port`.
+    #[serde(default = "default_routers")]
+    pub(crate) routers: Vec<String>,
+    /// BEP 42: keeps nodes whose IDs weren't derived from their IP out of
+    /// the routing table.
+    #[serde(default = "default_enabled")]
+    pub(crate) enforce_node_ids: bool,
+    /// BEP 43: query the network without answering it, as crawlers should.
+    #[serde(default)]
+    pub(crate) read_only: bool,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+fn default_routers() -> Vec<String> {
+    [
+        "router.bittorrent.com:6881",
+        "router.utorrent.com:6881",
+        "dht.transmissionbt.com:6881",
+    ]
+    .map(String::from)
+    .to_vec()
+}
+
+impl Default for DhtConfig {
+    fn default() -> Self {
+        Self {
+            enabled: default_enabled(),
+            port: 0,
+            routers: default_routers(),
+            enforce_node_ids: true,
+            read_only: false,
+        }
+    }
+}
+
+#[derive(Debug, Snafu)]
+pub(crate) enum DhtError {
+    #[snafu(display("DHT query failed: {source}"))]
+    Io { source: io::Error },
+    #[snafu(display("DHT node timed out"))]
+    Timeout,
+    #[snafu(display("DHT node returned error {code}: {message}"))]
+    Remote { code: i64, message: String },
+    #[snafu(display("malformed DHT response"))]
+    Malformed,
+    #[snafu(display("no DHT node stored the item"))]
+    NotStored,
+}
+
+struct Pending {
+    addr: SocketAddr,
+    reply: oneshot::Sender<Result<Response, DhtError>>,
+}
+
+/// One address family's half of the node. BEP 32 keeps IPv6 nodes apart,
+/// with their own socket, routing table and ID.
+struct Family {
+    udp: UdpMux,
+    /// Our ID in this family is the table's own.
+    routing: Mutex<RoutingTable>,
+}
+
+struct Shared {
+    v4: Option<Family>,
+    v6: Option<Family>,
+    external_ip: ExternalIp,
+    enforce_node_ids: bool,
+    read_only: bool,
+    tokens: Mutex<Tokens>,
+    peers: Mutex<PeerStore>,
+    items: Mutex<ItemStore>,
+    pending: Mutex<HashMap<u16, Pending>>,
+    next_transaction: AtomicU16,
+    /// Where we joined from, to join again if every node goes away.
+    bootstrap: Mutex<Vec<SocketAddr>>,
+}
+
+impl Shared {
+    /// The family `addr` is talked to from, if we have one.
+    fn family(&self, addr: &SocketAddr) -> Option<&Family> {
+        match addr {
+            SocketAddr::V4(_) => self.v4.as_ref(),
+            SocketAddr::V6(_) => self.v6.as_ref(),
+        }
+    }
+
+    fn families(&self) -> impl Iterator<Item = &Family> {
+        self.v4.iter().chain(&self.v6)
+    }
+}
+
+/// BEP 33: about how many IPs seed a torrent, and how many download it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct SwarmSize {
+    pub(crate) seeds: usize,
+    pub(crate) downloaders: usize,
+}
+
+/// BEP 51: what a node told us about the torrents it knows.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Samples {
+    pub(crate) info_hashes: Vec<InfoHash>,
+    /// How many info hashes the node has in all.
+    pub(crate) num: usize,
+    /// How long until it has a different sample.
+    pub(crate) interval: Duration,
+}
+
+/// A DHT node, shared by every torrent in the session.
+#[derive(Clone)]
+pub(crate) struct Dht {
+    shared: Arc<Shared>,
+}
+
+/// What a lookup found: the closest nodes that answered, with the tokens
+/// they gave, and any peers or items they returned.
+#[derive(Debug, Default)]
+struct Lookup {
+    candidates: Vec<Candidate>,
+    peers: HashSet<SocketAddr>,
+    /// Answers to `get` that carried a value, yet to be checked.
+    values: Vec<Response>,
+    /// BEP 33: every scrape answer's filters, merged.
+    seeds: BloomFilter,
+    downloaders: BloomFilter,
+    /// BEP 51: the info hashes nodes sampled.
+    samples: HashSet<InfoHash>,
+}
+
+#[derive(Debug)]
+struct Candidate {
+    distance: [u8; 20],
+    addr: SocketAddr,
+    state: CandidateState,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum CandidateState {
+    New,
+    Queried,
+    Answered { token: Option<Vec<u8>> },
+    Failed,
+}
+
+impl Lookup {
+    fn add(&mut self, target: &NodeId, id: NodeId, addr: SocketAddr) {
+        if self.candidates.iter().any(|c| c.addr == addr) {
+            return;
+        }
+        let distance = id.distance(target);
+        let at = self.candidates.partition_point(|c| c.distance <= distance);
+        self.candidates.insert(
+            at,
+            Candidate {
+                distance,
+                addr,
+                state: CandidateState::New,
+            },
+        );
+    }
+
+    /// The closest [`K`] candidates of each family that haven't failed.
+    fn closest(&mut self) -> impl Iterator<Item = &mut Candidate> {
+        let (mut v4, mut v6) = (0, 0);
+        self.candidates
+            .iter_mut()
+            .filter(|c| c.state != CandidateState::Failed)
+            .filter(move |c| {
+                let taken = if c.addr.is_ipv4() { &mut v4 } else { &mut v6 };
+                *taken += 1;
+                *taken <= K
+            })
+    }
+
+    /// The next node to query. When there is none, and none are in flight,
+    /// the closest nodes have all been heard from and the lookup is done.
+    fn next(&mut self) -> Option<SocketAddr> {
+        let candidate = self.closest().find(|c| c.state == CandidateState::New)?;
+        candidate.state = CandidateState::Queried;
+        Some(candidate.addr)
+    }
+
+    fn set_state(&mut self, addr: SocketAddr, state: CandidateState) {
+        if let Some(candidate) = self.candidates.iter_mut().find(|c| c.addr == addr) {
+            candidate.state = state;
+        }
+    }
+
+    fn answered(&mut self) -> Vec<(SocketAddr, Option<Vec<u8>>)> {
+        self.closest()
+            .filter_map(|c| match &c.state {
+                CandidateState::Answered { token } => Some((c.addr, token.clone())),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+impl Dht {
+    /// Binds a node to each of `addrs`, one per address family. Our IDs
+    /// follow BEP 42 as soon as `external_ip` knows our address.
+    #[cfg(test)]
+    pub(crate) async fn bind(
+        addrs: &[SocketAddr],
+        config: &DhtConfig,
+        external_ip: ExternalIp,
+    ) -> io::Result<Self> {
+        let mut sockets = Vec::new();
+        for addr in addrs {
+            sockets.push(UdpMux::bind(*addr).await?);
+        }
+        Self::on(&sockets, config, external_ip)
+    }
+
+    /// Runs a node on each of `sockets`, one per address family, taking
+    /// the DHT messages they receive.
+    pub(crate) fn on(
+        sockets: &[UdpMux],
+        config: &DhtConfig,
+        external_ip: ExternalIp,
+    ) -> io::Result<Self> {
+        let now = Instant::now();
+        let (mut v4, mut v6) = (None, None);
+        for udp in sockets {
+            let addr = udp.local_addr()?;
+            let id = external_ip
+                .matching(&addr.ip())
+                .map_or_else(NodeId::random, security::generate);
+            let family = Family {
+                udp: udp.clone(),
+                routing: Mutex::new(RoutingTable::new(id, now)),
+            };
+            match addr {
+                SocketAddr::V4(_) => v4 = Some(family),
+                SocketAddr::V6(_) => v6 = Some(family),
+            }
+        }
+        if v4.is_none() && v6.is_none() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "no address to bind the DHT to",
+            ));
+        }
+        let dht = Self {
+            shared: Arc::new(Shared {
+                v4,
+                v6,
+                external_ip,
+                enforce_node_ids: config.enforce_node_ids,
+                read_only: config.read_only,
+                tokens: Mutex::new(Tokens::new(now)),
+                peers: Mutex::default(),
+                items: Mutex::default(),
+                pending: Mutex::default(),
+                next_transaction: AtomicU16::new(rand::random()),
+                bootstrap: Mutex::default(),
+            }),
+        };
+        for family in dht.shared.families() {
+            let mut messages = family.udp.dht();
+            let receiver = dht.clone();
+            tokio::spawn(async move {
+                while let Some((from, bytes)) = messages.recv().await {
+                    receiver.handle(from, &bytes).await;
+                }
+            });
+        }
+        Ok(dht)
+    }
+
+    /// Our ID in the first of our families.
+    pub(crate) fn id(&self) -> NodeId {
+        let family = self.shared.families().next().unwrap();
+        family.routing.lock().unwrap().own()
+    }
+
+    #[cfg(test)]
+    pub(crate) fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.shared.families().next().unwrap().udp.local_addr()
+    }
+
+    /// The address of each family's socket.
+    pub(crate) fn local_addrs(&self) -> Vec<SocketAddr> {
+        self.shared
+            .families()
+            .filter_map(|family| family.udp.local_addr().ok())
+            .collect()
+    }
+
+    /// The nodes in our routing tables.
+    pub(crate) fn nodes(&self) -> Vec<(NodeId, SocketAddr)> {
+        self.shared
+            .families()
+            .flat_map(|family| family.routing.lock().unwrap().nodes())
+            .collect()
+    }
+
+    /// Takes a datagram that arrived on a socket: answers queries, and hands
+    /// responses to whoever is waiting for them.
+    pub(crate) async fn handle(&self, from: SocketAddr, bytes: &[u8]) {
+        let message: Message = match serde_bencode::from_bytes(bytes) {
+            Ok(message) => message,
+            Err(e) => {
+                debug!("Ignoring malformed DHT message from {from}: {e}");
+                return;
+            }
+        };
+        let Some(family) = self.shared.family(&from) else {
+            return;
+        };
+        match message.kind {
+            // BEP 43: read-only nodes keep quiet.
+            Kind::Query if self.shared.read_only => {}
+            Kind::Query => {
+                let reply = self.answer(from, &message);
+                if let Err(e) = family.udp.send_to(&reply.to_bytes(), from).await {
+                    debug!("Couldn't answer DHT query from {from}: {e}");
+                }
+            }
+            Kind::Response | Kind::Error => {
+                let Ok(transaction) = <[u8; 2]>::try_from(&message.transaction[..]) else {
+                    return;
+                };
+                let transaction = u16::from_be_bytes(transaction);
+                {
+                    let mut pending = self.shared.pending.lock().unwrap();
+                    // Only the node we asked may answer.
+                    if pending.get(&transaction).map(|p| p.addr) != Some(from) {
+                        return;
+                    }
+                    let result = match (message.failure(), message.response) {
+                        (_, Some(response)) => Ok(response),
+                        (Some((code, message)), None) => Err(DhtError::Remote { code, message }),
+                        (None, None) => Err(DhtError::Malformed),
+                    };
+                    let _ = pending.remove(&transaction).unwrap().reply.send(result);
+                }
+                // BEP 42: nodes tell us the address they see us at.
+                if let Some(ours) = message.ip.as_ref().and_then(|ip| krpc::decode_addr(ip)) {
+                    self.shared
+                        .external_ip
+                        .vote(Voter::Node(from.ip()), ours.ip());
+                    self.check_ids();
+                }
+            }
+        }
+    }
+
+    fn answer(&self, from: SocketAddr, message: &Message) -> Message {
+        let transaction = &message.transaction;
+        let Some(family) = self.shared.family(&from) else {
+            return Message::error(transaction, GENERIC_ERROR, "wrong address family".into());
+        };
+        let (id, query) = match Query::from_message(message) {
+            Ok(query) => query,
+            Err((code, reason)) => return Message::error(transaction, code, reason),
+        };
+        // Nodes that query us are only added once they've answered a query
+        // of ours, which read-only ones never will.
+        let (own, known) = {
+            let routing = family.routing.lock().unwrap();
+            (
+                routing.own(),
+                routing.contains(&id) || !routing.has_room(&id),
+            )
+        };
+        let known = known || message.read_only == Some(1);
+        if !known {
+            let dht = self.clone();
+            tokio::spawn(async move { dht.query(from, Query::Ping).await });
+        }
+
+        let now = Instant::now();
+        let want = message.args.as_ref().and_then(|args| args.want.as_ref());
+        let mut response = Response::new(&own);
+        match query {
+            Query::Ping => {}
+            Query::FindNode { target } => {
+                response.set_nodes(&self.closest(&from, want, &target));
+            }
+            Query::GetPeers { info_hash, scrape } => {
+                let token = self.shared.tokens.lock().unwrap().issue(from.ip(), now);
+                response.token = Some(ByteBuf::from(token));
+                if scrape {
+                    let filters = self.shared.peers.lock().unwrap().scrape(&info_hash, now);
+                    if let Some((seeds, downloaders)) = filters {
+                        response.seeds = Some(ByteBuf::from(seeds.as_bytes()));
+                        response.downloaders = Some(ByteBuf::from(downloaders.as_bytes()));
+                    }
+                }
+                // Peers of the family the query came in on.
+                let mut peers = self.shared.peers.lock().unwrap().peers(&info_hash, now);
+                peers.retain(|peer| peer.is_ipv4() == from.is_ipv4());
+                if peers.is_empty() {
+                    let target = NodeId::from(&info_hash);
+                    response.set_nodes(&self.closest(&from, want, &target));
+                } else {
+                    let values = peers.iter().map(addr_to_bytes);
+                    response.values = Some(values.map(ByteBuf::from).collect());
+                }
+            }
+            Query::AnnouncePeer {
+                info_hash,
+                port,
+                token,
+                seed,
+            } => {
+                let valid = self
+                    .shared
+                    .tokens
+                    .lock()
+                    .unwrap()
+                    .validate(from.ip(), &token, now);
+                if !valid {
+                    return Message::error(transaction, PROTOCOL_ERROR, "bad token".into());
+                }
+                let peer = SocketAddr::new(from.ip(), port.unwrap_or(from.port()));
+                self.shared
+                    .peers
+                    .lock()
+                    .unwrap()
+                    .announce(info_hash, peer, seed, now);
+            }
+            Query::SampleInfohashes { target } => {
+                let (samples, num) = self.shared.peers.lock().unwrap().sample(now);
+                let samples: Vec<u8> = samples.iter().flat_map(|h| h.as_bytes().to_vec()).collect();
+                response.samples = Some(ByteBuf::from(samples));
+                response.num = Some(num as i64);
+                response.interval = Some(SAMPLE_INTERVAL.as_secs() as i64);
+                response.set_nodes(&self.closest(&from, want, &target));
+            }
+            Query::Get { target, seq } => {
+                let token = self.shared.tokens.lock().unwrap().issue(from.ip(), now);
+                response.token = Some(ByteBuf::from(token));
+                response.set_nodes(&self.closest(&from, want, &target));
+                if let Some(item) = self.shared.items.lock().unwrap().get(&target, now) {
+                    // Asked only for something newer than they have.
+                    match (item.seq(), seq) {
+                        (Some(stored), Some(seq)) if stored <= seq => response.seq = Some(stored),
+                        _ => response.set_item(item),
+                    }
+                }
+            }
+            Query::Put { item, cas, token } => {
+                let valid = self
+                    .shared
+                    .tokens
+                    .lock()
+                    .unwrap()
+                    .validate(from.ip(), &token, now);
+                if !valid {
+                    return Message::error(transaction, PROTOCOL_ERROR, "bad token".into());
+                }
+                let stored = self.shared.items.lock().unwrap().put(item, cas, now);
+                if let Err((code, reason)) = stored {
+                    return Message::error(transaction, code, reason);
+                }
+            }
+        }
+        let mut reply = Message::response(transaction, response);
+        reply.ip = Some(ByteBuf::from(addr_to_bytes(&from)));
+        reply
+    }
+
+    /// BEP 32: the nodes closest to `target` in each table the querier
+    /// `want`s, or in the table of its own family if it didn't say.
+    fn closest(
+        &self,
+        from: &SocketAddr,
+        want: Option<&Vec<ByteBuf>>,
+        target: &NodeId,
+    ) -> Vec<(NodeId, SocketAddr)> {
+        let mut closest = Vec::new();
+        for (family, name, v6) in [
+            (&self.shared.v4, "n4", false),
+            (&self.shared.v6, "n6", true),
+        ] {
+            let wanted = match want {
+                Some(want) => want.iter().any(|w| w.as_slice() == name.as_bytes()),
+                None => from.is_ipv6() == v6,
+            };
+            if let Some(family) = family.as_ref().filter(|_| wanted) {
+                closest.extend(family.routing.lock().unwrap().closest(target, K));
+            }
+        }
+        closest
+    }
+
+    /// Sends `query` to `addr` and waits for the answer. Nodes that answer
+    /// go into the routing table if their ID is valid for their IP, and ones
+    /// that don't answer are marked as failing.
+    async fn query(&self, addr: SocketAddr, query: Query) -> Result<Response, DhtError> {
+        let Some(family) = self.shared.family(&addr) else {
+            let source = io::Error::from(io::ErrorKind::Unsupported);
+            return Err(DhtError::Io { source });
+        };
+        let transaction = self.shared.next_transaction.fetch_add(1, Ordering::Relaxed);
+        let (reply, answer) = oneshot::channel();
+        self.shared
+            .pending
+            .lock()
+            .unwrap()
+            .insert(transaction, Pending { addr, reply });
+        let own = family.routing.lock().unwrap().own();
+        let mut message = query.to_message(&own, &transaction.to_be_bytes());
+        if self.shared.read_only {
+            message.read_only = Some(1);
+        }
+        // With both families, ask for nodes of both so each table fills.
+        if let (Some(args), Some(_), Some(_)) =
+            (message.args.as_mut(), &self.shared.v4, &self.shared.v6)
+        {
+            args.want = Some(vec![
+                ByteBuf::from(b"n4".to_vec()),
+                ByteBuf::from(b"n6".to_vec()),
+            ]);
+        }
+        let result = match family.udp.send_to(&message.to_bytes(), addr).await {
+            Err(source) => Err(DhtError::Io { source }),
+            Ok(_) => match tokio::time::timeout(QUERY_TIMEOUT, answer).await {
+                Ok(Ok(result)) => result,
+                _ => Err(DhtError::Timeout),
+            },
+        };
+        self.shared.pending.lock().unwrap().remove(&transaction);
+
+        let mut routing = family.routing.lock().unwrap();
+        match &result {
+            Ok(response) => match response.node_id() {
+                Some(id) if self.shared.enforce_node_ids && !security::is_valid(&id, addr.ip()) => {
+                    debug!("Not adding DHT node {addr}: its ID doesn't fit its IP");
+                }
+                Some(id) => {
+                    routing.insert(id, addr, Instant::now());
+                }
+                None => return Err(DhtError::Malformed),
+            },
+            Err(DhtError::Timeout) => routing.failed(&addr),
+            Err(_) => {}
+        }
+        result
+    }
+
+    /// BEP 42: once we know our address in a family, moves to an ID that's
+    /// valid for it, keeping the nodes we have.
+    fn check_ids(&self) {
+        let now = Instant::now();
+        for family in self.shared.families() {
+            let Ok(local) = family.udp.local_addr() else {
+                continue;
+            };
+            let Some(ip) = self.shared.external_ip.matching(&local.ip()) else {
+                continue;
+            };
+            let mut routing = family.routing.lock().unwrap();
+            if !security::is_valid(&routing.own(), ip) {
+                let id = security::generate(ip);
+                info!("DHT node ID is now {id:?}, to match {ip}");
+                routing.set_own(id, now);
+            }
+        }
+    }
+
+    /// Joins the network through `addrs`, which may be anything that speaks
+    /// the DHT: routers, nodes from a torrent file or from the last run.
+    pub(crate) async fn bootstrap(&self, addrs: Vec<SocketAddr>) {
+        *self.shared.bootstrap.lock().unwrap() = addrs.clone();
+        // Each family looks up its own ID, through the nodes it can reach.
+        let lookups = self.shared.families().map(|family| {
+            let target = family.routing.lock().unwrap().own();
+            let v4 = family.udp.is_ipv4();
+            // Their IDs are unknown, so they all start out as far as can be.
+            let candidates = addrs
+                .iter()
+                .filter(|addr| addr.is_ipv4() == v4)
+                .map(|&addr| Candidate {
+                    distance: [0xff; 20],
+                    addr,
+                    state: CandidateState::New,
+                })
+                .collect();
+            let lookup = Lookup {
+                candidates,
+                ..Default::default()
+            };
+            async move {
+                self.run_lookup(&target, Query::FindNode { target }, lookup)
+                    .await
+            }
+        });
+        futures::future::join_all(lookups).await;
+        info!("DHT bootstrapped with {} nodes", self.nodes().len());
+    }
+
+    /// Finds the nodes closest to `target`, in every family, by sending them
+    /// `query`, which may ask for more than nodes: peers, or an item.
+    async fn lookup(&self, target: &NodeId, query: Query) -> Lookup {
+        let mut lookup = Lookup::default();
+        for family in self.shared.families() {
+            for (id, addr) in family.routing.lock().unwrap().closest(target, K) {
+                lookup.add(target, id, addr);
+            }
+        }
+        self.run_lookup(target, query, lookup).await
+    }
+
+    async fn run_lookup(&self, target: &NodeId, query: Query, mut lookup: Lookup) -> Lookup {
+        let mut in_flight = FuturesUnordered::new();
+        loop {
+            while in_flight.len() < ALPHA {
+                let Some(addr) = lookup.next() else {
+                    break;
+                };
+                let query = query.clone();
+                in_flight.push(async move { (addr, self.query(addr, query).await) });
+            }
+            let Some((addr, result)) = in_flight.next().await else {
+                break;
+            };
+            match result {
+                Ok(response) => {
+                    for (id, node) in response.nodes() {
+                        if self.shared.family(&node).is_some() {
+                            lookup.add(target, id, node);
+                        }
+                    }
+                    lookup.peers.extend(response.peers());
+                    lookup.samples.extend(response.samples());
+                    let filter = |bytes: &Option<ByteBuf>| {
+                        bytes.as_ref().and_then(|b| BloomFilter::from_bytes(b))
+                    };
+                    if let Some(seeds) = filter(&response.seeds) {
+                        lookup.seeds.union(&seeds);
+                    }
+                    if let Some(downloaders) = filter(&response.downloaders) {
+                        lookup.downloaders.union(&downloaders);
+                    }
+                    let token = response.token.as_ref().map(|t| t.to_vec());
+                    lookup.set_state(addr, CandidateState::Answered { token });
+                    if response.value.is_some() {
+                        lookup.values.push(response);
+                    }
+                }
+                Err(e) => {
+                    debug!("DHT lookup query to {addr} failed: {e}");
+                    lookup.set_state(addr, CandidateState::Failed);
+                }
+            }
+        }
+        lookup
+    }
+
+    pub(crate) async fn get_peers(&self, info_hash: &InfoHash) -> Vec<SocketAddr> {
+        let query = Query::GetPeers {
+            info_hash: info_hash.clone(),
+            scrape: false,
+        };
+        let lookup = self.lookup(&NodeId::from(info_hash), query).await;
+        lookup.peers.into_iter().collect()
+    }
+
+    /// BEP 33: estimates the swarm of `info_hash` from what the nodes
+    /// closest to it have seen announced, with no tracker involved.
+    pub(crate) async fn scrape(&self, info_hash: &InfoHash) -> SwarmSize {
+        let query = Query::GetPeers {
+            info_hash: info_hash.clone(),
+            scrape: true,
+        };
+        let lookup = self.lookup(&NodeId::from(info_hash), query).await;
+        SwarmSize {
+            seeds: lookup.seeds.estimate().round() as usize,
+            downloaders: lookup.downloaders.estimate().round() as usize,
+        }
+    }
+
+    /// BEP 51: the info hashes the nodes around `target` sampled for us.
+    /// Crawlers that go node by node use [`Dht::sample_node`] instead.
+    pub(crate) async fn sample(&self, target: &NodeId) -> Vec<InfoHash> {
+        let query = Query::SampleInfohashes { target: *target };
+        let lookup = self.lookup(target, query).await;
+        lookup.samples.into_iter().collect()
+    }
+
+    /// BEP 51: asks the node at `addr` for a sample of its info hashes.
+    pub(crate) async fn sample_node(
+        &self,
+        addr: SocketAddr,
+        target: &NodeId,
+    ) -> Result<Samples, DhtError> {
+        let query = Query::SampleInfohashes { target: *target };
+        let response = self.query(addr, query).await?;
+        Ok(Samples {
+            info_hashes: response.samples(),
+            num: response.num.map_or(0, |num| num.max(0) as usize),
+            interval: Duration::from_secs(response.interval.map_or(0, |i| i.max(0) as u64)),
+        })
+    }
+
+    /// Finds the peers of `info_hash` and adds us to them, at `port`, or the
+    /// port our queries come from if None, as a seed or not.
+    pub(crate) async fn announce(
+        &self,
+        info_hash: &InfoHash,
+        port: Option<u16>,
+        seed: bool,
+    ) -> Vec<SocketAddr> {
+        let query = Query::GetPeers {
+            info_hash: info_hash.clone(),
+            scrape: false,
+        };
+        let mut lookup = self.lookup(&NodeId::from(info_hash), query).await;
+        let announces = lookup.answered().into_iter().filter_map(|(addr, token)| {
+            let query = Query::AnnouncePeer {
+                info_hash: info_hash.clone(),
+                port,
+                token: token?,
+                seed,
+            };
+            Some(async move { self.query(addr, query).await })
+        });
+        let announced = futures::future::join_all(announces)
+            .await
+            .iter()
+            .filter(|r| r.is_ok())
+            .count();
+        debug!("Announced {info_hash} to {announced} DHT nodes");
+        lookup.peers.into_iter().collect()
+    }
+
+    /// BEP 44: the immutable value stored under `target`.
+    pub(crate) async fn get_immutable(&self, target: &NodeId) -> Option<Value> {
+        let query = Query::Get {
+            target: *target,
+            seq: None,
+        };
+        let lookup = self.lookup(target, query).await;
+        let item = lookup
+            .values
+            .iter()
+            .find_map(|response| response.item(target, &[]))?;
+        Some(item.value())
+    }
+
+    /// BEP 44: the newest version of the mutable item `key` publishes under
+    /// `salt`.
+    pub(crate) async fn get_mutable(&self, key: &[u8; 32], salt: &[u8]) -> Option<Item> {
+        self.latest(key, salt).await.0
+    }
+
+    async fn latest(&self, key: &[u8; 32], salt: &[u8]) -> (Option<Item>, Lookup) {
+        let target = item::mutable_target(key, salt);
+        let query = Query::Get { target, seq: None };
+        let lookup = self.lookup(&target, query).await;
+        let item = lookup
+            .values
+            .iter()
+            .filter_map(|response| response.item(&target, salt))
+            .max_by_key(|item| item.seq());
+        (item, lookup)
+    }
+
+    /// BEP 44: stores `value` where it can be found by its hash, which is
+    /// returned.
+    pub(crate) async fn put_immutable(&self, value: &Value) -> Result<NodeId, DhtError> {
+        let item = Item::immutable(value);
+        let target = item.target();
+        let query = Query::Get { target, seq: None };
+        let lookup = self.lookup(&target, query).await;
+        self.store(lookup, item, None).await?;
+        Ok(target)
+    }
+
+    /// BEP 44: publishes `value` as the next version of the mutable item
+    /// `key` signs under `salt`, returning its sequence number.
+    pub(crate) async fn put_mutable(
+        &self,
+        key: &SigningKey,
+        salt: &[u8],
+        value: &Value,
+    ) -> Result<i64, DhtError> {
+        let (current, lookup) = self.latest(&key.verifying_key().to_bytes(), salt).await;
+        let cas = current.and_then(|item| item.seq());
+        let seq = cas.map_or(0, |seq| seq + 1);
+        let item = Item::mutable(key, salt.to_vec(), seq, value);
+        self.store(lookup, item, cas).await?;
+        Ok(seq)
+    }
+
+    /// BEP 46: the info hash `key` currently publishes under `salt`.
+    pub(crate) async fn resolve(&self, key: &[u8; 32], salt: &[u8]) -> Option<InfoHash> {
+        item::torrent_info_hash(&self.get_mutable(key, salt).await?.value())
+    }
+
+    /// BEP 46: points the mutable torrent `key` publishes under `salt` at
+    /// `info_hash`.
+    pub(crate) async fn publish_torrent(
+        &self,
+        key: &SigningKey,
+        salt: &[u8],
+        info_hash: &InfoHash,
+    ) -> Result<i64, DhtError> {
+        self.put_mutable(key, salt, &item::torrent_value(info_hash))
+            .await
+    }
+
+    /// BEP 46: resolves the mutable torrent every [`FOLLOW_INTERVAL`], and
+    /// sends each new info hash it points at. Stops once nobody's listening.
+    pub(crate) fn follow(&self, key: [u8; 32], salt: Vec<u8>) -> watch::Receiver<Option<InfoHash>> {
+        let (sender, receiver) = watch::channel(None);
+        let dht = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(FOLLOW_INTERVAL);
+            while !sender.is_closed() {
+                interval.tick().await;
+                if let Some(info_hash) = dht.resolve(&key, &salt).await {
+                    sender.send_if_modified(|current| {
+                        let changed = current.as_ref() != Some(&info_hash);
+                        *current = Some(info_hash);
+                        changed
+                    });
+                }
+            }
+        });
+        receiver
+    }
+
+    /// Puts `item` on the closest nodes `lookup` got tokens from.
+    async fn store(
+        &self,
+        mut lookup: Lookup,
+        item: Item,
+        cas: Option<i64>,
+    ) -> Result<(), DhtError> {
+        let puts = lookup.answered().into_iter().filter_map(|(addr, token)| {
+            let query = Query::Put {
+                item: item.clone(),
+                cas,
+                token: token?,
+            };
+            Some(async move { self.query(addr, query).await })
+        });
+        let mut last_error = DhtError::NotStored;
+        let mut stored = 0;
+        for result in futures::future::join_all(puts).await {
+            match result {
+                Ok(_) => stored += 1,
+                Err(e) => last_error = e,
+            }
+        }
+        debug!("Stored DHT item {:?} on {stored} nodes", item.target());
+        match stored {
+            0 => Err(last_error),
+            _ => Ok(()),
+        }
+    }
+
+    /// Announces `info_hash` every [`ANNOUNCE_INTERVAL`], queuing the peers
+    /// found on `manager`.
+    pub(crate) async fn track(self, manager: Arc<ConnectionManager>, port: u16) {
+        loop {
+            let peers = self.announce(manager.info_hash(), Some(port), false).await;
+            manager.add_peers(PeerSource::Dht, peers);
+            tokio::time::sleep(ANNOUNCE_INTERVAL).await;
+        }
+    }
+
+    /// Keeps the routing table healthy forever: refreshes buckets nobody has
+    /// joined in a while, bootstraps again if every node is gone, and saves
+    /// the table to `store`.
+    pub(crate) async fn maintain(self, store: Option<NodeStore>) {
+        let mut interval = tokio::time::interval(MAINTENANCE_INTERVAL);
+        let mut saved = Instant::now();
+        loop {
+            interval.tick().await;
+            let now = Instant::now();
+            self.check_ids();
+            if self.nodes().is_empty() {
+                let addrs = self.shared.bootstrap.lock().unwrap().clone();
+                self.bootstrap(addrs).await;
+            }
+            let stale: Vec<_> = self
+                .shared
+                .families()
+                .flat_map(|family| family.routing.lock().unwrap().stale(now))
+                .collect();
+            for target in stale {
+                self.lookup(&target, Query::FindNode { target }).await;
+            }
+            if let Some(store) = &store {
+                if now.duration_since(saved) >= SAVE_INTERVAL {
+                    store.save_or_warn(&self.nodes()).await;
+                    saved = now;
+                }
+            }
+        }
+    }
+}
+
+/// Resolves `host:port` names, skipping ones that don't.
+pub(crate) async fn resolve(hosts: &[String]) -> Vec<SocketAddr> {
+    let mut addrs = Vec::new();
+    for host in hosts {
+        match tokio::net::lookup_host(host.as_str()).await {
+            Ok(found) => addrs.extend(found),
+            Err(e) => debug!("Couldn't resolve DHT node {host}: {e}"),
+        }
+    }
+    addrs
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use ed25519_dalek::SigningKey;
+    use serde_bencode::value::Value;
+    use serde_bytes::ByteBuf;
+
+    use super::krpc::{Query, Response, METHOD_UNKNOWN};
+    use super::routing::NodeId;
+    use super::{security, Dht, DhtConfig, DhtError, SwarmSize};
+    use crate::external_ip::{ExternalIp, Voter};
+    use crate::peer::extension::pex::addr_to_bytes;
+    use crate::torrent::InfoHash;
+
+    async fn bind(addrs: &[&str], external_ip: ExternalIp) -> Dht {
+        let addrs: Vec<SocketAddr> = addrs.iter().map(|a| a.parse().unwrap()).collect();
+        Dht::bind(&addrs, &DhtConfig::default(), external_ip)
+            .await
+            .unwrap()
+    }
+
+    async fn node() -> Dht {
+        bind(&["127.0.0.1:0"], ExternalIp::default()).await
+    }
+
+    /// `n` nodes, all bootstrapped from the first.
+    async fn network(n: usize) -> Vec<Dht> {
+        let mut nodes = vec![node().await];
+        let first = nodes[0].local_addr().unwrap();
+        for _ in 1..n {
+            let dht = node().await;
+            dht.bootstrap(vec![first]).await;
+            nodes.push(dht);
+        }
+        nodes
+    }
+
+    #[tokio::test]
+    async fn finds_announced_peers() {
+        let nodes = network(8).await;
+        // Every node has found others, and the first has been told of them.
+        for dht in &nodes {
+            assert!(!dht.nodes().is_empty());
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        assert_eq!(nodes[0].nodes().len(), 7);
+
+        let info_hash = InfoHash::from([9; 20]);
+        assert!(nodes[3]
+            .announce(&info_hash, Some(6881), false)
+            .await
+            .is_empty());
+        let announcer = SocketAddr::from(([127, 0, 0, 1], 6881));
+        assert_eq!(nodes[6].get_peers(&info_hash).await, vec![announcer]);
+        // Implied ports use the port the announce came from.
+        nodes[5].announce(&info_hash, None, false).await;
+        let mut peers = nodes[1].get_peers(&info_hash).await;
+        peers.sort();
+        assert_eq!(peers, vec![announcer, nodes[5].local_addr().unwrap()]);
+    }
+
+    #[tokio::test]
+    async fn rejects_bad_tokens_and_unknown_queries() {
+        let (ours, theirs) = (node().await, node().await);
+        let addr = theirs.local_addr().unwrap();
+        let announce = Query::AnnouncePeer {
+            info_hash: InfoHash::from([9; 20]),
+            port: Some(6881),
+            token: b"made up".to_vec(),
+            seed: false,
+        };
+        assert!(matches!(
+            ours.query(addr, announce).await,
+            Err(DhtError::Remote { code: 203, .. })
+        ));
+
+        let mut unknown = Query::Ping.to_message(&ours.id(), b"aa");
+        unknown.query = Some("vote".into());
+        let reply = theirs.answer(ours.local_addr().unwrap(), &unknown);
+        assert_eq!(reply.failure().map(|e| e.0), Some(METHOD_UNKNOWN));
+        // Answering peers go into the routing table.
+        assert_eq!(
+            ours.query(addr, Query::Ping).await.unwrap(),
+            Response::new(&theirs.id())
+        );
+        assert_eq!(ours.nodes(), vec![(theirs.id(), addr)]);
+    }
+
+    #[tokio::test]
+    async fn stores_items_and_follows_mutable_torrents() {
+        let nodes = network(6).await;
+        let value = Value::Bytes(b"Hello World!".to_vec());
+        let target = nodes[2].put_immutable(&value).await.unwrap();
+        assert_eq!(nodes[4].get_immutable(&target).await, Some(value));
+
+        let key = SigningKey::from_bytes(&[5; 32]);
+        let public = key.verifying_key().to_bytes();
+        let (first, second) = (InfoHash::from([1; 20]), InfoHash::from([2; 20]));
+        assert_eq!(
+            nodes[1]
+                .publish_torrent(&key, b"name", &first)
+                .await
+                .unwrap(),
+            0
+        );
+        assert_eq!(nodes[5].resolve(&public, b"name").await, Some(first));
+        assert_eq!(nodes[5].resolve(&public, b"other").await, None);
+        assert_eq!(
+            nodes[3]
+                .publish_torrent(&key, b"name", &second)
+                .await
+                .unwrap(),
+            1
+        );
+        let item = nodes[5].get_mutable(&public, b"name").await.unwrap();
+        assert_eq!(item.seq(), Some(1));
+        assert_eq!(nodes[0].resolve(&public, b"name").await, Some(second));
+    }
+
+    #[tokio::test]
+    async fn keeps_ipv6_nodes_in_their_own_table() {
+        let dual = || bind(&["127.0.0.1:0", "[::1]:0"], ExternalIp::default());
+        let first = dual().await;
+        let mut nodes = Vec::new();
+        for _ in 0..5 {
+            let dht = dual().await;
+            dht.bootstrap(first.local_addrs()).await;
+            nodes.push(dht);
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        for dht in nodes.iter().chain([&first]) {
+            let found = dht.nodes();
+            assert!(found.iter().any(|(_, addr)| addr.is_ipv4()));
+            assert!(found.iter().any(|(_, addr)| addr.is_ipv6()));
+        }
+        assert_eq!(first.nodes().len(), 10);
+
+        // Announces reach both families, and so do lookups.
+        let info_hash = InfoHash::from([6; 20]);
+        nodes[0].announce(&info_hash, Some(6881), false).await;
+        let mut peers = nodes[4].get_peers(&info_hash).await;
+        peers.sort();
+        let expected: Vec<SocketAddr> = vec![
+            "127.0.0.1:6881".parse().unwrap(),
+            "[::1]:6881".parse().unwrap(),
+        ];
+        assert_eq!(peers, expected);
+
+        // An IPv4 querier can ask for IPv6 nodes, and is told its address.
+        let from: SocketAddr = "127.0.0.1:9".parse().unwrap();
+        let mut find = Query::FindNode {
+            target: NodeId::random(),
+        }
+        .to_message(&NodeId::random(), b"aa");
+        find.args.as_mut().unwrap().want = Some(vec![ByteBuf::from(b"n6".to_vec())]);
+        let reply = first.answer(from, &find);
+        let response = reply.response.unwrap();
+        assert!(response.nodes.is_none());
+        assert!(response.nodes().iter().all(|(_, addr)| addr.is_ipv6()));
+        assert_eq!(response.nodes().len(), 5);
+        assert_eq!(reply.ip.unwrap().to_vec(), addr_to_bytes(&from));
+    }
+
+    #[tokio::test]
+    async fn moves_to_an_id_valid_for_our_address() {
+        let external_ip = ExternalIp::default();
+        let (ours, theirs) = (
+            bind(&["127.0.0.1:0"], external_ip.clone()).await,
+            node().await,
+        );
+        ours.query(theirs.local_addr().unwrap(), Query::Ping)
+            .await
+            .unwrap();
+        let ip = "124.31.75.21".parse().unwrap();
+        external_ip.vote(Voter::Peer("198.51.100.1".parse().unwrap()), ip);
+        external_ip.vote(Voter::Tracker("udp://t.example:6969".into()), ip);
+        ours.check_ids();
+        assert!(security::is_valid(&ours.id(), ip));
+        assert_eq!(ours.nodes().len(), 1);
+        // Nodes that start out knowing the address use it straight away.
+        let fresh = bind(&["127.0.0.1:0"], external_ip).await;
+        assert!(security::is_valid(&fresh.id(), ip));
+    }
+
+    #[tokio::test]
+    async fn scrapes_samples_and_crawls_read_only() {
+        let nodes = network(6).await;
+        let info_hash = InfoHash::from([4; 20]);
+        nodes[1].announce(&info_hash, Some(6881), true).await;
+        nodes[2].announce(&info_hash, Some(6882), false).await;
+        // Every node is on one IP, which both seeds and downloads.
+        let size = SwarmSize {
+            seeds: 1,
+            downloaders: 1,
+        };
+        assert_eq!(nodes[3].scrape(&info_hash).await, size);
+        assert_eq!(
+            nodes[4].sample(&NodeId::random()).await,
+            vec![info_hash.clone()]
+        );
+        let samples = nodes[0]
+            .sample_node(nodes[5].local_addr().unwrap(), &NodeId::random())
+            .await
+            .unwrap();
+        assert_eq!((samples.info_hashes, samples.num), (vec![info_hash], 1));
+
+        let config = DhtConfig {
+            read_only: true,
+            ..Default::default()
+        };
+        let crawler = Dht::bind(
+            &["127.0.0.1:0".parse().unwrap()],
+            &config,
+            ExternalIp::default(),
+        )
+        .await
+        .unwrap();
+        crawler
+            .bootstrap(vec![nodes[0].local_addr().unwrap()])
+            .await;
+        assert_eq!(crawler.nodes().len(), 6);
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        // Nobody adds a node that won't answer them.
+        let addr = crawler.local_addr().unwrap();
+        for dht in &nodes {
+            assert!(dht.nodes().iter().all(|(_, node)| *node != addr));
+        }
+        assert!(matches!(
+            nodes[0].query(addr, Query::Ping).await,
+            Err(DhtError::Timeout)
+        ));
+    }
+}
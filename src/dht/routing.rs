@@ -0,0 +1,339 @@
+//! The Kademlia routing table: for each bit of distance from our ID, up to
+//! [`K`] nodes that have answered us.
+
+use std::fmt;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use log::warn;
+use sea_orm::{ActiveValue, DatabaseConnection, DbErr, EntityTrait};
+use tokio::time::Instant;
+
+use crate::db::{self, dht_node};
+use crate::torrent::InfoHash;
+
+/// Nodes per bucket, and how many closest nodes a lookup settles on.
+pub(crate) const K: usize = 8;
+/// Queries a node may fail in a row before another can take its place.
+const MAX_FAILURES: u32 = 2;
+/// Buckets nobody has been added to for this long are looked up afresh.
+pub(crate) const REFRESH_INTERVAL: Duration = Duration::from_secs(15 * 60);
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub(crate) struct NodeId([u8; 20]);
+
+impl NodeId {
+    pub(crate) fn random() -> Self {
+        Self(rand::random())
+    }
+
+    pub(crate) fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        Some(Self(bytes.try_into().ok()?))
+    }
+
+    pub(crate) fn as_bytes(&self) -> &[u8; 20] {
+        &self.0
+    }
+
+    /// The XOR metric: compared as big-endian numbers, smaller is closer.
+    pub(crate) fn distance(&self, other: &NodeId) -> [u8; 20] {
+        let mut distance = [0; 20];
+        for (d, (a, b)) in distance.iter_mut().zip(self.0.iter().zip(other.0)) {
+            *d = a ^ b;
+        }
+        distance
+    }
+
+    fn leading_zeros(distance: &[u8; 20]) -> usize {
+        distance
+            .iter()
+            .position(|b| *b != 0)
+            .map_or(160, |i| i * 8 + distance[i].leading_zeros() as usize)
+    }
+}
+
+impl From<[u8; 20]> for NodeId {
+    fn from(bytes: [u8; 20]) -> Self {
+        Self(bytes)
+    }
+}
+
+impl From<&InfoHash> for NodeId {
+    fn from(info_hash: &InfoHash) -> Self {
+        Self(*info_hash.as_array())
+    }
+}
+
+impl fmt::Debug for NodeId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", InfoHash::from(self.0))
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Entry {
+    id: NodeId,
+    addr: SocketAddr,
+    failures: u32,
+}
+
+#[derive(Debug)]
+pub(crate) struct RoutingTable {
+    own: NodeId,
+    /// Bucket `i` holds nodes whose distance from us has `i` leading zero
+    /// bits.
+    buckets: Vec<Vec<Entry>>,
+    changed: Vec<Instant>,
+}
+
+impl RoutingTable {
+    pub(crate) fn new(own: NodeId, now: Instant) -> Self {
+        Self {
+            own,
+            buckets: vec![Vec::new(); 160],
+            changed: vec![now; 160],
+        }
+    }
+
+    pub(crate) fn own(&self) -> NodeId {
+        self.own
+    }
+
+    /// Moves the table to a new ID of ours, keeping the nodes that fit.
+    pub(crate) fn set_own(&mut self, own: NodeId, now: Instant) {
+        let entries: Vec<_> = self.buckets.iter().flatten().cloned().collect();
+        *self = Self::new(own, now);
+        for entry in entries {
+            self.insert(entry.id, entry.addr, now);
+        }
+    }
+
+    fn bucket(&self, id: &NodeId) -> Option<usize> {
+        let zeros = NodeId::leading_zeros(&self.own.distance(id));
+        (zeros < 160).then_some(zeros)
+    }
+
+    #[cfg(test)]
+    pub(crate) fn len(&self) -> usize {
+        self.buckets.iter().map(Vec::len).sum()
+    }
+
+    pub(crate) fn contains(&self, id: &NodeId) -> bool {
+        self.bucket(id)
+            .is_some_and(|i| self.buckets[i].iter().any(|e| &e.id == id))
+    }
+
+    /// Whether a node with this ID would be taken in.
+    pub(crate) fn has_room(&self, id: &NodeId) -> bool {
+        self.bucket(id).is_some_and(|i| {
+            let bucket = &self.buckets[i];
+            bucket.len() < K || bucket.iter().any(|e| e.failures >= MAX_FAILURES)
+        })
+    }
+
+    /// Records that a node answered us. It's added if its bucket has room or
+    /// holds a node that stopped answering.
+    pub(crate) fn insert(&mut self, id: NodeId, addr: SocketAddr, now: Instant) -> bool {
+        let Some(i) = self.bucket(&id) else {
+            return false;
+        };
+        let bucket = &mut self.buckets[i];
+        if let Some(entry) = bucket.iter_mut().find(|e| e.id == id) {
+            // A known ID from a new address isn't trusted to have moved.
+            if entry.addr == addr {
+                entry.failures = 0;
+            }
+            return entry.addr == addr;
+        }
+        let entry = Entry {
+            id,
+            addr,
+            failures: 0,
+        };
+        if bucket.len() < K {
+            bucket.push(entry);
+        } else if let Some(bad) = bucket.iter_mut().find(|e| e.failures >= MAX_FAILURES) {
+            *bad = entry;
+        } else {
+            return false;
+        }
+        self.changed[i] = now;
+        true
+    }
+
+    /// Records that the node at `addr` didn't answer.
+    pub(crate) fn failed(&mut self, addr: &SocketAddr) {
+        for entry in self.buckets.iter_mut().flatten() {
+            if &entry.addr == addr {
+                entry.failures += 1;
+            }
+        }
+    }
+
+    /// The `n` nodes closest to `target`, closest first, preferring nodes
+    /// that answer.
+    pub(crate) fn closest(&self, target: &NodeId, n: usize) -> Vec<(NodeId, SocketAddr)> {
+        let mut nodes: Vec<_> = self.buckets.iter().flatten().collect();
+        nodes.sort_by_key(|e| (e.failures >= MAX_FAILURES, e.id.distance(target)));
+        nodes.iter().take(n).map(|e| (e.id, e.addr)).collect()
+    }
+
+    pub(crate) fn nodes(&self) -> Vec<(NodeId, SocketAddr)> {
+        self.buckets
+            .iter()
+            .flatten()
+            .map(|e| (e.id, e.addr))
+            .collect()
+    }
+
+    /// A random ID in each bucket that needs refreshing. Buckets closer than
+    /// any node we know are left alone, as nobody there can be found.
+    pub(crate) fn stale(&mut self, now: Instant) -> Vec<NodeId> {
+        let deepest = self.buckets.iter().rposition(|b| !b.is_empty());
+        let Some(deepest) = deepest else {
+            return Vec::new();
+        };
+        let mut targets = Vec::new();
+        for i in 0..=deepest {
+            if now.duration_since(self.changed[i]) >= REFRESH_INTERVAL {
+                self.changed[i] = now;
+                targets.push(self.random_in(i));
+            }
+        }
+        targets
+    }
+
+    /// A random ID sharing the first `i` bits with ours, and not the next.
+    fn random_in(&self, i: usize) -> NodeId {
+        let mut id = NodeId::random().0;
+        for bit in 0..=i {
+            let (byte, mask) = (bit / 8, 0x80 >> (bit % 8));
+            let ours = self.own.0[byte] & mask;
+            let flip = if bit == i { mask } else { 0 };
+            id[byte] = (id[byte] & !mask) | (ours ^ flip);
+        }
+        NodeId(id)
+    }
+}
+
+/// Keeps the routing table's nodes in the database, so the next run can
+/// bootstrap from them rather than only the routers.
+#[derive(Clone)]
+pub(crate) struct NodeStore {
+    db: DatabaseConnection,
+}
+
+impl NodeStore {
+    pub(crate) fn new(db: DatabaseConnection) -> Self {
+        Self { db }
+    }
+
+    pub(crate) async fn load(&self) -> Result<Vec<(NodeId, SocketAddr)>, DbErr> {
+        Ok(dht_node::Entity::find()
+            .all(&self.db)
+            .await?
+            .into_iter()
+            .filter_map(|model| {
+                let id = NodeId::from_bytes(&model.id)?;
+                Some((id, model.addr.parse().ok()?))
+            })
+            .collect())
+    }
+
+    /// Replaces what was stored with `nodes`.
+    pub(crate) async fn save(&self, nodes: &[(NodeId, SocketAddr)]) -> Result<(), DbErr> {
+        dht_node::Entity::delete_many().exec(&self.db).await?;
+        if nodes.is_empty() {
+            return Ok(());
+        }
+        let now = db::now();
+        dht_node::Entity::insert_many(nodes.iter().map(|(id, addr)| dht_node::ActiveModel {
+            addr: ActiveValue::Set(addr.to_string()),
+            id: ActiveValue::Set(id.as_bytes().to_vec()),
+            saved_at: ActiveValue::Set(now),
+        }))
+        .exec(&self.db)
+        .await?;
+        Ok(())
+    }
+
+    /// Saves `nodes`, logging rather than failing, since a stale table only
+    /// costs a slower bootstrap.
+    pub(crate) async fn save_or_warn(&self, nodes: &[(NodeId, SocketAddr)]) {
+        if let Err(e) = self.save(nodes).await {
+            warn!("Couldn't save DHT nodes: {e}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use tokio::time::Instant;
+
+    use super::{NodeId, NodeStore, RoutingTable, K, REFRESH_INTERVAL};
+
+    fn id(first: u8) -> NodeId {
+        let mut bytes = [0; 20];
+        bytes[0] = first;
+        NodeId::from(bytes)
+    }
+
+    fn addr(n: u16) -> SocketAddr {
+        SocketAddr::from(([10, 0, 0, 1], n))
+    }
+
+    #[test]
+    fn buckets_fill_and_replace_failing_nodes() {
+        let now = Instant::now();
+        let mut table = RoutingTable::new(id(0), now);
+        assert!(!table.insert(id(0), addr(1), now));
+        // Everything with the top bit set shares bucket 0.
+        for n in 0..K as u8 {
+            assert!(table.insert(id(0x80 | n), addr(n.into()), now));
+        }
+        assert!(!table.has_room(&id(0xff)));
+        assert!(!table.insert(id(0xff), addr(100), now));
+        assert!(table.insert(id(0x40), addr(101), now));
+
+        table.failed(&addr(3));
+        table.failed(&addr(3));
+        assert!(table.insert(id(0xff), addr(100), now));
+        assert!(!table.contains(&id(0x83)));
+        assert_eq!(table.len(), K + 1);
+
+        assert_eq!(
+            table.closest(&id(0x41), 2),
+            vec![(id(0x40), addr(101)), (id(0xff), addr(100))]
+        );
+    }
+
+    #[test]
+    fn refreshes_buckets_left_alone() {
+        let now = Instant::now();
+        let mut table = RoutingTable::new(id(0), now);
+        table.insert(id(0x20), addr(1), now);
+        assert!(table.stale(now).is_empty());
+        let targets = table.stale(now + REFRESH_INTERVAL);
+        // Buckets 0 to 2, down to the deepest one with a node.
+        assert_eq!(targets.len(), 3);
+        for (i, target) in targets.iter().enumerate() {
+            assert_eq!(table.bucket(target), Some(i));
+        }
+        assert!(table.stale(now + REFRESH_INTERVAL).is_empty());
+    }
+
+    #[tokio::test]
+    async fn store_round_trip() {
+        let db = crate::db::connect("sqlite::memory:").await.unwrap();
+        let store = NodeStore::new(db);
+        let nodes = vec![
+            (id(1), addr(1)),
+            (NodeId::random(), "[::1]:6881".parse().unwrap()),
+        ];
+        store.save(&nodes).await.unwrap();
+        store.save(&nodes[1..]).await.unwrap();
+        assert_eq!(store.load().await.unwrap(), nodes[1..]);
+    }
+}
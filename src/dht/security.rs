@@ -0,0 +1,114 @@
+//! BEP 42: node IDs tied to the node's IP address, so an attacker can't pick
+//! IDs next to a target from a handful of addresses. The first 21 bits of
+//! an ID come from a CRC32-C of the masked IP and a 3-bit random number,
+//! which goes in the last byte.
+//! See: http://www.bittorrent.org/beps/bep_0042.html
+
+use std::net::IpAddr;
+
+use super::routing::NodeId;
+
+const V4_MASK: [u8; 4] = [0x03, 0x0f, 0x3f, 0xff];
+const V6_MASK: [u8; 8] = [0x01, 0x03, 0x07, 0x0f, 0x1f, 0x3f, 0x7f, 0xff];
+
+/// A random ID that's valid for `ip`.
+pub(crate) fn generate(ip: IpAddr) -> NodeId {
+    let mut id: [u8; 20] = rand::random();
+    let crc = prefix(ip, id[19] & 0x07);
+    id[0] = (crc >> 24) as u8;
+    id[1] = (crc >> 16) as u8;
+    id[2] = ((crc >> 8) as u8 & 0xf8) | (id[2] & 0x07);
+    NodeId::from(id)
+}
+
+/// Whether `id` may be used from `ip`. Nodes on local networks can't know
+/// their public address, so they may use any ID.
+pub(crate) fn is_valid(id: &NodeId, ip: IpAddr) -> bool {
+    if is_exempt(ip) {
+        return true;
+    }
+    let id = id.as_bytes();
+    let crc = prefix(ip, id[19] & 0x07);
+    id[0] == (crc >> 24) as u8
+        && id[1] == (crc >> 16) as u8
+        && id[2] & 0xf8 == (crc >> 8) as u8 & 0xf8
+}
+
+fn prefix(ip: IpAddr, r: u8) -> u32 {
+    let mut masked = match ip {
+        IpAddr::V4(ip) => masked(&ip.octets(), &V4_MASK),
+        IpAddr::V6(ip) => masked(&ip.octets()[..8], &V6_MASK),
+    };
+    masked[0] |= r << 5;
+    crc32c(&masked)
+}
+
+fn masked(ip: &[u8], mask: &[u8]) -> Vec<u8> {
+    ip.iter().zip(mask).map(|(b, m)| b & m).collect()
+}
+
+fn is_exempt(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => ip.is_private() || ip.is_loopback() || ip.is_link_local(),
+        IpAddr::V6(ip) => {
+            let unique_local = ip.segments()[0] & 0xfe00 == 0xfc00;
+            let link_local = ip.segments()[0] & 0xffc0 == 0xfe80;
+            ip.is_loopback() || unique_local || link_local
+        }
+    }
+}
+
+/// CRC32-C (Castagnoli), bit by bit: it only ever sees a few bytes.
+fn crc32c(bytes: &[u8]) -> u32 {
+    let mut crc = !0_u32;
+    for byte in bytes {
+        crc ^= u32::from(*byte);
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0x82f6_3b78 & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use super::{generate, is_valid};
+    use crate::dht::routing::NodeId;
+
+    #[test]
+    fn matches_the_bep_vectors() {
+        // The IP, the random byte the ID ends in, and the first three bytes.
+        for (ip, r, prefix) in [
+            ("124.31.75.21", 1, [0x5f, 0xbf, 0xb8]),
+            ("21.75.31.124", 86, [0x5a, 0x3c, 0xe8]),
+            ("65.23.51.170", 22, [0xa5, 0xd4, 0x30]),
+            ("84.124.73.14", 65, [0x1b, 0x03, 0x20]),
+            ("43.213.53.83", 90, [0xe5, 0x6f, 0x68]),
+        ] {
+            let ip: IpAddr = ip.parse().unwrap();
+            let mut id = [0; 20];
+            id[..3].copy_from_slice(&prefix);
+            id[19] = r;
+            assert!(is_valid(&NodeId::from(id), ip), "{ip}");
+            id[0] ^= 1;
+            assert!(!is_valid(&NodeId::from(id), ip), "{ip}");
+        }
+    }
+
+    #[test]
+    fn generates_valid_ids_and_exempts_local_nodes() {
+        for ip in ["124.31.75.21", "2001:db8::1"] {
+            let ip: IpAddr = ip.parse().unwrap();
+            assert!(is_valid(&generate(ip), ip));
+        }
+        let mut id = [0; 20];
+        id[..3].copy_from_slice(&[0x5f, 0xbf, 0xb8]);
+        id[19] = 1;
+        let id = NodeId::from(id);
+        assert!(!is_valid(&id, "21.75.31.124".parse().unwrap()));
+        assert!(is_valid(&id, "192.168.1.1".parse().unwrap()));
+        assert!(is_valid(&id, "::1".parse().unwrap()));
+    }
+}
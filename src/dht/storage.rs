@@ -0,0 +1,308 @@
+//! What other nodes leave with us: the peers announced for each torrent, the
+//! BEP 44 items put here, and the tokens that prove an announcing or putting
+//! node got our answer to its `get_peers` or `get`.
+
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+use rand::seq::IteratorRandom;
+use sha1::{Digest, Sha1};
+use tokio::time::Instant;
+
+use super::bloom::BloomFilter;
+use super::item::Item;
+use super::krpc::{CAS_MISMATCH, GENERIC_ERROR, SEQ_TOO_OLD};
+use super::routing::NodeId;
+use crate::torrent::InfoHash;
+
+/// Tokens stay good for between one and two of these.
+const TOKEN_ROTATION: Duration = Duration::from_secs(5 * 60);
+/// Announced peers are forgotten unless announced again within this long.
+const PEER_TTL: Duration = Duration::from_secs(30 * 60);
+/// Peers in a `get_peers` answer, which keeps it within one datagram.
+pub(crate) const MAX_VALUES: usize = 50;
+const MAX_TORRENTS: usize = 2000;
+const MAX_PEERS_PER_TORRENT: usize = 500;
+/// Info hashes in a `sample_infohashes` answer.
+pub(crate) const MAX_SAMPLES: usize = 20;
+/// How long a sample is handed out before another is drawn.
+pub(crate) const SAMPLE_INTERVAL: Duration = Duration::from_secs(10 * 60);
+/// Items are forgotten unless put again within this long.
+const ITEM_TTL: Duration = Duration::from_secs(2 * 60 * 60);
+const MAX_ITEMS: usize = 1000;
+
+#[derive(Debug)]
+pub(crate) struct Tokens {
+    secret: [u8; 20],
+    previous: [u8; 20],
+    rotated_at: Instant,
+}
+
+impl Tokens {
+    pub(crate) fn new(now: Instant) -> Self {
+        Self {
+            secret: rand::random(),
+            previous: rand::random(),
+            rotated_at: now,
+        }
+    }
+
+    fn rotate(&mut self, now: Instant) {
+        if now.duration_since(self.rotated_at) >= TOKEN_ROTATION {
+            self.previous = std::mem::replace(&mut self.secret, rand::random());
+            self.rotated_at = now;
+        }
+    }
+
+    pub(crate) fn issue(&mut self, ip: IpAddr, now: Instant) -> Vec<u8> {
+        self.rotate(now);
+        token(&self.secret, ip)
+    }
+
+    pub(crate) fn validate(&mut self, ip: IpAddr, token: &[u8], now: Instant) -> bool {
+        self.rotate(now);
+        [&self.secret, &self.previous]
+            .iter()
+            .any(|secret| self::token(secret, ip) == token)
+    }
+}
+
+fn token(secret: &[u8; 20], ip: IpAddr) -> Vec<u8> {
+    let mut hasher = Sha1::new();
+    match ip {
+        IpAddr::V4(ip) => hasher.update(ip.octets()),
+        IpAddr::V6(ip) => hasher.update(ip.octets()),
+    }
+    hasher.update(secret);
+    hasher.finalize()[..8].to_vec()
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct PeerStore {
+    /// Each peer, with whether it's a seed and when it announced.
+    torrents: HashMap<InfoHash, HashMap<SocketAddr, (bool, Instant)>>,
+    sampled: Option<(Vec<InfoHash>, Instant)>,
+}
+
+impl PeerStore {
+    pub(crate) fn announce(
+        &mut self,
+        info_hash: InfoHash,
+        peer: SocketAddr,
+        seed: bool,
+        now: Instant,
+    ) {
+        if !self.torrents.contains_key(&info_hash) && self.torrents.len() >= MAX_TORRENTS {
+            self.expire(now);
+            if self.torrents.len() >= MAX_TORRENTS {
+                return;
+            }
+        }
+        let peers = self.torrents.entry(info_hash).or_default();
+        if peers.len() < MAX_PEERS_PER_TORRENT || peers.contains_key(&peer) {
+            peers.insert(peer, (seed, now));
+        }
+    }
+
+    /// Up to [`MAX_VALUES`] of the peers announced for `info_hash`, picked at
+    /// random so every peer gets handed out.
+    pub(crate) fn peers(&mut self, info_hash: &InfoHash, now: Instant) -> Vec<SocketAddr> {
+        let Some(peers) = self.torrents.get_mut(info_hash) else {
+            return Vec::new();
+        };
+        peers.retain(|_, (_, at)| now.duration_since(*at) < PEER_TTL);
+        peers
+            .keys()
+            .copied()
+            .choose_multiple(&mut rand::thread_rng(), MAX_VALUES)
+    }
+
+    /// BEP 33: bloom filters of the IPs seeding `info_hash` and of the ones
+    /// downloading it, unless nobody announced it.
+    pub(crate) fn scrape(
+        &mut self,
+        info_hash: &InfoHash,
+        now: Instant,
+    ) -> Option<(BloomFilter, BloomFilter)> {
+        let peers = self.torrents.get_mut(info_hash)?;
+        peers.retain(|_, (_, at)| now.duration_since(*at) < PEER_TTL);
+        if peers.is_empty() {
+            return None;
+        }
+        let (mut seeds, mut downloaders) = (BloomFilter::default(), BloomFilter::default());
+        for (peer, (seed, _)) in peers.iter() {
+            match seed {
+                true => seeds.insert(&peer.ip()),
+                false => downloaders.insert(&peer.ip()),
+            }
+        }
+        Some((seeds, downloaders))
+    }
+
+    /// BEP 51: up to [`MAX_SAMPLES`] of the info hashes we have peers for,
+    /// picked at random every [`SAMPLE_INTERVAL`], and how many there are in
+    /// all.
+    pub(crate) fn sample(&mut self, now: Instant) -> (Vec<InfoHash>, usize) {
+        self.expire(now);
+        match &self.sampled {
+            Some((sample, at)) if now.duration_since(*at) < SAMPLE_INTERVAL => {
+                (sample.clone(), self.torrents.len())
+            }
+            _ => {
+                let sample = self
+                    .torrents
+                    .keys()
+                    .cloned()
+                    .choose_multiple(&mut rand::thread_rng(), MAX_SAMPLES);
+                self.sampled = Some((sample.clone(), now));
+                (sample, self.torrents.len())
+            }
+        }
+    }
+
+    fn expire(&mut self, now: Instant) {
+        self.torrents.retain(|_, peers| {
+            peers.retain(|_, (_, at)| now.duration_since(*at) < PEER_TTL);
+            !peers.is_empty()
+        });
+    }
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct ItemStore {
+    items: HashMap<NodeId, (Item, Instant)>,
+}
+
+impl ItemStore {
+    pub(crate) fn get(&mut self, target: &NodeId, now: Instant) -> Option<&Item> {
+        self.items
+            .retain(|_, (_, at)| now.duration_since(*at) < ITEM_TTL);
+        self.items.get(target).map(|(item, _)| item)
+    }
+
+    /// Stores `item`, or says why not as a KRPC error. A mutable item only
+    /// replaces an older version, and only the one `cas` names if given.
+    pub(crate) fn put(
+        &mut self,
+        item: Item,
+        cas: Option<i64>,
+        now: Instant,
+    ) -> Result<(), (i64, String)> {
+        let target = item.target();
+        if let Some((current, _)) = self.items.get(&target) {
+            if let (Some(stored), Some(new)) = (current.seq(), item.seq()) {
+                if cas.is_some_and(|cas| cas != stored) {
+                    return Err((
+                        CAS_MISMATCH,
+                        "CAS mismatch, re-read value and try again".into(),
+                    ));
+                }
+                if new < stored || (new == stored && item != *current) {
+                    return Err((SEQ_TOO_OLD, "sequence number less than current".into()));
+                }
+            }
+        } else if self.items.len() >= MAX_ITEMS {
+            self.items
+                .retain(|_, (_, at)| now.duration_since(*at) < ITEM_TTL);
+            if self.items.len() >= MAX_ITEMS {
+                return Err((GENERIC_ERROR, "storage full".into()));
+            }
+        }
+        self.items.insert(target, (item, now));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use ed25519_dalek::SigningKey;
+    use serde_bencode::value::Value;
+    use tokio::time::Instant;
+
+    use super::{
+        ItemStore, PeerStore, Tokens, ITEM_TTL, MAX_SAMPLES, MAX_VALUES, PEER_TTL, TOKEN_ROTATION,
+    };
+    use crate::dht::item::Item;
+    use crate::dht::krpc::{CAS_MISMATCH, SEQ_TOO_OLD};
+    use crate::torrent::InfoHash;
+
+    #[test]
+    fn tokens_outlive_one_rotation() {
+        let now = Instant::now();
+        let mut tokens = Tokens::new(now);
+        let (ours, theirs) = ("10.0.0.1".parse().unwrap(), "10.0.0.2".parse().unwrap());
+        let token = tokens.issue(ours, now);
+        assert!(tokens.validate(ours, &token, now));
+        assert!(!tokens.validate(theirs, &token, now));
+        assert!(tokens.validate(ours, &token, now + TOKEN_ROTATION));
+        assert!(!tokens.validate(ours, &token, now + TOKEN_ROTATION * 2));
+    }
+
+    #[test]
+    fn peers_expire_and_are_capped() {
+        let now = Instant::now();
+        let info_hash = InfoHash::from([1; 20]);
+        let mut store = PeerStore::default();
+        for port in 0..100 {
+            store.announce(
+                info_hash.clone(),
+                SocketAddr::from(([10, 0, 0, 1], port)),
+                false,
+                now,
+            );
+        }
+        assert_eq!(store.peers(&info_hash, now).len(), MAX_VALUES);
+        assert!(store.peers(&InfoHash::from([2; 20]), now).is_empty());
+        assert!(store.peers(&info_hash, now + PEER_TTL).is_empty());
+    }
+
+    #[test]
+    fn scrapes_and_samples_torrents() {
+        let now = Instant::now();
+        let mut store = PeerStore::default();
+        for n in 0..30 {
+            let info_hash = InfoHash::from([n; 20]);
+            for (ip, seed) in [([10, 0, 0, 1], true), ([10, 0, 0, 2], false)] {
+                store.announce(info_hash.clone(), SocketAddr::from((ip, 6881)), seed, now);
+            }
+        }
+        let (seeds, downloaders) = store.scrape(&InfoHash::from([1; 20]), now).unwrap();
+        assert_eq!(seeds.estimate().round(), 1.0);
+        assert_eq!(downloaders.estimate().round(), 1.0);
+        assert!(store.scrape(&InfoHash::from([99; 20]), now).is_none());
+
+        let (sample, num) = store.sample(now);
+        assert_eq!((sample.len(), num), (MAX_SAMPLES, 30));
+        assert_eq!(store.sample(now).0, sample);
+        assert_eq!(store.sample(now + PEER_TTL), (Vec::new(), 0));
+    }
+
+    #[test]
+    fn mutable_items_only_move_forwards() {
+        let now = Instant::now();
+        let key = SigningKey::from_bytes(&[3; 32]);
+        let version = |seq, n| Item::mutable(&key, Vec::new(), seq, &Value::Int(n));
+        let mut store = ItemStore::default();
+        let target = version(1, 1).target();
+        store.put(version(1, 1), None, now).unwrap();
+        store.put(version(1, 1), None, now).unwrap();
+        assert_eq!(
+            store.put(version(1, 2), None, now).unwrap_err().0,
+            SEQ_TOO_OLD
+        );
+        assert_eq!(
+            store.put(version(2, 2), Some(0), now).unwrap_err().0,
+            CAS_MISMATCH
+        );
+        store.put(version(2, 2), Some(1), now).unwrap();
+        assert_eq!(
+            store.put(version(1, 1), None, now).unwrap_err().0,
+            SEQ_TOO_OLD
+        );
+        assert_eq!(store.get(&target, now), Some(&version(2, 2)));
+        assert_eq!(store.get(&target, now + ITEM_TTL), None);
+    }
+}
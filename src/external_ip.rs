@@ -0,0 +1,292 @@
+//! Works out our public address from what others tell us: trackers can
+//! return `external ip` (BEP 24) and peers send `yourip` in the extension
+//! handshake (BEP 10). Each voter gets one vote per address family, and the
+//! address with the most votes wins once voters on two networks agree.
+//! See: http://www.bittorrent.org/beps/bep_0024.html
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::{Arc, Mutex};
+
+use log::info;
+use url::Url;
+
+/// Only the most recent voters are remembered, so a changed address wins
+/// out once enough of them have noticed.
+const MAX_VOTERS: usize = 64;
+/// Votes for an address must come from this many networks before we take it.
+const MIN_NETWORKS: usize = 2;
+
+/// Who told us our address. Peers and DHT nodes are keyed by IP rather than
+/// socket address so one host can't stuff the ballot.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) enum Voter {
+    Tracker(String),
+    Peer(IpAddr),
+    Node(IpAddr),
+    /// A NAT gateway we mapped a port on, by its own address.
+    Gateway(IpAddr),
+}
+
+impl Voter {
+    /// Where the voter is: a /24 for IPv4 and a /48 for IPv6, or the
+    /// tracker's host.
+    fn network(&self) -> Network {
+        match self {
+            Voter::Tracker(url) => Network::Tracker(
+                Url::parse(url)
+                    .ok()
+                    .and_then(|url| url.host_str().map(str::to_owned))
+                    .unwrap_or_else(|| url.clone()),
+            ),
+            Voter::Peer(ip) | Voter::Node(ip) | Voter::Gateway(ip) => {
+                Network::Addr(match canonical(*ip) {
+                    IpAddr::V4(ip) => IpAddr::V4((u32::from(ip) & !0xff).into()),
+                    IpAddr::V6(ip) => IpAddr::V6((u128::from(ip) & !(u128::MAX >> 48)).into()),
+                })
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Network {
+    Tracker(String),
+    Addr(IpAddr),
+}
+
+/// A shared handle to the session's external address estimate.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ExternalIp {
+    inner: Arc<Mutex<Ballots>>,
+}
+
+#[derive(Debug, Default)]
+struct Ballots {
+    v4: Ballot,
+    v6: Ballot,
+}
+
+#[derive(Debug, Default)]
+struct Ballot {
+    votes: HashMap<Voter, IpAddr>,
+    /// Voters from oldest to newest vote.
+    order: VecDeque<Voter>,
+    consensus: Option<IpAddr>,
+}
+
+impl Ballot {
+    fn vote(&mut self, voter: Voter, addr: IpAddr) -> bool {
+        if self.votes.insert(voter.clone(), addr).is_some() {
+            self.order.retain(|v| v != &voter);
+        }
+        self.order.push_back(voter);
+        while self.order.len() > MAX_VOTERS {
+            if let Some(oldest) = self.order.pop_front() {
+                self.votes.remove(&oldest);
+            }
+        }
+
+        let mut tally: HashMap<IpAddr, (usize, HashSet<Network>)> = HashMap::new();
+        for (voter, addr) in &self.votes {
+            let (count, networks) = tally.entry(*addr).or_default();
+            *count += 1;
+            networks.insert(voter.network());
+        }
+        let current = self
+            .consensus
+            .and_then(|addr| tally.get(&addr).map(|(count, _)| *count));
+        // The DHT node ID is derived from our address, so one voter, or a
+        // few on one network, can't settle it alone.
+        let best = tally
+            .into_iter()
+            .filter(|(_, (_, networks))| networks.len() >= MIN_NETWORKS)
+            .map(|(addr, (count, _))| (addr, count))
+            .max_by_key(|&(_, count)| count);
+        match (best, current) {
+            // The current estimate keeps its place on a tie.
+            (Some((_, count)), Some(current)) if current >= count => false,
+            (Some((addr, _)), _) => {
+                self.consensus = Some(addr);
+                true
+            }
+            (None, _) => false,
+        }
+    }
+}
+
+impl ExternalIp {
+    /// Records that `voter` sees us as `addr`. Addresses that can't be our
+    /// public one (private, loopback and so on) are ignored.
+    pub(crate) fn vote(&self, voter: Voter, addr: IpAddr) {
+        let addr = canonical(addr);
+        if !is_public(&addr) {
+            return;
+        }
+        let mut ballots = self.inner.lock().unwrap();
+        let ballot = match addr {
+            IpAddr::V4(_) => &mut ballots.v4,
+            IpAddr::V6(_) => &mut ballots.v6,
+        };
+        if ballot.vote(voter, addr) {
+            info!("External address is now {addr}");
+        }
+    }
+
+    pub(crate) fn ipv4(&self) -> Option<Ipv4Addr> {
+        match self.inner.lock().unwrap().v4.consensus {
+            Some(IpAddr::V4(ip)) => Some(ip),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn ipv6(&self) -> Option<Ipv6Addr> {
+        match self.inner.lock().unwrap().v6.consensus {
+            Some(IpAddr::V6(ip)) => Some(ip),
+            _ => None,
+        }
+    }
+
+    /// Our address in the same family as `like`, if we know it.
+    pub(crate) fn matching(&self, like: &IpAddr) -> Option<IpAddr> {
+        match like {
+            IpAddr::V4(_) => self.ipv4().map(IpAddr::V4),
+            IpAddr::V6(_) => self.ipv6().map(IpAddr::V6),
+        }
+    }
+}
+
+/// Reads an address in its compact form, as `external ip` and `yourip` carry it.
+pub(crate) fn ip_from_bytes(bytes: &[u8]) -> Option<IpAddr> {
+    match bytes.len() {
+        4 => Some(IpAddr::V4(<[u8; 4]>::try_from(bytes).ok()?.into())),
+        16 => Some(IpAddr::V6(<[u8; 16]>::try_from(bytes).ok()?.into())),
+        _ => None,
+    }
+}
+
+/// The compact form of `ip`, for sending `external ip` and `yourip`.
+pub(crate) fn ip_to_bytes(ip: &IpAddr) -> Vec<u8> {
+    match ip {
+        IpAddr::V4(ip) => ip.octets().to_vec(),
+        IpAddr::V6(ip) => ip.octets().to_vec(),
+    }
+}
+
+/// IPv4 addresses can arrive mapped into IPv6.
+fn canonical(addr: IpAddr) -> IpAddr {
+    match addr {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(addr, IpAddr::V4),
+        IpAddr::V4(_) => addr,
+    }
+}
+
+fn is_public(addr: &IpAddr) -> bool {
+    match addr {
+        IpAddr::V4(ip) => {
+            !(ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_multicast()
+                || ip.is_broadcast())
+        }
+        IpAddr::V6(ip) => {
+            let unique_local = ip.segments()[0] & 0xfe00 == 0xfc00;
+            let link_local = ip.segments()[0] & 0xffc0 == 0xfe80;
+            !(ip.is_loopback()
+                || ip.is_unspecified()
+                || ip.is_multicast()
+                || unique_local
+                || link_local)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr};
+
+    use super::{ip_from_bytes, ExternalIp, Voter};
+
+    /// A peer on a network of its own.
+    fn peer(n: u8) -> Voter {
+        Voter::Peer(IpAddr::V4(Ipv4Addr::new(198, 51, n, 1)))
+    }
+
+    #[test]
+    fn majority_wins_per_family() {
+        let estimate = ExternalIp::default();
+        let ours: IpAddr = "203.0.113.5".parse().unwrap();
+        let other: IpAddr = "203.0.113.6".parse().unwrap();
+        let ours6: IpAddr = "2001:db8::5".parse().unwrap();
+
+        estimate.vote(peer(1), other);
+        estimate.vote(peer(2), ours);
+        estimate.vote(Voter::Tracker("udp://t.example:6969".into()), ours);
+        estimate.vote(peer(3), ours6);
+        estimate.vote(Voter::Peer("2001:db8:1::1".parse().unwrap()), ours6);
+        assert_eq!(estimate.matching(&ours), Some(ours));
+        assert_eq!(estimate.matching(&ours6), Some(ours6));
+    }
+
+    #[test]
+    fn each_voter_counts_once() {
+        let estimate = ExternalIp::default();
+        let ours: IpAddr = "203.0.113.5".parse().unwrap();
+        let other: IpAddr = "203.0.113.6".parse().unwrap();
+
+        estimate.vote(peer(1), ours);
+        estimate.vote(peer(2), ours);
+        estimate.vote(peer(3), other);
+        estimate.vote(peer(4), other);
+        estimate.vote(peer(4), other);
+        estimate.vote(peer(4), other);
+        // A tie keeps the address we already had.
+        assert_eq!(estimate.matching(&ours), Some(ours));
+
+        estimate.vote(peer(5), other);
+        assert_eq!(estimate.matching(&ours), Some(other));
+    }
+
+    #[test]
+    fn needs_voters_on_two_networks() {
+        let estimate = ExternalIp::default();
+        let ours: IpAddr = "203.0.113.5".parse().unwrap();
+
+        estimate.vote(peer(1), ours);
+        assert_eq!(estimate.ipv4(), None);
+        // A neighbour of the first voter, or the same host as a DHT node,
+        // doesn't make a second network.
+        estimate.vote(Voter::Peer("198.51.1.2".parse().unwrap()), ours);
+        estimate.vote(Voter::Node("198.51.1.1".parse().unwrap()), ours);
+        assert_eq!(estimate.ipv4(), None);
+
+        estimate.vote(Voter::Tracker("udp://t.example:6969".into()), ours);
+        assert_eq!(estimate.matching(&ours), Some(ours));
+    }
+
+    #[test]
+    fn ignores_non_public_addresses() {
+        let estimate = ExternalIp::default();
+        for addr in ["10.0.0.1", "127.0.0.1", "192.168.1.2", "fd00::1", "fe80::1"] {
+            estimate.vote(peer(1), addr.parse().unwrap());
+        }
+        assert_eq!(estimate.ipv4(), None);
+        assert_eq!(estimate.ipv6(), None);
+
+        estimate.vote(peer(1), "::ffff:203.0.113.5".parse().unwrap());
+        estimate.vote(peer(2), "203.0.113.5".parse().unwrap());
+        assert_eq!(estimate.ipv4(), Some(Ipv4Addr::new(203, 0, 113, 5)));
+    }
+
+    #[test]
+    fn reads_compact_addresses() {
+        assert_eq!(
+            ip_from_bytes(&[203, 0, 113, 5]),
+            Some("203.0.113.5".parse().unwrap())
+        );
+        assert_eq!(ip_from_bytes(&[0; 16]), Some("::".parse().unwrap()));
+        assert_eq!(ip_from_bytes(&[1, 2, 3]), None);
+    }
+}
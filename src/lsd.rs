@@ -0,0 +1,386 @@
+//! Local Service Discovery: peers on the same network announce the torrents
+//! they have to a multicast group, in HTTP-like datagrams, and find each
+//! other without a tracker. Each client tags its announces with a cookie so
+//! it can skip its own when they're looped back.
+//! See: http://www.bittorrent.org/beps/bep_0014.html
+
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use log::{debug, info};
+use serde::Deserialize;
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::UdpSocket;
+use tokio::time::Instant;
+
+use crate::peer::manager::{ConnectionManager, PeerSource};
+use crate::torrent::InfoHash;
+
+pub(crate) const V4_GROUP: SocketAddr =
+    SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(239, 192, 152, 143), 6771));
+pub(crate) const V6_GROUP: SocketAddr = SocketAddr::V6(SocketAddrV6::new(
+    Ipv6Addr::new(0xff15, 0, 0, 0, 0, 0, 0xefc0, 0x988f),
+    6771,
+    0,
+    0,
+));
+
+/// How often each torrent is announced.
+const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+/// A host's announces of a torrent are only heard once per this long, so a
+/// chatty or looping client can't flood us with the same peer.
+const MIN_ANNOUNCE_GAP: Duration = Duration::from_secs(60);
+const MAX_DATAGRAM: usize = 1400;
+
+#[derive(Debug, Clone, Deserialize)]
+pub(crate) struct LsdConfig {
+    #[serde(default = "default_enabled")]
+    pub(crate) enabled: bool,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+impl Default for LsdConfig {
+    fn default() -> Self {
+        Self {
+            enabled: default_enabled(),
+        }
+    }
+}
+
+/// A `BT-SEARCH` datagram.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Announce {
+    /// Where the announcing peer listens.
+    pub(crate) port: u16,
+    pub(crate) info_hashes: Vec<InfoHash>,
+    pub(crate) cookie: Option<String>,
+}
+
+impl Announce {
+    /// The datagram sent to `group`.
+    pub(crate) fn to_bytes(&self, group: &SocketAddr) -> Vec<u8> {
+        let mut message = format!(
+            "BT-SEARCH * HTTP/1.1\r\nHost: {group}\r\nPort: {}\r\n",
+            self.port
+        );
+        for info_hash in &self.info_hashes {
+            message.push_str(&format!("Infohash: {}\r\n", info_hash.to_hex_string()));
+        }
+        if let Some(cookie) = &self.cookie {
+            message.push_str(&format!("cookie: {cookie}\r\n"));
+        }
+        message.push_str("\r\n\r\n");
+        message.into_bytes()
+    }
+
+    /// Reads a datagram, skipping headers we don't know. Announces without
+    /// a port or an info hash are no use and read as None.
+    pub(crate) fn parse(bytes: &[u8]) -> Option<Self> {
+        let text = std::str::from_utf8(bytes).ok()?;
+        let mut lines = text.split("\r\n");
+        if lines.next()? != "BT-SEARCH * HTTP/1.1" {
+            return None;
+        }
+        let mut port = None;
+        let mut info_hashes = Vec::new();
+        let mut cookie = None;
+        for line in lines.take_while(|line| !line.is_empty()) {
+            let Some((name, value)) = line.split_once(':') else {
+                continue;
+            };
+            let value = value.trim();
+            match name.trim().to_ascii_lowercase().as_str() {
+                "port" => port = value.parse().ok(),
+                "infohash" => info_hashes.extend(InfoHash::from_hex(value).ok()),
+                "cookie" => cookie = Some(value.to_string()),
+                _ => {}
+            }
+        }
+        if info_hashes.is_empty() {
+            return None;
+        }
+        Some(Self {
+            port: port?,
+            info_hashes,
+            cookie,
+        })
+    }
+}
+
+struct Shared {
+    /// A socket in each multicast group, with the group.
+    sockets: Vec<(UdpSocket, SocketAddr)>,
+    cookie: String,
+    /// The port we accept peers on, which we announce.
+    port: u16,
+    torrents: Mutex<HashMap<InfoHash, Arc<ConnectionManager>>>,
+    /// When each host was last heard announcing each torrent.
+    heard: Mutex<HashMap<(IpAddr, InfoHash), Instant>>,
+}
+
+/// Local Service Discovery, shared by every torrent in the session.
+#[derive(Clone)]
+pub(crate) struct Lsd {
+    shared: Arc<Shared>,
+}
+
+/// Forgets a torrent once its [`Lsd::track`] stops.
+struct Tracked {
+    lsd: Lsd,
+    info_hash: InfoHash,
+}
+
+impl Drop for Tracked {
+    fn drop(&mut self) {
+        let mut torrents = self.lsd.shared.torrents.lock().unwrap();
+        torrents.remove(&self.info_hash);
+    }
+}
+
+impl Lsd {
+    /// Joins the BEP 14 groups, to announce peers listening on `port`.
+    pub(crate) async fn bind(port: u16) -> io::Result<Self> {
+        Self::bind_groups(&[V4_GROUP, V6_GROUP], port).await
+    }
+
+    /// Joins whichever of `groups` we can; hosts without IPv6 make do with
+    /// IPv4.
+    pub(crate) async fn bind_groups(groups: &[SocketAddr], port: u16) -> io::Result<Self> {
+        let mut sockets = Vec::new();
+        let mut last_error = None;
+        for group in groups {
+            match join(group) {
+                Ok(udp) => sockets.push((udp, *group)),
+                Err(e) => {
+                    debug!("Couldn't join local service discovery group {group}: {e}");
+                    last_error = Some(e);
+                }
+            }
+        }
+        if sockets.is_empty() {
+            return Err(last_error.unwrap_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidInput, "no group to join")
+            }));
+        }
+        let lsd = Self {
+            shared: Arc::new(Shared {
+                sockets,
+                cookie: format!("{:016x}", rand::random::<u64>()),
+                port,
+                torrents: Mutex::default(),
+                heard: Mutex::default(),
+            }),
+        };
+        for index in 0..lsd.shared.sockets.len() {
+            let receiver = lsd.clone();
+            tokio::spawn(async move {
+                let udp = &receiver.shared.sockets[index].0;
+                let mut buf = vec![0; MAX_DATAGRAM];
+                loop {
+                    match udp.recv_from(&mut buf).await {
+                        Ok((len, from)) => receiver.handle(from, &buf[..len]),
+                        Err(e) => debug!("Local service discovery receive failed: {e}"),
+                    }
+                }
+            });
+        }
+        info!("Local service discovery started");
+        Ok(lsd)
+    }
+
+    /// Takes a datagram from a group: queues the peer it announces on each
+    /// of its torrents we have, unless it's our own or heard too recently.
+    pub(crate) fn handle(&self, from: SocketAddr, bytes: &[u8]) {
+        let Some(announce) = Announce::parse(bytes) else {
+            debug!("Ignoring malformed local service discovery announce from {from}");
+            return;
+        };
+        if announce.cookie.as_ref() == Some(&self.shared.cookie) {
+            return;
+        }
+        // The announcer's address, with its interface if link-local.
+        let mut peer = from;
+        peer.set_port(announce.port);
+
+        let now = Instant::now();
+        let torrents = self.shared.torrents.lock().unwrap();
+        let mut heard = self.shared.heard.lock().unwrap();
+        heard.retain(|_, at| now.duration_since(*at) < MIN_ANNOUNCE_GAP);
+        for info_hash in announce.info_hashes {
+            let Some(manager) = torrents.get(&info_hash) else {
+                continue;
+            };
+            let key = (from.ip(), info_hash);
+            if heard.contains_key(&key) {
+                continue;
+            }
+            heard.insert(key, now);
+            manager.add_peers(PeerSource::Lsd, [peer]);
+        }
+    }
+
+    /// Announces `manager`'s torrent every [`ANNOUNCE_INTERVAL`], queuing
+    /// the local peers that announce it on `manager` until stopped.
+    pub(crate) async fn track(self, manager: Arc<ConnectionManager>) {
+        let info_hash = manager.info_hash().clone();
+        self.shared
+            .torrents
+            .lock()
+            .unwrap()
+            .insert(info_hash.clone(), manager);
+        let _tracked = Tracked {
+            lsd: self.clone(),
+            info_hash: info_hash.clone(),
+        };
+        loop {
+            self.announce(&info_hash).await;
+            tokio::time::sleep(ANNOUNCE_INTERVAL).await;
+        }
+    }
+
+    async fn announce(&self, info_hash: &InfoHash) {
+        let announce = Announce {
+            port: self.shared.port,
+            info_hashes: vec![info_hash.clone()],
+            cookie: Some(self.shared.cookie.clone()),
+        };
+        for (udp, group) in &self.shared.sockets {
+            if let Err(e) = udp.send_to(&announce.to_bytes(group), group).await {
+                debug!("Couldn't announce {info_hash} to {group}: {e}");
+            }
+        }
+    }
+}
+
+/// A socket in `group`, which every client on the host shares.
+fn join(group: &SocketAddr) -> io::Result<UdpSocket> {
+    let socket = Socket::new(
+        Domain::for_address(*group),
+        Type::DGRAM,
+        Some(Protocol::UDP),
+    )?;
+    socket.set_reuse_address(true)?;
+    match group.ip() {
+        IpAddr::V4(ip) => {
+            let any = SocketAddr::from((Ipv4Addr::UNSPECIFIED, group.port()));
+            socket.bind(&any.into())?;
+            socket.join_multicast_v4(&ip, &Ipv4Addr::UNSPECIFIED)?;
+        }
+        IpAddr::V6(ip) => {
+            socket.set_only_v6(true)?;
+            let any = SocketAddr::from((Ipv6Addr::UNSPECIFIED, group.port()));
+            socket.bind(&any.into())?;
+            socket.join_multicast_v6(&ip, 0)?;
+        }
+    }
+    socket.set_nonblocking(true)?;
+    UdpSocket::from_std(socket.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use super::{Announce, Lsd, V4_GROUP, V6_GROUP};
+    use crate::peer::manager::{ConnectionConfig, ConnectionLimits, ConnectionManager, PeerSource};
+    use crate::torrent::{InfoHash, PeerId};
+
+    const INFO_HASH: [u8; 20] = [0xab; 20];
+
+    fn manager() -> Arc<ConnectionManager> {
+        Arc::new(ConnectionManager::new(
+            InfoHash::from(INFO_HASH),
+            PeerId::try_from("-CH0001-lsdlsdlsdlsd").unwrap(),
+            ConnectionLimits::new(ConnectionConfig::default()),
+        ))
+    }
+
+    /// The groups on a port of their own, away from real clients.
+    fn groups() -> Vec<SocketAddr> {
+        let port = 20000 + rand::random::<u16>() % 20000;
+        [V4_GROUP, V6_GROUP]
+            .into_iter()
+            .map(|mut group| {
+                group.set_port(port);
+                group
+            })
+            .collect()
+    }
+
+    #[test]
+    fn reads_what_it_writes() {
+        let datagram = b"BT-SEARCH * HTTP/1.1\r\nHost: 239.192.152.143:6771\r\nPort: 6881\r\n\
+            Infohash: ABABABABABABABABABABABABABABABABABABABAB\r\nX-Other: 1\r\n\r\n\r\n";
+        let announce = Announce::parse(datagram).unwrap();
+        let expected = Announce {
+            port: 6881,
+            info_hashes: vec![InfoHash::from(INFO_HASH)],
+            cookie: None,
+        };
+        assert_eq!(announce, expected);
+
+        let with_cookie = Announce {
+            cookie: Some("c00k1e".into()),
+            ..expected
+        };
+        let bytes = with_cookie.to_bytes(&V6_GROUP);
+        assert!(bytes.starts_with(b"BT-SEARCH * HTTP/1.1\r\nHost: [ff15::efc0:988f]:6771\r\n"));
+        assert_eq!(Announce::parse(&bytes), Some(with_cookie));
+        assert_eq!(
+            Announce::parse(b"M-SEARCH * HTTP/1.1\r\nPort: 1\r\n\r\n"),
+            None
+        );
+        assert_eq!(
+            Announce::parse(b"BT-SEARCH * HTTP/1.1\r\nPort: 6881\r\n\r\n\r\n"),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn finds_local_peers_but_not_itself() {
+        let groups = groups();
+        let (ours, theirs) = (manager(), manager());
+        let us = Lsd::bind_groups(&groups, 7001).await.unwrap();
+        let them = Lsd::bind_groups(&groups, 7002).await.unwrap();
+        tokio::spawn(them.clone().track(theirs.clone()));
+        tokio::spawn(us.clone().track(ours.clone()));
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        // Announces come from whatever address reaches the group.
+        let probe = std::net::UdpSocket::bind("0.0.0.0:0").unwrap();
+        probe.connect(groups[0]).unwrap();
+        let ip = probe.local_addr().unwrap().ip();
+        let (us_addr, them_addr) = (SocketAddr::new(ip, 7001), SocketAddr::new(ip, 7002));
+        assert_eq!(ours.source(&them_addr), Some(PeerSource::Lsd));
+        assert_eq!(theirs.source(&us_addr), Some(PeerSource::Lsd));
+        assert_eq!(ours.source(&us_addr), None);
+    }
+
+    #[tokio::test]
+    async fn hears_each_host_once_in_a_while() {
+        let lsd = Lsd::bind_groups(&groups(), 7001).await.unwrap();
+        let ours = manager();
+        tokio::spawn(lsd.clone().track(ours.clone()));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let from: SocketAddr = "192.168.1.20:6771".parse().unwrap();
+        let announce = |port| Announce {
+            port,
+            info_hashes: vec![InfoHash::from(INFO_HASH)],
+            cookie: Some("theirs".into()),
+        };
+        lsd.handle(from, &announce(6881).to_bytes(&V4_GROUP));
+        lsd.handle(from, &announce(6882).to_bytes(&V4_GROUP));
+        let peer = |port| SocketAddr::new(from.ip(), port);
+        assert_eq!(ours.source(&peer(6881)), Some(PeerSource::Lsd));
+        assert_eq!(ours.source(&peer(6882)), None);
+    }
+}
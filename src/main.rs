@@ -1,28 +1,106 @@
-#![feature(async_fn_in_trait)]
-#![feature(addr_parse_ascii)]
+// deku's derives work out field sizes without `div_ceil`.
+#![allow(clippy::manual_div_ceil)]
 
-// mod net;
+mod db;
+mod deku_ext;
+mod dht;
+mod external_ip;
+mod lsd;
+mod net;
+mod peer;
+mod piece;
+mod portmap;
 mod torrent;
 mod tracker;
+mod utp;
 
 use std::fs;
 use std::io::Read;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
 
 use clap::Parser;
 use config::{Config, File, FileFormat};
-use log::{debug, info};
+use ed25519_dalek::SigningKey;
+use log::{debug, info, warn};
+use sea_orm::DatabaseConnection;
 use serde_bencode::de;
+use serde_bencode::value::Value;
+use tokio::sync::{mpsc, watch, Mutex};
 
-use crate::torrent::{PeerId, Torrent};
-use crate::tracker::{AnnounceEvent, HTTPTracker, Tracker};
+use crate::dht::routing::{NodeId, NodeStore};
+use crate::dht::{Dht, DhtConfig};
+use crate::external_ip::ExternalIp;
+use crate::lsd::{Lsd, LsdConfig};
+use crate::net::UdpMux;
+use crate::peer::choker::{Choker, ChokerConfig, UploadSlots};
+use crate::peer::extension::holepunch::HolepunchSwarm;
+use crate::peer::extension::pex::PexSwarm;
+use crate::peer::listener::PeerListener;
+use crate::peer::manager::{ConnectionConfig, ConnectionLimits, ConnectionManager, PeerSource};
+use crate::peer::swarm::Swarm;
+use crate::piece::picker::Priority;
+use crate::portmap::{PortMapConfig, PortMapper};
+use crate::torrent::magnet::{self, Magnet};
+use crate::torrent::{InfoHash, PeerId, Torrent};
+use crate::tracker::server::ServerConfig;
+use crate::tracker::{AnnounceEvent, TierManager};
+
+/// How often we log how the torrent is doing.
+const STATUS_INTERVAL: Duration = Duration::from_secs(60);
+/// Never announce more often than this, in seconds, whatever trackers ask.
+const MIN_REANNOUNCE: i64 = 30;
+/// How long trackers get to hear that we're stopping before we exit anyway.
+const STOP_TIMEOUT: Duration = Duration::from_secs(5);
 
 #[derive(clap::Parser)]
-#[clap(author, version, about, long_about = None)]
+#[clap(author, version, about, long_about = None, subcommand_negates_reqs = true)]
 struct Cli {
     #[clap(short = 'c', long = "config", value_name = "CONFIG")]
     config: String,
-    path: PathBuf,
+    #[clap(subcommand)]
+    command: Option<Command>,
+    #[clap(required = true)]
+    path: Option<PathBuf>,
+}
+
+#[derive(clap::Subcommand)]
+enum Command {
+    /// Run a BitTorrent tracker, configured by the `tracker` section of the config file
+    Tracker,
+    /// Query the DHT as a read-only node, configured by the `dht` section of the config file
+    Dht {
+        #[clap(subcommand)]
+        command: DhtCommand,
+    },
+}
+
+#[derive(clap::Subcommand)]
+enum DhtCommand {
+    /// Print info hashes sampled by the nodes closest to TARGET, or to a random ID
+    Sample { target: Option<String> },
+    /// Print info hashes sampled by the node at ADDR near TARGET, or a random ID
+    SampleNode {
+        addr: SocketAddr,
+        target: Option<String>,
+    },
+    /// Estimate how many IPs seed and download INFO_HASH
+    Scrape { info_hash: String },
+    /// Print the peers of INFO_HASH
+    Peers { info_hash: String },
+    /// Print the immutable item stored under TARGET
+    Get { target: String },
+    /// Store VALUE as an immutable item and print where it went
+    Put { value: String },
+    /// Point the mutable torrent of the ed25519 key SECRET, in hex, at INFO_HASH
+    Publish {
+        secret: String,
+        info_hash: String,
+        #[clap(long, default_value = "")]
+        salt: String,
+    },
 }
 
 #[tokio::main]
@@ -46,10 +124,158 @@ async fn main() {
     info!("Successfully loaded config file {}", args.config);
     debug!("{:#?}", config);
 
-    let mut file = fs::File::open(args.path).unwrap();
-    let mut buffer = Vec::new();
-    file.read_to_end(&mut buffer).unwrap();
-    let torrent = de::from_bytes::<Torrent>(&buffer).unwrap();
+    if let Some(Command::Tracker) = args.command {
+        let server_config = match config.get::<ServerConfig>("tracker") {
+            Err(e) => {
+                eprintln!("Couldn't parse tracker config: {e}");
+                std::process::exit(1);
+            }
+            Ok(c) => c,
+        };
+        if let Err(e) = tracker::server::run(server_config).await {
+            eprintln!("Tracker failed: {e}");
+            std::process::exit(1);
+        }
+        return;
+    }
+    if let Some(Command::Dht { command }) = args.command {
+        let mut dht_config = config.get::<DhtConfig>("dht").unwrap_or_default();
+        // Crawling shouldn't make us part of the network.
+        dht_config.read_only = true;
+        let sockets = match bind_udp(dht_config.port).await {
+            Err(e) => {
+                eprintln!("Couldn't start the DHT on port {}: {e}", dht_config.port);
+                std::process::exit(1);
+            }
+            Ok(sockets) => sockets,
+        };
+        let Some(dht) = start_dht(
+            dht_config,
+            &sockets,
+            Vec::new(),
+            None,
+            ExternalIp::default(),
+        )
+        .await
+        else {
+            std::process::exit(1);
+        };
+        let parse = |hex: &str| match InfoHash::from_hex(hex) {
+            Err(e) => {
+                eprintln!("Couldn't parse {hex}: {e}");
+                std::process::exit(1);
+            }
+            Ok(info_hash) => info_hash,
+        };
+        match command {
+            DhtCommand::Sample { target } => {
+                let target = target.map_or_else(NodeId::random, |t| NodeId::from(&parse(&t)));
+                for info_hash in dht.sample(&target).await {
+                    println!("{info_hash}");
+                }
+            }
+            DhtCommand::SampleNode { addr, target } => {
+                let target = target.map_or_else(NodeId::random, |t| NodeId::from(&parse(&t)));
+                match dht.sample_node(addr, &target).await {
+                    Err(e) => eprintln!("Couldn't sample {addr}: {e}"),
+                    Ok(samples) => {
+                        for info_hash in samples.info_hashes {
+                            println!("{info_hash}");
+                        }
+                        eprintln!(
+                            "{addr} knows {} info hashes, and samples anew in {}s",
+                            samples.num,
+                            samples.interval.as_secs()
+                        );
+                    }
+                }
+            }
+            DhtCommand::Scrape { info_hash } => {
+                let size = dht.scrape(&parse(&info_hash)).await;
+                println!("seeds: {}, downloaders: {}", size.seeds, size.downloaders);
+            }
+            DhtCommand::Peers { info_hash } => {
+                for peer in dht.get_peers(&parse(&info_hash)).await {
+                    println!("{peer}");
+                }
+            }
+            DhtCommand::Get { target } => {
+                match dht.get_immutable(&NodeId::from(&parse(&target))).await {
+                    None => eprintln!("Nothing is stored under {target}"),
+                    Some(Value::Bytes(bytes)) => println!("{}", String::from_utf8_lossy(&bytes)),
+                    Some(value) => println!("{value:?}"),
+                }
+            }
+            DhtCommand::Put { value } => {
+                match dht.put_immutable(&Value::Bytes(value.into_bytes())).await {
+                    Err(e) => eprintln!("Couldn't store the item: {e}"),
+                    Ok(target) => println!("{target:?}"),
+                }
+            }
+            DhtCommand::Publish {
+                secret,
+                info_hash,
+                salt,
+            } => {
+                let key = match magnet::parse_hex::<32>(&secret) {
+                    Err(e) => {
+                        eprintln!("Couldn't parse the secret key: {e}");
+                        std::process::exit(1);
+                    }
+                    Ok(secret) => SigningKey::from_bytes(&secret),
+                };
+                let info_hash = parse(&info_hash);
+                match dht.publish_torrent(&key, salt.as_bytes(), &info_hash).await {
+                    Err(e) => eprintln!("Couldn't publish {info_hash}: {e}"),
+                    Ok(seq) => println!("Published {info_hash} as version {seq}"),
+                }
+            }
+        }
+        return;
+    }
+
+    // Either a magnet link or the path of a torrent file.
+    let path = args.path.unwrap();
+    let (info_hash, publisher, trackers, nodes, private, left, torrent) =
+        match path.to_str().filter(|p| p.starts_with("magnet:")) {
+            Some(uri) => {
+                let magnet = match uri.parse::<Magnet>() {
+                    Err(e) => {
+                        eprintln!("Couldn't parse magnet link: {e}");
+                        std::process::exit(1);
+                    }
+                    Ok(m) => m,
+                };
+                let trackers = TierManager::from_urls(&[magnet.trackers]);
+                let publisher = magnet.public_key.map(|key| (key, magnet.salt));
+                // Until we have the metadata we can't tell what's left.
+                (
+                    magnet.info_hash,
+                    publisher,
+                    trackers,
+                    Vec::new(),
+                    false,
+                    0,
+                    None,
+                )
+            }
+            None => {
+                let mut file = fs::File::open(path).unwrap();
+                let mut buffer = Vec::new();
+                file.read_to_end(&mut buffer).unwrap();
+                let torrent = de::from_bytes::<Torrent>(&buffer).unwrap();
+                log_torrent(&torrent);
+                (
+                    Some(torrent.info().info_hash().unwrap()),
+                    None,
+                    TierManager::from_torrent(&torrent),
+                    torrent.nodes(),
+                    torrent.info().is_private(),
+                    torrent.info().total_length(),
+                    Some(torrent),
+                )
+            }
+        };
 
     // info!("{:?}", torrent.info());
 
@@ -58,111 +284,399 @@ async fn main() {
     //     torrent.info().info_hash().unwrap().to_hex_string()
     // );
 
-    let peerid = PeerId::try_from("ABCDEFGHIJKLMNOPQRST").unwrap();
-    let tracker =
-        // HTTPTracker::new(torrent.announce_list.as_ref().unwrap().list[0][0].as_str()).unwrap();
-        HTTPTracker::new(torrent.announce.as_ref().unwrap()).unwrap();
-    let peers = tracker
-        .get_peers(
-            torrent.info().info_hash().unwrap(),
-            peerid,
-            None,
-            6881,
-            0,
-            0,
-            torrent.info().length.unwrap().try_into().unwrap(),
-            AnnounceEvent::Started,
-        )
-        .await
-        .unwrap();
-    info!("{:#?}", peers);
-
-    // println!("{}", torrent.info().info_hash_hex());
-
-    // let port: u16 = config.get_int("port").unwrap().try_into().unwrap();
-    // let peer_id = {
-    //     let mut buf = [0_u8; 20];
-    //     buf.copy_from_slice(&config.get_string("peer_id").unwrap().as_bytes()[0..20]);
-    //     buf
-    // };
-
-    // let socket = UdpSocket::bind(format!("0.0.0.0:{port}")).await.unwrap();
-
-    // let addr = torrent.announce_addr().await.unwrap().next().unwrap();
-    // println!("{addr}");
-    // let tx_id = rand::random();
-    // println!("{tx_id}");
-    // socket
-    //     .send_to(
-    //         ConnectRequest::new(tx_id).to_bytes().unwrap().as_ref(),
-    //         addr,
-    //     )
-    //     .await
-    //     .unwrap();
-    // println!("Sent connect request");
-
-    // let mut buf = [0_u8; u16::MAX as usize];
-    // let (len, addr) = socket.recv_from(&mut buf).await.unwrap();
-    // let (_, connect_response) = ConnectResponse::from_bytes((&buf[0..len], 0)).unwrap();
-    // println!("{connect_response:?}");
-
-    // socket
-    //     .send_to(
-    //         AnnounceRequest::new(
-    //             connect_response.connection_id,
-    //             rand::random(),
-    //             &torrent.info().info_hash(),
-    //             &peer_id,
-    //             0,
-    //             0,
-    //             0,
-    //             net::udp::AnnounceEvent::None,
-    //             None,
-    //             rand::random(),
-    //             None,
-    //             port,
-    //         )
-    //         .to_bytes()
-    //         .unwrap()
-    //         .as_ref(),
-    //         addr,
-    //     )
-    //     .await
-    //     .unwrap();
-
-    // let (len, _addr) = socket.recv_from(&mut buf).await.unwrap();
-    // let (_, announce_response) = AnnounceResponseV4::from_bytes((&buf[0..len], 0)).unwrap();
-    // println!("{announce_response:?}");
-
-    // let (_, connection_id) = recv_connect_response(&socket).await.unwrap();
-    // println!("{}", connection_id);
-
-    // socket
-    //     .send_to(
-    //         &announce_response(
-    //             connection_id,
-    //             rand::random(),
-    //             &torrent.info().info_hash(),
-    //             &peer_id,
-    //             0,
-    //             0,
-    //             0,
-    //             None,
-    //             Option::<std::net::Ipv4Addr>::None,
-    //             rand::random(),
-    //             None,
-    //             port,
-    //         ),
-    //         addr,
-    //     )
-    //     .await
-    //     .unwrap();
-
-    // let mut buf = BytesMut::new();
-    // buf.put_bytes(0, 4096);
-    // let (l, addr) = socket.recv_from(buf.as_mut()).await.unwrap();
-    // println!("{} {}", l, addr);
-    // println!("{:?}", buf.as_ref());
-
-    // let _ = send_announce_request(&socket, connection_id).await;
+    let peerid = PeerId::new();
+    let database = config
+        .get_string("database")
+        .unwrap_or_else(|_| "sqlite://chitauri.db?mode=rwc".to_string());
+    let external_ip = ExternalIp::default();
+    let mut trackers = trackers.with_external_ip(external_ip.clone());
+    let db = match db::connect(&database).await {
+        Ok(db) => Some(db),
+        Err(e) => {
+            eprintln!("Couldn't open database {database}, tracker health won't be kept: {e}");
+            None
+        }
+    };
+    if let Some(db) = &db {
+        trackers = trackers.with_database(db.clone()).await;
+    }
+    let connection_config = config
+        .get::<ConnectionConfig>("connections")
+        .unwrap_or_default();
+    let limits = ConnectionLimits::new(connection_config);
+    let port = config
+        .get_int("port")
+        .ok()
+        .and_then(|p| u16::try_from(p).ok())
+        .unwrap_or(6881);
+    let listener =
+        match PeerListener::bind(SocketAddr::from((Ipv4Addr::UNSPECIFIED, port)), &limits).await {
+            Err(e) => {
+                eprintln!("Couldn't listen on port {port}: {e}");
+                std::process::exit(1);
+            }
+            Ok(l) => l,
+        };
+    // Announce the port we actually got, which differs when `port` is 0.
+    let port = listener.local_addr().unwrap().port();
+    let routes = listener.routes();
+    // Peers reach us over IPv6 on the same port, when the host has it.
+    let listener_v6 =
+        match PeerListener::bind(SocketAddr::from((Ipv6Addr::UNSPECIFIED, port)), &limits).await {
+            Ok(l) => Some(l.with_routes(routes.clone())),
+            Err(e) => {
+                debug!("Couldn't listen on IPv6 port {port}: {e}");
+                None
+            }
+        };
+    // Trackers, the DHT and uTP share one UDP socket per family, on the
+    // port we listen on.
+    let mut limits = limits.with_utp(listener.utp());
+    let mut sockets = vec![listener.udp()];
+    tokio::spawn(listener.run());
+    if let Some(listener) = listener_v6 {
+        limits = limits.with_utp(listener.utp());
+        sockets.push(listener.udp());
+        tokio::spawn(listener.run());
+    }
+    let trackers = Arc::new(Mutex::new(trackers.with_udp_sockets(&sockets)));
+
+    let portmap_config = config.get::<PortMapConfig>("portmap").unwrap_or_default();
+    let mapper = match portmap_config.enabled {
+        true => Some(
+            PortMapper::start(
+                portmap::gateways(&portmap_config).await,
+                port,
+                Duration::from_secs(portmap_config.lease),
+                external_ip.clone(),
+            )
+            .await,
+        ),
+        false => None,
+    };
+    // Peers outside the NAT reach us on whatever port the gateway gave us.
+    let announced_port = mapper
+        .as_ref()
+        .and_then(PortMapper::external_port)
+        .unwrap_or(port);
+
+    let dht_config = config.get::<DhtConfig>("dht").unwrap_or_default();
+    let dht = match dht_config.enabled {
+        true => start_dht(dht_config, &sockets, nodes, db.clone(), external_ip.clone()).await,
+        false => None,
+    };
+    let lsd = match config.get::<LsdConfig>("lsd").unwrap_or_default().enabled {
+        true => match Lsd::bind(port).await {
+            Ok(lsd) => Some(lsd),
+            Err(e) => {
+                warn!("Couldn't start local service discovery: {e}");
+                None
+            }
+        },
+        false => None,
+    };
+
+    // Upload slots are shared by every torrent we serve.
+    let choker_config = config.get::<ChokerConfig>("choking").unwrap_or_default();
+    let upload_slots = UploadSlots::new(choker_config.upload_slots);
+    // One per file, in torrent order.
+    let file_priorities = config
+        .get::<Vec<Priority>>("file_priorities")
+        .unwrap_or_default();
+
+    // BEP 46 links name a publisher rather than a torrent: follow whatever
+    // it points at.
+    let mut updates = None;
+    let mut info_hash = match (info_hash, publisher, &dht) {
+        (Some(info_hash), _, _) => info_hash,
+        (None, Some((key, salt)), Some(dht)) => {
+            let mut receiver = dht.follow(key, salt);
+            let info_hash = match receiver.wait_for(Option::is_some).await {
+                Ok(info_hash) => info_hash.clone().unwrap(),
+                Err(_) => unreachable!("the follower runs while we listen"),
+            };
+            updates = Some(receiver);
+            info_hash
+        }
+        (None, _, _) => {
+            eprintln!("Mutable torrent links need the DHT");
+            std::process::exit(1);
+        }
+    };
+
+    loop {
+        // PEX peers we can't reach may be holepunched to through the
+        // peers we're connected to.
+        let holepunch = HolepunchSwarm::default();
+        let mut manager = ConnectionManager::new(info_hash.clone(), peerid.clone(), limits.clone());
+        if !private {
+            manager = manager.with_holepunch(holepunch.clone());
+        }
+        let manager = Arc::new(manager);
+        let mut tasks = vec![tokio::spawn(announce(
+            trackers.clone(),
+            manager.clone(),
+            peerid.clone(),
+            announced_port,
+            left,
+        ))];
+        // BEP 27: private torrents only get peers from their trackers.
+        if !private {
+            if let Some(dht) = &dht {
+                tasks.push(tokio::spawn(
+                    dht.clone().track(manager.clone(), announced_port),
+                ));
+            }
+            if let Some(lsd) = &lsd {
+                tasks.push(tokio::spawn(lsd.clone().track(manager.clone())));
+            }
+        }
+        // Each connection runs until its peer leaves, giving its slot back.
+        let (tx, rx) = mpsc::channel(16);
+        let choker = Choker::new(upload_slots.share(), choker_config.seeding);
+        let mut swarm = Swarm::new(manager.clone())
+            .with_choker(choker)
+            .with_port(announced_port)
+            .with_external_ip(external_ip.clone());
+        // Mutable torrents start from magnet links, so a torrent file's
+        // pieces are always the ones being swarmed.
+        if let Some(torrent) = &torrent {
+            swarm = swarm.with_pieces(torrent.info(), &file_priorities);
+        }
+        let pex = match &torrent {
+            Some(torrent) => PexSwarm::new(torrent.info()),
+            None => Some(PexSwarm::default()),
+        };
+        if let Some(pex) = pex {
+            swarm = swarm.with_pex(pex).with_holepunch(holepunch);
+        }
+        let swarm = Arc::new(swarm);
+        tasks.push(tokio::spawn(log_status(swarm.clone(), external_ip.clone())));
+        tasks.push(tokio::spawn(swarm.run(rx)));
+        routes.add(manager.clone(), tx.clone());
+        tasks.push(tokio::spawn(manager.run(tx)));
+
+        // Serve this torrent until we're interrupted or it moves.
+        let moved = tokio::select! {
+            _ = tokio::signal::ctrl_c() => None,
+            next = moved(&mut updates) => Some(next),
+        };
+        routes.remove(&info_hash);
+        for task in tasks {
+            task.abort();
+        }
+        stop(&trackers, &info_hash, &peerid, announced_port, left).await;
+        let Some(next) = moved else {
+            break;
+        };
+        info!("Mutable torrent moved from {info_hash} to {next}");
+        info_hash = next;
+    }
+    // Give the gateway its port back.
+    if let Some(mapper) = mapper {
+        mapper.shutdown().await;
+    }
+    std::process::exit(130);
+}
+
+/// Waits for a mutable torrent to point somewhere new. Torrents that can't
+/// move never do.
+async fn moved(updates: &mut Option<watch::Receiver<Option<InfoHash>>>) -> InfoHash {
+    let Some(updates) = updates.as_mut() else {
+        return std::future::pending().await;
+    };
+    if updates.changed().await.is_err() {
+        // The follower is gone, so the torrent stays where it is.
+        return std::future::pending().await;
+    }
+    updates.borrow_and_update().clone().unwrap()
+}
+
+/// Announces `manager`'s torrent to the trackers as started, then again
+/// whenever they next want to hear from us, handing it the peers they
+/// return.
+async fn announce(
+    trackers: Arc<Mutex<TierManager>>,
+    manager: Arc<ConnectionManager>,
+    peer_id: PeerId,
+    port: u16,
+    left: u64,
+) {
+    let mut event = AnnounceEvent::Started;
+    loop {
+        let mut tiers = trackers.lock().await;
+        match tiers
+            .announce(
+                manager.info_hash().clone(),
+                peer_id.clone(),
+                None,
+                port,
+                0,
+                0,
+                left,
+                event.clone(),
+            )
+            .await
+        {
+            Ok(resp) => manager.add_peers(PeerSource::Tracker, resp.peers),
+            // Trackerless torrents and magnets rely on the DHT.
+            Err(e) => info!("No peers from trackers: {e}"),
+        }
+        event = AnnounceEvent::Empty;
+        let Some(next) = tiers.next_announce() else {
+            return;
+        };
+        drop(tiers);
+        let wait = (next - db::now()).max(MIN_REANNOUNCE);
+        debug!("Announcing {} again in {wait}s", manager.info_hash());
+        tokio::time::sleep(Duration::from_secs(wait as u64)).await;
+    }
+}
+
+/// Tells the trackers we've stopped serving `info_hash`, giving up after
+/// [`STOP_TIMEOUT`] so that dead trackers don't hold up shutdown.
+async fn stop(
+    trackers: &Mutex<TierManager>,
+    info_hash: &InfoHash,
+    peer_id: &PeerId,
+    port: u16,
+    left: u64,
+) {
+    let mut tiers = trackers.lock().await;
+    if tiers.tiers().is_empty() {
+        return;
+    }
+    let stopped = tiers.announce(
+        info_hash.clone(),
+        peer_id.clone(),
+        None,
+        port,
+        0,
+        0,
+        left,
+        AnnounceEvent::Stopped,
+    );
+    match tokio::time::timeout(STOP_TIMEOUT, stopped).await {
+        Ok(Ok(_)) => debug!("Told the trackers we stopped {info_hash}"),
+        Ok(Err(e)) => debug!("Couldn't tell the trackers we stopped {info_hash}: {e}"),
+        Err(_) => debug!("Trackers took too long to hear we stopped {info_hash}"),
+    }
+}
+
+/// Logs what a torrent file says about where it came from.
+fn log_torrent(torrent: &Torrent) {
+    if let Some(created_by) = torrent.created_by() {
+        info!("Torrent created by {created_by}");
+    }
+    if let Some(date) = torrent.creation_date() {
+        info!("Torrent created at {date} (Unix time)");
+    }
+    if let Some(comment) = torrent.comment() {
+        info!("Torrent comment: {comment}");
+    }
+    if let Some(encoding) = torrent.encoding() {
+        debug!("Torrent strings are encoded as {encoding}");
+    }
+    if let Some(seeds) = torrent
+        .httpseeds()
+        .as_ref()
+        .filter(|seeds| !seeds.is_empty())
+    {
+        info!(
+            "Ignoring {} HTTP seeds, which we don't support",
+            seeds.len()
+        );
+    }
+}
+
+/// Logs our progress on `swarm`'s torrent, and the address peers see us
+/// at, every [`STATUS_INTERVAL`].
+async fn log_status(swarm: Arc<Swarm>, external_ip: ExternalIp) {
+    let mut interval = tokio::time::interval(STATUS_INTERVAL);
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        let ips: Vec<_> = [
+            external_ip.ipv4().map(IpAddr::V4),
+            external_ip.ipv6().map(IpAddr::V6),
+        ]
+        .into_iter()
+        .flatten()
+        .map(|ip| ip.to_string())
+        .collect();
+        if !ips.is_empty() {
+            info!("External address: {}", ips.join(", "));
+        }
+        let (Some(have), Some(stats)) = (swarm.bitfield(), swarm.verify_stats()) else {
+            continue;
+        };
+        info!(
+            "{}: have {}/{} pieces, {} failed their hash check, {} peers banned",
+            swarm.info_hash(),
+            have.count(),
+            have.len(),
+            stats.hash_failures,
+            stats.peers_banned
+        );
+        if let Some(peers) = swarm.rarest() {
+            info!("The rarest piece we want is on {peers} peers");
+        }
+    }
+}
+
+/// Binds UDP sockets on `port`: an IPv4 one, and an IPv6 one on the same
+/// port when the host has IPv6.
+async fn bind_udp(port: u16) -> std::io::Result<Vec<UdpMux>> {
+    let v4 = UdpMux::bind(SocketAddr::from((Ipv4Addr::UNSPECIFIED, port))).await?;
+    let port = v4.local_addr()?.port();
+    Ok([Some(v4), bind_v6(port).await]
+        .into_iter()
+        .flatten()
+        .collect())
+}
+
+async fn bind_v6(port: u16) -> Option<UdpMux> {
+    match UdpMux::bind(SocketAddr::from((Ipv6Addr::UNSPECIFIED, port))).await {
+        Ok(v6) => Some(v6),
+        Err(e) => {
+            debug!("Couldn't bind IPv6 UDP port {port}: {e}");
+            None
+        }
+    }
+}
+
+/// Joins the DHT on `sockets` through the configured routers, `nodes` from
+/// the torrent and the nodes saved last time, and keeps the routing table
+/// saved.
+async fn start_dht(
+    config: DhtConfig,
+    sockets: &[UdpMux],
+    nodes: Vec<String>,
+    db: Option<DatabaseConnection>,
+    external_ip: ExternalIp,
+) -> Option<Dht> {
+    let dht = match Dht::on(sockets, &config, external_ip) {
+        Ok(dht) => dht,
+        Err(e) => {
+            eprintln!("Couldn't start the DHT: {e}");
+            return None;
+        }
+    };
+    let store = db.map(NodeStore::new);
+    let mut addrs = dht::resolve(&[config.routers, nodes].concat()).await;
+    if let Some(store) = &store {
+        match store.load().await {
+            Ok(nodes) => addrs.extend(nodes.into_iter().map(|(_, addr)| addr)),
+            Err(e) => warn!("Couldn't load DHT nodes: {e}"),
+        }
+    }
+    dht.bootstrap(addrs).await;
+    info!(
+        "DHT node {:?} on {:?} knows {} nodes",
+        dht.id(),
+        dht.local_addrs(),
+        dht.nodes().len()
+    );
+    tokio::spawn(dht.clone().maintain(store));
+    Some(dht)
 }
@@ -1 +1,5 @@
-pub mod udp;
+//! Sockets shared between the parts of the client that speak over them.
+
+pub(crate) mod udp;
+
+pub(crate) use udp::UdpMux;
@@ -1,157 +1,353 @@
-use deku::prelude::*;
-use derive_builder::Builder;
+//! One UDP socket per listen address, shared by everything that speaks UDP
+//! to the swarm: the UDP tracker client, the DHT and uTP. Each datagram that
+//! arrives goes to whichever of them it's for, so there's a single port to
+//! forward and a single queue to bound.
 
-use crate::torrent::InfoHash;
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
 
-enum Action {
-    Connect = 0,
-    Announce = 1,
-    Scrape = 2,
+use log::debug;
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::UdpSocket;
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+
+use crate::utp::UtpSocket;
+
+const MAX_DATAGRAM: usize = 64 * 1024;
+/// DHT messages waiting to be handled. More than this are dropped, as the
+/// network would if we were slower to read them.
+const DHT_BACKLOG: usize = 256;
+/// The shortest uTP packet: a header with no payload.
+const UTP_HEADER_LEN: usize = 20;
+/// Datagrams a second we take from one address, and how many it may send in
+/// a burst. Enough for a fast uTP transfer, not for one host to drown out
+/// everyone else.
+const SOURCE_RATE: f64 = 10_000.0;
+const SOURCE_BURST: f64 = 1_000.0;
+/// How often addresses that have gone quiet are forgotten.
+const FORGET_AFTER: Duration = Duration::from_secs(60);
+
+/// Who a datagram that no tracker is waiting for belongs to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    /// KRPC messages are bencoded dictionaries: `d1:a...`, `d1:r...`, or
+    /// `d2:ip...` for replies that tell us our address. A `d` would be uTP
+    /// version 4, which there isn't.
+    Dht,
+    /// uTP packets start with the type in the high nibble and version 1 in
+    /// the low one.
+    Utp,
 }
 
-impl ConnectRequest {
-    pub(crate) fn new(transaction_id: u32) -> Self {
-        Self {
-            protocol_id: BITTORRENT_UDP_MAGIC,
-            action: Action::Connect as u32,
-            transaction_id,
+fn classify(bytes: &[u8]) -> Option<Kind> {
+    if bytes.first() == Some(&b'd') && bytes.last() == Some(&b'e') {
+        return Some(Kind::Dht);
+    }
+    match bytes.first() {
+        Some(first) if bytes.len() >= UTP_HEADER_LEN && first & 0x0f == 1 && first >> 4 <= 4 => {
+            Some(Kind::Utp)
         }
+        _ => None,
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, DekuRead, DekuWrite)]
-#[deku(endian = "big")]
-pub(crate) struct ConnectResponse {
-    #[deku(assert_eq = "Action::Connect as u32")]
-    action: u32,
-    transaction_id: u32,
-    pub connection_id: u64,
-}
-
-#[derive(Debug, Clone, PartialEq, Eq, DekuRead, DekuWrite)]
-#[deku(type = "u32", endian = "endian", ctx = "endian: deku::ctx::Endian")]
-pub(crate) enum AnnounceEvent {
-    None = 0,
-    Completed = 1,
-    Started = 2,
-    Stopped = 3,
+/// A token bucket per source address. Sources are keyed by IP, so a host
+/// can't get around its limit by changing ports.
+struct RateLimit {
+    buckets: HashMap<IpAddr, Bucket>,
+    pruned: Instant,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, DekuRead, DekuWrite, Builder)]
-#[deku(endian = "big")]
-pub(crate) struct AnnounceRequest {
-    connection_id: u64,
-    #[deku(assert_eq = "Action::Announce as u32")]
-    action: u32,
-    transaction_id: u32,
-    info_hash: [u8; 20],
-    peer_id: [u8; 20],
-    downloaded: u64,
-    left: u64,
-    uploaded: u64,
-    event: AnnounceEvent,
-    ip: [u8; 4],
-    key: u32,
-    num_want: i32,
-    port: u16,
+struct Bucket {
+    tokens: f64,
+    at: Instant,
 }
 
-impl AnnounceRequest {
-    #[allow(clippy::too_many_arguments)]
-    pub(crate) fn new(
-        connection_id: u64,
-        transaction_id: u32,
-        info_hash: &[u8; 20],
-        peer_id: &[u8; 20],
-        downloaded: u64,
-        left: u64,
-        uploaded: u64,
-        event: AnnounceEvent,
-        ip: Option<&[u8; 4]>,
-        key: u32,
-        num_want: Option<i32>,
-        port: u16,
-    ) -> Self {
+impl RateLimit {
+    fn new(now: Instant) -> Self {
         Self {
-            connection_id,
-            action: Action::Announce as u32,
-            transaction_id,
-            info_hash: *info_hash,
-            peer_id: *peer_id,
-            downloaded,
-            left,
-            uploaded,
-            event,
-            ip: *ip.unwrap_or(&[0; 4]),
-            key,
-            num_want: num_want.unwrap_or(-1),
-            port,
+            buckets: HashMap::new(),
+            pruned: now,
         }
     }
+
+    /// Whether to take a datagram from `ip`.
+    fn allow(&mut self, ip: IpAddr, now: Instant) -> bool {
+        if now.duration_since(self.pruned) >= FORGET_AFTER {
+            self.buckets
+                .retain(|_, bucket| now.duration_since(bucket.at) < FORGET_AFTER);
+            self.pruned = now;
+        }
+        let bucket = self.buckets.entry(ip).or_insert(Bucket {
+            tokens: SOURCE_BURST,
+            at: now,
+        });
+        let refill = now.duration_since(bucket.at).as_secs_f64() * SOURCE_RATE;
+        bucket.tokens = (bucket.tokens + refill).min(SOURCE_BURST);
+        bucket.at = now;
+        if bucket.tokens < 1.0 {
+            return false;
+        }
+        bucket.tokens -= 1.0;
+        true
+    }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, DekuRead, DekuWrite)]
-#[deku(endian = "endian", ctx = "endian: deku::ctx::Endian")]
-pub(crate) struct AnnounceResponsePeerV4 {
-    ip: [u8; 4],
-    port: u16,
+/// A tracker transaction is known by the tracker's address and the ID its
+/// response carries.
+type TransactionKey = (SocketAddr, u32);
+/// A datagram and who sent it.
+pub(crate) type Datagram = (SocketAddr, Vec<u8>);
+
+struct Shared {
+    udp: Arc<UdpSocket>,
+    /// A plain handle on the socket, for senders like uTP that can't wait
+    /// for the reactor to find it writable.
+    std: std::net::UdpSocket,
+    transactions: Mutex<HashMap<TransactionKey, oneshot::Sender<Vec<u8>>>>,
+    dht: Mutex<Option<mpsc::Sender<Datagram>>>,
+    utp: Mutex<Option<UtpSocket>>,
+    receiver: JoinHandle<()>,
 }
 
-impl AnnounceResponsePeerV4 {
-    pub(crate) fn new(ip: &[u8; 4], port: u16) -> Self {
-        Self { ip: *ip, port }
+impl Drop for Shared {
+    fn drop(&mut self) {
+        self.receiver.abort();
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, DekuRead, DekuWrite)]
-#[deku(endian = "big")]
-pub(crate) struct AnnounceResponseV4 {
-    #[deku(assert_eq = "Action::Announce as u32")]
-    action: u32,
-    transaction_id: u32,
-    interval: u32,
-    leechers: u32,
-    seeders: u32,
-    #[deku(until = "|_| true")]
-    peers: Vec<AnnounceResponsePeerV4>,
+/// A UDP socket that hands what it receives to the tracker client, the DHT
+/// and uTP. Clones share the socket, which closes when the last is dropped.
+#[derive(Clone)]
+pub(crate) struct UdpMux {
+    shared: Arc<Shared>,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, DekuRead, DekuWrite, Builder)]
-#[deku(endian = "big")]
-pub(crate) struct ScrapeRequest {
-    connection_id: u64,
-    #[deku(assert_eq = "Action::Scrape as u32")]
-    action: u32,
-    transaction_id: u32,
-    #[deku(until = "|_| true")]
-    info_hashes: Vec<InfoHash>,
+impl UdpMux {
+    /// Binds to `addr`. IPv6 sockets are kept to IPv6, so that the IPv4
+    /// one can have the same port.
+    pub(crate) async fn bind(addr: SocketAddr) -> io::Result<Self> {
+        let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+        if addr.is_ipv6() {
+            socket.set_only_v6(true)?;
+        }
+        socket.set_nonblocking(true)?;
+        socket.bind(&addr.into())?;
+        let std: std::net::UdpSocket = socket.into();
+        let udp = Arc::new(UdpSocket::from_std(std.try_clone()?)?);
+
+        let shared = Arc::new_cyclic(|weak: &Weak<Shared>| {
+            let (receiving, weak) = (udp.clone(), weak.clone());
+            let receiver = tokio::spawn(async move {
+                let mut buf = vec![0; MAX_DATAGRAM];
+                let mut limit = RateLimit::new(Instant::now());
+                loop {
+                    match receiving.recv_from(&mut buf).await {
+                        Ok((_, from)) if !limit.allow(from.ip(), Instant::now()) => {}
+                        Ok((len, from)) => match weak.upgrade() {
+                            Some(shared) => shared.route(from, &buf[..len]),
+                            None => break,
+                        },
+                        // Such as an ICMP error for something we sent one peer.
+                        Err(e) => debug!("UDP receive failed: {e}"),
+                    }
+                }
+            });
+            Shared {
+                udp,
+                std,
+                transactions: Mutex::default(),
+                dht: Mutex::default(),
+                utp: Mutex::default(),
+                receiver,
+            }
+        });
+        Ok(Self { shared })
+    }
+
+    pub(crate) fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.shared.udp.local_addr()
+    }
+
+    pub(crate) fn is_ipv4(&self) -> bool {
+        self.local_addr().map_or(true, |addr| addr.is_ipv4())
+    }
+
+    pub(crate) async fn send_to(&self, bytes: &[u8], to: SocketAddr) -> io::Result<usize> {
+        self.shared.udp.send_to(bytes, to).await
+    }
+
+    /// Sends without waiting: fails with `WouldBlock` if the socket has no
+    /// room.
+    pub(crate) fn send_now(&self, bytes: &[u8], to: SocketAddr) -> io::Result<usize> {
+        self.shared.std.send_to(bytes, to)
+    }
+
+    /// Takes over the DHT messages that arrive, replacing whoever had them.
+    pub(crate) fn dht(&self) -> mpsc::Receiver<Datagram> {
+        let (sender, receiver) = mpsc::channel(DHT_BACKLOG);
+        *self.shared.dht.lock().unwrap() = Some(sender);
+        receiver
+    }
+
+    /// Hands the uTP packets that arrive to `utp`.
+    pub(crate) fn set_utp(&self, utp: UtpSocket) {
+        *self.shared.utp.lock().unwrap() = Some(utp);
+    }
+
+    /// Waits for the tracker at `from` to answer `transaction_id`, until
+    /// the [`Transaction`] is dropped.
+    pub(crate) fn transaction(&self, from: SocketAddr, transaction_id: u32) -> Transaction {
+        let (sender, response) = oneshot::channel();
+        let key = (from, transaction_id);
+        self.shared.transactions.lock().unwrap().insert(key, sender);
+        Transaction {
+            shared: self.shared.clone(),
+            key,
+            response,
+        }
+    }
 }
 
-impl ScrapeRequest {
-    pub(crate) fn new(connection_id: u64, transaction_id: u32, info_hashes: Vec<InfoHash>) -> Self {
-        Self {
-            connection_id,
-            action: 2,
-            transaction_id,
-            info_hashes,
+impl Shared {
+    fn route(&self, from: SocketAddr, bytes: &[u8]) {
+        // Tracker responses carry the transaction ID after the action.
+        if let Some(id) = bytes.get(4..8) {
+            let key = (from, u32::from_be_bytes(id.try_into().unwrap()));
+            if let Some(sender) = self.transactions.lock().unwrap().remove(&key) {
+                let _ = sender.send(bytes.to_vec());
+                return;
+            }
+        }
+        match classify(bytes) {
+            Some(Kind::Dht) => {
+                let dht = self.dht.lock().unwrap();
+                if let Some(Err(e)) = dht.as_ref().map(|dht| dht.try_send((from, bytes.to_vec()))) {
+                    debug!("Dropped DHT message from {from}: {e}");
+                }
+            }
+            Some(Kind::Utp) => {
+                let utp = self.utp.lock().unwrap().clone();
+                if let Some(utp) = utp {
+                    utp.handle(from, bytes);
+                }
+            }
+            None => debug!("Ignored {} byte datagram from {from}", bytes.len()),
+        }
+    }
+}
+
+/// A tracker request waiting for its response.
+pub(crate) struct Transaction {
+    shared: Arc<Shared>,
+    key: TransactionKey,
+    response: oneshot::Receiver<Vec<u8>>,
+}
+
+impl Transaction {
+    /// The response, once it arrives.
+    pub(crate) async fn response(&mut self) -> Vec<u8> {
+        match (&mut self.response).await {
+            Ok(response) => response,
+            // The sender lives in the map until it's used or we're dropped.
+            Err(_) => std::future::pending().await,
         }
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, DekuRead, DekuWrite, Builder)]
-#[deku(endian = "endian", ctx = "endian: deku::ctx::Endian")]
-pub(crate) struct ScrapeResponseFile {
-    seeders: u32,
-    completed: u32,
-    leechers: u32,
+impl Drop for Transaction {
+    fn drop(&mut self) {
+        self.shared.transactions.lock().unwrap().remove(&self.key);
+    }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, DekuRead, DekuWrite)]
-#[deku(endian = "big")]
-pub(crate) struct ScrapeResponse {
-    #[deku(assert_eq = "Action::Scrape as u32")]
-    action: u32,
-    transaction_id: u32,
-    #[deku(until = "|_| true")]
-    files: Vec<ScrapeResponseFile>,
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::time::Duration;
+
+    use tokio::net::UdpSocket;
+    use tokio::time::{timeout, Instant};
+
+    use super::{classify, Kind, RateLimit, UdpMux, FORGET_AFTER, SOURCE_BURST};
+    use crate::utp::UtpSocket;
+
+    #[test]
+    fn classifies_datagrams() {
+        assert_eq!(classify(b"d1:ad2:id0:ee"), Some(Kind::Dht));
+        assert_eq!(classify(b"d2:ip6:abcdef1:rd2:id0:ee"), Some(Kind::Dht));
+        // Cut short.
+        assert_eq!(classify(b"d1:rd2:id20:"), None);
+        // ST_SYN, version 1, then the rest of a header.
+        let mut syn = [0; 20];
+        syn[0] = 0x41;
+        assert_eq!(classify(&syn), Some(Kind::Utp));
+        syn[0] = 0x51;
+        assert_eq!(classify(&syn), None);
+        assert_eq!(classify(&syn[..19]), None);
+        // A UDP tracker's connect response.
+        assert_eq!(classify(&[0; 16]), None);
+    }
+
+    #[test]
+    fn limits_each_source() {
+        let now = Instant::now();
+        let mut limit = RateLimit::new(now);
+        let (flood, other) = ([10, 0, 0, 1].into(), [10, 0, 0, 2].into());
+        let allowed = (0..2 * SOURCE_BURST as usize)
+            .filter(|_| limit.allow(flood, now))
+            .count();
+        assert_eq!(allowed, SOURCE_BURST as usize);
+        assert!(limit.allow(other, now));
+        // Tokens come back over time.
+        assert!(limit.allow(flood, now + Duration::from_millis(1)));
+
+        let later = now + 2 * FORGET_AFTER;
+        assert!(limit.allow(other, later));
+        assert_eq!(limit.buckets.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn shares_one_socket() {
+        let mux = UdpMux::bind("127.0.0.1:0".parse().unwrap()).await.unwrap();
+        let addr = mux.local_addr().unwrap();
+        let mut dht = mux.dht();
+        let utp = UtpSocket::on(&mux);
+        let remote = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let remote_addr: SocketAddr = remote.local_addr().unwrap();
+
+        // The tracker's answer goes to the transaction, not the DHT or uTP.
+        let mut transaction = mux.transaction(remote_addr, 0xdead_beef);
+        let mut response = vec![0, 0, 0, 1];
+        response.extend(0xdead_beef_u32.to_be_bytes());
+        response.extend([0; 8]);
+        remote.send_to(&response, addr).await.unwrap();
+        let received = timeout(Duration::from_secs(1), transaction.response());
+        assert_eq!(received.await.unwrap(), response);
+
+        remote
+            .send_to(b"d1:q4:ping1:t2:aa1:y1:qe", addr)
+            .await
+            .unwrap();
+        let (from, message) = timeout(Duration::from_secs(1), dht.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(from, remote_addr);
+        assert_eq!(message, b"d1:q4:ping1:t2:aa1:y1:qe");
+
+        // uTP connects over the same port.
+        let other = UtpSocket::bind("127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap();
+        let (connected, accepted) = tokio::join!(other.connect(addr), utp.accept());
+        assert_eq!(connected.unwrap().peer_addr(), addr);
+        assert_eq!(accepted.peer_addr(), other.local_addr().unwrap());
+        assert_eq!(utp.local_addr().unwrap(), addr);
+    }
 }
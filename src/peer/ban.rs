@@ -0,0 +1,22 @@
+use std::collections::HashSet;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+
+/// Addresses we won't talk to again this session, e.g. for sending data that
+/// failed its hash check. Peers are banned by IP, as they can come back on
+/// any port.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct BanList {
+    banned: Arc<Mutex<HashSet<IpAddr>>>,
+}
+
+impl BanList {
+    /// Returns whether `ip` wasn't already banned.
+    pub(crate) fn ban(&self, ip: IpAddr) -> bool {
+        self.banned.lock().unwrap().insert(ip)
+    }
+
+    pub(crate) fn is_banned(&self, ip: &IpAddr) -> bool {
+        self.banned.lock().unwrap().contains(ip)
+    }
+}
@@ -0,0 +1,311 @@
+//! Deciding whom we upload to. Every [`CHOKE_INTERVAL`] each torrent's
+//! [`Choker`] unchokes the interested peers that give the most back: while
+//! leeching, those uploading to us fastest, and while seeding, those we
+//! upload to fastest or everyone in turn. The last slot is an optimistic
+//! unchoke given to a random peer and rotated every [`OPTIMISTIC_ROUNDS`]
+//! rounds, so newcomers get a chance to show what they can do.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use rand::seq::IteratorRandom;
+use serde::Deserialize;
+
+use crate::piece::download::PeerKey;
+
+pub(crate) const CHOKE_INTERVAL: Duration = Duration::from_secs(10);
+/// The optimistic unchoke moves on every third round, i.e. every 30 seconds.
+const OPTIMISTIC_ROUNDS: u32 = 3;
+
+/// How a seed ranks the peers it uploads to.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum SeedChoking {
+    /// Those taking data fastest.
+    #[default]
+    Fastest,
+    /// Whoever has waited longest since it was last unchoked.
+    RoundRobin,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub(crate) struct ChokerConfig {
+    /// Peers we upload to at once, across every torrent.
+    #[serde(default = "default_upload_slots")]
+    pub(crate) upload_slots: usize,
+    #[serde(default)]
+    pub(crate) seeding: SeedChoking,
+}
+
+fn default_upload_slots() -> usize {
+    8
+}
+
+impl Default for ChokerConfig {
+    fn default() -> Self {
+        Self {
+            upload_slots: default_upload_slots(),
+            seeding: SeedChoking::default(),
+        }
+    }
+}
+
+/// Upload slots shared by every torrent in the session. Each torrent asks
+/// for as many as it has interested peers, and the slots are split evenly,
+/// with what small torrents don't need going to the rest.
+#[derive(Debug, Clone)]
+pub(crate) struct UploadSlots {
+    total: usize,
+    demand: Arc<Mutex<BTreeMap<u64, usize>>>,
+    next_id: Arc<AtomicU64>,
+}
+
+impl UploadSlots {
+    pub(crate) fn new(total: usize) -> Self {
+        Self {
+            total,
+            demand: Arc::default(),
+            next_id: Arc::default(),
+        }
+    }
+
+    /// A torrent's claim on the slots, given up when dropped.
+    pub(crate) fn share(&self) -> SlotShare {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.demand.lock().unwrap().insert(id, 0);
+        SlotShare {
+            id,
+            slots: self.clone(),
+        }
+    }
+
+    fn allocate(&self, id: u64) -> usize {
+        let demand = self.demand.lock().unwrap();
+        let mut torrents: Vec<_> = demand.iter().map(|(&id, &wants)| (wants, id)).collect();
+        torrents.sort();
+        let mut remaining = self.total;
+        for (i, (wants, torrent)) in torrents.iter().enumerate() {
+            let given = (*wants).min(remaining.div_ceil(torrents.len() - i));
+            if *torrent == id {
+                return given;
+            }
+            remaining -= given;
+        }
+        0
+    }
+}
+
+pub(crate) struct SlotShare {
+    id: u64,
+    slots: UploadSlots,
+}
+
+impl SlotShare {
+    fn set_demand(&self, wants: usize) {
+        self.slots.demand.lock().unwrap().insert(self.id, wants);
+    }
+
+    fn get(&self) -> usize {
+        self.slots.allocate(self.id)
+    }
+}
+
+impl Drop for SlotShare {
+    fn drop(&mut self) {
+        self.slots.demand.lock().unwrap().remove(&self.id);
+    }
+}
+
+/// What a connection did since the last round.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct PeerRates {
+    pub(crate) peer: PeerKey,
+    pub(crate) interested: bool,
+    /// Bytes the peer sent us.
+    pub(crate) downloaded: u64,
+    /// Bytes we sent the peer.
+    pub(crate) uploaded: u64,
+}
+
+/// The choke and unchoke messages a round calls for.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub(crate) struct Rechoke {
+    pub(crate) unchoke: Vec<PeerKey>,
+    pub(crate) choke: Vec<PeerKey>,
+}
+
+pub(crate) struct Choker {
+    share: SlotShare,
+    seeding: SeedChoking,
+    unchoked: HashSet<PeerKey>,
+    optimistic: Option<PeerKey>,
+    round: u32,
+    /// The round each peer was last unchoked in, for round robin.
+    last_unchoked: HashMap<PeerKey, u32>,
+}
+
+impl Choker {
+    pub(crate) fn new(share: SlotShare, seeding: SeedChoking) -> Self {
+        Self {
+            share,
+            seeding,
+            unchoked: HashSet::new(),
+            optimistic: None,
+            round: 0,
+            last_unchoked: HashMap::new(),
+        }
+    }
+
+    #[cfg(test)]
+    pub(crate) fn is_unchoked(&self, peer: &PeerKey) -> bool {
+        self.unchoked.contains(peer)
+    }
+
+    pub(crate) fn remove_peer(&mut self, peer: &PeerKey) {
+        self.unchoked.remove(peer);
+        self.last_unchoked.remove(peer);
+        if self.optimistic.as_ref() == Some(peer) {
+            self.optimistic = None;
+        }
+    }
+
+    /// Runs one round, to be called every [`CHOKE_INTERVAL`] with every
+    /// connected peer.
+    pub(crate) fn rechoke(&mut self, peers: &[PeerRates], seeding: bool) -> Rechoke {
+        let mut interested: Vec<_> = peers.iter().filter(|p| p.interested).collect();
+        self.share.set_demand(interested.len());
+        let slots = self.share.get();
+
+        let rotate = self.round.is_multiple_of(OPTIMISTIC_ROUNDS);
+        self.optimistic = self
+            .optimistic
+            .filter(|o| slots > 0 && !rotate && interested.iter().any(|p| &p.peer == o));
+
+        match (seeding, self.seeding) {
+            (false, _) => interested.sort_by_key(|p| std::cmp::Reverse(p.downloaded)),
+            (true, SeedChoking::Fastest) => {
+                interested.sort_by_key(|p| std::cmp::Reverse(p.uploaded))
+            }
+            (true, SeedChoking::RoundRobin) => {
+                let last = &self.last_unchoked;
+                interested.sort_by_key(|p| (last.get(&p.peer).copied(), p.peer));
+            }
+        }
+        let mut unchoked: HashSet<_> = interested
+            .iter()
+            .map(|p| p.peer)
+            .filter(|p| Some(p) != self.optimistic.as_ref())
+            .take(slots.saturating_sub(1))
+            .collect();
+        if slots > 0 && self.optimistic.is_none() {
+            self.optimistic = interested
+                .iter()
+                .map(|p| p.peer)
+                .filter(|p| !unchoked.contains(p))
+                .choose(&mut rand::thread_rng());
+        }
+        unchoked.extend(self.optimistic);
+
+        for peer in &unchoked {
+            self.last_unchoked.insert(*peer, self.round);
+        }
+        self.round += 1;
+
+        let mut rechoke = Rechoke {
+            unchoke: unchoked.difference(&self.unchoked).copied().collect(),
+            choke: self.unchoked.difference(&unchoked).copied().collect(),
+        };
+        rechoke.unchoke.sort();
+        rechoke.choke.sort();
+        self.unchoked = unchoked;
+        rechoke
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Choker, PeerRates, SeedChoking, UploadSlots, OPTIMISTIC_ROUNDS};
+    use crate::piece::download::PeerKey;
+
+    fn peer(n: u8) -> PeerKey {
+        PeerKey::from(([10, 0, 0, n], 6881))
+    }
+
+    /// Interested peers 1 to `n`, where peer `i` sends us `i` KiB and takes
+    /// `n - i` KiB.
+    fn rates(n: u8) -> Vec<PeerRates> {
+        (1..=n)
+            .map(|i| PeerRates {
+                peer: peer(i),
+                interested: true,
+                downloaded: u64::from(i) * 1024,
+                uploaded: u64::from(n - i) * 1024,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn splits_slots_across_torrents() {
+        let slots = UploadSlots::new(8);
+        let (small, large, other) = (slots.share(), slots.share(), slots.share());
+        small.set_demand(1);
+        large.set_demand(10);
+        other.set_demand(10);
+        assert_eq!(
+            [small.get(), large.get(), other.get()]
+                .iter()
+                .sum::<usize>(),
+            8
+        );
+        assert_eq!(small.get(), 1);
+        assert!(large.get().abs_diff(other.get()) <= 1);
+
+        drop(small);
+        drop(other);
+        assert_eq!(large.get(), 8);
+    }
+
+    #[test]
+    fn leeching_unchokes_best_uploaders_and_one_optimistic() {
+        let mut choker = Choker::new(UploadSlots::new(4).share(), SeedChoking::Fastest);
+        let peers = rates(6);
+        let first = choker.rechoke(&peers, false);
+        assert_eq!(first.unchoke.len(), 4);
+        assert!(first.choke.is_empty());
+        for best in [peer(4), peer(5), peer(6)] {
+            assert!(choker.is_unchoked(&best));
+        }
+        let optimistic = choker.optimistic.unwrap();
+        assert!([peer(1), peer(2), peer(3)].contains(&optimistic));
+
+        // The optimistic unchoke holds until it's time to rotate.
+        for _ in 1..OPTIMISTIC_ROUNDS {
+            assert_eq!(choker.rechoke(&peers, false), Default::default());
+        }
+
+        // A peer that stops being interested is choked.
+        let mut peers = peers;
+        peers[5].interested = false;
+        let rechoke = choker.rechoke(&peers, false);
+        assert!(rechoke.choke.contains(&peer(6)));
+        assert!(choker.is_unchoked(&peer(3)));
+    }
+
+    #[test]
+    fn seeding_ranks_by_upload_or_turns() {
+        let peers = rates(6);
+        let mut fastest = Choker::new(UploadSlots::new(3).share(), SeedChoking::Fastest);
+        fastest.rechoke(&peers, true);
+        assert!(fastest.is_unchoked(&peer(1)) && fastest.is_unchoked(&peer(2)));
+
+        let mut round_robin = Choker::new(UploadSlots::new(3).share(), SeedChoking::RoundRobin);
+        let mut seen = std::collections::HashSet::new();
+        for _ in 0..3 {
+            round_robin.rechoke(&peers, true);
+            seen.extend(round_robin.unchoked.iter().copied());
+        }
+        assert_eq!(seen.len(), 6);
+    }
+}
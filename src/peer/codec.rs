@@ -0,0 +1,308 @@
+//! Framing for the peer wire protocol. A connection starts out with
+//! [`HandshakeCodec`] and switches to [`MessageCodec`] once both handshakes
+//! are through, e.g. with `Framed::map_codec`.
+
+use bytes::{Buf, BufMut, BytesMut};
+use deku::prelude::*;
+use snafu::prelude::*;
+use tokio_util::codec::{Decoder, Encoder};
+
+use super::message::{Handshake, Message, HANDSHAKE_LEN};
+use super::{MalformedSnafu, PeerError, TooLargeSnafu};
+
+/// Large enough for a bitfield of a million pieces or a 128 KiB block, which
+/// is more than any sane peer sends.
+pub(crate) const DEFAULT_MAX_MESSAGE_LEN: usize = 256 * 1024;
+
+#[derive(Debug, Default)]
+pub(crate) struct HandshakeCodec;
+
+impl Decoder for HandshakeCodec {
+    type Item = Handshake;
+    type Error = PeerError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        if src.len() < HANDSHAKE_LEN {
+            src.reserve(HANDSHAKE_LEN - src.len());
+            return Ok(None);
+        }
+        let bytes = src.split_to(HANDSHAKE_LEN);
+        let (_, handshake) = Handshake::from_bytes((&bytes, 0)).context(MalformedSnafu)?;
+        Ok(Some(handshake))
+    }
+}
+
+impl Encoder<Handshake> for HandshakeCodec {
+    type Error = PeerError;
+
+    fn encode(&mut self, item: Handshake, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        dst.extend_from_slice(&item.to_bytes().context(MalformedSnafu)?);
+        Ok(())
+    }
+}
+
+/// What comes after the handshake: a message, or a keep-alive (an empty
+/// frame) that only tells us the peer is still there.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Frame {
+    KeepAlive,
+    Message(Message),
+}
+
+impl From<Message> for Frame {
+    fn from(message: Message) -> Self {
+        Frame::Message(message)
+    }
+}
+
+/// Length-prefixed messages, refusing any larger than `max_len` in either
+/// direction.
+#[derive(Debug)]
+pub(crate) struct MessageCodec {
+    max_len: usize,
+}
+
+impl MessageCodec {
+    pub(crate) fn new(max_len: usize) -> Self {
+        Self { max_len }
+    }
+}
+
+impl Default for MessageCodec {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_MESSAGE_LEN)
+    }
+}
+
+impl Decoder for MessageCodec {
+    type Item = Frame;
+    type Error = PeerError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        if src.len() < 4 {
+            return Ok(None);
+        }
+        let length = u32::from_be_bytes([src[0], src[1], src[2], src[3]]) as usize;
+        ensure!(
+            length <= self.max_len,
+            TooLargeSnafu {
+                length,
+                max: self.max_len
+            }
+        );
+        if src.len() < 4 + length {
+            src.reserve(4 + length - src.len());
+            return Ok(None);
+        }
+
+        src.advance(4);
+        if length == 0 {
+            return Ok(Some(Frame::KeepAlive));
+        }
+        let body = src.split_to(length);
+        let (_, message) = Message::from_bytes((&body, 0)).context(MalformedSnafu)?;
+        Ok(Some(Frame::Message(message)))
+    }
+}
+
+impl Encoder<Frame> for MessageCodec {
+    type Error = PeerError;
+
+    fn encode(&mut self, item: Frame, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        let body = match item {
+            Frame::KeepAlive => Vec::new(),
+            Frame::Message(message) => message.to_bytes().context(MalformedSnafu)?,
+        };
+        ensure!(
+            body.len() <= self.max_len,
+            TooLargeSnafu {
+                length: body.len(),
+                max: self.max_len
+            }
+        );
+        dst.reserve(4 + body.len());
+        dst.put_u32(body.len() as u32);
+        dst.extend_from_slice(&body);
+        Ok(())
+    }
+}
+
+impl Encoder<Message> for MessageCodec {
+    type Error = PeerError;
+
+    fn encode(&mut self, item: Message, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        self.encode(Frame::Message(item), dst)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::BytesMut;
+    use futures::{SinkExt, StreamExt};
+    use tokio_util::codec::{Decoder, Encoder, Framed};
+
+    use super::{Frame, HandshakeCodec, MessageCodec};
+    use crate::peer::message::{Handshake, Message, ReservedBit};
+    use crate::peer::PeerError;
+    use crate::torrent::{InfoHash, PeerId};
+
+    fn round_trip(frame: Frame) {
+        let mut codec = MessageCodec::default();
+        let mut buf = BytesMut::new();
+        codec.encode(frame.clone(), &mut buf).unwrap();
+        // Feed the bytes one at a time to check partial frames are waited on.
+        let mut partial = BytesMut::new();
+        let mut decoded = None;
+        for byte in buf {
+            assert!(decoded.is_none());
+            partial.extend_from_slice(&[byte]);
+            decoded = codec.decode(&mut partial).unwrap();
+        }
+        assert_eq!(decoded, Some(frame));
+        assert!(partial.is_empty());
+    }
+
+    #[test]
+    fn messages_round_trip() {
+        let frames = [
+            Frame::KeepAlive,
+            Message::Choke.into(),
+            Message::Unchoke.into(),
+            Message::Interested.into(),
+            Message::NotInterested.into(),
+            Message::Have { index: 7 }.into(),
+            Message::Bitfield {
+                bitfield: vec![0xff, 0x80],
+            }
+            .into(),
+            Message::Request {
+                index: 1,
+                begin: 16384,
+                length: 16384,
+            }
+            .into(),
+            Message::Piece {
+                index: 1,
+                begin: 16384,
+                block: vec![0xab; 100],
+            }
+            .into(),
+            Message::Cancel {
+                index: 1,
+                begin: 16384,
+                length: 16384,
+            }
+            .into(),
+            Message::Port { port: 6881 }.into(),
+            Message::SuggestPiece { index: 3 }.into(),
+            Message::HaveAll.into(),
+            Message::HaveNone.into(),
+            Message::RejectRequest {
+                index: 1,
+                begin: 0,
+                length: 16384,
+            }
+            .into(),
+            Message::AllowedFast { index: 9 }.into(),
+            Message::Unknown {
+                id: 42,
+                payload: vec![1, 2, 3],
+            }
+            .into(),
+        ];
+        for frame in frames {
+            round_trip(frame);
+        }
+    }
+
+    #[test]
+    fn encodes_wire_format() {
+        let mut buf = BytesMut::new();
+        MessageCodec::default()
+            .encode(
+                Message::Request {
+                    index: 1,
+                    begin: 2,
+                    length: 3,
+                },
+                &mut buf,
+            )
+            .unwrap();
+        assert_eq!(
+            &buf[..],
+            &[0, 0, 0, 13, 6, 0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0, 3]
+        );
+    }
+
+    #[test]
+    fn rejects_oversized_messages() {
+        let mut codec = MessageCodec::new(16);
+        let mut buf = BytesMut::from(&[0, 0, 0, 17][..]);
+        assert!(matches!(
+            codec.decode(&mut buf),
+            Err(PeerError::TooLarge { length: 17, .. })
+        ));
+
+        let big = Message::Piece {
+            index: 0,
+            begin: 0,
+            block: vec![0; 16],
+        };
+        assert!(codec.encode(big, &mut BytesMut::new()).is_err());
+    }
+
+    #[test]
+    fn rejects_truncated_message() {
+        // A `have` with only two bytes of index.
+        let mut buf = BytesMut::from(&[0, 0, 0, 3, 4, 0, 1][..]);
+        assert!(matches!(
+            MessageCodec::default().decode(&mut buf),
+            Err(PeerError::Malformed { .. })
+        ));
+    }
+
+    #[test]
+    fn handshake_round_trip() {
+        let handshake = Handshake::new(InfoHash::from([9; 20]), PeerId::new())
+            .with(ReservedBit::Extension)
+            .with(ReservedBit::Fast);
+        let mut buf = BytesMut::new();
+        HandshakeCodec.encode(handshake.clone(), &mut buf).unwrap();
+        assert_eq!(buf.len(), 68);
+        assert_eq!(&buf[..20], b"\x13BitTorrent protocol");
+        assert_eq!(&buf[20..28], &[0, 0, 0, 0, 0, 0x10, 0, 0x04]);
+
+        let decoded = HandshakeCodec.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(decoded, handshake);
+        assert!(decoded.supports(ReservedBit::Extension));
+        assert!(!decoded.supports(ReservedBit::Dht));
+    }
+
+    #[test]
+    fn rejects_other_protocols() {
+        let mut buf = BytesMut::from(&b"\x13BitTorrent protocoX"[..]);
+        buf.extend_from_slice(&[0; 48]);
+        assert!(HandshakeCodec.decode(&mut buf).is_err());
+    }
+
+    #[tokio::test]
+    async fn handshake_then_messages_over_a_stream() {
+        let (a, b) = tokio::io::duplex(1024);
+        let mut a = Framed::new(a, HandshakeCodec);
+        let mut b = Framed::new(b, HandshakeCodec);
+
+        let handshake = Handshake::new(InfoHash::from([1; 20]), PeerId::new());
+        a.send(handshake.clone()).await.unwrap();
+        assert_eq!(b.next().await.unwrap().unwrap(), handshake);
+
+        let mut a = a.map_codec(|_| MessageCodec::default());
+        let mut b = b.map_codec(|_| MessageCodec::default());
+        a.send(Message::Interested).await.unwrap();
+        a.send(Frame::KeepAlive).await.unwrap();
+        assert_eq!(
+            b.next().await.unwrap().unwrap(),
+            Frame::Message(Message::Interested)
+        );
+        assert_eq!(b.next().await.unwrap().unwrap(), Frame::KeepAlive);
+    }
+}
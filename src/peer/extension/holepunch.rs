@@ -0,0 +1,383 @@
+//! Holepunching: two peers behind NATs that are both connected to a third
+//! can get it to tell each of them to dial the other over uTP at the same
+//! time, which opens both NATs. The peer that wants the connection sends
+//! `rendezvous` to the relay, which answers with `connect` to both ends, or
+//! with `error` if it can't.
+//! See: http://www.bittorrent.org/beps/bep_0055.html
+
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use deku::prelude::*;
+use log::debug;
+use tokio::time::Instant;
+
+use super::{ExtendedHandshake, Extension, ExtensionError};
+use crate::external_ip::{ip_from_bytes, ip_to_bytes};
+use crate::peer::manager::ConnectionManager;
+
+pub(crate) const NAME: &str = "ut_holepunch";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, DekuRead, DekuWrite)]
+#[deku(type = "u8", endian = "endian", ctx = "endian: deku::ctx::Endian")]
+pub(crate) enum MessageType {
+    /// Asks the relay to connect us with the peer at the address.
+    #[deku(id = "0")]
+    Rendezvous,
+    /// From the relay: dial the peer at the address now.
+    #[deku(id = "1")]
+    Connect,
+    /// From the relay: it can't put us in touch with the peer.
+    #[deku(id = "2")]
+    Error,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, DekuRead, DekuWrite)]
+#[deku(type = "u32", endian = "endian", ctx = "endian: deku::ctx::Endian")]
+pub(crate) enum ErrorCode {
+    #[deku(id = "0")]
+    None,
+    /// The address isn't one a peer could have.
+    #[deku(id = "1")]
+    NoSuchPeer,
+    /// The relay isn't connected to the peer.
+    #[deku(id = "2")]
+    NotConnected,
+    /// The peer doesn't support holepunching.
+    #[deku(id = "3")]
+    NoSupport,
+    /// The peer is the one asking.
+    #[deku(id = "4")]
+    NoSelf,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, DekuRead, DekuWrite)]
+#[deku(endian = "big")]
+pub(crate) struct HolepunchMessage {
+    pub(crate) kind: MessageType,
+    /// 0 for IPv4, 1 for IPv6.
+    #[deku(assert = "*addr_type <= 1")]
+    addr_type: u8,
+    #[deku(count = "if *addr_type == 0 { 4 } else { 16 }")]
+    ip: Vec<u8>,
+    port: u16,
+    pub(crate) error: ErrorCode,
+}
+
+impl HolepunchMessage {
+    pub(crate) fn new(kind: MessageType, addr: SocketAddr, error: ErrorCode) -> Self {
+        Self {
+            kind,
+            addr_type: u8::from(addr.is_ipv6()),
+            ip: ip_to_bytes(&addr.ip()),
+            port: addr.port(),
+            error,
+        }
+    }
+
+    pub(crate) fn addr(&self) -> SocketAddr {
+        let ip = ip_from_bytes(&self.ip).expect("the length follows the address type");
+        SocketAddr::new(ip, self.port)
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        self.to_bytes().expect("holepunch messages always encode")
+    }
+}
+
+/// The connections of one torrent, for relaying between them.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct HolepunchSwarm {
+    inner: Arc<Mutex<Relays>>,
+}
+
+#[derive(Debug, Default)]
+struct Relays {
+    /// Connected peers by where others reach them.
+    peers: HashMap<SocketAddr, Relay>,
+    /// Peers we've asked a relay to connect us with.
+    pending: HashSet<SocketAddr>,
+}
+
+#[derive(Debug, Default)]
+struct Relay {
+    /// It sent `ut_holepunch` in its extended handshake.
+    supported: bool,
+    /// Messages for it, sent when its connection next polls.
+    outbox: Vec<Vec<u8>>,
+}
+
+impl HolepunchSwarm {
+    /// Asks a connected peer that supports holepunching to connect us with
+    /// `target`. False if none does.
+    pub(crate) fn rendezvous(&self, target: SocketAddr) -> bool {
+        let mut relays = self.inner.lock().unwrap();
+        let Some(relay) = relays
+            .peers
+            .iter_mut()
+            .find(|(addr, relay)| relay.supported && **addr != target)
+            .map(|(_, relay)| relay)
+        else {
+            return false;
+        };
+        relay
+            .outbox
+            .push(HolepunchMessage::new(MessageType::Rendezvous, target, ErrorCode::None).encode());
+        relays.pending.insert(target);
+        true
+    }
+
+    /// Whether `target` can be relayed to from `from`, and if not why.
+    fn check(&self, from: SocketAddr, target: SocketAddr) -> ErrorCode {
+        let relays = self.inner.lock().unwrap();
+        if target.port() == 0 || target.ip().is_unspecified() {
+            ErrorCode::NoSuchPeer
+        } else if target == from {
+            ErrorCode::NoSelf
+        } else {
+            match relays.peers.get(&target) {
+                None => ErrorCode::NotConnected,
+                Some(relay) if !relay.supported => ErrorCode::NoSupport,
+                Some(_) => ErrorCode::None,
+            }
+        }
+    }
+}
+
+/// ut_holepunch on one connection.
+pub(crate) struct UtHolepunch {
+    peer: SocketAddr,
+    swarm: HolepunchSwarm,
+    manager: Arc<ConnectionManager>,
+    /// The peer connected to us, so `peer` isn't where it listens.
+    accepted: bool,
+}
+
+impl UtHolepunch {
+    /// `peer` is where others can reach it, as for PEX. Peers we're told to
+    /// connect to are dialed through `manager`.
+    pub(crate) fn new(
+        peer: SocketAddr,
+        swarm: HolepunchSwarm,
+        manager: Arc<ConnectionManager>,
+    ) -> Self {
+        swarm
+            .inner
+            .lock()
+            .unwrap()
+            .peers
+            .insert(peer, Relay::default());
+        Self {
+            peer,
+            swarm,
+            manager,
+            accepted: false,
+        }
+    }
+
+    /// The peer connected to us, so it's relayed to at the port from its
+    /// handshake.
+    pub(crate) fn accepted(mut self) -> Self {
+        self.accepted = true;
+        self
+    }
+
+    /// Answers a rendezvous as the relay: `connect` to both ends, or an
+    /// error to the one asking.
+    fn relay(&self, target: SocketAddr) -> Vec<Vec<u8>> {
+        let error = self.swarm.check(self.peer, target);
+        if error != ErrorCode::None {
+            debug!(
+                "Can't relay a holepunch from {} to {target}: {error:?}",
+                self.peer
+            );
+            return vec![HolepunchMessage::new(MessageType::Error, target, error).encode()];
+        }
+        let mut relays = self.swarm.inner.lock().unwrap();
+        if let Some(relay) = relays.peers.get_mut(&target) {
+            relay.outbox.push(
+                HolepunchMessage::new(MessageType::Connect, self.peer, ErrorCode::None).encode(),
+            );
+        }
+        vec![HolepunchMessage::new(MessageType::Connect, target, ErrorCode::None).encode()]
+    }
+}
+
+impl Extension for UtHolepunch {
+    fn name(&self) -> &'static str {
+        NAME
+    }
+
+    fn on_handshake(&mut self, theirs: &ExtendedHandshake) {
+        let supported = theirs.m.get(NAME).is_some_and(|&id| id != 0);
+        let mut relays = self.swarm.inner.lock().unwrap();
+        let listen = theirs
+            .listen_addr(self.peer)
+            .filter(|listen| self.accepted && *listen != self.peer);
+        if let Some(listen) = listen {
+            let relay = relays.peers.remove(&self.peer).unwrap_or_default();
+            relays.peers.insert(listen, relay);
+            self.peer = listen;
+        }
+        if let Some(relay) = relays.peers.get_mut(&self.peer) {
+            relay.supported = supported;
+        }
+    }
+
+    fn on_message(&mut self, payload: &[u8]) -> Result<Vec<Vec<u8>>, ExtensionError> {
+        let (_, message) =
+            HolepunchMessage::from_bytes((payload, 0)).map_err(|e| ExtensionError::Invalid {
+                name: NAME,
+                reason: e.to_string(),
+            })?;
+        let addr = message.addr();
+        match message.kind {
+            MessageType::Rendezvous => return Ok(self.relay(addr)),
+            MessageType::Connect => {
+                let initiator = self.swarm.inner.lock().unwrap().pending.remove(&addr);
+                debug!("{} connects us with {addr}", self.peer);
+                self.manager.holepunch(addr, initiator);
+            }
+            MessageType::Error => {
+                self.swarm.inner.lock().unwrap().pending.remove(&addr);
+                debug!(
+                    "{} can't connect us with {addr}: {:?}",
+                    self.peer, message.error
+                );
+            }
+        }
+        Ok(Vec::new())
+    }
+
+    fn poll(&mut self, _now: Instant) -> Vec<Vec<u8>> {
+        let mut relays = self.swarm.inner.lock().unwrap();
+        relays
+            .peers
+            .get_mut(&self.peer)
+            .map(|relay| std::mem::take(&mut relay.outbox))
+            .unwrap_or_default()
+    }
+}
+
+impl Drop for UtHolepunch {
+    fn drop(&mut self) {
+        self.swarm.inner.lock().unwrap().peers.remove(&self.peer);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+
+    use deku::prelude::*;
+    use tokio::time::Instant;
+
+    use super::{ErrorCode, HolepunchMessage, HolepunchSwarm, MessageType, UtHolepunch, NAME};
+    use crate::peer::extension::{ExtendedHandshake, Extension};
+    use crate::peer::manager::{ConnectionConfig, ConnectionLimits, ConnectionManager, PeerSource};
+    use crate::torrent::{InfoHash, PeerId};
+
+    fn manager() -> Arc<ConnectionManager> {
+        Arc::new(ConnectionManager::new(
+            InfoHash::from([1; 20]),
+            PeerId::new(),
+            ConnectionLimits::new(ConnectionConfig::default()),
+        ))
+    }
+
+    fn decode(payload: &[u8]) -> HolepunchMessage {
+        HolepunchMessage::from_bytes((payload, 0)).unwrap().1
+    }
+
+    fn connected(addr: &str, swarm: &HolepunchSwarm, supported: bool) -> UtHolepunch {
+        let mut extension = UtHolepunch::new(addr.parse().unwrap(), swarm.clone(), manager());
+        let mut handshake = ExtendedHandshake::default();
+        if supported {
+            handshake.m.insert(NAME.to_string(), 4);
+        }
+        extension.on_handshake(&handshake);
+        extension
+    }
+
+    #[test]
+    fn writes_messages_as_the_bep_lays_them_out() {
+        let addr: SocketAddr = "203.0.113.5:6881".parse().unwrap();
+        let message = HolepunchMessage::new(MessageType::Error, addr, ErrorCode::NoSupport);
+        let bytes = message.encode();
+        assert_eq!(bytes, [2, 0, 203, 0, 113, 5, 0x1a, 0xe1, 0, 0, 0, 3]);
+        assert_eq!(decode(&bytes).addr(), addr);
+
+        let v6: SocketAddr = "[2001:db8::5]:6881".parse().unwrap();
+        let bytes = HolepunchMessage::new(MessageType::Connect, v6, ErrorCode::None).encode();
+        assert_eq!(bytes.len(), 24);
+        assert_eq!(decode(&bytes).addr(), v6);
+        assert!(HolepunchMessage::from_bytes((&[0, 2, 1, 2, 3, 4, 0, 1, 0, 0, 0, 0], 0)).is_err());
+    }
+
+    #[test]
+    fn relays_between_connected_peers() {
+        let swarm = HolepunchSwarm::default();
+        let mut a = connected("203.0.113.1:6881", &swarm, true);
+        let mut c = connected("203.0.113.3:6881", &swarm, true);
+        let _d = connected("203.0.113.4:6881", &swarm, false);
+        let now = Instant::now();
+
+        let rendezvous = |target: &str| {
+            HolepunchMessage::new(
+                MessageType::Rendezvous,
+                target.parse().unwrap(),
+                ErrorCode::None,
+            )
+            .encode()
+        };
+        let reply = decode(&a.on_message(&rendezvous("203.0.113.3:6881")).unwrap()[0]);
+        assert_eq!(reply.kind, MessageType::Connect);
+        assert_eq!(reply.addr(), "203.0.113.3:6881".parse().unwrap());
+        let relayed = decode(&c.poll(now)[0]);
+        assert_eq!(relayed.kind, MessageType::Connect);
+        assert_eq!(relayed.addr(), "203.0.113.1:6881".parse().unwrap());
+        assert!(c.poll(now).is_empty());
+
+        for (target, error) in [
+            ("203.0.113.9:6881", ErrorCode::NotConnected),
+            ("203.0.113.4:6881", ErrorCode::NoSupport),
+            ("203.0.113.1:6881", ErrorCode::NoSelf),
+            ("0.0.0.0:6881", ErrorCode::NoSuchPeer),
+        ] {
+            let reply = decode(&a.on_message(&rendezvous(target)).unwrap()[0]);
+            assert_eq!((reply.kind, reply.error), (MessageType::Error, error));
+        }
+        assert!(a.on_message(&[0, 1, 2]).is_err());
+
+        drop(c);
+        let reply = decode(&a.on_message(&rendezvous("203.0.113.3:6881")).unwrap()[0]);
+        assert_eq!(reply.error, ErrorCode::NotConnected);
+    }
+
+    #[test]
+    fn dials_the_peer_it_asked_for() {
+        let swarm = HolepunchSwarm::default();
+        let target: SocketAddr = "203.0.113.3:6881".parse().unwrap();
+        // No relay yet.
+        assert!(!swarm.rendezvous(target));
+
+        let manager = manager();
+        let mut relay = UtHolepunch::new(
+            "203.0.113.2:6881".parse().unwrap(),
+            swarm.clone(),
+            manager.clone(),
+        );
+        let mut handshake = ExtendedHandshake::default();
+        handshake.m.insert(NAME.to_string(), 4);
+        relay.on_handshake(&handshake);
+        assert!(swarm.rendezvous(target));
+        let sent = decode(&relay.poll(Instant::now())[0]);
+        assert_eq!((sent.kind, sent.addr()), (MessageType::Rendezvous, target));
+
+        let connect = HolepunchMessage::new(MessageType::Connect, target, ErrorCode::None);
+        assert!(relay.on_message(&connect.encode()).unwrap().is_empty());
+        assert_eq!(manager.source(&target), Some(PeerSource::Holepunch));
+    }
+}
@@ -0,0 +1,324 @@
+//! The extension protocol, over which everything past the base protocol is
+//! negotiated. Each side sends an extended handshake naming the extensions it
+//! supports and the message IDs it wants them sent with; extensions plug
+//! into a connection's [`Extensions`] to get their messages.
+//! See: http://www.bittorrent.org/beps/bep_0010.html
+
+pub(crate) mod holepunch;
+pub(crate) mod pex;
+
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+
+use log::debug;
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use snafu::prelude::*;
+use tokio::time::Instant;
+
+use super::message::Message;
+use crate::external_ip::{ip_from_bytes, ip_to_bytes, ExternalIp, Voter};
+
+/// The extended message ID of the handshake itself.
+pub(crate) const HANDSHAKE_ID: u8 = 0;
+/// Requests we'll queue from a peer before dropping them.
+const MAX_QUEUED_REQUESTS: i64 = 250;
+const CLIENT: &str = concat!("Chitauri ", env!("CARGO_PKG_VERSION"));
+
+/// The payload of extended message 0.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct ExtendedHandshake {
+    /// Extension names and the IDs to send them with; 0 turns one off.
+    #[serde(default)]
+    pub(crate) m: BTreeMap<String, u8>,
+    /// Client name and version.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) v: Option<String>,
+    /// The port the sender listens on.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) p: Option<u16>,
+    /// The receiver's address as the sender sees it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) yourip: Option<ByteBuf>,
+    /// Requests the sender will queue.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) reqq: Option<i64>,
+    /// Size of the info dictionary, for BEP 9.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) metadata_size: Option<i64>,
+}
+
+impl ExtendedHandshake {
+    /// Where the sender takes connections, given the address it reached us
+    /// from.
+    pub(crate) fn listen_addr(&self, from: SocketAddr) -> Option<SocketAddr> {
+        self.p
+            .filter(|&port| port != 0)
+            .map(|port| SocketAddr::new(from.ip(), port))
+    }
+}
+
+/// Something built on the extension protocol. Each connection gets its own
+/// instance, which keeps whatever per-peer state it needs.
+pub(crate) trait Extension: Send {
+    /// The name it goes by in `m`, e.g. `ut_pex`.
+    fn name(&self) -> &'static str;
+
+    /// Adds anything beyond `m` this extension advertises in our handshake.
+    fn extend_handshake(&self, _handshake: &mut ExtendedHandshake) {}
+
+    /// Called once the peer's handshake arrives, whether or not it supports
+    /// this extension.
+    fn on_handshake(&mut self, _theirs: &ExtendedHandshake) {}
+
+    /// Handles a message for this extension, returning payloads to send back.
+    fn on_message(&mut self, payload: &[u8]) -> Result<Vec<Vec<u8>>, ExtensionError>;
+
+    /// Called regularly once the peer has said it supports this extension,
+    /// returning payloads to send.
+    fn poll(&mut self, _now: Instant) -> Vec<Vec<u8>> {
+        Vec::new()
+    }
+}
+
+#[derive(Debug, Snafu)]
+pub(crate) enum ExtensionError {
+    #[snafu(display("malformed extension message: {source}"))]
+    Bencode { source: serde_bencode::Error },
+    #[snafu(display("unknown extended message id {id}"))]
+    UnknownId { id: u8 },
+    #[snafu(display("{name} message is invalid: {reason}"))]
+    Invalid { name: &'static str, reason: String },
+}
+
+/// The extensions on one connection. Ours are numbered in the order they're
+/// registered, starting from 1.
+pub(crate) struct Extensions {
+    peer: SocketAddr,
+    port: u16,
+    external_ip: Option<ExternalIp>,
+    metadata_size: Option<usize>,
+    ours: Vec<Box<dyn Extension>>,
+    theirs: Option<ExtendedHandshake>,
+}
+
+impl Extensions {
+    /// `port` is the one we listen on, to tell `peer`.
+    pub(crate) fn new(peer: SocketAddr, port: u16) -> Self {
+        Self {
+            peer,
+            port,
+            external_ip: None,
+            metadata_size: None,
+            ours: Vec::new(),
+            theirs: None,
+        }
+    }
+
+    /// Counts the peer's `yourip` towards our external address.
+    pub(crate) fn with_external_ip(mut self, external_ip: ExternalIp) -> Self {
+        self.external_ip = Some(external_ip);
+        self
+    }
+
+    /// Tells the peer how big our info dictionary is.
+    pub(crate) fn with_metadata_size(mut self, size: usize) -> Self {
+        self.metadata_size = Some(size);
+        self
+    }
+
+    pub(crate) fn register(&mut self, extension: impl Extension + 'static) {
+        self.ours.push(Box::new(extension));
+    }
+
+    /// The handshake the peer sent, once it has.
+    pub(crate) fn theirs(&self) -> Option<&ExtendedHandshake> {
+        self.theirs.as_ref()
+    }
+
+    /// Whether the peer takes messages for the extension called `name`.
+    #[cfg(test)]
+    pub(crate) fn supports(&self, name: &str) -> bool {
+        self.their_id(name).is_some()
+    }
+
+    fn their_id(&self, name: &str) -> Option<u8> {
+        let id = *self.theirs.as_ref()?.m.get(name)?;
+        (id != HANDSHAKE_ID).then_some(id)
+    }
+
+    /// Our extended handshake, to send once the connection is up.
+    pub(crate) fn handshake(&self) -> Message {
+        let mut handshake = ExtendedHandshake {
+            m: self
+                .ours
+                .iter()
+                .zip(1..)
+                .map(|(extension, id)| (extension.name().to_string(), id))
+                .collect(),
+            v: Some(CLIENT.to_string()),
+            p: Some(self.port),
+            yourip: Some(ByteBuf::from(ip_to_bytes(&self.peer.ip()))),
+            reqq: Some(MAX_QUEUED_REQUESTS),
+            metadata_size: self.metadata_size.map(|size| size as i64),
+        };
+        for extension in &self.ours {
+            extension.extend_handshake(&mut handshake);
+        }
+        Message::Extended {
+            id: HANDSHAKE_ID,
+            payload: serde_bencode::to_bytes(&handshake).expect("handshake always encodes"),
+        }
+    }
+
+    /// A message for the extension called `name`, if the peer supports it.
+    pub(crate) fn message(&self, name: &str, payload: Vec<u8>) -> Option<Message> {
+        Some(Message::Extended {
+            id: self.their_id(name)?,
+            payload,
+        })
+    }
+
+    /// Whatever the extensions the peer supports have to send.
+    pub(crate) fn poll(&mut self, now: Instant) -> Vec<Message> {
+        let Some(theirs) = &self.theirs else {
+            return Vec::new();
+        };
+        let mut messages = Vec::new();
+        for extension in &mut self.ours {
+            let Some(&id) = theirs.m.get(extension.name()) else {
+                continue;
+            };
+            if id == HANDSHAKE_ID {
+                continue;
+            }
+            messages.extend(
+                extension
+                    .poll(now)
+                    .into_iter()
+                    .map(|payload| Message::Extended { id, payload }),
+            );
+        }
+        messages
+    }
+
+    /// Handles an extended message, returning the messages to reply with.
+    pub(crate) fn handle(
+        &mut self,
+        id: u8,
+        payload: &[u8],
+    ) -> Result<Vec<Message>, ExtensionError> {
+        if id == HANDSHAKE_ID {
+            let theirs: ExtendedHandshake =
+                serde_bencode::from_bytes(payload).context(BencodeSnafu)?;
+            debug!("{} supports {:?}", self.peer, theirs.m.keys());
+            if let (Some(external_ip), Some(ip)) = (
+                &self.external_ip,
+                theirs.yourip.as_ref().and_then(|ip| ip_from_bytes(ip)),
+            ) {
+                external_ip.vote(Voter::Peer(self.peer.ip()), ip);
+            }
+            for extension in &mut self.ours {
+                extension.on_handshake(&theirs);
+            }
+            self.theirs = Some(theirs);
+            return Ok(Vec::new());
+        }
+
+        let extension = self
+            .ours
+            .get_mut(usize::from(id) - 1)
+            .context(UnknownIdSnafu { id })?;
+        let name = extension.name();
+        let replies = extension.on_message(payload)?;
+        Ok(replies
+            .into_iter()
+            .filter_map(|payload| self.message(name, payload))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use super::{Extension, ExtensionError, Extensions};
+    use crate::external_ip::{ExternalIp, Voter};
+    use crate::peer::message::Message;
+
+    /// Answers every message with the same payload.
+    struct Echo;
+
+    impl Extension for Echo {
+        fn name(&self) -> &'static str {
+            "echo"
+        }
+
+        fn on_message(&mut self, payload: &[u8]) -> Result<Vec<Vec<u8>>, ExtensionError> {
+            Ok(vec![payload.to_vec()])
+        }
+    }
+
+    /// Never replies.
+    struct Other;
+
+    impl Extension for Other {
+        fn name(&self) -> &'static str {
+            "other"
+        }
+
+        fn on_message(&mut self, _: &[u8]) -> Result<Vec<Vec<u8>>, ExtensionError> {
+            Ok(Vec::new())
+        }
+    }
+
+    fn payload(message: Message) -> (u8, Vec<u8>) {
+        match message {
+            Message::Extended { id, payload } => (id, payload),
+            other => panic!("expected an extended message, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn exchanges_handshakes_and_routes_messages() {
+        let (a, b): (SocketAddr, SocketAddr) = (
+            "203.0.113.1:6881".parse().unwrap(),
+            "198.51.100.2:51413".parse().unwrap(),
+        );
+        let external_ip = ExternalIp::default();
+        // A voter on another network already agrees, so their `yourip`
+        // settles our address.
+        external_ip.vote(Voter::Node("192.0.2.1".parse().unwrap()), a.ip());
+        let mut ours = Extensions::new(b, 6881).with_external_ip(external_ip.clone());
+        ours.register(Echo);
+        // The peer numbers its extensions differently.
+        let mut theirs = Extensions::new(a, 51413);
+        theirs.register(Other);
+        theirs.register(Echo);
+
+        assert!(ours.message("echo", vec![1]).is_none());
+        let (id, handshake) = payload(theirs.handshake());
+        assert!(ours.handle(id, &handshake).unwrap().is_empty());
+        assert_eq!(ours.theirs().unwrap().p, Some(51413));
+        assert_eq!(ours.theirs().unwrap().reqq, Some(250));
+        assert_eq!(external_ip.ipv4(), Some("203.0.113.1".parse().unwrap()));
+        assert!(ours.supports("other") && !ours.supports("ut_pex"));
+
+        // We send with their ID for echo, and they reply with ours.
+        let (id, data) = payload(ours.message("echo", vec![7]).unwrap());
+        assert_eq!(id, 2);
+        let (id, handshake) = payload(ours.handshake());
+        theirs.handle(id, &handshake).unwrap();
+        let reply = theirs.handle(2, &data).unwrap();
+        assert_eq!(
+            reply,
+            vec![Message::Extended {
+                id: 1,
+                payload: vec![7]
+            }]
+        );
+
+        assert!(ours.handle(9, &[]).is_err());
+        assert!(ours.handle(0, b"not bencode").is_err());
+    }
+}
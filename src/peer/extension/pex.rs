@@ -0,0 +1,333 @@
+//! Peer exchange: connected peers tell each other who else is in the swarm,
+//! as a diff against what they said last time.
+//! See: http://www.bittorrent.org/beps/bep_0011.html
+
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use snafu::prelude::*;
+use tokio::time::Instant;
+
+use super::{holepunch, BencodeSnafu, ExtendedHandshake, Extension, ExtensionError};
+use crate::peer::manager::{ConnectionManager, PeerSource};
+use crate::torrent::Info;
+
+const NAME: &str = "ut_pex";
+/// We send each peer at most one message this often.
+const PEX_INTERVAL: Duration = Duration::from_secs(60);
+/// Peers added, and dropped, per message.
+const MAX_PEERS: usize = 50;
+
+/// What a peer in a PEX message is known to do.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct PexFlags(u8);
+
+impl PexFlags {
+    pub(crate) const ENCRYPTION: Self = Self(0x01);
+    #[cfg(test)]
+    pub(crate) const SEED: Self = Self(0x02);
+    pub(crate) const UTP: Self = Self(0x04);
+    pub(crate) const HOLEPUNCH: Self = Self(0x08);
+    /// We reached it by connecting out, so others can too.
+    pub(crate) const REACHABLE: Self = Self(0x10);
+
+    pub(crate) fn with(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+}
+
+/// The peers a torrent is connected to, as its PEX messages describe them.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct PexSwarm {
+    peers: Arc<Mutex<HashMap<SocketAddr, PexFlags>>>,
+}
+
+impl PexSwarm {
+    /// None for private torrents, whose peers may only come from the tracker.
+    pub(crate) fn new(info: &Info) -> Option<Self> {
+        (!info.is_private()).then(Self::default)
+    }
+
+    /// `addr` is where others can reach the peer: for peers that connected
+    /// to us, the port from their extended handshake.
+    pub(crate) fn connected(&self, addr: SocketAddr, flags: PexFlags) {
+        self.peers.lock().unwrap().insert(addr, flags);
+    }
+
+    pub(crate) fn disconnected(&self, addr: &SocketAddr) {
+        self.peers.lock().unwrap().remove(addr);
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct PexMessage {
+    #[serde(default)]
+    added: ByteBuf,
+    #[serde(default, rename = "added.f")]
+    added_flags: ByteBuf,
+    #[serde(default)]
+    dropped: ByteBuf,
+    #[serde(default)]
+    added6: ByteBuf,
+    #[serde(default, rename = "added6.f")]
+    added6_flags: ByteBuf,
+    #[serde(default)]
+    dropped6: ByteBuf,
+}
+
+/// ut_pex on one connection. Once the peer's extended handshake arrives it
+/// is listed in the [`PexSwarm`] until the connection closes.
+pub(crate) struct UtPex {
+    peer: SocketAddr,
+    swarm: PexSwarm,
+    manager: Arc<ConnectionManager>,
+    flags: PexFlags,
+    /// The peer connected to us, so `peer` isn't where it listens.
+    accepted: bool,
+    /// Where we list the peer.
+    listed: Option<SocketAddr>,
+    /// The peers we've told this one about.
+    sent: HashMap<SocketAddr, PexFlags>,
+    last_sent: Option<Instant>,
+}
+
+impl UtPex {
+    /// Peers `peer` tells us about are queued on `manager`.
+    pub(crate) fn new(peer: SocketAddr, swarm: PexSwarm, manager: Arc<ConnectionManager>) -> Self {
+        Self {
+            peer,
+            swarm,
+            manager,
+            flags: PexFlags::default(),
+            accepted: false,
+            listed: None,
+            sent: HashMap::new(),
+            last_sent: None,
+        }
+    }
+
+    /// Lists the peer with `flags`, on top of what its handshake tells us.
+    pub(crate) fn with_flags(mut self, flags: PexFlags) -> Self {
+        self.flags = flags;
+        self
+    }
+
+    /// The peer connected to us, so it's listed at the port from its
+    /// handshake.
+    pub(crate) fn accepted(mut self) -> Self {
+        self.accepted = true;
+        self
+    }
+}
+
+impl Extension for UtPex {
+    fn name(&self) -> &'static str {
+        NAME
+    }
+
+    fn on_handshake(&mut self, theirs: &ExtendedHandshake) {
+        let (listed, mut flags) = match self.accepted {
+            false => (Some(self.peer), self.flags.with(PexFlags::REACHABLE)),
+            true => (theirs.listen_addr(self.peer), self.flags),
+        };
+        if theirs.m.get(holepunch::NAME).is_some_and(|&id| id != 0) {
+            flags = flags.with(PexFlags::HOLEPUNCH);
+        }
+        if let Some(old) = self.listed.take() {
+            self.swarm.disconnected(&old);
+        }
+        if let Some(listed) = listed {
+            self.swarm.connected(listed, flags);
+        }
+        self.listed = listed;
+    }
+
+    fn on_message(&mut self, payload: &[u8]) -> Result<Vec<Vec<u8>>, ExtensionError> {
+        let message: PexMessage = serde_bencode::from_bytes(payload).context(BencodeSnafu)?;
+        let mut added = addrs_from_bytes(&message.added, false)?;
+        added.extend(addrs_from_bytes(&message.added6, true)?);
+        self.manager.add_peers(PeerSource::Pex, added);
+        Ok(Vec::new())
+    }
+
+    fn poll(&mut self, now: Instant) -> Vec<Vec<u8>> {
+        if self
+            .last_sent
+            .is_some_and(|at| now.duration_since(at) < PEX_INTERVAL)
+        {
+            return Vec::new();
+        }
+        let current = self.swarm.peers.lock().unwrap().clone();
+        let them = self.listed.unwrap_or(self.peer);
+        let added: Vec<_> = current
+            .iter()
+            .filter(|(addr, flags)| **addr != them && self.sent.get(addr) != Some(flags))
+            .map(|(addr, flags)| (*addr, *flags))
+            .take(MAX_PEERS)
+            .collect();
+        let dropped: Vec<_> = self
+            .sent
+            .keys()
+            .filter(|addr| !current.contains_key(addr))
+            .copied()
+            .take(MAX_PEERS)
+            .collect();
+        if added.is_empty() && dropped.is_empty() {
+            return Vec::new();
+        }
+
+        let mut message = PexMessage::default();
+        for (addr, flags) in &added {
+            let (list, list_flags) = match addr {
+                SocketAddr::V4(_) => (&mut message.added, &mut message.added_flags),
+                SocketAddr::V6(_) => (&mut message.added6, &mut message.added6_flags),
+            };
+            list.extend(addr_to_bytes(addr));
+            list_flags.push(flags.0);
+            self.sent.insert(*addr, *flags);
+        }
+        for addr in &dropped {
+            match addr {
+                SocketAddr::V4(_) => message.dropped.extend(addr_to_bytes(addr)),
+                SocketAddr::V6(_) => message.dropped6.extend(addr_to_bytes(addr)),
+            }
+            self.sent.remove(addr);
+        }
+        self.last_sent = Some(now);
+        vec![serde_bencode::to_bytes(&message).expect("pex message always encodes")]
+    }
+}
+
+impl Drop for UtPex {
+    fn drop(&mut self) {
+        if let Some(listed) = self.listed {
+            self.swarm.disconnected(&listed);
+        }
+    }
+}
+
+/// The compact form of an address: the IP then the port, big-endian.
+pub(crate) fn addr_to_bytes(addr: &SocketAddr) -> Vec<u8> {
+    let mut bytes = match addr.ip() {
+        IpAddr::V4(ip) => ip.octets().to_vec(),
+        IpAddr::V6(ip) => ip.octets().to_vec(),
+    };
+    bytes.extend(addr.port().to_be_bytes());
+    bytes
+}
+
+/// Reads a list of compact addresses, all IPv4 or all IPv6.
+pub(crate) fn addrs_from_bytes(bytes: &[u8], v6: bool) -> Result<Vec<SocketAddr>, ExtensionError> {
+    let len = if v6 { 18 } else { 6 };
+    ensure!(
+        bytes.len().is_multiple_of(len),
+        super::InvalidSnafu {
+            name: NAME,
+            reason: format!("{} bytes isn't a list of compact addresses", bytes.len()),
+        }
+    );
+    Ok(bytes
+        .chunks_exact(len)
+        .map(|chunk| {
+            let (ip, port) = chunk.split_at(len - 2);
+            let ip = match <[u8; 16]>::try_from(ip) {
+                Ok(octets) => IpAddr::V6(Ipv6Addr::from(octets)),
+                Err(_) => IpAddr::V4(Ipv4Addr::new(ip[0], ip[1], ip[2], ip[3])),
+            };
+            SocketAddr::new(ip, u16::from_be_bytes([port[0], port[1]]))
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+
+    use tokio::time::Instant;
+
+    use super::{PexFlags, PexMessage, PexSwarm, UtPex, PEX_INTERVAL};
+    use crate::peer::extension::Extension;
+    use crate::peer::manager::{ConnectionConfig, ConnectionLimits, ConnectionManager, PeerSource};
+    use crate::torrent::{Info, InfoHash, PeerId};
+
+    fn manager() -> Arc<ConnectionManager> {
+        Arc::new(ConnectionManager::new(
+            InfoHash::from([1; 20]),
+            PeerId::new(),
+            ConnectionLimits::new(ConnectionConfig::default()),
+        ))
+    }
+
+    fn decode(payload: &[u8]) -> PexMessage {
+        serde_bencode::from_bytes(payload).unwrap()
+    }
+
+    #[test]
+    fn sends_diffs_at_most_once_a_minute() {
+        let them: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let (v4, v6): (SocketAddr, SocketAddr) = (
+            "10.0.0.2:6881".parse().unwrap(),
+            "[2001:db8::2]:6881".parse().unwrap(),
+        );
+        let swarm = PexSwarm::default();
+        swarm.connected(them, PexFlags::default());
+        swarm.connected(v4, PexFlags::SEED.with(PexFlags::UTP));
+        swarm.connected(v6, PexFlags::REACHABLE);
+        let mut pex = UtPex::new(them, swarm.clone(), manager());
+
+        let now = Instant::now();
+        let first = decode(&pex.poll(now)[0]);
+        // The peer isn't told about itself.
+        assert_eq!(&first.added[..], &[10, 0, 0, 2, 0x1a, 0xe1]);
+        assert_eq!(&first.added_flags[..], &[0x06]);
+        assert_eq!(first.added6.len(), 18);
+        assert_eq!(&first.added6_flags[..], &[0x10]);
+
+        swarm.disconnected(&v4);
+        assert!(pex.poll(now + PEX_INTERVAL / 2).is_empty());
+        let second = decode(&pex.poll(now + PEX_INTERVAL)[0]);
+        assert!(second.added.is_empty() && second.added6.is_empty());
+        assert_eq!(&second.dropped[..], &[10, 0, 0, 2, 0x1a, 0xe1]);
+        // Nothing changed since.
+        assert!(pex.poll(now + PEX_INTERVAL * 2).is_empty());
+    }
+
+    #[test]
+    fn queues_received_peers_as_pex() {
+        let them: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let swarm = PexSwarm::default();
+        swarm.connected("10.0.0.3:51413".parse().unwrap(), PexFlags::ENCRYPTION);
+        swarm.connected("[2001:db8::3]:51413".parse().unwrap(), PexFlags::default());
+        let payload = UtPex::new(them, swarm, manager()).poll(Instant::now());
+
+        let manager = manager();
+        let mut pex = UtPex::new(them, PexSwarm::default(), manager.clone());
+        assert!(pex.on_message(&payload[0]).unwrap().is_empty());
+        for addr in ["10.0.0.3:51413", "[2001:db8::3]:51413"] {
+            assert_eq!(
+                manager.source(&addr.parse().unwrap()),
+                Some(PeerSource::Pex)
+            );
+        }
+        assert!(pex.on_message(b"d5:added5:shorte").is_err());
+    }
+
+    #[test]
+    fn disabled_for_private_torrents() {
+        let info = |private: &str| -> Info {
+            let bencode = format!(
+                "d6:lengthi1e4:name1:a12:piece lengthi16384e6:pieces20:{}{private}e",
+                "x".repeat(20)
+            );
+            serde_bencode::from_bytes(bencode.as_bytes()).unwrap()
+        };
+        assert!(PexSwarm::new(&info("")).is_some());
+        assert!(PexSwarm::new(&info("7:privatei0e")).is_some());
+        assert!(PexSwarm::new(&info("7:privatei1e")).is_none());
+    }
+}
@@ -0,0 +1,192 @@
+//! The fast extension: shorthand for having everything or nothing, explicit
+//! rejects, and pieces a peer may download even while choked.
+//! See: http://www.bittorrent.org/beps/bep_0006.html
+
+use std::collections::HashSet;
+use std::net::IpAddr;
+
+use sha1::{Digest, Sha1};
+
+use super::message::Message;
+use crate::piece::Bitfield;
+use crate::torrent::InfoHash;
+
+/// Pieces we let each peer download while choked.
+const ALLOWED_FAST_COUNT: usize = 10;
+/// Suggestions kept per peer; older ones are dropped.
+const MAX_SUGGESTIONS: usize = 16;
+
+/// The canonical allowed fast set for a peer at `ip`, so that peers behind
+/// one address can't collect a set each. Only defined for IPv4.
+pub(crate) fn allowed_fast_set(
+    ip: IpAddr,
+    info_hash: &InfoHash,
+    pieces: usize,
+    count: usize,
+) -> Vec<u32> {
+    let ip = match ip {
+        IpAddr::V4(ip) => ip,
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => ip,
+            None => return Vec::new(),
+        },
+    };
+    let count = count.min(pieces);
+    let mut x = (u32::from(ip) & 0xffff_ff00).to_be_bytes().to_vec();
+    x.extend_from_slice(info_hash.as_bytes());
+    let mut set = Vec::with_capacity(count);
+    while set.len() < count {
+        x = Sha1::digest(&x).to_vec();
+        for chunk in x.chunks_exact(4) {
+            if set.len() == count {
+                break;
+            }
+            let y = u32::from_be_bytes(chunk.try_into().unwrap());
+            let index = y % pieces as u32;
+            if !set.contains(&index) {
+                set.push(index);
+            }
+        }
+    }
+    set
+}
+
+/// What to send in place of a bitfield to a peer with the fast extension.
+pub(crate) fn have_message(ours: &Bitfield) -> Message {
+    match ours.count() {
+        0 => Message::HaveNone,
+        n if n == ours.len() => Message::HaveAll,
+        _ => Message::Bitfield {
+            bitfield: ours.as_bytes().to_vec(),
+        },
+    }
+}
+
+/// Fast extension state for a connection to a peer that supports it.
+#[derive(Debug, Default)]
+pub(crate) struct FastState {
+    /// Pieces we let the peer download while we choke it. Only tests ask,
+    /// since we don't serve pieces yet.
+    #[cfg(test)]
+    allowed_to_them: HashSet<u32>,
+    /// Pieces the peer lets us download while it chokes us.
+    allowed_to_us: HashSet<u32>,
+    suggested: Vec<u32>,
+}
+
+impl FastState {
+    /// Works out what the peer at `ip` may download while choked, returning
+    /// the messages telling it so.
+    pub(crate) fn new(ip: IpAddr, info_hash: &InfoHash, pieces: usize) -> (Self, Vec<Message>) {
+        let allowed = allowed_fast_set(ip, info_hash, pieces, ALLOWED_FAST_COUNT);
+        let messages = allowed
+            .iter()
+            .map(|&index| Message::AllowedFast { index })
+            .collect();
+        let state = Self {
+            #[cfg(test)]
+            allowed_to_them: allowed.into_iter().collect(),
+            ..Default::default()
+        };
+        (state, messages)
+    }
+
+    /// Takes in an allowed fast or suggest piece message.
+    pub(crate) fn received(&mut self, message: &Message) {
+        match *message {
+            Message::AllowedFast { index } => {
+                self.allowed_to_us.insert(index);
+            }
+            Message::SuggestPiece { index } => {
+                self.suggested.retain(|&i| i != index);
+                if self.suggested.len() == MAX_SUGGESTIONS {
+                    self.suggested.remove(0);
+                }
+                self.suggested.push(index);
+            }
+            _ => {}
+        }
+    }
+
+    /// Whether to serve a request for `index` rather than reject it.
+    #[cfg(test)]
+    pub(crate) fn accepts_request(&self, index: u32, choking: bool) -> bool {
+        !choking || self.allowed_to_them.contains(&index)
+    }
+
+    /// The pieces we can request from a peer that `has` them: all of them
+    /// when unchoked, and only the allowed fast ones while choked.
+    pub(crate) fn requestable(&self, has: &Bitfield, choked: bool) -> Bitfield {
+        self.only(has, |index| !choked || self.allowed_to_us.contains(&index))
+    }
+
+    /// The requestable pieces the peer suggested, to try first.
+    pub(crate) fn suggested(&self, has: &Bitfield, choked: bool) -> Bitfield {
+        let requestable = self.requestable(has, choked);
+        self.only(&requestable, |index| self.suggested.contains(&index))
+    }
+
+    fn only(&self, has: &Bitfield, keep: impl Fn(u32) -> bool) -> Bitfield {
+        let mut bitfield = Bitfield::new(has.len());
+        for index in has.iter_ones().filter(|&i| keep(i as u32)) {
+            bitfield.set(index);
+        }
+        bitfield
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{allowed_fast_set, have_message, FastState};
+    use crate::peer::message::Message;
+    use crate::piece::Bitfield;
+    use crate::torrent::InfoHash;
+
+    #[test]
+    fn allowed_fast_set_matches_bep() {
+        let info_hash = InfoHash::from([0xaa; 20]);
+        let ip = "80.4.4.200".parse().unwrap();
+        assert_eq!(
+            allowed_fast_set(ip, &info_hash, 1313, 7),
+            vec![1059, 431, 808, 1217, 287, 376, 1188]
+        );
+        assert_eq!(
+            allowed_fast_set(ip, &info_hash, 1313, 9),
+            vec![1059, 431, 808, 1217, 287, 376, 1188, 353, 508]
+        );
+        // The last octet doesn't matter.
+        let neighbour = "80.4.4.1".parse().unwrap();
+        assert_eq!(
+            allowed_fast_set(neighbour, &info_hash, 1313, 7),
+            allowed_fast_set(ip, &info_hash, 1313, 7)
+        );
+    }
+
+    #[test]
+    fn choked_peers_get_allowed_fast_pieces_only() {
+        let (mut state, messages) =
+            FastState::new("10.0.0.1".parse().unwrap(), &InfoHash::from([1; 20]), 8);
+        assert_eq!(messages.len(), 8);
+        assert!(state.accepts_request(3, true));
+
+        state.received(&Message::AllowedFast { index: 2 });
+        state.received(&Message::SuggestPiece { index: 5 });
+        let has = Bitfield::full(8);
+        assert_eq!(
+            state
+                .requestable(&has, true)
+                .iter_ones()
+                .collect::<Vec<_>>(),
+            vec![2]
+        );
+        assert_eq!(state.requestable(&has, false), has);
+        assert_eq!(
+            state.suggested(&has, false).iter_ones().collect::<Vec<_>>(),
+            vec![5]
+        );
+        assert_eq!(state.suggested(&has, true).count(), 0);
+
+        assert_eq!(have_message(&has), Message::HaveAll);
+        assert_eq!(have_message(&Bitfield::new(8)), Message::HaveNone);
+    }
+}
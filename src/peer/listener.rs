@@ -0,0 +1,408 @@
+//! Accepting peers that connect to us. Every torrent shares one port, over
+//! TCP and uTP, so connections are routed by the info hash in their
+//! handshake.
+//! Encrypted connections are told apart from plaintext ones by their first
+//! bytes, which for plaintext are always the protocol header.
+
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use futures::StreamExt;
+use log::{debug, info, warn};
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::io::AsyncReadExt;
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
+use tokio_util::codec::Framed;
+
+use super::codec::HandshakeCodec;
+use super::manager::{ConnectionLimits, ConnectionManager, PeerConnection};
+use super::message::{Handshake, PROTOCOL};
+use super::mse::{self, EncryptionPolicy, MseStream};
+use super::transport::PeerStream;
+use crate::net::UdpMux;
+use crate::torrent::InfoHash;
+use crate::utp::UtpSocket;
+
+/// How long to wait after a failed accept before trying again.
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+struct Route {
+    manager: Arc<ConnectionManager>,
+    connections: mpsc::Sender<PeerConnection>,
+}
+
+/// The torrents accepting connections, by info hash.
+#[derive(Clone, Default)]
+pub(crate) struct Routes {
+    routes: Arc<Mutex<HashMap<InfoHash, Route>>>,
+}
+
+impl Routes {
+    /// Sends peers connecting for `manager`'s torrent to `connections`, once
+    /// `manager` has let them in.
+    pub(crate) fn add(
+        &self,
+        manager: Arc<ConnectionManager>,
+        connections: mpsc::Sender<PeerConnection>,
+    ) {
+        self.routes.lock().unwrap().insert(
+            manager.info_hash().clone(),
+            Route {
+                manager,
+                connections,
+            },
+        );
+    }
+
+    pub(crate) fn remove(&self, info_hash: &InfoHash) {
+        self.routes.lock().unwrap().remove(info_hash);
+    }
+
+    fn info_hashes(&self) -> Vec<InfoHash> {
+        self.routes.lock().unwrap().keys().cloned().collect()
+    }
+
+    fn get(
+        &self,
+        info_hash: &InfoHash,
+    ) -> Option<(Arc<ConnectionManager>, mpsc::Sender<PeerConnection>)> {
+        let routes = self.routes.lock().unwrap();
+        let route = routes.get(info_hash)?;
+        Some((route.manager.clone(), route.connections.clone()))
+    }
+}
+
+pub(crate) struct PeerListener {
+    listener: TcpListener,
+    udp: UdpMux,
+    utp: UtpSocket,
+    routes: Routes,
+    handshake_timeout: Duration,
+    encryption: EncryptionPolicy,
+}
+
+impl PeerListener {
+    /// Listens for TCP on `addr`, and for uTP on the same port. IPv6
+    /// listeners are kept to IPv6, so that an IPv4 one can have the same
+    /// port.
+    pub(crate) async fn bind(addr: SocketAddr, limits: &ConnectionLimits) -> io::Result<Self> {
+        let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+        if addr.is_ipv6() {
+            socket.set_only_v6(true)?;
+        }
+        socket.set_reuse_address(true)?;
+        socket.set_nonblocking(true)?;
+        socket.bind(&addr.into())?;
+        socket.listen(1024)?;
+        let listener = TcpListener::from_std(socket.into())?;
+        let udp = UdpMux::bind(listener.local_addr()?).await?;
+        let utp = UtpSocket::on(&udp);
+        Ok(Self {
+            listener,
+            udp,
+            utp,
+            routes: Routes::default(),
+            handshake_timeout: limits.connect_timeout(),
+            encryption: limits.encryption(),
+        })
+    }
+
+    /// The address we're listening on, whose port is the one to announce.
+    pub(crate) fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    pub(crate) fn routes(&self) -> Routes {
+        self.routes.clone()
+    }
+
+    /// Routes connections to the same torrents as another listener, such as
+    /// the one for the other address family.
+    pub(crate) fn with_routes(mut self, routes: Routes) -> Self {
+        self.routes = routes;
+        self
+    }
+
+    /// The UDP socket uTP runs over, for the DHT and trackers to share.
+    pub(crate) fn udp(&self) -> UdpMux {
+        self.udp.clone()
+    }
+
+    /// The uTP socket, for dialing peers from the port we announce.
+    pub(crate) fn utp(&self) -> UtpSocket {
+        self.utp.clone()
+    }
+
+    pub(crate) async fn run(self) -> io::Result<()> {
+        info!("Listening for peers on {}", self.local_addr()?);
+        loop {
+            let (stream, addr) = tokio::select! {
+                accepted = self.listener.accept() => {
+                    match accepted {
+                        Ok((stream, addr)) => (PeerStream::Tcp(stream), addr),
+                        Err(e) => {
+                            // Running out of descriptors or a peer hanging up
+                            // mid-accept passes; don't stop listening for it.
+                            warn!("Couldn't accept a peer: {e}");
+                            tokio::time::sleep(ACCEPT_BACKOFF).await;
+                            continue;
+                        }
+                    }
+                }
+                stream = self.utp.accept() => {
+                    let addr = stream.peer_addr();
+                    (PeerStream::Utp(stream), addr)
+                }
+            };
+            let routes = self.routes.clone();
+            let timeout = self.handshake_timeout;
+            let encryption = self.encryption;
+            tokio::spawn(async move {
+                if let Err(reason) = accept(routes, stream, addr, timeout, encryption).await {
+                    debug!("Dropping incoming connection from {addr}: {reason}");
+                }
+            });
+        }
+    }
+}
+
+async fn accept(
+    routes: Routes,
+    stream: PeerStream,
+    addr: SocketAddr,
+    timeout: Duration,
+    encryption: EncryptionPolicy,
+) -> Result<(), String> {
+    let (framed, handshake) =
+        match tokio::time::timeout(timeout, handshake(&routes, stream, encryption)).await {
+            Err(_) => return Err("handshake timed out".into()),
+            Ok(result) => result?,
+        };
+    let (manager, connections) = routes
+        .get(handshake.info_hash())
+        .ok_or_else(|| format!("unknown info hash {}", handshake.info_hash()))?;
+    let connection = manager
+        .accept(addr, framed, handshake)
+        .await
+        .map_err(|e| e.to_string())?;
+    let _ = connections.send(connection).await;
+    Ok(())
+}
+
+async fn handshake(
+    routes: &Routes,
+    mut stream: PeerStream,
+    encryption: EncryptionPolicy,
+) -> Result<(Framed<MseStream<PeerStream>, HandshakeCodec>, Handshake), String> {
+    let mut header = vec![0; 1 + PROTOCOL.len()];
+    stream
+        .read_exact(&mut header)
+        .await
+        .map_err(|e| e.to_string())?;
+    let plaintext = header[0] as usize == PROTOCOL.len() && header[1..] == PROTOCOL[..];
+    let stream = MseStream::new(stream, header);
+    let stream = match (plaintext, encryption) {
+        (true, EncryptionPolicy::Required) => return Err("plaintext refused".into()),
+        (false, EncryptionPolicy::Disabled) => return Err("encryption refused".into()),
+        (true, _) => stream,
+        (false, _) => {
+            mse::respond(stream, &routes.info_hashes(), encryption)
+                .await
+                .map_err(|e| e.to_string())?
+                .0
+        }
+    };
+    let mut framed = Framed::new(stream, HandshakeCodec);
+    match framed.next().await {
+        None => Err("closed before handshake".into()),
+        Some(handshake) => Ok((framed, handshake.map_err(|e| e.to_string())?)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use futures::{SinkExt, StreamExt};
+    use tokio::net::TcpStream;
+    use tokio::sync::mpsc;
+    use tokio_util::codec::Framed;
+
+    use super::PeerListener;
+    use crate::peer::codec::{Frame, HandshakeCodec};
+    use crate::peer::manager::{
+        ConnectionConfig, ConnectionLimits, ConnectionManager, PeerConnection, PeerSource,
+    };
+    use crate::peer::message::{Handshake, Message, ReservedBit};
+    use crate::peer::mse::EncryptionPolicy;
+    use crate::torrent::{InfoHash, PeerId};
+    use crate::utp::UtpSocket;
+
+    async fn listen(
+        config: ConnectionConfig,
+    ) -> (SocketAddr, PeerId, mpsc::Receiver<PeerConnection>) {
+        let limits = ConnectionLimits::new(config);
+        let listener = PeerListener::bind("127.0.0.1:0".parse().unwrap(), &limits)
+            .await
+            .unwrap();
+        let addr = listener.local_addr().unwrap();
+        let ours = PeerId::new();
+        let manager = ConnectionManager::new(InfoHash::from([5; 20]), ours.clone(), limits);
+        let (tx, rx) = mpsc::channel(8);
+        listener.routes().add(Arc::new(manager), tx);
+        tokio::spawn(listener.run());
+        (addr, ours, rx)
+    }
+
+    /// Connects and handshakes, returning the reply if there is one.
+    async fn connect(addr: SocketAddr, info_hash: [u8; 20]) -> Option<Handshake> {
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let mut framed = Framed::new(stream, HandshakeCodec);
+        framed
+            .send(Handshake::new(InfoHash::from(info_hash), PeerId::new()))
+            .await
+            .unwrap();
+        framed.next().await.and_then(Result::ok)
+    }
+
+    /// Dials `addr` as another client would, giving up after a second.
+    async fn dial(addr: SocketAddr, limits: ConnectionLimits) -> Option<PeerConnection> {
+        let manager = Arc::new(ConnectionManager::new(
+            InfoHash::from([5; 20]),
+            PeerId::new(),
+            limits,
+        ));
+        let (tx, mut rx) = mpsc::channel(1);
+        tokio::spawn(manager.clone().run(tx));
+        manager.add_peers(PeerSource::Tracker, [addr]);
+        tokio::time::timeout(Duration::from_secs(1), rx.recv())
+            .await
+            .ok()
+            .flatten()
+    }
+
+    #[tokio::test]
+    async fn routes_by_info_hash() {
+        let (addr, ours, mut rx) = listen(ConnectionConfig::default()).await;
+
+        let reply = connect(addr, [5; 20]).await.unwrap();
+        assert_eq!(reply.peer_id(), &ours);
+        assert!(reply.supports(ReservedBit::Extension));
+        assert!(reply.supports(ReservedBit::Fast));
+        let connection = rx.recv().await.unwrap();
+        assert_eq!(connection.source, PeerSource::Incoming);
+
+        assert_eq!(connect(addr, [6; 20]).await, None);
+    }
+
+    #[tokio::test]
+    async fn refuses_connections_over_the_cap() {
+        let (addr, _, mut rx) = listen(ConnectionConfig {
+            max_connections: 1,
+            ..Default::default()
+        })
+        .await;
+
+        assert!(connect(addr, [5; 20]).await.is_some());
+        let _connection = rx.recv().await.unwrap();
+        assert_eq!(connect(addr, [5; 20]).await, None);
+    }
+
+    #[tokio::test]
+    async fn encrypts_as_both_policies_allow() {
+        use EncryptionPolicy::*;
+        let config = |encryption| ConnectionConfig {
+            encryption,
+            ..Default::default()
+        };
+        for (dialer, listener, encrypted) in [
+            (Required, Required, Some(true)),
+            (Preferred, Preferred, Some(true)),
+            (Preferred, Disabled, Some(false)),
+            (Disabled, Preferred, Some(false)),
+            (Required, Disabled, None),
+            (Disabled, Required, None),
+        ] {
+            let (addr, _, mut rx) = listen(config(listener)).await;
+            let connection = dial(addr, ConnectionLimits::new(config(dialer))).await;
+            assert_eq!(
+                connection
+                    .as_ref()
+                    .map(|c| c.framed.get_ref().is_encrypted()),
+                encrypted,
+                "{dialer:?} dialing {listener:?}"
+            );
+            if let Some(mut ours) = connection {
+                let mut theirs = rx.recv().await.unwrap();
+                assert_eq!(theirs.framed.get_ref().is_encrypted(), encrypted.unwrap());
+                ours.framed.send(Message::Interested).await.unwrap();
+                let received = theirs.framed.next().await.unwrap().unwrap();
+                assert_eq!(received, Frame::Message(Message::Interested));
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn accepts_utp_on_the_same_port() {
+        let (addr, ours, mut rx) = listen(ConnectionConfig::default()).await;
+        let utp = UtpSocket::bind("127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap();
+        let limits = ConnectionLimits::new(ConnectionConfig::default()).with_utp(utp);
+
+        let mut connection = dial(addr, limits).await.unwrap();
+        assert!(connection.framed.get_ref().get_ref().is_utp());
+        assert_eq!(connection.handshake.peer_id(), &ours);
+        let mut theirs = rx.recv().await.unwrap();
+        assert!(theirs.framed.get_ref().get_ref().is_utp());
+        connection.framed.send(Message::Interested).await.unwrap();
+        let received = theirs.framed.next().await.unwrap().unwrap();
+        assert_eq!(received, Frame::Message(Message::Interested));
+    }
+
+    #[tokio::test]
+    async fn listens_on_both_families_on_one_port() {
+        let limits = ConnectionLimits::new(ConnectionConfig::default());
+        let v4 = PeerListener::bind("127.0.0.1:0".parse().unwrap(), &limits)
+            .await
+            .unwrap();
+        let port = v4.local_addr().unwrap().port();
+        let v6 = PeerListener::bind(SocketAddr::from(([0, 0, 0, 0, 0, 0, 0, 1], port)), &limits)
+            .await
+            .unwrap()
+            .with_routes(v4.routes());
+        let manager = ConnectionManager::new(InfoHash::from([5; 20]), PeerId::new(), limits);
+        let (tx, mut rx) = mpsc::channel(8);
+        v4.routes().add(Arc::new(manager), tx);
+        tokio::spawn(v4.run());
+        tokio::spawn(v6.run());
+
+        // Over TCP, then from an IPv6 uTP socket beside an IPv4 one.
+        let addr = SocketAddr::from(([0, 0, 0, 0, 0, 0, 0, 1], port));
+        let utp = |addr: &str| UtpSocket::bind(addr.parse().unwrap());
+        let (utp_v4, utp_v6) = (
+            utp("127.0.0.1:0").await.unwrap(),
+            utp("[::1]:0").await.unwrap(),
+        );
+        for (limits, is_utp) in [
+            (ConnectionLimits::new(ConnectionConfig::default()), false),
+            (
+                ConnectionLimits::new(ConnectionConfig::default())
+                    .with_utp(utp_v4)
+                    .with_utp(utp_v6),
+                true,
+            ),
+        ] {
+            let connection = dial(addr, limits).await.unwrap();
+            assert_eq!(connection.framed.get_ref().get_ref().is_utp(), is_utp);
+            let theirs = rx.recv().await.unwrap();
+            assert!(theirs.addr.is_ipv6());
+        }
+    }
+}
@@ -0,0 +1,777 @@
+//! Dialing the peers we hear about. Each torrent has a [`ConnectionManager`]
+//! that queues candidate addresses from every source and dials them a few at
+//! a time, while [`ConnectionLimits`] caps connections across the session.
+//! Peers are dialed over uTP first, and over TCP if that fails.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use futures::{SinkExt, StreamExt};
+use log::{debug, info};
+use serde::Deserialize;
+use snafu::prelude::*;
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, Notify, OwnedSemaphorePermit, Semaphore};
+use tokio::time::Instant;
+use tokio_util::codec::Framed;
+
+use super::ban::BanList;
+use super::codec::{HandshakeCodec, MessageCodec};
+use super::extension::holepunch::HolepunchSwarm;
+use super::message::{Handshake, ReservedBit};
+use super::mse::{self, EncryptionPolicy, MseStream};
+use super::transport::PeerStream;
+use super::{EncryptionSnafu, IoSnafu, PeerError};
+use crate::torrent::{InfoHash, PeerId};
+use crate::utp::UtpSocket;
+
+/// How long to wait before retrying an address after its first failure; the
+/// wait doubles with each failure after that.
+const RETRY_BASE: Duration = Duration::from_secs(30);
+/// Addresses that fail this many times in a row are never dialed again.
+const MAX_FAILURES: u32 = 5;
+
+#[derive(Debug, Clone, Deserialize)]
+pub(crate) struct ConnectionConfig {
+    /// Connections across every torrent.
+    #[serde(default = "default_max_connections")]
+    pub(crate) max_connections: usize,
+    #[serde(default = "default_max_per_torrent")]
+    pub(crate) max_per_torrent: usize,
+    /// Outbound connection attempts in flight at once, per torrent.
+    #[serde(default = "default_max_half_open")]
+    pub(crate) max_half_open: usize,
+    /// Seconds to connect and complete the handshake.
+    #[serde(default = "default_connect_timeout")]
+    pub(crate) connect_timeout: u64,
+    #[serde(default)]
+    pub(crate) encryption: EncryptionPolicy,
+}
+
+fn default_max_connections() -> usize {
+    200
+}
+
+fn default_max_per_torrent() -> usize {
+    50
+}
+
+fn default_max_half_open() -> usize {
+    8
+}
+
+fn default_connect_timeout() -> u64 {
+    10
+}
+
+impl Default for ConnectionConfig {
+    fn default() -> Self {
+        Self {
+            max_connections: default_max_connections(),
+            max_per_torrent: default_max_per_torrent(),
+            max_half_open: default_max_half_open(),
+            connect_timeout: default_connect_timeout(),
+            encryption: EncryptionPolicy::default(),
+        }
+    }
+}
+
+/// Caps shared by every torrent in the session.
+#[derive(Clone)]
+pub(crate) struct ConnectionLimits {
+    config: ConnectionConfig,
+    connections: Arc<Semaphore>,
+    bans: BanList,
+    /// One per address family we listen on.
+    utp: Vec<UtpSocket>,
+}
+
+impl ConnectionLimits {
+    pub(crate) fn new(config: ConnectionConfig) -> Self {
+        Self {
+            connections: Arc::new(Semaphore::new(config.max_connections)),
+            config,
+            bans: BanList::default(),
+            utp: Vec::new(),
+        }
+    }
+
+    /// Dials peers over uTP from `utp` before trying TCP. Called once for
+    /// each address family's socket.
+    pub(crate) fn with_utp(mut self, utp: UtpSocket) -> Self {
+        self.utp.push(utp);
+        self
+    }
+
+    /// The uTP socket that can reach `addr`.
+    fn utp_for(&self, addr: &SocketAddr) -> Option<&UtpSocket> {
+        self.utp.iter().find(|utp| {
+            utp.local_addr()
+                .is_ok_and(|local| local.is_ipv6() == addr.is_ipv6())
+        })
+    }
+
+    /// How long a peer gets to connect and complete the handshake.
+    pub(crate) fn connect_timeout(&self) -> Duration {
+        Duration::from_secs(self.config.connect_timeout)
+    }
+
+    pub(crate) fn encryption(&self) -> EncryptionPolicy {
+        self.config.encryption
+    }
+
+    /// Peers no torrent will connect to again.
+    pub(crate) fn bans(&self) -> &BanList {
+        &self.bans
+    }
+}
+
+/// Where we heard about a peer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) enum PeerSource {
+    Tracker,
+    Dht,
+    Pex,
+    Lsd,
+    /// A holepunch relay put us in touch.
+    Holepunch,
+    Incoming,
+}
+
+/// Why a peer we reached isn't kept.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Rejection {
+    /// It's us, reached through one of our own addresses.
+    OurOwn,
+    /// We already have a connection to this peer.
+    Duplicate,
+    /// The peer is serving a different torrent.
+    WrongTorrent,
+    /// There's no room for another connection.
+    Full,
+    /// The peer has been banned this session.
+    Banned,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Status {
+    Queued,
+    Dialing,
+    Connected,
+    /// Known but not queued until a source mentions it again.
+    Idle,
+    /// Failed, and queued again at the given time.
+    Waiting(Instant),
+    /// Never dialed again.
+    Banned,
+}
+
+#[derive(Debug)]
+struct Candidate {
+    source: PeerSource,
+    status: Status,
+    failures: u32,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    candidates: HashMap<SocketAddr, Candidate>,
+    queue: VecDeque<SocketAddr>,
+    peer_ids: HashSet<PeerId>,
+}
+
+impl State {
+    fn add(&mut self, addr: SocketAddr, source: PeerSource) -> bool {
+        let candidate = self.candidates.entry(addr).or_insert(Candidate {
+            source,
+            status: Status::Idle,
+            failures: 0,
+        });
+        if candidate.status != Status::Idle {
+            return false;
+        }
+        candidate.status = Status::Queued;
+        self.queue.push_back(addr);
+        true
+    }
+
+    fn next(&mut self, now: Instant) -> Option<SocketAddr> {
+        for (addr, candidate) in self.candidates.iter_mut() {
+            if matches!(candidate.status, Status::Waiting(at) if at <= now) {
+                candidate.status = Status::Queued;
+                self.queue.push_back(*addr);
+            }
+        }
+        while let Some(addr) = self.queue.pop_front() {
+            let candidate = self.candidates.get_mut(&addr)?;
+            if candidate.status == Status::Queued {
+                candidate.status = Status::Dialing;
+                return Some(addr);
+            }
+        }
+        None
+    }
+
+    fn next_retry(&self) -> Option<Instant> {
+        self.candidates
+            .values()
+            .filter_map(|c| match c.status {
+                Status::Waiting(at) => Some(at),
+                _ => None,
+            })
+            .min()
+    }
+
+    fn failed(&mut self, addr: SocketAddr, now: Instant) {
+        if let Some(candidate) = self.candidates.get_mut(&addr) {
+            candidate.failures += 1;
+            candidate.status = if candidate.failures >= MAX_FAILURES {
+                Status::Banned
+            } else {
+                Status::Waiting(now + RETRY_BASE * 2_u32.pow(candidate.failures - 1))
+            };
+        }
+    }
+
+    fn set_status(&mut self, addr: SocketAddr, status: Status) {
+        if let Some(candidate) = self.candidates.get_mut(&addr) {
+            candidate.status = status;
+        }
+    }
+}
+
+/// An established, handshaken connection. Dropping it frees its place under
+/// the connection caps.
+pub(crate) struct PeerConnection {
+    pub(crate) addr: SocketAddr,
+    pub(crate) source: PeerSource,
+    pub(crate) handshake: Handshake,
+    pub(crate) framed: Framed<MseStream<PeerStream>, MessageCodec>,
+    _slot: ConnectionSlot,
+}
+
+struct ConnectionSlot {
+    state: Arc<Mutex<State>>,
+    addr: SocketAddr,
+    peer_id: PeerId,
+    _torrent: OwnedSemaphorePermit,
+    _global: OwnedSemaphorePermit,
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        let mut state = self.state.lock().unwrap();
+        state.peer_ids.remove(&self.peer_id);
+        state.set_status(self.addr, Status::Idle);
+    }
+}
+
+pub(crate) struct ConnectionManager {
+    info_hash: InfoHash,
+    peer_id: PeerId,
+    limits: ConnectionLimits,
+    torrent_connections: Arc<Semaphore>,
+    state: Arc<Mutex<State>>,
+    added: Notify,
+    holepunch: Option<HolepunchSwarm>,
+}
+
+impl ConnectionManager {
+    pub(crate) fn new(info_hash: InfoHash, peer_id: PeerId, limits: ConnectionLimits) -> Self {
+        Self {
+            info_hash,
+            peer_id,
+            torrent_connections: Arc::new(Semaphore::new(limits.config.max_per_torrent)),
+            limits,
+            state: Arc::default(),
+            added: Notify::new(),
+            holepunch: None,
+        }
+    }
+
+    /// Asks connected peers to relay a holepunch to PEX peers we can't
+    /// reach over uTP.
+    pub(crate) fn with_holepunch(mut self, swarm: HolepunchSwarm) -> Self {
+        self.holepunch = Some(swarm);
+        self
+    }
+
+    /// Peers we won't connect to, shared by every torrent.
+    pub(crate) fn bans(&self) -> &BanList {
+        self.limits.bans()
+    }
+
+    pub(crate) fn info_hash(&self) -> &InfoHash {
+        &self.info_hash
+    }
+
+    /// Queues addresses to dial. Ones we're connected to, already trying,
+    /// banned or have given up on are left alone.
+    pub(crate) fn add_peers(
+        &self,
+        source: PeerSource,
+        addrs: impl IntoIterator<Item = SocketAddr>,
+    ) {
+        let mut state = self.state.lock().unwrap();
+        let added = addrs
+            .into_iter()
+            .filter(|addr| !self.limits.bans.is_banned(&addr.ip()))
+            .filter(|addr| state.add(*addr, source))
+            .count();
+        if added > 0 {
+            debug!("Queued {added} peers from {source:?}");
+            self.added.notify_one();
+        }
+    }
+
+    /// Dials `addr` at the same time as it dials us, as a holepunch relay
+    /// has just told us both to. If we asked for the holepunch it's dialed
+    /// like any peer, ahead of the queue and even if it failed recently;
+    /// if it asked, we speak second once connected, as if it had dialed us.
+    pub(crate) fn holepunch(&self, addr: SocketAddr, initiator: bool) {
+        if !initiator {
+            if let Some(utp) = self.limits.utp_for(&addr).cloned() {
+                tokio::spawn(async move {
+                    if let Err(e) = utp.holepunch(addr).await {
+                        debug!("Holepunch to {addr} failed: {e}");
+                    }
+                });
+            }
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        let candidate = state.candidates.entry(addr).or_insert(Candidate {
+            source: PeerSource::Holepunch,
+            status: Status::Idle,
+            failures: 0,
+        });
+        if matches!(
+            candidate.status,
+            Status::Idle | Status::Queued | Status::Waiting(_)
+        ) {
+            candidate.status = Status::Queued;
+            state.queue.retain(|queued| *queued != addr);
+            state.queue.push_front(addr);
+            self.added.notify_one();
+        }
+    }
+
+    /// Where we first heard of `addr`, if we have.
+    #[cfg(test)]
+    pub(crate) fn source(&self, addr: &SocketAddr) -> Option<PeerSource> {
+        let state = self.state.lock().unwrap();
+        state.candidates.get(addr).map(|c| c.source)
+    }
+
+    /// How many times in a row dialing `addr` has failed.
+    #[cfg(test)]
+    pub(crate) fn failures(&self, addr: &SocketAddr) -> u32 {
+        let state = self.state.lock().unwrap();
+        state.candidates.get(addr).map_or(0, |c| c.failures)
+    }
+
+    /// Dials queued peers forever, handing each connection that survives the
+    /// handshake to `connections`.
+    pub(crate) async fn run(self: Arc<Self>, connections: mpsc::Sender<PeerConnection>) {
+        let half_open = Arc::new(Semaphore::new(self.limits.config.max_half_open));
+        loop {
+            let addr = self.next_candidate().await;
+            let permit = half_open.clone().acquire_owned().await.unwrap();
+            let manager = self.clone();
+            let connections = connections.clone();
+            tokio::spawn(async move {
+                let result = manager.dial(addr).await;
+                drop(permit);
+                match result {
+                    Ok(connection) => {
+                        let _ = connections.send(connection).await;
+                    }
+                    Err(e) => debug!("Couldn't connect to {addr}: {e}"),
+                }
+            });
+        }
+    }
+
+    async fn next_candidate(&self) -> SocketAddr {
+        loop {
+            let (next, retry) = {
+                let mut state = self.state.lock().unwrap();
+                (state.next(Instant::now()), state.next_retry())
+            };
+            if let Some(addr) = next {
+                return addr;
+            }
+            match retry {
+                Some(at) => {
+                    tokio::select! {
+                        _ = self.added.notified() => {}
+                        _ = tokio::time::sleep_until(at) => {}
+                    }
+                }
+                None => self.added.notified().await,
+            }
+        }
+    }
+
+    async fn dial(&self, addr: SocketAddr) -> Result<PeerConnection, ConnectError> {
+        let torrent = self
+            .torrent_connections
+            .clone()
+            .acquire_owned()
+            .await
+            .unwrap();
+        let global = self
+            .limits
+            .connections
+            .clone()
+            .acquire_owned()
+            .await
+            .unwrap();
+
+        let timeout = self.limits.connect_timeout();
+        let result = tokio::time::timeout(timeout, self.handshake(addr)).await;
+        let (framed, handshake) = match result {
+            Ok(Ok(ok)) => ok,
+            Ok(Err(e)) => {
+                self.failed(addr);
+                return Err(ConnectError::Peer { source: e });
+            }
+            Err(_) => {
+                self.failed(addr);
+                return Err(ConnectError::Timeout);
+            }
+        };
+
+        let source = self.state.lock().unwrap().candidates[&addr].source;
+        let slot = self
+            .register(addr, &handshake, torrent, global)
+            .map_err(|rejection| ConnectError::Rejected { rejection })?;
+        info!("Connected to {addr} ({})", handshake.peer_id());
+        Ok(PeerConnection {
+            addr,
+            source,
+            handshake,
+            framed: framed.map_codec(|_| MessageCodec::default()),
+            _slot: slot,
+        })
+    }
+
+    /// Backs off `addr`. A PEX peer may just be behind a NAT, so we ask for
+    /// a holepunch to it.
+    fn failed(&self, addr: SocketAddr) {
+        let source = {
+            let mut state = self.state.lock().unwrap();
+            state.failed(addr, Instant::now());
+            state.candidates[&addr].source
+        };
+        if let (PeerSource::Pex, Some(swarm), Some(_)) =
+            (source, &self.holepunch, self.limits.utp_for(&addr))
+        {
+            if swarm.rendezvous(addr) {
+                debug!("Asked for a holepunch to {addr}");
+            }
+        }
+    }
+
+    /// Takes on a peer that connected to us and sent `handshake`, replying
+    /// with ours if there's room for it.
+    pub(crate) async fn accept(
+        &self,
+        addr: SocketAddr,
+        mut framed: Framed<MseStream<PeerStream>, HandshakeCodec>,
+        handshake: Handshake,
+    ) -> Result<PeerConnection, ConnectError> {
+        let full = || ConnectError::Rejected {
+            rejection: Rejection::Full,
+        };
+        let torrent = self
+            .torrent_connections
+            .clone()
+            .try_acquire_owned()
+            .map_err(|_| full())?;
+        let global = self
+            .limits
+            .connections
+            .clone()
+            .try_acquire_owned()
+            .map_err(|_| full())?;
+        let slot = self
+            .register(addr, &handshake, torrent, global)
+            .map_err(|rejection| ConnectError::Rejected { rejection })?;
+
+        framed.send(self.our_handshake()).await.context(PeerSnafu)?;
+        info!("Accepted {addr} ({})", handshake.peer_id());
+        Ok(PeerConnection {
+            addr,
+            source: PeerSource::Incoming,
+            handshake,
+            framed: framed.map_codec(|_| MessageCodec::default()),
+            _slot: slot,
+        })
+    }
+
+    fn our_handshake(&self) -> Handshake {
+        Handshake::new(self.info_hash.clone(), self.peer_id.clone())
+            .with(ReservedBit::Extension)
+            .with(ReservedBit::Fast)
+    }
+
+    /// Connects and handshakes, over uTP if the peer takes it and TCP if
+    /// not.
+    async fn handshake(
+        &self,
+        addr: SocketAddr,
+    ) -> Result<(Framed<MseStream<PeerStream>, HandshakeCodec>, Handshake), PeerError> {
+        if let Some(utp) = self.limits.utp_for(&addr) {
+            match self.handshake_with(addr, Some(utp)).await {
+                Err(e) => debug!("uTP connection to {addr} failed, trying TCP: {e}"),
+                result => return result,
+            }
+        }
+        self.handshake_with(addr, None).await
+    }
+
+    /// Encrypts as the policy says. Under [`EncryptionPolicy::Preferred`],
+    /// peers that won't encrypt are dialed again in plaintext.
+    async fn handshake_with(
+        &self,
+        addr: SocketAddr,
+        utp: Option<&UtpSocket>,
+    ) -> Result<(Framed<MseStream<PeerStream>, HandshakeCodec>, Handshake), PeerError> {
+        let policy = self.limits.encryption();
+        if policy != EncryptionPolicy::Disabled {
+            match self.handshake_over(addr, utp, Some(policy)).await {
+                // Only a peer that connected but wouldn't negotiate may take
+                // plaintext instead. Peers without MSE tend to hang up on
+                // our key, which is why an I/O error mid-negotiation counts.
+                Err(e @ PeerError::Encryption { .. }) if policy == EncryptionPolicy::Preferred => {
+                    debug!("Encrypted connection to {addr} failed, trying plaintext: {e}");
+                }
+                result => return result,
+            }
+        }
+        self.handshake_over(addr, utp, None).await
+    }
+
+    async fn handshake_over(
+        &self,
+        addr: SocketAddr,
+        utp: Option<&UtpSocket>,
+        encryption: Option<EncryptionPolicy>,
+    ) -> Result<(Framed<MseStream<PeerStream>, HandshakeCodec>, Handshake), PeerError> {
+        let stream = match utp {
+            Some(utp) => PeerStream::Utp(utp.connect(addr).await.context(IoSnafu)?),
+            None => PeerStream::Tcp(TcpStream::connect(addr).await.context(IoSnafu)?),
+        };
+        let stream = match encryption {
+            Some(policy) => mse::initiate(stream, &self.info_hash, policy)
+                .await
+                .context(EncryptionSnafu)?,
+            None => MseStream::new(stream, Vec::new()),
+        };
+        let mut framed = Framed::new(stream, HandshakeCodec);
+        framed.send(self.our_handshake()).await?;
+        let handshake = match framed.next().await {
+            Some(handshake) => handshake?,
+            None => {
+                return Err(PeerError::Io {
+                    source: std::io::ErrorKind::UnexpectedEof.into(),
+                })
+            }
+        };
+        Ok((framed, handshake))
+    }
+
+    /// Claims a place for a handshaken peer, unless it's us, a duplicate,
+    /// banned or for another torrent.
+    fn register(
+        &self,
+        addr: SocketAddr,
+        handshake: &Handshake,
+        torrent: OwnedSemaphorePermit,
+        global: OwnedSemaphorePermit,
+    ) -> Result<ConnectionSlot, Rejection> {
+        let mut state = self.state.lock().unwrap();
+        let rejection = if handshake.info_hash() != &self.info_hash {
+            Some((Rejection::WrongTorrent, Status::Banned))
+        } else if self.limits.bans.is_banned(&addr.ip()) {
+            Some((Rejection::Banned, Status::Banned))
+        } else if handshake.peer_id() == &self.peer_id {
+            Some((Rejection::OurOwn, Status::Banned))
+        } else if state.peer_ids.contains(handshake.peer_id()) {
+            Some((Rejection::Duplicate, Status::Idle))
+        } else {
+            None
+        };
+        if let Some((rejection, status)) = rejection {
+            debug!("Dropping {addr}: {rejection:?}");
+            state.set_status(addr, status);
+            return Err(rejection);
+        }
+
+        state.peer_ids.insert(handshake.peer_id().clone());
+        state.set_status(addr, Status::Connected);
+        if let Some(candidate) = state.candidates.get_mut(&addr) {
+            candidate.failures = 0;
+        }
+        Ok(ConnectionSlot {
+            state: self.state.clone(),
+            addr,
+            peer_id: handshake.peer_id().clone(),
+            _torrent: torrent,
+            _global: global,
+        })
+    }
+}
+
+#[derive(Debug, Snafu)]
+pub(crate) enum ConnectError {
+    #[snafu(display("{source}"))]
+    Peer { source: PeerError },
+    #[snafu(display("timed out"))]
+    Timeout,
+    #[snafu(display("rejected: {rejection:?}"))]
+    Rejected { rejection: Rejection },
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use futures::{SinkExt, StreamExt};
+    use tokio::net::TcpListener;
+    use tokio::sync::mpsc;
+    use tokio::time::Instant;
+    use tokio_util::codec::Framed;
+
+    use super::{
+        ConnectionConfig, ConnectionLimits, ConnectionManager, PeerSource, State, Status,
+        MAX_FAILURES, RETRY_BASE,
+    };
+    use crate::peer::codec::HandshakeCodec;
+    use crate::peer::message::Handshake;
+    use crate::torrent::{InfoHash, PeerId};
+
+    const INFO_HASH: [u8; 20] = [3; 20];
+
+    /// A peer that answers every handshake as `peer_id`.
+    async fn fake_peer(peer_id: PeerId) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let peer_id = peer_id.clone();
+                tokio::spawn(async move {
+                    let mut framed = Framed::new(stream, HandshakeCodec);
+                    let theirs = framed.next().await.unwrap().unwrap();
+                    let ours = Handshake::new(theirs.info_hash().clone(), peer_id);
+                    framed.send(ours).await.unwrap();
+                    // Hold the connection open until the test ends.
+                    tokio::time::sleep(Duration::from_secs(3600)).await;
+                });
+            }
+        });
+        addr
+    }
+
+    fn manager(peer_id: PeerId, config: ConnectionConfig) -> Arc<ConnectionManager> {
+        Arc::new(ConnectionManager::new(
+            InfoHash::from(INFO_HASH),
+            peer_id,
+            ConnectionLimits::new(config),
+        ))
+    }
+
+    #[test]
+    fn failures_back_off_then_ban() {
+        let addr: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let mut state = State::default();
+        let now = Instant::now();
+        assert!(state.add(addr, PeerSource::Tracker));
+        assert!(!state.add(addr, PeerSource::Dht));
+        assert_eq!(state.next(now), Some(addr));
+
+        state.failed(addr, now);
+        assert_eq!(state.next(now), None);
+        assert_eq!(state.next_retry(), Some(now + RETRY_BASE));
+        // Hearing about it again doesn't skip the backoff.
+        assert!(!state.add(addr, PeerSource::Tracker));
+        assert_eq!(state.next(now + RETRY_BASE), Some(addr));
+
+        for _ in 1..MAX_FAILURES {
+            state.failed(addr, now);
+        }
+        assert_eq!(state.candidates[&addr].status, Status::Banned);
+        assert_eq!(state.next(now + RETRY_BASE * 1000), None);
+    }
+
+    #[tokio::test]
+    async fn connects_and_rejects_self_and_duplicates() {
+        let ours = PeerId::new();
+        let theirs = PeerId::new();
+        let peer = fake_peer(theirs.clone()).await;
+        let same_peer = fake_peer(theirs.clone()).await;
+        let us = fake_peer(ours.clone()).await;
+        let closed = {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            listener.local_addr().unwrap()
+        };
+
+        let manager = manager(ours, ConnectionConfig::default());
+        let (tx, mut rx) = mpsc::channel(8);
+        tokio::spawn(manager.clone().run(tx));
+        manager.add_peers(PeerSource::Tracker, [peer]);
+        let connection = rx.recv().await.unwrap();
+        assert_eq!(connection.addr, peer);
+        assert_eq!(connection.handshake.peer_id(), &theirs);
+
+        manager.add_peers(PeerSource::Dht, [same_peer, us, closed]);
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(rx.try_recv().is_err());
+        assert_eq!(manager.failures(&closed), 1);
+        let state = manager.state.lock().unwrap();
+        assert_eq!(state.candidates[&us].status, Status::Banned);
+        assert_eq!(state.candidates[&same_peer].status, Status::Idle);
+    }
+
+    #[test]
+    fn skips_banned_peers() {
+        let manager = manager(PeerId::new(), ConnectionConfig::default());
+        let banned: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        manager.limits.bans().ban(banned.ip());
+        manager.add_peers(PeerSource::Pex, [banned, "10.0.0.2:6881".parse().unwrap()]);
+        let state = manager.state.lock().unwrap();
+        assert!(!state.candidates.contains_key(&banned));
+        assert_eq!(state.queue.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn respects_per_torrent_cap() {
+        let first = fake_peer(PeerId::new()).await;
+        let second = fake_peer(PeerId::new()).await;
+        let manager = manager(
+            PeerId::new(),
+            ConnectionConfig {
+                max_per_torrent: 1,
+                ..Default::default()
+            },
+        );
+        let (tx, mut rx) = mpsc::channel(8);
+        tokio::spawn(manager.clone().run(tx));
+        manager.add_peers(PeerSource::Tracker, [first, second]);
+
+        let connection = rx.recv().await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(rx.try_recv().is_err());
+
+        let connected = connection.addr;
+        drop(connection);
+        assert_ne!(rx.recv().await.unwrap().addr, connected);
+    }
+}
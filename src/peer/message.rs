@@ -0,0 +1,139 @@
+use deku::ctx::Endian;
+use deku::prelude::*;
+use getset::{CopyGetters, Getters};
+
+use crate::deku_ext::read_to_end;
+use crate::torrent::{InfoHash, PeerId};
+
+pub(crate) const PROTOCOL: &[u8; 19] = b"BitTorrent protocol";
+pub(crate) const HANDSHAKE_LEN: usize = 68;
+
+/// Capabilities advertised in the handshake's reserved bytes, as
+/// (byte, mask) pairs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ReservedBit {
+    /// BEP 5
+    #[cfg(test)]
+    Dht,
+    /// BEP 6
+    Fast,
+    /// BEP 10
+    Extension,
+}
+
+impl ReservedBit {
+    fn position(self) -> (usize, u8) {
+        match self {
+            #[cfg(test)]
+            ReservedBit::Dht => (7, 0x01),
+            ReservedBit::Fast => (7, 0x04),
+            ReservedBit::Extension => (5, 0x10),
+        }
+    }
+}
+
+/// The first thing either side sends.
+/// See: http://www.bittorrent.org/beps/bep_0003.html#peer-protocol
+#[derive(Debug, Clone, PartialEq, Eq, DekuRead, DekuWrite, Getters, CopyGetters)]
+pub(crate) struct Handshake {
+    #[deku(assert_eq = "PROTOCOL.len() as u8")]
+    pstrlen: u8,
+    #[deku(assert_eq = "*PROTOCOL")]
+    pstr: [u8; 19],
+    #[getset(get_copy = "pub(crate)")]
+    reserved: [u8; 8],
+    #[deku(ctx = "Endian::Big")]
+    #[getset(get = "pub(crate)")]
+    info_hash: InfoHash,
+    #[getset(get = "pub(crate)")]
+    peer_id: PeerId,
+}
+
+impl Handshake {
+    pub(crate) fn new(info_hash: InfoHash, peer_id: PeerId) -> Self {
+        Self {
+            pstrlen: PROTOCOL.len() as u8,
+            pstr: *PROTOCOL,
+            reserved: [0; 8],
+            info_hash,
+            peer_id,
+        }
+    }
+
+    pub(crate) fn with(mut self, bit: ReservedBit) -> Self {
+        let (byte, mask) = bit.position();
+        self.reserved[byte] |= mask;
+        self
+    }
+
+    pub(crate) fn supports(&self, bit: ReservedBit) -> bool {
+        let (byte, mask) = bit.position();
+        self.reserved[byte] & mask != 0
+    }
+}
+
+/// A message after the handshake, less its length prefix. Keep-alives have
+/// no body at all, so they're only known to the codec; see
+/// [`super::codec::Frame`].
+#[derive(Debug, Clone, PartialEq, Eq, DekuRead, DekuWrite)]
+#[deku(type = "u8", endian = "big")]
+pub(crate) enum Message {
+    #[deku(id = "0")]
+    Choke,
+    #[deku(id = "1")]
+    Unchoke,
+    #[deku(id = "2")]
+    Interested,
+    #[deku(id = "3")]
+    NotInterested,
+    #[deku(id = "4")]
+    Have { index: u32 },
+    #[deku(id = "5")]
+    Bitfield {
+        #[deku(reader = "read_to_end(deku::rest, Endian::Big)")]
+        bitfield: Vec<u8>,
+    },
+    #[deku(id = "6")]
+    Request { index: u32, begin: u32, length: u32 },
+    #[deku(id = "7")]
+    Piece {
+        index: u32,
+        begin: u32,
+        #[deku(reader = "read_to_end(deku::rest, Endian::Big)")]
+        block: Vec<u8>,
+    },
+    #[deku(id = "8")]
+    Cancel { index: u32, begin: u32, length: u32 },
+    /// BEP 5: the DHT port of the sender.
+    #[deku(id = "9")]
+    Port { port: u16 },
+    /// BEP 6: a piece the sender would like us to download.
+    #[deku(id = "13")]
+    SuggestPiece { index: u32 },
+    /// BEP 6: in place of a bitfield with every bit set.
+    #[deku(id = "14")]
+    HaveAll,
+    /// BEP 6: in place of a bitfield with no bits set.
+    #[deku(id = "15")]
+    HaveNone,
+    /// BEP 6: a request the sender won't answer.
+    #[deku(id = "16")]
+    RejectRequest { index: u32, begin: u32, length: u32 },
+    /// BEP 6: a piece we may request even while choked.
+    #[deku(id = "17")]
+    AllowedFast { index: u32 },
+    /// BEP 10: a message for an extension, by the ID the receiver gave it.
+    #[deku(id = "20")]
+    Extended {
+        id: u8,
+        #[deku(reader = "read_to_end(deku::rest, Endian::Big)")]
+        payload: Vec<u8>,
+    },
+    /// Anything we don't understand, which the peer is free to send.
+    #[deku(id_pat = "_")]
+    Unknown {
+        id: u8,
+        #[deku(reader = "read_to_end(deku::rest, Endian::Big)")]
+        payload: Vec<u8>,
+    },
+}
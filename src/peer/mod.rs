@@ -0,0 +1,35 @@
+//! Talking to other peers over the peer wire protocol.
+//! See: http://www.bittorrent.org/beps/bep_0003.html#peer-protocol
+
+pub(crate) mod ban;
+pub(crate) mod choker;
+pub(crate) mod codec;
+pub(crate) mod extension;
+pub(crate) mod fast;
+pub(crate) mod listener;
+pub(crate) mod manager;
+pub(crate) mod message;
+pub(crate) mod mse;
+pub(crate) mod session;
+pub(crate) mod swarm;
+pub(crate) mod transport;
+
+use snafu::prelude::*;
+
+#[derive(Debug, Snafu)]
+pub(crate) enum PeerError {
+    #[snafu(display("peer connection failed: {source}"))]
+    Io { source: std::io::Error },
+    #[snafu(display("malformed peer message: {source}"))]
+    Malformed { source: deku::DekuError },
+    #[snafu(display("peer message of {length} bytes exceeds the limit of {max}"))]
+    TooLarge { length: usize, max: usize },
+    #[snafu(display("encryption handshake failed: {source}"))]
+    Encryption { source: mse::MseError },
+}
+
+impl From<std::io::Error> for PeerError {
+    fn from(source: std::io::Error) -> Self {
+        PeerError::Io { source }
+    }
+}
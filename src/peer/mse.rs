@@ -0,0 +1,490 @@
+//! Message stream encryption: a Diffie-Hellman exchange before the
+//! BitTorrent handshake, after which the connection is RC4 encrypted or,
+//! if both sides agree, carries on in plaintext. The info hash doubles as a
+//! shared secret (SKEY), so an observer can't tell which torrent it's for.
+//! See: https://wiki.vuze.com/w/Message_Stream_Encryption
+
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+use num_bigint::BigUint;
+use rand::Rng;
+use serde::Deserialize;
+use sha1::{Digest, Sha1};
+use snafu::prelude::*;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+use crate::torrent::InfoHash;
+
+/// The 768-bit MODP prime; the generator is 2.
+const PRIME: &[u8] = b"FFFFFFFFFFFFFFFFC90FDAA22168C234C4C6628B80DC1CD129024E088A67CC74020BBEA63B139B22514A08798E3404DDEF9519B3CD3A431B302B0A6DF25F14374FE1356D6D51C245E485B576625E7EC6F44C42E9A63A36210000000000090563";
+const KEY_LEN: usize = 96;
+/// Padding either side may send, and so how far we look for the other
+/// side's sync marker.
+const MAX_PAD: usize = 512;
+/// The verification constant, which proves the other side has the key.
+const VC: [u8; 8] = [0; 8];
+const CRYPTO_PLAINTEXT: u32 = 0x01;
+const CRYPTO_RC4: u32 = 0x02;
+
+/// Whether connections are encrypted.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum EncryptionPolicy {
+    /// Only RC4 encrypted connections, in and out.
+    Required,
+    /// Try to encrypt, but fall back to plaintext for peers that won't.
+    #[default]
+    Preferred,
+    /// Only plaintext connections.
+    Disabled,
+}
+
+#[derive(Debug, Snafu)]
+pub(crate) enum MseError {
+    #[snafu(display("{source}"))]
+    Io { source: io::Error },
+    #[snafu(display("peer sent an invalid public key"))]
+    BadKey,
+    #[snafu(display("couldn't find where the encrypted stream starts"))]
+    NoSync,
+    #[snafu(display("peer asked for a torrent we don't have"))]
+    UnknownTorrent,
+    #[snafu(display("no encryption method in common with {provided:#x}"))]
+    NoCommonMethod { provided: u32 },
+    #[snafu(display("padding of {length} bytes is too long"))]
+    BadPadding { length: usize },
+}
+
+impl From<io::Error> for MseError {
+    fn from(source: io::Error) -> Self {
+        MseError::Io { source }
+    }
+}
+
+struct Rc4 {
+    s: [u8; 256],
+    i: u8,
+    j: u8,
+}
+
+impl Rc4 {
+    fn new(key: &[u8]) -> Self {
+        let mut s = [0; 256];
+        for (i, x) in s.iter_mut().enumerate() {
+            *x = i as u8;
+        }
+        let mut j = 0_u8;
+        for i in 0..256 {
+            j = j.wrapping_add(s[i]).wrapping_add(key[i % key.len()]);
+            s.swap(i, j as usize);
+        }
+        Self { s, i: 0, j: 0 }
+    }
+
+    /// A cipher keyed as MSE does, with the first KiB of keystream dropped.
+    fn mse(key: [u8; 20]) -> Self {
+        let mut rc4 = Self::new(&key);
+        rc4.apply(&mut [0; 1024]);
+        rc4
+    }
+
+    fn apply(&mut self, data: &mut [u8]) {
+        for byte in data {
+            self.i = self.i.wrapping_add(1);
+            self.j = self.j.wrapping_add(self.s[self.i as usize]);
+            self.s.swap(self.i as usize, self.j as usize);
+            let k = self.s[self.i as usize].wrapping_add(self.s[self.j as usize]);
+            *byte ^= self.s[k as usize];
+        }
+    }
+}
+
+fn hash(parts: &[&[u8]]) -> [u8; 20] {
+    let mut sha1 = Sha1::new();
+    for part in parts {
+        sha1.update(part);
+    }
+    sha1.finalize().into()
+}
+
+fn xor(a: [u8; 20], b: [u8; 20]) -> [u8; 20] {
+    let mut out = a;
+    for (x, y) in out.iter_mut().zip(b) {
+        *x ^= y;
+    }
+    out
+}
+
+struct DhKey {
+    private: BigUint,
+    public: [u8; KEY_LEN],
+}
+
+impl DhKey {
+    fn generate() -> Self {
+        let private = BigUint::from_bytes_be(&rand::thread_rng().gen::<[u8; 20]>());
+        let public = BigUint::from(2_u32).modpow(&private, &prime());
+        Self {
+            private,
+            public: to_key_bytes(&public),
+        }
+    }
+
+    fn shared_secret(&self, theirs: &[u8; KEY_LEN]) -> Result<[u8; KEY_LEN], MseError> {
+        let prime = prime();
+        let theirs = BigUint::from_bytes_be(theirs);
+        ensure!(
+            theirs > BigUint::from(1_u32) && theirs < &prime - 1_u32,
+            BadKeySnafu
+        );
+        Ok(to_key_bytes(&theirs.modpow(&self.private, &prime)))
+    }
+}
+
+fn prime() -> BigUint {
+    BigUint::parse_bytes(PRIME, 16).unwrap()
+}
+
+fn to_key_bytes(n: &BigUint) -> [u8; KEY_LEN] {
+    let bytes = n.to_bytes_be();
+    let mut key = [0; KEY_LEN];
+    key[KEY_LEN - bytes.len()..].copy_from_slice(&bytes);
+    key
+}
+
+fn padding() -> Vec<u8> {
+    let mut rng = rand::thread_rng();
+    let len = rng.gen_range(0..=MAX_PAD);
+    (0..len).map(|_| rng.gen()).collect()
+}
+
+/// A connection that may be encrypted. Without ciphers it passes bytes
+/// straight through, so plaintext connections use it too.
+pub(crate) struct MseStream<S> {
+    inner: S,
+    read: Option<Rc4>,
+    write: Option<Rc4>,
+    /// Plaintext read ahead of the caller, handed out before anything else.
+    pending: Vec<u8>,
+    /// Ciphertext accepted by `poll_write` but not yet written.
+    unsent: Vec<u8>,
+    sent: usize,
+}
+
+impl<S> MseStream<S> {
+    /// Starts with `pending` still to be read, e.g. bytes read to tell
+    /// whether the peer is encrypting.
+    pub(crate) fn new(inner: S, pending: Vec<u8>) -> Self {
+        Self {
+            inner,
+            read: None,
+            write: None,
+            pending,
+            unsent: Vec::new(),
+            sent: 0,
+        }
+    }
+
+    pub(crate) fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    pub(crate) fn is_encrypted(&self) -> bool {
+        self.write.is_some()
+    }
+
+    fn encrypt(&mut self, read: Rc4, write: Rc4) {
+        self.read = Some(read);
+        self.write = Some(write);
+    }
+}
+
+impl<S: AsyncWrite + Unpin> MseStream<S> {
+    fn poll_send_unsent(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.sent < self.unsent.len() {
+            let n = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.unsent[self.sent..]))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.sent += n;
+        }
+        self.unsent.clear();
+        self.sent = 0;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for MseStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if !this.pending.is_empty() {
+            let n = buf.remaining().min(this.pending.len());
+            buf.put_slice(&this.pending[..n]);
+            this.pending.drain(..n);
+            return Poll::Ready(Ok(()));
+        }
+        let before = buf.filled().len();
+        ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        if let Some(cipher) = &mut this.read {
+            cipher.apply(&mut buf.filled_mut()[before..]);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for MseStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if this.write.is_none() {
+            return Pin::new(&mut this.inner).poll_write(cx, buf);
+        }
+        // The keystream moves on as we encrypt, so once bytes are encrypted
+        // they're ours to send; take more only when the last lot is out.
+        ready!(this.poll_send_unsent(cx))?;
+        this.unsent.extend_from_slice(buf);
+        this.write.as_mut().unwrap().apply(&mut this.unsent);
+        if let Poll::Ready(Err(e)) = this.poll_send_unsent(cx) {
+            return Poll::Ready(Err(e));
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_send_unsent(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_send_unsent(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+/// Reads until `marker`, which may come after up to `MAX_PAD` bytes of
+/// padding. Reads a byte at a time so nothing after it is consumed.
+async fn sync<S: AsyncRead + Unpin>(stream: &mut S, marker: &[u8]) -> Result<(), MseError> {
+    let mut window = vec![0; marker.len()];
+    stream.read_exact(&mut window).await?;
+    while !window.ends_with(marker) {
+        ensure!(window.len() < marker.len() + MAX_PAD, NoSyncSnafu);
+        window.push(stream.read_u8().await?);
+    }
+    Ok(())
+}
+
+async fn read_padding<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    cipher: &mut Rc4,
+    length: usize,
+) -> Result<(), MseError> {
+    ensure!(length <= MAX_PAD, BadPaddingSnafu { length });
+    let mut pad = vec![0; length];
+    stream.read_exact(&mut pad).await?;
+    cipher.apply(&mut pad);
+    Ok(())
+}
+
+/// Encrypts an outgoing connection for `info_hash`, ahead of the BitTorrent
+/// handshake.
+pub(crate) async fn initiate<S>(
+    stream: S,
+    info_hash: &InfoHash,
+    policy: EncryptionPolicy,
+) -> Result<MseStream<S>, MseError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let provide = match policy {
+        EncryptionPolicy::Required => CRYPTO_RC4,
+        EncryptionPolicy::Preferred => CRYPTO_RC4 | CRYPTO_PLAINTEXT,
+        EncryptionPolicy::Disabled => CRYPTO_PLAINTEXT,
+    };
+    let mut stream = MseStream::new(stream, Vec::new());
+    let key = DhKey::generate();
+    stream
+        .write_all(&[&key.public[..], &padding()].concat())
+        .await?;
+    let mut theirs = [0; KEY_LEN];
+    stream.read_exact(&mut theirs).await?;
+    let secret = key.shared_secret(&theirs)?;
+
+    let skey = info_hash.as_bytes();
+    let mut write = Rc4::mse(hash(&[b"keyA", &secret, skey]));
+    let mut read = Rc4::mse(hash(&[b"keyB", &secret, skey]));
+    let mut offer = VC.to_vec();
+    offer.extend(provide.to_be_bytes());
+    // No padding, and no initial payload: the handshake follows as usual.
+    offer.extend(0_u16.to_be_bytes());
+    offer.extend(0_u16.to_be_bytes());
+    write.apply(&mut offer);
+    let mut message = hash(&[b"req1", &secret]).to_vec();
+    message.extend(xor(hash(&[b"req2", skey]), hash(&[b"req3", &secret])));
+    message.extend(offer);
+    stream.write_all(&message).await?;
+
+    let mut vc = VC;
+    read.apply(&mut vc);
+    sync(&mut stream, &vc).await?;
+    let mut reply = [0; 6];
+    stream.read_exact(&mut reply).await?;
+    read.apply(&mut reply);
+    let select = u32::from_be_bytes(reply[..4].try_into().unwrap());
+    let pad = u16::from_be_bytes([reply[4], reply[5]]);
+    read_padding(&mut stream, &mut read, pad.into()).await?;
+    ensure!(
+        select.count_ones() == 1 && provide & select != 0,
+        NoCommonMethodSnafu { provided: provide }
+    );
+    if select == CRYPTO_RC4 {
+        stream.encrypt(read, write);
+    }
+    Ok(stream)
+}
+
+/// Answers a peer that connected to us encrypting, working out which of
+/// `info_hashes` it's for from the SKEY it proves it knows.
+pub(crate) async fn respond<S>(
+    mut stream: MseStream<S>,
+    info_hashes: &[InfoHash],
+    policy: EncryptionPolicy,
+) -> Result<(MseStream<S>, InfoHash), MseError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut theirs = [0; KEY_LEN];
+    stream.read_exact(&mut theirs).await?;
+    let key = DhKey::generate();
+    stream
+        .write_all(&[&key.public[..], &padding()].concat())
+        .await?;
+    let secret = key.shared_secret(&theirs)?;
+
+    sync(&mut stream, &hash(&[b"req1", &secret])).await?;
+    let mut req2 = [0; 20];
+    stream.read_exact(&mut req2).await?;
+    let req2 = xor(req2, hash(&[b"req3", &secret]));
+    let info_hash = info_hashes
+        .iter()
+        .find(|info_hash| hash(&[b"req2", info_hash.as_bytes()]) == req2)
+        .context(UnknownTorrentSnafu)?
+        .clone();
+
+    let skey = info_hash.as_bytes();
+    let mut read = Rc4::mse(hash(&[b"keyA", &secret, skey]));
+    let mut write = Rc4::mse(hash(&[b"keyB", &secret, skey]));
+    let mut offer = [0; 14];
+    stream.read_exact(&mut offer).await?;
+    read.apply(&mut offer);
+    ensure!(offer[..8] == VC, NoSyncSnafu);
+    let provide = u32::from_be_bytes(offer[8..12].try_into().unwrap());
+    let pad = u16::from_be_bytes([offer[12], offer[13]]);
+    read_padding(&mut stream, &mut read, pad.into()).await?;
+    let mut initial_len = [0; 2];
+    stream.read_exact(&mut initial_len).await?;
+    read.apply(&mut initial_len);
+    let mut initial = vec![0; u16::from_be_bytes(initial_len).into()];
+    stream.read_exact(&mut initial).await?;
+    read.apply(&mut initial);
+
+    let select = match policy {
+        EncryptionPolicy::Required => provide & CRYPTO_RC4,
+        EncryptionPolicy::Preferred if provide & CRYPTO_RC4 != 0 => CRYPTO_RC4,
+        EncryptionPolicy::Preferred | EncryptionPolicy::Disabled => provide & CRYPTO_PLAINTEXT,
+    };
+    ensure!(select != 0, NoCommonMethodSnafu { provided: provide });
+    let mut reply = VC.to_vec();
+    reply.extend(select.to_be_bytes());
+    reply.extend(0_u16.to_be_bytes());
+    write.apply(&mut reply);
+    stream.write_all(&reply).await?;
+
+    // The initial payload is usually the peer's handshake.
+    initial.append(&mut stream.pending);
+    stream.pending = initial;
+    if select == CRYPTO_RC4 {
+        stream.encrypt(read, write);
+    }
+    Ok((stream, info_hash))
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+
+    use super::{initiate, respond, EncryptionPolicy, MseError, MseStream, Rc4};
+    use crate::torrent::InfoHash;
+
+    #[test]
+    fn rc4_matches_known_keystream() {
+        let mut data = *b"Plaintext";
+        Rc4::new(b"Key").apply(&mut data);
+        assert_eq!(data, [0xbb, 0xf3, 0x16, 0xe8, 0xd9, 0x40, 0xaf, 0x0a, 0xd3]);
+    }
+
+    async fn connect(
+        ours: EncryptionPolicy,
+        theirs: EncryptionPolicy,
+    ) -> Result<(bool, bool), MseError> {
+        let info_hash = InfoHash::from([7; 20]);
+        let known = [InfoHash::from([1; 20]), info_hash.clone()];
+        let (a, b) = duplex(4096);
+        let responder = tokio::spawn(async move {
+            let (mut stream, found) =
+                respond(MseStream::new(b, Vec::new()), &known, theirs).await?;
+            assert_eq!(found, known[1]);
+            let mut buf = [0; 5];
+            stream.read_exact(&mut buf).await?;
+            assert_eq!(&buf, b"hello");
+            stream.write_all(b"world").await?;
+            stream.flush().await?;
+            Ok::<_, MseError>(stream.is_encrypted())
+        });
+        let mut stream = initiate(a, &info_hash, ours).await?;
+        stream.write_all(b"hello").await?;
+        stream.flush().await?;
+        let mut buf = [0; 5];
+        stream.read_exact(&mut buf).await?;
+        assert_eq!(&buf, b"world");
+        Ok((stream.is_encrypted(), responder.await.unwrap()?))
+    }
+
+    #[tokio::test]
+    async fn negotiates_by_policy() {
+        use EncryptionPolicy::*;
+        assert_eq!(connect(Required, Preferred).await.unwrap(), (true, true));
+        assert_eq!(connect(Preferred, Preferred).await.unwrap(), (true, true));
+        assert_eq!(connect(Preferred, Disabled).await.unwrap(), (false, false));
+        assert!(connect(Required, Disabled).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn rejects_unknown_torrents() {
+        let (a, b) = duplex(4096);
+        let responder = tokio::spawn(async move {
+            respond(
+                MseStream::new(b, Vec::new()),
+                &[InfoHash::from([1; 20])],
+                EncryptionPolicy::Preferred,
+            )
+            .await
+            .map(|_| ())
+        });
+        let _ = initiate(a, &InfoHash::from([2; 20]), EncryptionPolicy::Preferred).await;
+        assert!(matches!(
+            responder.await.unwrap(),
+            Err(MseError::UnknownTorrent)
+        ));
+    }
+}
@@ -0,0 +1,368 @@
+//! Running one connection once both handshakes are through. Each connection
+//! gets a task of its own, which owns it until the peer goes away, so its
+//! connection slot is given back as soon as it does.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::{SinkExt, StreamExt};
+use log::debug;
+use snafu::prelude::*;
+use tokio::sync::mpsc;
+use tokio::time::{Instant, MissedTickBehavior};
+
+use super::codec::Frame;
+use super::extension::{ExtensionError, Extensions, HANDSHAKE_ID};
+use super::fast::{self, FastState};
+use super::manager::PeerConnection;
+use super::message::{Message, ReservedBit};
+use super::swarm::{Command, Swarm};
+use super::PeerError;
+use crate::external_ip::ip_from_bytes;
+use crate::piece::download::Block;
+use crate::piece::Bitfield;
+
+/// How often a session checks its timers.
+const TICK: Duration = Duration::from_secs(1);
+/// We send a keep-alive after this long without sending anything.
+const KEEP_ALIVE: Duration = Duration::from_secs(120);
+/// A peer that sends nothing, not even keep-alives, for this long is gone.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(180);
+
+#[derive(Debug, Snafu)]
+pub(crate) enum SessionError {
+    #[snafu(display("{source}"))]
+    Peer { source: PeerError },
+    #[snafu(display("sent nothing for {}s", IDLE_TIMEOUT.as_secs()))]
+    Idle,
+    #[snafu(display("sent a bitfield of the wrong length"))]
+    BadBitfield,
+    #[snafu(display("banned for sending bad data"))]
+    Banned,
+    #[snafu(display("{source}"))]
+    Extension { source: ExtensionError },
+    #[snafu(display("has metadata of {theirs} bytes, not {ours}"))]
+    MetadataSize { theirs: i64, ours: usize },
+}
+
+struct Session {
+    swarm: Arc<Swarm>,
+    connection: PeerConnection,
+    last_received: Instant,
+    last_sent: Instant,
+    /// The pieces the peer has. None until we know how many there are.
+    has: Option<Bitfield>,
+    peer_choking: bool,
+    am_interested: bool,
+    am_choking: bool,
+    /// Set when the peer has the fast extension.
+    fast: Option<FastState>,
+    /// Set when the peer has the extension protocol.
+    extensions: Option<Extensions>,
+}
+
+/// Runs `connection` until either end closes it.
+pub(crate) async fn run(swarm: Arc<Swarm>, connection: PeerConnection) {
+    let addr = connection.addr;
+    let now = Instant::now();
+    let mut session = Session {
+        swarm,
+        connection,
+        last_received: now,
+        last_sent: now,
+        has: None,
+        peer_choking: true,
+        am_interested: false,
+        am_choking: true,
+        fast: None,
+        extensions: None,
+    };
+    let commands = session.swarm.join(addr);
+    match session.run(commands).await {
+        Ok(()) => debug!("{addr} disconnected"),
+        Err(e) => debug!("Dropping {addr}: {e}"),
+    }
+    session.swarm.leave(&addr, session.has.as_ref());
+}
+
+impl Session {
+    async fn run(
+        &mut self,
+        mut commands: mpsc::UnboundedReceiver<Command>,
+    ) -> Result<(), SessionError> {
+        let ours = self.swarm.bitfield();
+        if let Some(ours) = &ours {
+            self.has = Some(Bitfield::new(ours.len()));
+        }
+        if self.connection.handshake.supports(ReservedBit::Fast) {
+            // Without the metadata we have nothing to offer.
+            let ours = ours.unwrap_or_else(|| Bitfield::new(0));
+            self.send(fast::have_message(&ours)).await?;
+            let (fast, allowed) = FastState::new(
+                self.connection.addr.ip(),
+                self.swarm.info_hash(),
+                ours.len(),
+            );
+            for message in allowed {
+                self.send(message).await?;
+            }
+            self.fast = Some(fast);
+        } else if let Some(ours) = ours.filter(|ours| ours.count() > 0) {
+            self.send(Message::Bitfield {
+                bitfield: ours.as_bytes().to_vec(),
+            })
+            .await?;
+        }
+        if self.connection.handshake.supports(ReservedBit::Extension) {
+            let extensions = self.swarm.extensions(&self.connection);
+            self.send(extensions.handshake()).await?;
+            self.extensions = Some(extensions);
+        }
+        let mut ticker = tokio::time::interval(TICK);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            tokio::select! {
+                frame = self.connection.framed.next() => {
+                    let Some(frame) = frame else {
+                        return Ok(());
+                    };
+                    self.last_received = Instant::now();
+                    if let Frame::Message(message) = frame.context(PeerSnafu)? {
+                        self.handle(message).await?;
+                    }
+                }
+                Some(command) = commands.recv() => match command {
+                    Command::Have(index) => self.send(Message::Have { index }).await?,
+                    Command::Cancel(block) => self.send(block.cancel()).await?,
+                    Command::Close => return BannedSnafu.fail(),
+                    Command::Choke => self.choke(true).await?,
+                    Command::Unchoke => self.choke(false).await?,
+                    Command::Request => self.request(Instant::now()).await?,
+                },
+                _ = ticker.tick() => self.tick(Instant::now()).await?,
+            }
+        }
+    }
+
+    async fn handle(&mut self, message: Message) -> Result<(), SessionError> {
+        debug!(
+            "{} sent {message:?} for {}",
+            self.connection.addr,
+            self.swarm.info_hash()
+        );
+        let addr = self.connection.addr;
+        match message {
+            Message::Bitfield { bitfield } => {
+                let Some(len) = self.has.as_ref().map(Bitfield::len) else {
+                    return Ok(());
+                };
+                let has = Bitfield::from_bytes(&bitfield, len).context(BadBitfieldSnafu)?;
+                self.replace_has(has).await?;
+            }
+            Message::HaveAll | Message::HaveNone if self.fast.is_some() => {
+                let Some(len) = self.has.as_ref().map(Bitfield::len) else {
+                    return Ok(());
+                };
+                let has = match message {
+                    Message::HaveAll => Bitfield::full(len),
+                    _ => Bitfield::new(len),
+                };
+                self.replace_has(has).await?;
+            }
+            Message::Have { index } => {
+                let Some(has) = &mut self.has else {
+                    return Ok(());
+                };
+                if (index as usize) < has.len() && !has.has(index as usize) {
+                    has.set(index as usize);
+                    self.swarm.peer_has(index);
+                    self.update_interest().await?;
+                }
+            }
+            Message::Choke => {
+                self.peer_choking = true;
+                // Peers with the fast extension reject each request instead.
+                if self.fast.is_none() {
+                    self.swarm.choked(&addr);
+                }
+            }
+            Message::Unchoke => {
+                self.peer_choking = false;
+                self.request(Instant::now()).await?;
+            }
+            Message::Interested => self.swarm.interested(&addr, true),
+            Message::NotInterested => self.swarm.interested(&addr, false),
+            Message::Piece {
+                index,
+                begin,
+                block,
+            } => {
+                self.swarm.received(&addr, index, begin, &block);
+                self.request(Instant::now()).await?;
+            }
+            Message::SuggestPiece { .. } | Message::AllowedFast { .. } => {
+                if let Some(fast) = &mut self.fast {
+                    fast.received(&message);
+                    self.request(Instant::now()).await?;
+                }
+            }
+            Message::RejectRequest {
+                index,
+                begin,
+                length,
+            } if self.fast.is_some() => {
+                let block = Block {
+                    index,
+                    begin,
+                    length,
+                };
+                self.swarm.rejected(&addr, &block);
+            }
+            Message::Extended { id, payload } => {
+                let Some(extensions) = &mut self.extensions else {
+                    return Ok(());
+                };
+                let replies = extensions.handle(id, &payload).context(ExtensionSnafu)?;
+                if id == HANDSHAKE_ID {
+                    self.extended_handshake()?;
+                }
+                for reply in replies {
+                    self.send(reply).await?;
+                }
+            }
+            Message::Request {
+                index,
+                begin,
+                length,
+            } if self.fast.is_some() => {
+                // We don't keep pieces to serve, so say so rather than leave
+                // the request hanging.
+                self.send(Message::RejectRequest {
+                    index,
+                    begin,
+                    length,
+                })
+                .await?;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Acts on what the peer's extended handshake told us.
+    fn extended_handshake(&mut self) -> Result<(), SessionError> {
+        let addr = self.connection.addr;
+        let Some(theirs) = self.extensions.as_ref().and_then(Extensions::theirs) else {
+            return Ok(());
+        };
+        if let (Some(theirs), Some(ours)) = (theirs.metadata_size, self.swarm.metadata_size()) {
+            ensure!(theirs == ours as i64, MetadataSizeSnafu { theirs, ours });
+        }
+        if let Some(ip) = theirs.yourip.as_ref().and_then(|ip| ip_from_bytes(ip)) {
+            debug!("{addr} sees us at {ip}");
+        }
+        if let Some(reqq) = theirs.reqq.filter(|&reqq| reqq > 0) {
+            self.swarm.limit_requests(addr, reqq as usize);
+        }
+        Ok(())
+    }
+
+    /// Chokes or unchokes the peer, as the choker decided.
+    async fn choke(&mut self, choke: bool) -> Result<(), SessionError> {
+        if choke != self.am_choking {
+            self.am_choking = choke;
+            self.send(match choke {
+                true => Message::Choke,
+                false => Message::Unchoke,
+            })
+            .await?;
+        }
+        Ok(())
+    }
+
+    /// The peer told us everything it has at once.
+    async fn replace_has(&mut self, has: Bitfield) -> Result<(), SessionError> {
+        if let Some(had) = &self.has {
+            self.swarm
+                .peer_has_bitfield(&self.connection.addr, had, &has);
+        }
+        self.has = Some(has);
+        self.update_interest().await
+    }
+
+    /// Tells the peer whether it has anything we want, when that changes.
+    async fn update_interest(&mut self) -> Result<(), SessionError> {
+        let interested = self.has.as_ref().is_some_and(|has| self.swarm.wants(has));
+        if interested != self.am_interested {
+            self.am_interested = interested;
+            self.send(match interested {
+                true => Message::Interested,
+                false => Message::NotInterested,
+            })
+            .await?;
+        }
+        Ok(())
+    }
+
+    /// Tops up our requests, if the peer is letting us download. With the
+    /// fast extension that includes its allowed fast pieces while it chokes
+    /// us, and the pieces it suggested come first.
+    async fn request(&mut self, now: Instant) -> Result<(), SessionError> {
+        if !self.am_interested {
+            return Ok(());
+        }
+        let Some(has) = &self.has else {
+            return Ok(());
+        };
+        let addr = self.connection.addr;
+        let blocks = match &self.fast {
+            Some(fast) => {
+                let mut blocks =
+                    self.swarm
+                        .fill(addr, &fast.suggested(has, self.peer_choking), now);
+                blocks.extend(self.swarm.fill(
+                    addr,
+                    &fast.requestable(has, self.peer_choking),
+                    now,
+                ));
+                blocks
+            }
+            None if self.peer_choking => return Ok(()),
+            None => self.swarm.fill(addr, has, now),
+        };
+        for block in blocks {
+            self.send(block.request()).await?;
+        }
+        Ok(())
+    }
+
+    async fn tick(&mut self, now: Instant) -> Result<(), SessionError> {
+        ensure!(
+            now.duration_since(self.last_received) < IDLE_TIMEOUT,
+            IdleSnafu
+        );
+        if now.duration_since(self.last_sent) >= KEEP_ALIVE {
+            self.send(Frame::KeepAlive).await?;
+        }
+        let messages = match &mut self.extensions {
+            Some(extensions) => extensions.poll(now),
+            None => Vec::new(),
+        };
+        for message in messages {
+            self.send(message).await?;
+        }
+        // Pieces that just completed, or failed, change what we want.
+        self.update_interest().await?;
+        self.request(now).await
+    }
+
+    async fn send(&mut self, frame: impl Into<Frame>) -> Result<(), SessionError> {
+        self.connection
+            .framed
+            .send(frame.into())
+            .await
+            .context(PeerSnafu)?;
+        self.last_sent = Instant::now();
+        Ok(())
+    }
+}
@@ -0,0 +1,1010 @@
+//! The peers one torrent is connected to. Connections come in from the
+//! listener and the connection manager, and each is run by a
+//! [`session`](super::session) until the peer goes away. What the sessions
+//! share, such as the piece downloader, lives here.
+
+use std::collections::HashMap;
+use std::iter;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use log::{debug, info};
+use tokio::sync::mpsc;
+use tokio::task::JoinSet;
+use tokio::time::Instant;
+
+use super::choker::{Choker, PeerRates, CHOKE_INTERVAL};
+use super::extension::holepunch::{HolepunchSwarm, UtHolepunch};
+use super::extension::pex::{PexFlags, PexSwarm, UtPex};
+use super::extension::Extensions;
+use super::manager::{ConnectionManager, PeerConnection, PeerSource};
+use super::session;
+use crate::external_ip::ExternalIp;
+use crate::piece::download::{Block, Downloader, PeerKey};
+use crate::piece::picker::{PiecePicker, Priority};
+use crate::piece::verify::{Verifier, VerifyStats};
+use crate::piece::Bitfield;
+use crate::torrent::{Info, InfoHash};
+
+/// How often requests are checked for having stalled.
+const STALL_CHECK: Duration = Duration::from_secs(5);
+
+/// What the swarm asks of a session.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Command {
+    /// We've verified a piece: tell the peer.
+    Have(u32),
+    /// Another peer sent this block first.
+    Cancel(Block),
+    /// The peer sent too many bad pieces and is banned.
+    Close,
+    /// The choker took the peer's upload slot.
+    Choke,
+    /// The choker gave the peer an upload slot.
+    Unchoke,
+    /// Requests stalled, freeing blocks the peer may be able to fetch.
+    Request,
+}
+
+/// A session, as the rest of the swarm sees it.
+struct Peer {
+    commands: mpsc::UnboundedSender<Command>,
+    /// What it did since the last rechoke.
+    rates: PeerRates,
+    /// Whether the picker counts it as having every piece.
+    seed: bool,
+}
+
+/// The pieces of a torrent we have the metadata for.
+struct Pieces {
+    downloader: Downloader,
+    verifier: Verifier,
+}
+
+pub(crate) struct Swarm {
+    manager: Arc<ConnectionManager>,
+    /// None until we have the metadata, as for magnet links.
+    pieces: Option<Mutex<Pieces>>,
+    peers: Mutex<HashMap<PeerKey, Peer>>,
+    choker: Option<Mutex<Choker>>,
+    /// The port we tell peers we listen on.
+    port: u16,
+    external_ip: Option<ExternalIp>,
+    pex: Option<PexSwarm>,
+    holepunch: Option<HolepunchSwarm>,
+    metadata_size: Option<usize>,
+}
+
+impl Swarm {
+    pub(crate) fn new(manager: Arc<ConnectionManager>) -> Self {
+        Self {
+            manager,
+            pieces: None,
+            peers: Mutex::default(),
+            choker: None,
+            port: 0,
+            external_ip: None,
+            pex: None,
+            holepunch: None,
+            metadata_size: None,
+        }
+    }
+
+    /// Tells peers we listen on `port`.
+    pub(crate) fn with_port(mut self, port: u16) -> Self {
+        self.port = port;
+        self
+    }
+
+    /// Gives upload slots to the peers `choker` picks every
+    /// [`CHOKE_INTERVAL`].
+    pub(crate) fn with_choker(mut self, choker: Choker) -> Self {
+        self.choker = Some(Mutex::new(choker));
+        self
+    }
+
+    /// Counts the address each peer sees us at towards `external_ip`.
+    pub(crate) fn with_external_ip(mut self, external_ip: ExternalIp) -> Self {
+        self.external_ip = Some(external_ip);
+        self
+    }
+
+    /// Swaps peers with the connections that support ut_pex.
+    pub(crate) fn with_pex(mut self, pex: PexSwarm) -> Self {
+        self.pex = Some(pex);
+        self
+    }
+
+    /// Relays holepunches between the connections that support
+    /// ut_holepunch, and asks them for ours.
+    pub(crate) fn with_holepunch(mut self, holepunch: HolepunchSwarm) -> Self {
+        self.holepunch = Some(holepunch);
+        self
+    }
+
+    /// Downloads the pieces `info` describes, as the files' `priorities`
+    /// say. Files without one are downloaded at normal priority.
+    pub(crate) fn with_pieces(mut self, info: &Info, priorities: &[Priority]) -> Self {
+        let count = info.piece_count();
+        let mut picker = PiecePicker::new(count);
+        let files: Vec<_> = info
+            .file_lengths()
+            .into_iter()
+            .zip(
+                priorities
+                    .iter()
+                    .copied()
+                    .chain(iter::repeat(Priority::Normal)),
+            )
+            .collect();
+        picker.set_priorities(&Priority::of_pieces(&files, info.piece_length(), count));
+        self.pieces = Some(Mutex::new(Pieces {
+            downloader: Downloader::new(picker, info.piece_length(), info.total_length()),
+            verifier: Verifier::new(info, self.manager.bans().clone()),
+        }));
+        self.metadata_size = info.metadata_size().ok();
+        self
+    }
+
+    pub(crate) fn info_hash(&self) -> &InfoHash {
+        self.manager.info_hash()
+    }
+
+    /// Runs every connection sent to `connections` in a task of its own.
+    /// Dropping the future drops the connections still open.
+    pub(crate) async fn run(self: Arc<Self>, mut connections: mpsc::Receiver<PeerConnection>) {
+        let mut sessions = JoinSet::new();
+        let mut stall_check = tokio::time::interval(STALL_CHECK);
+        let mut rechoke = tokio::time::interval(CHOKE_INTERVAL);
+        loop {
+            tokio::select! {
+                connection = connections.recv() => match connection {
+                    Some(connection) => {
+                        sessions.spawn(session::run(self.clone(), connection));
+                    }
+                    None => break,
+                },
+                Some(_) = sessions.join_next() => {}
+                _ = stall_check.tick() => self.check_stalled(Instant::now()),
+                _ = rechoke.tick() => self.rechoke(),
+            }
+        }
+        while sessions.join_next().await.is_some() {}
+    }
+
+    /// Lets the rest of the swarm reach a new connection to `peer`.
+    pub(crate) fn join(&self, peer: PeerKey) -> mpsc::UnboundedReceiver<Command> {
+        let (commands, receiver) = mpsc::unbounded_channel();
+        let rates = PeerRates {
+            peer,
+            interested: false,
+            downloaded: 0,
+            uploaded: 0,
+        };
+        self.peers.lock().unwrap().insert(
+            peer,
+            Peer {
+                commands,
+                rates,
+                seed: false,
+            },
+        );
+        receiver
+    }
+
+    /// Forgets a connection that's closing, and the pieces it had.
+    pub(crate) fn leave(&self, peer: &PeerKey, has: Option<&Bitfield>) {
+        let seed = self
+            .peers
+            .lock()
+            .unwrap()
+            .remove(peer)
+            .is_some_and(|peer| peer.seed);
+        if let Some(choker) = &self.choker {
+            choker.lock().unwrap().remove_peer(peer);
+        }
+        self.with_downloader(|downloader| {
+            downloader.remove_peer(peer);
+            match (seed, has) {
+                (true, _) => downloader.picker_mut().peer_lost_all(),
+                (false, Some(has)) => downloader.picker_mut().peer_lost_bitfield(has),
+                (false, None) => {}
+            }
+        });
+    }
+
+    fn send(&self, peer: &PeerKey, command: Command) {
+        if let Some(peer) = self.peers.lock().unwrap().get(peer) {
+            let _ = peer.commands.send(command);
+        }
+    }
+
+    /// The extensions `connection` speaks.
+    pub(crate) fn extensions(&self, connection: &PeerConnection) -> Extensions {
+        let peer = connection.addr;
+        let mut extensions = Extensions::new(peer, self.port);
+        if let Some(size) = self.metadata_size {
+            extensions = extensions.with_metadata_size(size);
+        }
+        if let Some(external_ip) = &self.external_ip {
+            extensions = extensions.with_external_ip(external_ip.clone());
+        }
+        if let Some(pex) = &self.pex {
+            let stream = connection.framed.get_ref();
+            let mut flags = PexFlags::default();
+            if stream.is_encrypted() {
+                flags = flags.with(PexFlags::ENCRYPTION);
+            }
+            if stream.get_ref().is_utp() {
+                flags = flags.with(PexFlags::UTP);
+            }
+            let mut ut_pex = UtPex::new(peer, pex.clone(), self.manager.clone()).with_flags(flags);
+            if connection.source == PeerSource::Incoming {
+                ut_pex = ut_pex.accepted();
+            }
+            extensions.register(ut_pex);
+        }
+        if let Some(holepunch) = &self.holepunch {
+            let mut ut_holepunch = UtHolepunch::new(peer, holepunch.clone(), self.manager.clone());
+            if connection.source == PeerSource::Incoming {
+                ut_holepunch = ut_holepunch.accepted();
+            }
+            extensions.register(ut_holepunch);
+        }
+        extensions
+    }
+
+    /// The size of the info dictionary, if we have it.
+    pub(crate) fn metadata_size(&self) -> Option<usize> {
+        self.metadata_size
+    }
+
+    /// Whether `peer` wants to download from us, for the choker.
+    pub(crate) fn interested(&self, peer: &PeerKey, interested: bool) {
+        if let Some(peer) = self.peers.lock().unwrap().get_mut(peer) {
+            peer.rates.interested = interested;
+        }
+    }
+
+    /// Runs a round of the choker over the connected peers, telling the
+    /// sessions whose slots changed.
+    pub(crate) fn rechoke(&self) {
+        let Some(choker) = &self.choker else {
+            return;
+        };
+        let seeding = self.bitfield().is_some_and(|have| have.is_complete());
+        let peers = &mut *self.peers.lock().unwrap();
+        let rates: Vec<_> = peers
+            .values_mut()
+            .map(|peer| {
+                let rates = peer.rates;
+                peer.rates.downloaded = 0;
+                peer.rates.uploaded = 0;
+                rates
+            })
+            .collect();
+        let rechoke = choker.lock().unwrap().rechoke(&rates, seeding);
+        let choke = rechoke.choke.into_iter().map(|peer| (peer, Command::Choke));
+        let unchoke = rechoke
+            .unchoke
+            .into_iter()
+            .map(|peer| (peer, Command::Unchoke));
+        for (peer, command) in choke.chain(unchoke) {
+            if let Some(peer) = peers.get(&peer) {
+                let _ = peer.commands.send(command);
+            }
+        }
+    }
+
+    /// Runs `f` on the downloader, if we have the metadata.
+    fn with_downloader<T>(&self, f: impl FnOnce(&mut Downloader) -> T) -> Option<T> {
+        let pieces = self.pieces.as_ref()?;
+        Some(f(&mut pieces.lock().unwrap().downloader))
+    }
+
+    /// How the pieces we've downloaded fared. None until we have the
+    /// metadata.
+    pub(crate) fn verify_stats(&self) -> Option<VerifyStats> {
+        Some(self.pieces.as_ref()?.lock().unwrap().verifier.stats())
+    }
+
+    /// The pieces we have, to tell a new peer. None until we know how many
+    /// there are.
+    pub(crate) fn bitfield(&self) -> Option<Bitfield> {
+        self.with_downloader(|downloader| downloader.picker().bitfield())
+    }
+
+    /// A peer sent `have` for `index`.
+    pub(crate) fn peer_has(&self, index: u32) {
+        self.with_downloader(|downloader| downloader.picker_mut().peer_has(index as usize));
+    }
+
+    /// A peer sent its bitfield, replacing what it said it `had`. Peers
+    /// that send a complete one count as seeds.
+    pub(crate) fn peer_has_bitfield(&self, peer: &PeerKey, had: &Bitfield, has: &Bitfield) {
+        let (was_seed, seed) = match self.peers.lock().unwrap().get_mut(peer) {
+            Some(peer) => {
+                let seed = has.count() == has.len();
+                (std::mem::replace(&mut peer.seed, seed), seed)
+            }
+            None => (false, false),
+        };
+        self.with_downloader(|downloader| {
+            let picker = downloader.picker_mut();
+            match was_seed {
+                true => picker.peer_lost_all(),
+                false => picker.peer_lost_bitfield(had),
+            }
+            match seed {
+                true => picker.peer_has_all(),
+                false => picker.peer_has_bitfield(has),
+            }
+        });
+    }
+
+    /// How many peers have the rarest piece we still want.
+    pub(crate) fn rarest(&self) -> Option<u32> {
+        self.with_downloader(|downloader| downloader.picker().rarest())
+            .flatten()
+    }
+
+    /// Whether a peer that `has` these pieces has any we want.
+    pub(crate) fn wants(&self, has: &Bitfield) -> bool {
+        self.with_downloader(|downloader| downloader.wants(has))
+            .unwrap_or(false)
+    }
+
+    /// Blocks to request from `peer`, which `has` these pieces and is
+    /// letting us download.
+    pub(crate) fn fill(&self, peer: PeerKey, has: &Bitfield, now: Instant) -> Vec<Block> {
+        self.with_downloader(|downloader| downloader.fill(peer, has, now))
+            .unwrap_or_default()
+    }
+
+    /// `peer` queues no more than `limit` of our requests.
+    pub(crate) fn limit_requests(&self, peer: PeerKey, limit: usize) {
+        self.with_downloader(|downloader| downloader.limit_requests(peer, limit, Instant::now()));
+    }
+
+    /// `peer` choked us, dropping our requests.
+    pub(crate) fn choked(&self, peer: &PeerKey) {
+        self.with_downloader(|downloader| downloader.release(peer));
+    }
+
+    /// `peer` turned down our request for `block`.
+    pub(crate) fn rejected(&self, peer: &PeerKey, block: &Block) {
+        self.with_downloader(|downloader| downloader.rejected(peer, block));
+    }
+
+    /// Takes in a block `peer` sent, cancelling it elsewhere and checking
+    /// the piece if it's the last block.
+    pub(crate) fn received(self: &Arc<Self>, peer: &PeerKey, index: u32, begin: u32, data: &[u8]) {
+        let received = self.with_downloader(|downloader| {
+            downloader.received(peer, index, begin, data, Instant::now())
+        });
+        let Some(received) = received else {
+            return;
+        };
+        if let Some(peer) = self.peers.lock().unwrap().get_mut(peer) {
+            peer.rates.downloaded += data.len() as u64;
+        }
+        for (other, block) in received.cancels {
+            self.send(&other, Command::Cancel(block));
+        }
+        if let Some((index, data)) = received.piece {
+            tokio::spawn(self.clone().verify(index, data));
+        }
+    }
+
+    async fn verify(self: Arc<Self>, index: u32, data: Vec<u8>) {
+        let Some(pieces) = &self.pieces else {
+            return;
+        };
+        let check = pieces.lock().unwrap().verifier.check(index, data);
+        let (_, good) = check.await;
+        let mut guard = pieces.lock().unwrap();
+        let Pieces {
+            downloader,
+            verifier,
+        } = &mut *guard;
+        if good {
+            verifier.passed(downloader, index);
+            let complete = downloader.picker().bitfield().is_complete();
+            drop(guard);
+            for peer in self.peers.lock().unwrap().values() {
+                let _ = peer.commands.send(Command::Have(index));
+            }
+            if complete {
+                info!("Downloaded every piece of {}", self.info_hash());
+            }
+        } else {
+            let banned = verifier.failed(downloader, index);
+            drop(guard);
+            for (key, peer) in self.peers.lock().unwrap().iter() {
+                if banned.contains(&key.ip()) {
+                    let _ = peer.commands.send(Command::Close);
+                }
+            }
+        }
+    }
+
+    fn check_stalled(&self, now: Instant) {
+        let stalled = self.with_downloader(|downloader| downloader.stalled(now));
+        let Some(stalled) = stalled.filter(|stalled| !stalled.is_empty()) else {
+            return;
+        };
+        debug!("{} requests have stalled", stalled.len());
+        for peer in self.peers.lock().unwrap().values() {
+            let _ = peer.commands.send(Command::Request);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use futures::{SinkExt, StreamExt};
+    use serde::{Deserialize, Serialize};
+    use serde_bytes::ByteBuf;
+    use sha1::{Digest, Sha1};
+    use tokio::sync::mpsc;
+
+    use super::Swarm;
+    use crate::external_ip::{ExternalIp, Voter};
+    use crate::peer::choker::{Choker, SeedChoking, UploadSlots};
+    use crate::peer::codec::Frame;
+    use crate::peer::extension::holepunch::HolepunchSwarm;
+    use crate::peer::extension::pex::{addr_to_bytes, addrs_from_bytes, PexSwarm};
+    use crate::peer::extension::ExtendedHandshake;
+    use crate::peer::listener::PeerListener;
+    use crate::peer::manager::{
+        ConnectionConfig, ConnectionLimits, ConnectionManager, PeerConnection, PeerSource,
+    };
+    use crate::peer::message::Message;
+    use crate::piece::picker::Priority;
+    use crate::piece::Bitfield;
+    use crate::torrent::{Info, InfoHash, PeerId};
+
+    const INFO_HASH: [u8; 20] = [7; 20];
+    const PIECE_LENGTH: usize = 32768;
+
+    fn manager(limits: ConnectionLimits) -> Arc<ConnectionManager> {
+        Arc::new(ConnectionManager::new(
+            InfoHash::from(INFO_HASH),
+            PeerId::new(),
+            limits,
+        ))
+    }
+
+    /// A swarm, set up by `build`, running the connections a listener
+    /// accepts, and where it listens.
+    async fn listening(
+        limits: ConnectionLimits,
+        build: impl FnOnce(Swarm) -> Swarm,
+    ) -> (Arc<Swarm>, SocketAddr) {
+        let listener = PeerListener::bind("127.0.0.1:0".parse().unwrap(), &limits)
+            .await
+            .unwrap();
+        let addr = listener.local_addr().unwrap();
+        let swarm = Arc::new(build(Swarm::new(manager(limits))));
+        let (tx, rx) = mpsc::channel(8);
+        listener.routes().add(swarm.manager.clone(), tx);
+        tokio::spawn(listener.run());
+        tokio::spawn(swarm.clone().run(rx));
+        (swarm, addr)
+    }
+
+    /// Connects to `addr` as a peer the test drives by hand.
+    async fn dial(addr: SocketAddr) -> Option<PeerConnection> {
+        let manager = manager(ConnectionLimits::new(ConnectionConfig::default()));
+        let (tx, mut rx) = mpsc::channel(1);
+        tokio::spawn(manager.clone().run(tx));
+        manager.add_peers(PeerSource::Tracker, [addr]);
+        tokio::time::timeout(Duration::from_secs(1), rx.recv())
+            .await
+            .ok()
+            .flatten()
+    }
+
+    /// The next frame `connection` gets, unless it's quiet for a while.
+    async fn next_frame(connection: &mut PeerConnection) -> Option<Frame> {
+        let next = connection.framed.next();
+        tokio::time::timeout(Duration::from_millis(300), next)
+            .await
+            .ok()??
+            .ok()
+    }
+
+    /// The metainfo for `data`, cut into pieces of `PIECE_LENGTH`.
+    fn info(data: &[u8]) -> Info {
+        let hashes: Vec<u8> = data
+            .chunks(PIECE_LENGTH)
+            .flat_map(|piece| Sha1::digest(piece).to_vec())
+            .collect();
+        let mut bencode = format!(
+            "d6:lengthi{}e4:name1:a12:piece lengthi{PIECE_LENGTH}e6:pieces{}:",
+            data.len(),
+            hashes.len()
+        )
+        .into_bytes();
+        bencode.extend(hashes);
+        bencode.push(b'e');
+        serde_bencode::from_bytes(&bencode).unwrap()
+    }
+
+    #[test]
+    fn counts_seeds_once_and_skips_skipped_files() {
+        let info = info(&[1; 2 * PIECE_LENGTH]);
+        let limits = ConnectionLimits::new(ConnectionConfig::default());
+        let swarm = Swarm::new(manager(limits.clone())).with_pieces(&info, &[]);
+        let peer: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let _commands = swarm.join(peer);
+        let all = Bitfield::full(2);
+        swarm.peer_has_bitfield(&peer, &Bitfield::new(2), &all);
+        assert_eq!(swarm.rarest(), Some(1));
+        swarm.leave(&peer, Some(&all));
+        assert_eq!(swarm.rarest(), Some(0));
+
+        let skipping = Swarm::new(manager(limits)).with_pieces(&info, &[Priority::Skip]);
+        assert!(!skipping.wants(&all));
+        assert_eq!(skipping.rarest(), None);
+    }
+
+    #[tokio::test]
+    async fn frees_slots_when_peers_leave() {
+        let (_swarm, addr) = listening(
+            ConnectionLimits::new(ConnectionConfig {
+                max_connections: 1,
+                ..Default::default()
+            }),
+            |swarm| swarm,
+        )
+        .await;
+
+        let first = dial(addr).await.unwrap();
+        assert!(dial(addr).await.is_none());
+        drop(first);
+        let mut again = None;
+        for _ in 0..10 {
+            again = dial(addr).await;
+            if again.is_some() {
+                break;
+            }
+        }
+        assert!(again.is_some());
+    }
+
+    #[tokio::test]
+    async fn downloads_from_a_seed() {
+        let data: Vec<u8> = (0..PIECE_LENGTH + 5000).map(|i| i as u8).collect();
+        let info = info(&data);
+        let (swarm, addr) = listening(
+            ConnectionLimits::new(ConnectionConfig::default()),
+            |swarm| swarm.with_pieces(&info, &[]),
+        )
+        .await;
+
+        // Seed both pieces by hand until the swarm says it has them.
+        let mut seed = dial(addr).await.unwrap();
+        let framed = &mut seed.framed;
+        framed
+            .send(Message::Bitfield {
+                bitfield: vec![0b1100_0000],
+            })
+            .await
+            .unwrap();
+        framed.send(Message::Unchoke).await.unwrap();
+        let mut have = HashSet::new();
+        while have.len() < 2 {
+            let frame = tokio::time::timeout(Duration::from_secs(5), framed.next())
+                .await
+                .unwrap()
+                .unwrap()
+                .unwrap();
+            match frame {
+                Frame::Message(Message::Request {
+                    index,
+                    begin,
+                    length,
+                }) => {
+                    let start = index as usize * PIECE_LENGTH + begin as usize;
+                    let block = data[start..start + length as usize].to_vec();
+                    framed
+                        .send(Message::Piece {
+                            index,
+                            begin,
+                            block,
+                        })
+                        .await
+                        .unwrap();
+                }
+                Frame::Message(Message::Have { index }) => {
+                    have.insert(index);
+                }
+                _ => {}
+            }
+        }
+        assert!(swarm.bitfield().unwrap().is_complete());
+        assert_eq!(swarm.verify_stats().unwrap().pieces_verified, 2);
+    }
+
+    #[tokio::test]
+    async fn bans_peers_that_send_bad_pieces() {
+        let info = info(&[1; 1000]);
+        let (swarm, addr) = listening(
+            ConnectionLimits::new(ConnectionConfig::default()),
+            |swarm| swarm.with_pieces(&info, &[]),
+        )
+        .await;
+
+        // Answer every request with the wrong data until we're dropped.
+        let mut seed = dial(addr).await.unwrap();
+        let framed = &mut seed.framed;
+        framed
+            .send(Message::Bitfield {
+                bitfield: vec![0b1000_0000],
+            })
+            .await
+            .unwrap();
+        framed.send(Message::Unchoke).await.unwrap();
+        while let Some(Ok(frame)) = tokio::time::timeout(Duration::from_secs(10), framed.next())
+            .await
+            .unwrap()
+        {
+            if let Frame::Message(Message::Request {
+                index,
+                begin,
+                length,
+            }) = frame
+            {
+                let block = vec![0; length as usize];
+                let piece = Message::Piece {
+                    index,
+                    begin,
+                    block,
+                };
+                if framed.send(piece).await.is_err() {
+                    break;
+                }
+            }
+        }
+        // As the piece's only source, the seed is banned for the first one.
+        let stats = swarm.verify_stats().unwrap();
+        assert_eq!((stats.hash_failures, stats.peers_banned), (1, 1));
+        assert!(swarm.manager.bans().is_banned(&addr.ip()));
+    }
+
+    #[tokio::test]
+    async fn downloads_allowed_fast_pieces_while_choked() {
+        let data: Vec<u8> = (0..PIECE_LENGTH + 5000).map(|i| i as u8).collect();
+        let info = info(&data);
+        let (swarm, addr) = listening(
+            ConnectionLimits::new(ConnectionConfig::default()),
+            |swarm| swarm.with_pieces(&info, &[]),
+        )
+        .await;
+
+        // Keep the swarm choked, and turn down each block the first time
+        // it's asked for.
+        let mut seed = dial(addr).await.unwrap();
+        let framed = &mut seed.framed;
+        framed.send(Message::HaveAll).await.unwrap();
+        for index in 0..2 {
+            framed.send(Message::AllowedFast { index }).await.unwrap();
+        }
+        let (mut rejected, mut have) = (HashSet::new(), HashSet::new());
+        while have.len() < 2 {
+            let frame = tokio::time::timeout(Duration::from_secs(5), framed.next())
+                .await
+                .unwrap()
+                .unwrap()
+                .unwrap();
+            match frame {
+                Frame::Message(Message::Request {
+                    index,
+                    begin,
+                    length,
+                }) => {
+                    let reply = match rejected.insert((index, begin)) {
+                        true => Message::RejectRequest {
+                            index,
+                            begin,
+                            length,
+                        },
+                        false => {
+                            let start = index as usize * PIECE_LENGTH + begin as usize;
+                            Message::Piece {
+                                index,
+                                begin,
+                                block: data[start..start + length as usize].to_vec(),
+                            }
+                        }
+                    };
+                    framed.send(reply).await.unwrap();
+                }
+                Frame::Message(Message::Have { index }) => {
+                    have.insert(index);
+                }
+                _ => {}
+            }
+        }
+        assert!(swarm.bitfield().unwrap().is_complete());
+    }
+
+    #[tokio::test]
+    async fn unchokes_interested_peers() {
+        let slots = UploadSlots::new(2);
+        let (swarm, addr) = listening(
+            ConnectionLimits::new(ConnectionConfig::default()),
+            |swarm| swarm.with_choker(Choker::new(slots.share(), SeedChoking::Fastest)),
+        )
+        .await;
+
+        // Rechoke until the swarm has seen our interest, and again once it
+        // has seen we lost it.
+        let mut leech = dial(addr).await.unwrap();
+        let framed = &mut leech.framed;
+        for (interest, expected) in [
+            (Message::Interested, Message::Unchoke),
+            (Message::NotInterested, Message::Choke),
+        ] {
+            framed.send(interest).await.unwrap();
+            let mut rounds = 0;
+            loop {
+                match tokio::time::timeout(Duration::from_millis(50), framed.next()).await {
+                    Ok(frame) => match frame.unwrap().unwrap() {
+                        Frame::Message(message) if message == expected => break,
+                        _ => {}
+                    },
+                    Err(_) => {
+                        rounds += 1;
+                        assert!(rounds < 20, "never got {expected:?}");
+                        swarm.rechoke();
+                    }
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn follows_the_extended_handshake() {
+        let info = info(&[1; 3 * PIECE_LENGTH]);
+        let size = info.metadata_size().unwrap();
+        let (_swarm, addr) = listening(
+            ConnectionLimits::new(ConnectionConfig::default()),
+            |swarm| swarm.with_pieces(&info, &[]).with_port(1234),
+        )
+        .await;
+        let mut seed = dial(addr).await.unwrap();
+
+        let theirs = loop {
+            if let Frame::Message(Message::Extended { id: 0, payload }) =
+                next_frame(&mut seed).await.unwrap()
+            {
+                break serde_bencode::from_bytes::<ExtendedHandshake>(&payload).unwrap();
+            }
+        };
+        assert_eq!(theirs.metadata_size, Some(size as i64));
+        assert_eq!(theirs.p, Some(1234));
+
+        // The swarm keeps no more requests outstanding than we queue.
+        let handshake = |metadata_size: usize| Message::Extended {
+            id: 0,
+            payload: serde_bencode::to_bytes(&ExtendedHandshake {
+                reqq: Some(2),
+                metadata_size: Some(metadata_size as i64),
+                ..Default::default()
+            })
+            .unwrap(),
+        };
+        let framed = &mut seed.framed;
+        framed.send(handshake(size)).await.unwrap();
+        framed
+            .send(Message::Bitfield {
+                bitfield: vec![0b1110_0000],
+            })
+            .await
+            .unwrap();
+        framed.send(Message::Unchoke).await.unwrap();
+        let mut requests = 0;
+        while let Some(frame) = next_frame(&mut seed).await {
+            if let Frame::Message(Message::Request { .. }) = frame {
+                requests += 1;
+            }
+        }
+        assert_eq!(requests, 2);
+
+        // A peer with other metadata is on another torrent.
+        seed.framed.send(handshake(size + 1)).await.unwrap();
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while let Some(Ok(_)) = seed.framed.next().await {}
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn learns_our_address_from_peers() {
+        let external_ip = ExternalIp::default();
+        // A voter on another network already agrees, so the peer's `yourip`
+        // settles our address.
+        external_ip.vote(
+            Voter::Node("192.0.2.1".parse().unwrap()),
+            [203, 0, 113, 7].into(),
+        );
+        let (_swarm, addr) = listening(
+            ConnectionLimits::new(ConnectionConfig::default()),
+            |swarm| swarm.with_external_ip(external_ip.clone()),
+        )
+        .await;
+        let mut peer = dial(addr).await.unwrap();
+        let handshake = ExtendedHandshake {
+            yourip: Some(ByteBuf::from(vec![203, 0, 113, 7])),
+            ..Default::default()
+        };
+        peer.framed
+            .send(Message::Extended {
+                id: 0,
+                payload: serde_bencode::to_bytes(&handshake).unwrap(),
+            })
+            .await
+            .unwrap();
+        for _ in 0..50 {
+            if external_ip.ipv4().is_some() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(external_ip.ipv4(), Some([203, 0, 113, 7].into()));
+    }
+
+    /// The one part of a PEX message the tests look at.
+    #[derive(Serialize, Deserialize)]
+    struct Added {
+        added: ByteBuf,
+    }
+
+    #[tokio::test]
+    async fn swaps_peers_over_pex() {
+        let (swarm, addr) = listening(
+            ConnectionLimits::new(ConnectionConfig::default()),
+            |swarm| swarm.with_pex(PexSwarm::default()),
+        )
+        .await;
+        let handshake = |port| Message::Extended {
+            id: 0,
+            payload: serde_bencode::to_bytes(&ExtendedHandshake {
+                m: [("ut_pex".to_string(), 1)].into(),
+                p: Some(port),
+                ..Default::default()
+            })
+            .unwrap(),
+        };
+        let mut first = dial(addr).await.unwrap();
+        first.framed.send(handshake(7001)).await.unwrap();
+        let mut second = dial(addr).await.unwrap();
+        second.framed.send(handshake(7002)).await.unwrap();
+
+        // The first peer hears where the second listens, and not about
+        // itself.
+        let added = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                if let Some(Ok(Frame::Message(Message::Extended { id: 1, payload }))) =
+                    first.framed.next().await
+                {
+                    break serde_bencode::from_bytes::<Added>(&payload).unwrap();
+                }
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(
+            addrs_from_bytes(&added.added, false).unwrap(),
+            vec![SocketAddr::from(([127, 0, 0, 1], 7002))]
+        );
+
+        // And the peers it tells the swarm about are queued to connect to.
+        let theirs = SocketAddr::from(([10, 1, 2, 3], 6881));
+        let added = Added {
+            added: ByteBuf::from(addr_to_bytes(&theirs)),
+        };
+        first
+            .framed
+            .send(Message::Extended {
+                id: 1,
+                payload: serde_bencode::to_bytes(&added).unwrap(),
+            })
+            .await
+            .unwrap();
+        for _ in 0..50 {
+            if swarm.manager.source(&theirs).is_some() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(swarm.manager.source(&theirs), Some(PeerSource::Pex));
+    }
+
+    /// A peer set up as main sets one up: listening with uTP on the same
+    /// port, dialing what its manager is given, and relaying holepunches.
+    struct Node {
+        swarm: Arc<Swarm>,
+        addr: SocketAddr,
+        holepunch: HolepunchSwarm,
+    }
+
+    impl Node {
+        async fn start() -> Self {
+            let limits = ConnectionLimits::new(ConnectionConfig::default());
+            let listener = PeerListener::bind("127.0.0.1:0".parse().unwrap(), &limits)
+                .await
+                .unwrap();
+            let addr = listener.local_addr().unwrap();
+            let holepunch = HolepunchSwarm::default();
+            let manager = Arc::new(
+                ConnectionManager::new(
+                    InfoHash::from(INFO_HASH),
+                    PeerId::new(),
+                    limits.with_utp(listener.utp()),
+                )
+                .with_holepunch(holepunch.clone()),
+            );
+            let swarm = Swarm::new(manager.clone())
+                .with_port(addr.port())
+                .with_holepunch(holepunch.clone());
+            let swarm = Arc::new(swarm);
+            let (tx, rx) = mpsc::channel(8);
+            listener.routes().add(manager.clone(), tx.clone());
+            tokio::spawn(listener.run());
+            tokio::spawn(swarm.clone().run(rx));
+            tokio::spawn(manager.run(tx));
+            Self {
+                swarm,
+                addr,
+                holepunch,
+            }
+        }
+
+        fn connections(&self) -> usize {
+            self.swarm.peers.lock().unwrap().len()
+        }
+
+        async fn wait_for(&self, connections: usize) {
+            for _ in 0..500 {
+                if self.connections() >= connections {
+                    return;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            panic!("{} never got {connections} connections", self.addr);
+        }
+    }
+
+    #[tokio::test]
+    async fn holepunches_through_a_relay() {
+        let (relay, a, b) = (
+            Node::start().await,
+            Node::start().await,
+            Node::start().await,
+        );
+        for node in [&a, &b] {
+            node.swarm
+                .manager
+                .add_peers(PeerSource::Tracker, [relay.addr]);
+            node.wait_for(1).await;
+        }
+        relay.wait_for(2).await;
+        // Let the extended handshakes land, so the relay knows where the
+        // peers that dialed it listen.
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        assert!(a.holepunch.rendezvous(b.addr));
+        a.wait_for(2).await;
+        b.wait_for(2).await;
+        assert_eq!(a.swarm.manager.source(&b.addr), Some(PeerSource::Holepunch));
+    }
+}
@@ -0,0 +1,62 @@
+//! The connections the peer wire protocol runs over: TCP, or uTP on the same
+//! port.
+
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+
+use crate::utp::UtpStream;
+
+pub(crate) enum PeerStream {
+    Tcp(TcpStream),
+    Utp(UtpStream),
+}
+
+impl PeerStream {
+    pub(crate) fn is_utp(&self) -> bool {
+        matches!(self, PeerStream::Utp(_))
+    }
+}
+
+impl AsyncRead for PeerStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            PeerStream::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            PeerStream::Utp(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for PeerStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            PeerStream::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            PeerStream::Utp(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            PeerStream::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            PeerStream::Utp(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            PeerStream::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            PeerStream::Utp(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
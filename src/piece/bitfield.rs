@@ -0,0 +1,92 @@
+/// Which pieces someone has, packed high bit first as on the wire.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Bitfield {
+    bytes: Vec<u8>,
+    len: usize,
+}
+
+impl Bitfield {
+    pub(crate) fn new(len: usize) -> Self {
+        Self {
+            bytes: vec![0; len.div_ceil(8)],
+            len,
+        }
+    }
+
+    pub(crate) fn full(len: usize) -> Self {
+        let mut bitfield = Self {
+            bytes: vec![0xff; len.div_ceil(8)],
+            len,
+        };
+        bitfield.clear_spare_bits();
+        bitfield
+    }
+
+    /// Reads a bitfield message for a torrent of `len` pieces. Peers must
+    /// send exactly the right number of bytes with the spare bits clear.
+    pub(crate) fn from_bytes(bytes: &[u8], len: usize) -> Option<Self> {
+        if bytes.len() != len.div_ceil(8) {
+            return None;
+        }
+        let bitfield = Self {
+            bytes: bytes.to_vec(),
+            len,
+        };
+        let mut cleared = bitfield.clone();
+        cleared.clear_spare_bits();
+        (cleared == bitfield).then_some(bitfield)
+    }
+
+    fn clear_spare_bits(&mut self) {
+        if !self.len.is_multiple_of(8) {
+            if let Some(last) = self.bytes.last_mut() {
+                *last &= 0xff << (8 - self.len % 8);
+            }
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+
+    pub(crate) fn has(&self, index: usize) -> bool {
+        index < self.len && self.bytes[index / 8] & (0x80 >> (index % 8)) != 0
+    }
+
+    pub(crate) fn set(&mut self, index: usize) {
+        if index < self.len {
+            self.bytes[index / 8] |= 0x80 >> (index % 8);
+        }
+    }
+
+    pub(crate) fn count(&self) -> usize {
+        self.bytes.iter().map(|b| b.count_ones() as usize).sum()
+    }
+
+    pub(crate) fn is_complete(&self) -> bool {
+        self.count() == self.len
+    }
+
+    pub(crate) fn iter_ones(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.len).filter(|&i| self.has(i))
+    }
+
+    pub(crate) fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Bitfield;
+
+    #[test]
+    fn reads_wire_bytes() {
+        let bitfield = Bitfield::from_bytes(&[0b1010_0000, 0b1000_0000], 9).unwrap();
+        assert_eq!(bitfield.iter_ones().collect::<Vec<_>>(), vec![0, 2, 8]);
+        assert!(Bitfield::from_bytes(&[0xff], 9).is_none());
+        // Spare bits must be clear.
+        assert!(Bitfield::from_bytes(&[0, 0b0100_0000], 9).is_none());
+        assert_eq!(Bitfield::full(9).as_bytes(), &[0xff, 0x80]);
+    }
+}
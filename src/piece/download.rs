@@ -0,0 +1,672 @@
+//! Fetching pieces as 16 KiB blocks. The [`Downloader`] keeps each peer's
+//! queue of outstanding requests filled to a depth that follows the peer's
+//! throughput, re-requests blocks that stall, and in endgame asks several
+//! peers for the last blocks, cancelling the rest once one arrives.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+use super::picker::PiecePicker;
+use super::Bitfield;
+use crate::peer::message::Message;
+
+pub(crate) const BLOCK_SIZE: u32 = 16 * 1024;
+/// Aim to have this much of the peer's throughput in flight.
+const QUEUE_TIME: Duration = Duration::from_secs(3);
+const MIN_DEPTH: usize = 4;
+const MAX_DEPTH: usize = 250;
+/// A request older than this is stalled, and its block may be requested from
+/// someone else.
+const BLOCK_TIMEOUT: Duration = Duration::from_secs(30);
+/// In endgame, how many peers may be asked for the same block at once.
+const ENDGAME_REQUESTS: usize = 2;
+
+/// Peers are told apart by address, as the connection manager does.
+pub(crate) type PeerKey = SocketAddr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) struct Block {
+    pub(crate) index: u32,
+    pub(crate) begin: u32,
+    pub(crate) length: u32,
+}
+
+impl Block {
+    pub(crate) fn request(&self) -> Message {
+        Message::Request {
+            index: self.index,
+            begin: self.begin,
+            length: self.length,
+        }
+    }
+
+    pub(crate) fn cancel(&self) -> Message {
+        Message::Cancel {
+            index: self.index,
+            begin: self.begin,
+            length: self.length,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum BlockState {
+    Missing,
+    Requested(Vec<(PeerKey, Instant)>),
+    Received(PeerKey),
+}
+
+struct PieceDownload {
+    blocks: Vec<BlockState>,
+    data: Vec<u8>,
+}
+
+impl PieceDownload {
+    fn is_stalled(requests: &[(PeerKey, Instant)], now: Instant) -> bool {
+        requests
+            .iter()
+            .all(|(_, at)| now.duration_since(*at) >= BLOCK_TIMEOUT)
+    }
+
+    /// A block we can ask `peer` for outside endgame: one nobody has asked
+    /// for, or whose requests have all stalled.
+    fn next_block(&self, peer: &PeerKey, now: Instant) -> Option<usize> {
+        self.blocks.iter().position(|state| match state {
+            BlockState::Missing => true,
+            BlockState::Requested(requests) => {
+                Self::is_stalled(requests, now) && requests.iter().all(|(p, _)| p != peer)
+            }
+            BlockState::Received(_) => false,
+        })
+    }
+
+    fn endgame_block(&self, peer: &PeerKey) -> Option<usize> {
+        self.blocks.iter().position(|state| match state {
+            BlockState::Requested(requests) => {
+                requests.len() < ENDGAME_REQUESTS && requests.iter().all(|(p, _)| p != peer)
+            }
+            _ => false,
+        })
+    }
+
+    fn contributors(&self) -> Vec<PeerKey> {
+        let mut peers: Vec<_> = self
+            .blocks
+            .iter()
+            .filter_map(|state| match state {
+                BlockState::Received(peer) => Some(*peer),
+                _ => None,
+            })
+            .collect();
+        peers.sort();
+        peers.dedup();
+        peers
+    }
+
+    fn is_complete(&self) -> bool {
+        self.blocks
+            .iter()
+            .all(|state| matches!(state, BlockState::Received(_)))
+    }
+}
+
+/// Bytes per second over the last few seconds.
+#[derive(Debug)]
+struct Throughput {
+    rate: f64,
+    bytes: u64,
+    since: Instant,
+}
+
+impl Throughput {
+    fn new(now: Instant) -> Self {
+        Self {
+            rate: 0.0,
+            bytes: 0,
+            since: now,
+        }
+    }
+
+    fn record(&mut self, bytes: u64, now: Instant) {
+        self.bytes += bytes;
+        let elapsed = now.duration_since(self.since).as_secs_f64();
+        if elapsed >= 1.0 {
+            let sample = self.bytes as f64 / elapsed;
+            self.rate = if self.rate == 0.0 {
+                sample
+            } else {
+                0.7 * self.rate + 0.3 * sample
+            };
+            self.bytes = 0;
+            self.since = now;
+        }
+    }
+}
+
+struct PeerState {
+    outstanding: Vec<Block>,
+    /// Requests reported stalled. They no longer take up room in the queue,
+    /// but an answer is still taken if it turns up.
+    late: Vec<Block>,
+    throughput: Throughput,
+    /// Lowered when the peer lets requests stall, so we stop piling on.
+    max_depth: usize,
+    /// The most requests the peer said it queues.
+    limit: usize,
+}
+
+impl PeerState {
+    fn depth(&self) -> usize {
+        let wanted = self.throughput.rate * QUEUE_TIME.as_secs_f64() / f64::from(BLOCK_SIZE);
+        (wanted as usize)
+            .clamp(MIN_DEPTH, self.max_depth)
+            .min(self.limit)
+    }
+}
+
+/// What to do after a block arrives.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub(crate) struct Received {
+    /// Other peers that were also asked for the block, and should be sent
+    /// `cancel`.
+    pub(crate) cancels: Vec<(PeerKey, Block)>,
+    /// A piece whose last block this was, with its data, ready to verify.
+    pub(crate) piece: Option<(u32, Vec<u8>)>,
+}
+
+pub(crate) struct Downloader {
+    picker: PiecePicker,
+    piece_length: u64,
+    total_length: u64,
+    pieces: HashMap<u32, PieceDownload>,
+    /// Pieces with every block in, waiting on their hash check, and the
+    /// peers that sent their blocks.
+    verifying: HashMap<u32, Vec<PeerKey>>,
+    peers: HashMap<PeerKey, PeerState>,
+}
+
+impl Downloader {
+    pub(crate) fn new(picker: PiecePicker, piece_length: u64, total_length: u64) -> Self {
+        Self {
+            picker,
+            piece_length,
+            total_length,
+            pieces: HashMap::new(),
+            verifying: HashMap::new(),
+            peers: HashMap::new(),
+        }
+    }
+
+    pub(crate) fn picker(&self) -> &PiecePicker {
+        &self.picker
+    }
+
+    pub(crate) fn picker_mut(&mut self) -> &mut PiecePicker {
+        &mut self.picker
+    }
+
+    /// The length of piece `index`, or `None` if the torrent ends before it.
+    fn piece_size(&self, index: u32) -> Option<u32> {
+        let start = u64::from(index) * self.piece_length;
+        let left = self
+            .total_length
+            .checked_sub(start)
+            .filter(|&left| left > 0)?;
+        Some(left.min(self.piece_length) as u32)
+    }
+
+    /// Block `block` of a piece `size` bytes long.
+    fn block(index: u32, size: u32, block: usize) -> Block {
+        let begin = block as u32 * BLOCK_SIZE;
+        Block {
+            index,
+            begin,
+            length: (size - begin).min(BLOCK_SIZE),
+        }
+    }
+
+    /// Whether `has` holds any piece we still want.
+    pub(crate) fn wants(&self, has: &Bitfield) -> bool {
+        has.iter_ones()
+            .any(|index| index < self.picker.len() && self.picker.wants(index))
+    }
+
+    /// Whether every piece we want is underway, so the remaining blocks
+    /// should be requested from more than one peer.
+    pub(crate) fn in_endgame(&self) -> bool {
+        !self.picker.has_unstarted()
+            && self
+                .pieces
+                .values()
+                .all(|piece| !piece.blocks.contains(&BlockState::Missing))
+    }
+
+    pub(crate) fn add_peer(&mut self, peer: PeerKey, now: Instant) {
+        self.peers.entry(peer).or_insert(PeerState {
+            outstanding: Vec::new(),
+            late: Vec::new(),
+            throughput: Throughput::new(now),
+            max_depth: MAX_DEPTH,
+            limit: MAX_DEPTH,
+        });
+    }
+
+    /// Keeps no more than `limit` requests outstanding with `peer`, as it
+    /// asked in its extended handshake.
+    pub(crate) fn limit_requests(&mut self, peer: PeerKey, limit: usize, now: Instant) {
+        self.add_peer(peer, now);
+        self.peers.get_mut(&peer).unwrap().limit = limit.clamp(1, MAX_DEPTH);
+    }
+
+    /// Forgets `peer`'s requests, because it went away or choked us without
+    /// the fast extension. Peers with it reject each request instead.
+    pub(crate) fn release(&mut self, peer: &PeerKey) {
+        let Some(state) = self.peers.get_mut(peer) else {
+            return;
+        };
+        for block in state.outstanding.drain(..).chain(state.late.drain(..)) {
+            Self::unrequest(&mut self.pieces, peer, &block);
+        }
+    }
+
+    /// `peer` turned down a request, so its block can go to someone else
+    /// straight away.
+    pub(crate) fn rejected(&mut self, peer: &PeerKey, block: &Block) {
+        let Some(state) = self.peers.get_mut(peer) else {
+            return;
+        };
+        let before = state.outstanding.len() + state.late.len();
+        state.outstanding.retain(|b| b != block);
+        state.late.retain(|b| b != block);
+        if state.outstanding.len() + state.late.len() < before {
+            Self::unrequest(&mut self.pieces, peer, block);
+        }
+    }
+
+    fn unrequest(pieces: &mut HashMap<u32, PieceDownload>, peer: &PeerKey, block: &Block) {
+        if let Some(piece) = pieces.get_mut(&block.index) {
+            let slot = &mut piece.blocks[(block.begin / BLOCK_SIZE) as usize];
+            if let BlockState::Requested(requests) = slot {
+                requests.retain(|(p, _)| p != peer);
+                if requests.is_empty() {
+                    *slot = BlockState::Missing;
+                }
+            }
+        }
+    }
+
+    pub(crate) fn remove_peer(&mut self, peer: &PeerKey) {
+        self.release(peer);
+        self.peers.remove(peer);
+    }
+
+    /// Tops up `peer`'s request queue from the pieces it `has`, returning the
+    /// new requests to send.
+    pub(crate) fn fill(&mut self, peer: PeerKey, has: &Bitfield, now: Instant) -> Vec<Block> {
+        self.add_peer(peer, now);
+        let mut requests = Vec::new();
+        while self.peers[&peer].outstanding.len() < self.peers[&peer].depth() {
+            match self.next_request(&peer, has, now) {
+                Some(block) => {
+                    self.peers.get_mut(&peer).unwrap().outstanding.push(block);
+                    requests.push(block);
+                }
+                None => break,
+            }
+        }
+        requests
+    }
+
+    fn next_request(&mut self, peer: &PeerKey, has: &Bitfield, now: Instant) -> Option<Block> {
+        let (pieces, verifying) = (&self.pieces, &self.verifying);
+        let end = self.total_length.div_ceil(self.piece_length);
+        let index = self.picker.pick(has, |i| {
+            i as u64 >= end
+                || verifying.contains_key(&(i as u32))
+                || pieces
+                    .get(&(i as u32))
+                    .is_some_and(|piece| piece.next_block(peer, now).is_none())
+        });
+        let (index, block) = match index {
+            Some(index) => {
+                let index = index as u32;
+                if !self.pieces.contains_key(&index) {
+                    let size = self.piece_size(index)?;
+                    self.pieces.insert(
+                        index,
+                        PieceDownload {
+                            blocks: vec![BlockState::Missing; size.div_ceil(BLOCK_SIZE) as usize],
+                            data: vec![0; size as usize],
+                        },
+                    );
+                }
+                (index, self.pieces[&index].next_block(peer, now)?)
+            }
+            None => {
+                if !self.in_endgame() {
+                    return None;
+                }
+                self.pieces
+                    .iter()
+                    .filter(|(&index, _)| has.has(index as usize))
+                    .find_map(|(&index, piece)| Some((index, piece.endgame_block(peer)?)))?
+            }
+        };
+
+        let slot = &mut self.pieces.get_mut(&index).unwrap().blocks[block];
+        match slot {
+            BlockState::Requested(requests) => requests.push((*peer, now)),
+            _ => *slot = BlockState::Requested(vec![(*peer, now)]),
+        }
+        Some(Self::block(index, self.piece_size(index)?, block))
+    }
+
+    /// Takes in a block `peer` sent. Blocks we didn't ask for, or already
+    /// have, are dropped.
+    pub(crate) fn received(
+        &mut self,
+        peer: &PeerKey,
+        index: u32,
+        begin: u32,
+        data: &[u8],
+        now: Instant,
+    ) -> Received {
+        let mut received = Received::default();
+        let Some(state) = self.peers.get_mut(peer) else {
+            return received;
+        };
+        let asked =
+            |b: &Block| b.index == index && b.begin == begin && b.length as usize == data.len();
+        if let Some(position) = state.outstanding.iter().position(asked) {
+            state.outstanding.swap_remove(position);
+        } else if let Some(position) = state.late.iter().position(asked) {
+            state.late.swap_remove(position);
+        } else {
+            return received;
+        }
+        state.throughput.record(data.len() as u64, now);
+
+        let Some(piece) = self.pieces.get_mut(&index) else {
+            return received;
+        };
+        let slot = &mut piece.blocks[(begin / BLOCK_SIZE) as usize];
+        let BlockState::Requested(requests) = slot else {
+            return received;
+        };
+        let block = Block {
+            index,
+            begin,
+            length: data.len() as u32,
+        };
+        for (other, _) in requests.iter().filter(|(p, _)| p != peer) {
+            if let Some(state) = self.peers.get_mut(other) {
+                state.outstanding.retain(|b| b != &block);
+                state.late.retain(|b| b != &block);
+            }
+            received.cancels.push((*other, block));
+        }
+        *slot = BlockState::Received(*peer);
+        piece.data[begin as usize..begin as usize + data.len()].copy_from_slice(data);
+
+        if piece.is_complete() {
+            let piece = self.pieces.remove(&index).unwrap();
+            self.verifying.insert(index, piece.contributors());
+            received.piece = Some((index, piece.data));
+        }
+        received
+    }
+
+    /// A piece handed out by [`received`](Self::received) passed its hash
+    /// check.
+    pub(crate) fn verified(&mut self, index: u32) {
+        self.verifying.remove(&index);
+        self.picker.complete(index as usize);
+    }
+
+    /// A piece handed out by [`received`](Self::received) failed its hash
+    /// check, and has to be downloaded again. Returns the peers that sent
+    /// its blocks.
+    pub(crate) fn failed(&mut self, index: u32) -> Vec<PeerKey> {
+        self.picker.abandon(index as usize);
+        self.verifying.remove(&index).unwrap_or_default()
+    }
+
+    /// Requests that have gone unanswered too long, each reported once.
+    /// Their blocks become available to other peers, and the peers that let
+    /// them stall get shallower queues. A stalled request stops counting
+    /// against its peer's queue, but is kept in case the answer turns up late.
+    pub(crate) fn stalled(&mut self, now: Instant) -> Vec<(PeerKey, Block)> {
+        let mut stalled = Vec::new();
+        for (peer, state) in self.peers.iter_mut() {
+            let before = stalled.len();
+            let pieces = &self.pieces;
+            state.outstanding.retain(|block| {
+                let stale = pieces.get(&block.index).is_some_and(|piece| {
+                    match &piece.blocks[(block.begin / BLOCK_SIZE) as usize] {
+                        BlockState::Requested(requests) => requests
+                            .iter()
+                            .any(|(p, at)| p == peer && now.duration_since(*at) >= BLOCK_TIMEOUT),
+                        _ => false,
+                    }
+                });
+                if stale {
+                    stalled.push((*peer, *block));
+                }
+                !stale
+            });
+            if stalled.len() > before {
+                state
+                    .late
+                    .extend(stalled[before..].iter().map(|(_, block)| *block));
+                state.max_depth = (state.max_depth / 2).max(MIN_DEPTH);
+            }
+        }
+        stalled
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::time::Instant;
+
+    use super::{Block, Downloader, PeerKey, BLOCK_SIZE, BLOCK_TIMEOUT, MIN_DEPTH};
+    use crate::piece::picker::PiecePicker;
+    use crate::piece::Bitfield;
+
+    fn peer(n: u8) -> PeerKey {
+        PeerKey::from(([10, 0, 0, n], 6881))
+    }
+
+    /// A torrent of `pieces` pieces of two blocks each, the last one short.
+    fn downloader(pieces: usize) -> Downloader {
+        let piece_length = 2 * u64::from(BLOCK_SIZE);
+        let mut picker = PiecePicker::new(pieces);
+        picker.peer_has_all();
+        Downloader::new(picker, piece_length, piece_length * pieces as u64 - 100)
+    }
+
+    fn deliver(
+        downloader: &mut Downloader,
+        from: PeerKey,
+        block: Block,
+        now: Instant,
+    ) -> super::Received {
+        let data = vec![block.index as u8; block.length as usize];
+        downloader.received(&from, block.index, block.begin, &data, now)
+    }
+
+    #[test]
+    fn requests_blocks_and_assembles_pieces() {
+        let now = Instant::now();
+        let mut downloader = downloader(3);
+        let mut first_two = Bitfield::new(3);
+        first_two.set(0);
+        first_two.set(1);
+        let requests = downloader.fill(peer(1), &first_two, now);
+        assert_eq!(requests.len(), MIN_DEPTH);
+        // Two pieces' worth, block by block.
+        let mut pieces: Vec<_> = requests.iter().map(|b| b.index).collect();
+        pieces.dedup();
+        assert_eq!(pieces.len(), 2);
+        assert!(requests.iter().all(|b| b.begin % BLOCK_SIZE == 0));
+
+        let mut completed = Vec::new();
+        for block in requests {
+            if let Some((index, data)) = deliver(&mut downloader, peer(1), block, now).piece {
+                assert!(data.iter().all(|&b| b == index as u8));
+                completed.push(index);
+            }
+        }
+        assert_eq!(completed.len(), 2);
+        // Finished pieces aren't requested again while they're verified...
+        let all = Bitfield::full(3);
+        let last = downloader.fill(peer(1), &all, now);
+        assert_eq!(last.len(), 2);
+        // ...and the last piece is short, as is its last block.
+        assert_eq!(last[0].index, 2);
+        assert_eq!(last[1].length, BLOCK_SIZE - 100);
+
+        // A piece that fails verification is fetched again.
+        downloader.verified(completed[0]);
+        assert_eq!(downloader.failed(completed[1]), vec![peer(1)]);
+        let again = downloader.fill(peer(1), &all, now);
+        assert_eq!(again.len(), 2);
+        assert!(again.iter().all(|b| b.index == completed[1]));
+        assert!(downloader.picker().have(completed[0] as usize));
+    }
+
+    #[test]
+    fn depth_follows_throughput() {
+        let mut now = Instant::now();
+        let mut downloader = downloader(1000);
+        let all = Bitfield::full(1000);
+        let mut requests = downloader.fill(peer(1), &all, now);
+        // Answer everything at 1 MiB/s for a few seconds.
+        for _ in 0..5 {
+            now += Duration::from_millis(250);
+            for block in requests.drain(..) {
+                deliver(&mut downloader, peer(1), block, now);
+            }
+            requests = downloader.fill(peer(1), &all, now);
+            while requests.len() < 16 {
+                let more = downloader.fill(peer(1), &all, now);
+                if more.is_empty() {
+                    break;
+                }
+                requests.extend(more);
+            }
+        }
+        assert!(
+            downloader.peers[&peer(1)].depth() > MIN_DEPTH,
+            "{}",
+            downloader.peers[&peer(1)].depth()
+        );
+    }
+
+    #[test]
+    fn stalled_blocks_go_to_other_peers() {
+        let now = Instant::now();
+        let mut downloader = downloader(2);
+        let mut first = Bitfield::new(2);
+        first.set(0);
+        let slow = downloader.fill(peer(1), &first, now);
+        assert_eq!(slow.len(), 2);
+        assert!(downloader.fill(peer(2), &first, now).is_empty());
+
+        let later = now + BLOCK_TIMEOUT;
+        assert_eq!(downloader.stalled(later).len(), 2);
+        // Each stall is reported, and shrinks the queue, only once.
+        let depth = downloader.peers[&peer(1)].max_depth;
+        assert!(downloader
+            .stalled(later + Duration::from_secs(5))
+            .is_empty());
+        assert_eq!(downloader.peers[&peer(1)].max_depth, depth);
+        assert!(downloader.peers[&peer(1)].outstanding.is_empty());
+        let retry = downloader.fill(peer(3), &first, later);
+        assert_eq!(retry, slow);
+
+        // Whichever answer arrives first wins, and the other is cancelled.
+        let received = deliver(&mut downloader, peer(3), retry[0], later);
+        assert_eq!(received.cancels, vec![(peer(1), slow[0])]);
+    }
+
+    #[test]
+    fn endgame_requests_last_blocks_twice() {
+        let now = Instant::now();
+        let mut downloader = downloader(1);
+        let all = Bitfield::full(1);
+        assert!(!downloader.in_endgame());
+        let first = downloader.fill(peer(1), &all, now);
+        assert!(downloader.in_endgame());
+
+        let second = downloader.fill(peer(2), &all, now);
+        assert_eq!(second, first);
+        // No more than two peers per block.
+        assert!(downloader.fill(peer(3), &all, now).is_empty());
+
+        let received = deliver(&mut downloader, peer(2), second[0], now);
+        assert_eq!(received.cancels, vec![(peer(1), first[0])]);
+        assert!(received.piece.is_none());
+        let received = deliver(&mut downloader, peer(1), first[1], now);
+        assert_eq!(received.cancels, vec![(peer(2), second[1])]);
+        assert!(received.piece.is_some());
+
+        // A late answer to a cancelled request is dropped.
+        let late = deliver(&mut downloader, peer(1), first[0], now);
+        assert_eq!(late, super::Received::default());
+    }
+
+    #[test]
+    fn choking_releases_requests() {
+        let now = Instant::now();
+        let mut downloader = downloader(1);
+        let all = Bitfield::full(1);
+        let requests = downloader.fill(peer(1), &all, now);
+        downloader.release(&peer(1));
+        assert!(!downloader.in_endgame());
+        assert_eq!(downloader.fill(peer(2), &all, now), requests);
+    }
+
+    #[test]
+    fn rejected_blocks_are_requested_elsewhere() {
+        let now = Instant::now();
+        let mut downloader = downloader(1);
+        let all = Bitfield::full(1);
+        let requests = downloader.fill(peer(1), &all, now);
+        downloader.rejected(&peer(1), &requests[0]);
+        // A second reject for the same block changes nothing.
+        downloader.rejected(&peer(1), &requests[0]);
+        assert_eq!(downloader.fill(peer(2), &all, now)[0], requests[0]);
+    }
+
+    #[test]
+    fn skips_pieces_past_the_end() {
+        let now = Instant::now();
+        // Three pieces' hashes, but only two pieces' worth of data.
+        let mut picker = PiecePicker::new(3);
+        picker.peer_has_all();
+        let mut downloader =
+            Downloader::new(picker, u64::from(BLOCK_SIZE), 2 * u64::from(BLOCK_SIZE));
+        assert_eq!(downloader.piece_size(2), None);
+        let requests = downloader.fill(peer(1), &Bitfield::full(3), now);
+        assert_eq!(requests.len(), 2);
+        assert!(requests.iter().all(|b| b.index < 2));
+        assert!(deliver(
+            &mut downloader,
+            peer(1),
+            Block {
+                index: 2,
+                begin: 0,
+                length: 1
+            },
+            now
+        )
+        .piece
+        .is_none());
+    }
+}
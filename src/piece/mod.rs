@@ -0,0 +1,8 @@
+//! Tracking the pieces of a torrent: who has them and which to fetch next.
+
+mod bitfield;
+pub(crate) mod download;
+pub(crate) mod picker;
+pub(crate) mod verify;
+
+pub(crate) use bitfield::Bitfield;
@@ -0,0 +1,408 @@
+//! Choosing which piece to download next: pieces we've started come first,
+//! then the highest priority, then the rarest among our peers, with ties
+//! broken at random.
+//!
+//! Wanted pieces live in buckets by priority and availability, so a `have`
+//! moves one piece between two buckets in constant time and a pick only
+//! looks at the rarest pieces. Peers that have everything are counted once
+//! rather than added to every piece.
+
+use rand::Rng;
+use serde::Deserialize;
+
+use super::Bitfield;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Priority {
+    /// Don't download at all.
+    Skip = 0,
+    Low = 1,
+    #[default]
+    Normal = 2,
+    High = 3,
+}
+
+impl Priority {
+    const PICKABLE: [Priority; 3] = [Priority::High, Priority::Normal, Priority::Low];
+
+    /// Works out piece priorities from file priorities: a piece gets the
+    /// highest priority of the files it overlaps. `files` holds each file's
+    /// length and priority, in torrent order.
+    pub(crate) fn of_pieces(
+        files: &[(u64, Priority)],
+        piece_length: u64,
+        pieces: usize,
+    ) -> Vec<Priority> {
+        let mut priorities = vec![Priority::Skip; pieces];
+        let mut offset = 0;
+        for &(length, priority) in files {
+            if length > 0 {
+                let first = (offset / piece_length) as usize;
+                let last = ((offset + length - 1) / piece_length) as usize;
+                for piece in priorities.iter_mut().take(last + 1).skip(first) {
+                    *piece = (*piece).max(priority);
+                }
+            }
+            offset += length;
+        }
+        priorities
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Wanted,
+    Partial,
+    Have,
+}
+
+pub(crate) struct PiecePicker {
+    availability: Vec<u32>,
+    priority: Vec<Priority>,
+    state: Vec<State>,
+    /// Where each wanted piece sits in its bucket.
+    position: Vec<usize>,
+    /// Wanted pieces by priority, then by availability not counting seeds.
+    buckets: [Vec<Vec<u32>>; 4],
+    partial: Vec<u32>,
+    seeds: u32,
+}
+
+impl PiecePicker {
+    pub(crate) fn new(pieces: usize) -> Self {
+        let mut picker = Self {
+            availability: vec![0; pieces],
+            priority: vec![Priority::Normal; pieces],
+            state: vec![State::Wanted; pieces],
+            position: vec![0; pieces],
+            buckets: Default::default(),
+            partial: Vec::new(),
+            seeds: 0,
+        };
+        for index in 0..pieces {
+            picker.insert(index);
+        }
+        picker
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.state.len()
+    }
+
+    /// How many connected peers have `index`.
+    pub(crate) fn availability(&self, index: usize) -> u32 {
+        self.availability[index] + self.seeds
+    }
+
+    fn insert(&mut self, index: usize) {
+        let bucket = &mut self.buckets[self.priority[index] as usize];
+        let availability = self.availability[index] as usize;
+        if bucket.len() <= availability {
+            bucket.resize_with(availability + 1, Vec::new);
+        }
+        self.position[index] = bucket[availability].len();
+        bucket[availability].push(index as u32);
+    }
+
+    fn remove(&mut self, index: usize) {
+        let bucket =
+            &mut self.buckets[self.priority[index] as usize][self.availability[index] as usize];
+        let position = self.position[index];
+        bucket.swap_remove(position);
+        if let Some(&moved) = bucket.get(position) {
+            self.position[moved as usize] = position;
+        }
+    }
+
+    fn update(&mut self, index: usize, change: impl FnOnce(&mut Self)) {
+        let wanted = self.state[index] == State::Wanted;
+        if wanted {
+            self.remove(index);
+        }
+        change(self);
+        if wanted {
+            self.insert(index);
+        }
+    }
+
+    /// A peer sent `have`.
+    pub(crate) fn peer_has(&mut self, index: usize) {
+        if index < self.len() {
+            self.update(index, |p| p.availability[index] += 1);
+        }
+    }
+
+    /// A peer sent its bitfield.
+    pub(crate) fn peer_has_bitfield(&mut self, bitfield: &Bitfield) {
+        for index in bitfield.iter_ones() {
+            self.peer_has(index);
+        }
+    }
+
+    /// A peer sent `have all`, or a bitfield that's complete.
+    pub(crate) fn peer_has_all(&mut self) {
+        self.seeds += 1;
+    }
+
+    /// A peer without every piece disconnected, or took back a piece it had.
+    pub(crate) fn peer_lost_bitfield(&mut self, bitfield: &Bitfield) {
+        for index in bitfield.iter_ones() {
+            if index < self.len() && self.availability[index] > 0 {
+                self.update(index, |p| p.availability[index] -= 1);
+            }
+        }
+    }
+
+    pub(crate) fn peer_lost_all(&mut self) {
+        self.seeds = self.seeds.saturating_sub(1);
+    }
+
+    pub(crate) fn set_priority(&mut self, index: usize, priority: Priority) {
+        self.update(index, |p| p.priority[index] = priority);
+    }
+
+    pub(crate) fn set_priorities(&mut self, priorities: &[Priority]) {
+        for (index, &priority) in priorities.iter().enumerate().take(self.len()) {
+            self.set_priority(index, priority);
+        }
+    }
+
+    /// Picks a piece to download from a peer with the pieces in `has`,
+    /// skipping any `skip` rejects (e.g. started pieces with every block
+    /// already requested). The piece counts as started until it's
+    /// [`complete`](Self::complete) or [`abandon`](Self::abandon)ed.
+    pub(crate) fn pick(&mut self, has: &Bitfield, skip: impl Fn(usize) -> bool) -> Option<usize> {
+        let started = self
+            .partial
+            .iter()
+            .map(|&i| i as usize)
+            .filter(|&i| has.has(i) && !skip(i))
+            .min_by_key(|&i| self.availability[i]);
+        if started.is_some() {
+            return started;
+        }
+
+        let mut rng = rand::thread_rng();
+        for priority in Priority::PICKABLE {
+            for bucket in &self.buckets[priority as usize] {
+                if bucket.is_empty() {
+                    continue;
+                }
+                let start = rng.gen_range(0..bucket.len());
+                let found = (0..bucket.len())
+                    .map(|k| bucket[(start + k) % bucket.len()] as usize)
+                    .find(|&i| has.has(i) && !skip(i));
+                if let Some(index) = found {
+                    self.remove(index);
+                    self.state[index] = State::Partial;
+                    self.partial.push(index as u32);
+                    return Some(index);
+                }
+            }
+        }
+        None
+    }
+
+    fn leave_partial(&mut self, index: usize) {
+        if let Some(i) = self.partial.iter().position(|&p| p as usize == index) {
+            self.partial.swap_remove(i);
+        }
+    }
+
+    /// We have `index`, verified.
+    pub(crate) fn complete(&mut self, index: usize) {
+        match self.state[index] {
+            State::Wanted => self.remove(index),
+            State::Partial => self.leave_partial(index),
+            State::Have => return,
+        }
+        self.state[index] = State::Have;
+    }
+
+    /// A started piece is wanted again from scratch, e.g. after failing its
+    /// hash check.
+    pub(crate) fn abandon(&mut self, index: usize) {
+        if self.state[index] == State::Partial {
+            self.leave_partial(index);
+            self.state[index] = State::Wanted;
+            self.insert(index);
+        }
+    }
+
+    /// Whether any piece we want is yet to be started.
+    pub(crate) fn has_unstarted(&self) -> bool {
+        Priority::PICKABLE.iter().any(|&priority| {
+            self.buckets[priority as usize]
+                .iter()
+                .any(|b| !b.is_empty())
+        })
+    }
+
+    pub(crate) fn have(&self, index: usize) -> bool {
+        self.state[index] == State::Have
+    }
+
+    /// Whether we still want `index`: we don't have it and it isn't skipped.
+    pub(crate) fn wants(&self, index: usize) -> bool {
+        !self.have(index) && self.priority[index] != Priority::Skip
+    }
+
+    /// How many connected peers have the rarest piece we still want, if any.
+    pub(crate) fn rarest(&self) -> Option<u32> {
+        (0..self.len())
+            .filter(|&index| self.wants(index))
+            .map(|index| self.availability(index))
+            .min()
+    }
+
+    /// The pieces we have, to send as our bitfield.
+    pub(crate) fn bitfield(&self) -> Bitfield {
+        let mut bitfield = Bitfield::new(self.len());
+        for (index, state) in self.state.iter().enumerate() {
+            if *state == State::Have {
+                bitfield.set(index);
+            }
+        }
+        bitfield
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::time::Instant;
+
+    use super::{PiecePicker, Priority};
+    use crate::piece::Bitfield;
+
+    fn bitfield(len: usize, ones: &[usize]) -> Bitfield {
+        let mut bitfield = Bitfield::new(len);
+        for &i in ones {
+            bitfield.set(i);
+        }
+        bitfield
+    }
+
+    #[test]
+    fn picks_rarest_first() {
+        let mut picker = PiecePicker::new(4);
+        picker.peer_has_bitfield(&bitfield(4, &[0, 1, 2, 3]));
+        picker.peer_has_bitfield(&bitfield(4, &[0, 1, 3]));
+        picker.peer_has_bitfield(&bitfield(4, &[0, 3]));
+        picker.peer_has_all();
+        assert_eq!(picker.availability(0), 4);
+        assert_eq!(picker.availability(2), 2);
+
+        // Started pieces are returned until they're done, so skip them to
+        // see the order new pieces come in.
+        let all = Bitfield::full(4);
+        let mut order = Vec::new();
+        while let Some(i) = picker.pick(&all, |i| order.contains(&i)) {
+            order.push(i);
+        }
+        assert_eq!(order[..2], [2, 1]);
+        assert_eq!(
+            order[2..].iter().copied().collect::<HashSet<_>>(),
+            HashSet::from([0, 3])
+        );
+        assert_eq!(picker.pick(&bitfield(4, &[]), |_| false), None);
+    }
+
+    #[test]
+    fn breaks_ties_at_random() {
+        let all = Bitfield::full(8);
+        let picked: HashSet<_> = (0..200)
+            .filter_map(|_| PiecePicker::new(8).pick(&all, |_| false))
+            .collect();
+        assert!(picked.len() > 4, "{picked:?}");
+    }
+
+    #[test]
+    fn finishes_started_pieces_first() {
+        let mut picker = PiecePicker::new(3);
+        picker.peer_has_bitfield(&bitfield(3, &[0, 1, 2]));
+        picker.peer_has(2);
+        let started = picker.pick(&bitfield(3, &[2]), |_| false).unwrap();
+        assert_eq!(started, 2);
+        // A rarer piece doesn't jump ahead of the one already started.
+        assert_eq!(picker.pick(&Bitfield::full(3), |_| false), Some(2));
+        // Unless the started piece can't be used.
+        let next = picker.pick(&Bitfield::full(3), |i| i == 2).unwrap();
+        assert_ne!(next, 2);
+
+        picker.complete(2);
+        picker.abandon(next);
+        assert!(picker.have(2));
+        assert_eq!(picker.bitfield().iter_ones().collect::<Vec<_>>(), vec![2]);
+        assert!(matches!(
+            picker.pick(&Bitfield::full(3), |_| false),
+            Some(0 | 1)
+        ));
+    }
+
+    #[test]
+    fn respects_priorities() {
+        // Three files of 10, 25 and 5 bytes over 10-byte pieces.
+        let priorities = Priority::of_pieces(
+            &[
+                (10, Priority::Skip),
+                (25, Priority::Low),
+                (5, Priority::High),
+            ],
+            10,
+            4,
+        );
+        assert_eq!(
+            priorities,
+            vec![Priority::Skip, Priority::Low, Priority::Low, Priority::High]
+        );
+
+        let mut picker = PiecePicker::new(4);
+        picker.set_priorities(&priorities);
+        // Commonness doesn't matter across priorities.
+        picker.peer_has_bitfield(&bitfield(4, &[3]));
+        picker.peer_has_bitfield(&bitfield(4, &[3]));
+
+        let all = Bitfield::full(4);
+        let mut order = Vec::new();
+        while let Some(i) = picker.pick(&all, |i| order.contains(&i)) {
+            order.push(i);
+        }
+        assert_eq!(order[0], 3);
+        assert_eq!(order.len(), 3, "skipped pieces are never picked");
+    }
+
+    #[test]
+    fn losing_peers_lowers_availability() {
+        let mut picker = PiecePicker::new(2);
+        let peer = bitfield(2, &[1]);
+        picker.peer_has_bitfield(&peer);
+        picker.peer_has_all();
+        picker.peer_lost_bitfield(&peer);
+        picker.peer_lost_all();
+        assert_eq!(picker.availability(1), 0);
+    }
+
+    #[test]
+    fn handles_many_pieces() {
+        let pieces = 200_000;
+        let started = Instant::now();
+        let mut picker = PiecePicker::new(pieces);
+        let mut rng = rand::thread_rng();
+        for _ in 0..20 {
+            let mut peer = Bitfield::new(pieces);
+            for _ in 0..pieces / 2 {
+                peer.set(rand::Rng::gen_range(&mut rng, 0..pieces));
+            }
+            picker.peer_has_bitfield(&peer);
+        }
+        picker.peer_has_all();
+        let all = Bitfield::full(pieces);
+        for _ in 0..10_000 {
+            let index = picker.pick(&all, |_| false).unwrap();
+            picker.complete(index);
+        }
+        assert!(started.elapsed().as_secs() < 10, "{:?}", started.elapsed());
+    }
+}
@@ -0,0 +1,178 @@
+//! Checking finished pieces against the hashes in the metainfo, and holding
+//! the peers that sent bad data to account.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::IpAddr;
+
+use log::{info, warn};
+use sha1::{Digest, Sha1};
+
+use super::download::{Downloader, PeerKey};
+use crate::peer::ban::BanList;
+use crate::torrent::Info;
+
+/// A peer that helped with this many bad pieces is banned, even if others
+/// helped too.
+const MAX_STRIKES: u32 = 3;
+
+/// Whether `data` hashes to `expected`. Hashing runs on the blocking pool so
+/// it doesn't hold up other connections.
+pub(crate) async fn hash_matches(data: Vec<u8>, expected: [u8; 20]) -> (Vec<u8>, bool) {
+    tokio::task::spawn_blocking(move || {
+        let matches = Sha1::digest(&data)[..] == expected;
+        (data, matches)
+    })
+    .await
+    .expect("hashing a piece panicked")
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct VerifyStats {
+    pub(crate) pieces_verified: u64,
+    pub(crate) hash_failures: u64,
+    pub(crate) peers_banned: u64,
+}
+
+pub(crate) struct Verifier {
+    hashes: Vec<[u8; 20]>,
+    strikes: HashMap<IpAddr, u32>,
+    bans: BanList,
+    stats: VerifyStats,
+}
+
+impl Verifier {
+    pub(crate) fn new(info: &Info, bans: BanList) -> Self {
+        Self {
+            hashes: (0..info.piece_count())
+                .filter_map(|i| info.piece_hash(i))
+                .collect(),
+            strikes: HashMap::new(),
+            bans,
+            stats: VerifyStats::default(),
+        }
+    }
+
+    pub(crate) fn stats(&self) -> VerifyStats {
+        self.stats
+    }
+
+    /// Hashes a piece [`Downloader::received`] handed out, returning its data
+    /// and whether it's good. The future doesn't borrow the verifier, so it
+    /// needn't stay locked while the piece is hashed.
+    pub(crate) fn check(
+        &self,
+        index: u32,
+        data: Vec<u8>,
+    ) -> impl Future<Output = (Vec<u8>, bool)> + 'static {
+        let expected = self.hashes.get(index as usize).copied();
+        async move {
+            match expected {
+                Some(expected) => hash_matches(data, expected).await,
+                None => (data, false),
+            }
+        }
+    }
+
+    pub(crate) fn passed(&mut self, downloader: &mut Downloader, index: u32) {
+        downloader.verified(index);
+        self.stats.pieces_verified += 1;
+    }
+
+    /// Puts a bad piece back to be downloaded again and penalizes whoever
+    /// sent it: a sole source is certainly to blame and takes all
+    /// [`MAX_STRIKES`] at once, while each of several sources gets one strike.
+    /// Returns the peers banned.
+    pub(crate) fn failed(&mut self, downloader: &mut Downloader, index: u32) -> Vec<IpAddr> {
+        let peers = downloader.failed(index);
+        warn!("Piece {index} failed its hash check");
+        self.penalize(&peers)
+    }
+
+    fn penalize(&mut self, peers: &[PeerKey]) -> Vec<IpAddr> {
+        let mut ips: Vec<_> = peers.iter().map(|peer| peer.ip()).collect();
+        ips.sort();
+        ips.dedup();
+        self.stats.hash_failures += 1;
+
+        let penalty = match ips.len() {
+            1 => MAX_STRIKES,
+            _ => 1,
+        };
+        let mut banned = Vec::new();
+        for ip in ips {
+            let strikes = self.strikes.entry(ip).or_default();
+            *strikes += penalty;
+            if *strikes >= MAX_STRIKES && self.bans.ban(ip) {
+                info!("Banning {ip} after {strikes} bad pieces");
+                self.stats.peers_banned += 1;
+                banned.push(ip);
+            }
+        }
+        banned
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use sha1::{Digest, Sha1};
+
+    use super::{hash_matches, Verifier, VerifyStats, MAX_STRIKES};
+    use crate::peer::ban::BanList;
+    use crate::piece::download::PeerKey;
+
+    fn peer(n: u8) -> PeerKey {
+        PeerKey::from(([10, 0, 0, n], 6881))
+    }
+
+    fn verifier(bans: BanList) -> Verifier {
+        Verifier {
+            hashes: Vec::new(),
+            strikes: HashMap::new(),
+            bans,
+            stats: VerifyStats::default(),
+        }
+    }
+
+    #[tokio::test]
+    async fn hashes_off_the_runtime() {
+        let data = b"piece data".to_vec();
+        let expected = Sha1::digest(&data).into();
+        assert!(hash_matches(data.clone(), expected).await.1);
+        assert!(!hash_matches(b"bad data".to_vec(), expected).await.1);
+    }
+
+    #[test]
+    fn bans_sole_sources_outright() {
+        let bans = BanList::default();
+        let mut verifier = verifier(bans.clone());
+
+        // Two ports on one address are still a single source.
+        let alone = PeerKey::from(([10, 0, 0, 9], 6882));
+        assert_eq!(verifier.penalize(&[peer(9), alone]), vec![alone.ip()]);
+        assert!(bans.is_banned(&alone.ip()));
+        assert_eq!(verifier.stats().peers_banned, 1);
+    }
+
+    #[test]
+    fn bans_repeat_offenders() {
+        let bans = BanList::default();
+        let mut verifier = verifier(bans.clone());
+
+        for _ in 1..MAX_STRIKES {
+            assert!(verifier.penalize(&[peer(1), peer(2)]).is_empty());
+        }
+        assert_eq!(verifier.penalize(&[peer(1), peer(3)]), vec![peer(1).ip()]);
+        assert!(!bans.is_banned(&peer(3).ip()));
+        assert_eq!(
+            verifier.stats(),
+            VerifyStats {
+                pieces_verified: 0,
+                hash_failures: MAX_STRIKES as u64,
+                peers_banned: 1,
+            }
+        );
+    }
+}
@@ -0,0 +1,272 @@
+//! Fake NAT gateways for tests, listening on ephemeral loopback ports: one
+//! answering PCP (or only NAT-PMP), and a UPnP one with an SSDP responder
+//! and an HTTP server for its description and control URL.
+
+use std::convert::Infallible;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use deku::prelude::*;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response, Server, StatusCode};
+use tokio::net::UdpSocket;
+use tokio::task::JoinHandle;
+
+use super::pcp::{
+    NatPmpAddressResponse, NatPmpMapRequest, PcpRequest, NATPMP_VERSION, PCP_VERSION,
+};
+use super::upnp::tag;
+
+/// The public address the fake gateways claim.
+pub(crate) const MOCK_EXTERNAL_IP: Ipv4Addr = Ipv4Addr::new(203, 0, 113, 7);
+/// Added to the internal port to give the external one.
+const PORT_OFFSET: u16 = 10000;
+
+/// A fake PCP gateway. Maps every port to itself plus 10000.
+pub(crate) struct MockPcpGateway {
+    addr: SocketAddr,
+    lifetimes: Arc<Mutex<Vec<u32>>>,
+    refusing: Arc<AtomicBool>,
+    task: JoinHandle<()>,
+}
+
+impl MockPcpGateway {
+    /// A gateway speaking PCP, or only NAT-PMP if `natpmp`.
+    pub(crate) async fn start(natpmp: bool) -> Self {
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        let lifetimes: Arc<Mutex<Vec<u32>>> = Arc::default();
+        let refusing: Arc<AtomicBool> = Arc::default();
+
+        let (recorded, refuse) = (lifetimes.clone(), refusing.clone());
+        let task = tokio::spawn(async move {
+            let mut buf = [0; 1100];
+            while let Ok((len, from)) = socket.recv_from(&mut buf).await {
+                let request = &buf[..len];
+                let response = match (request[0], natpmp) {
+                    (PCP_VERSION, false) => {
+                        let (_, request) = PcpRequest::from_bytes((request, 0)).unwrap();
+                        recorded.lock().unwrap().push(request.lifetime());
+                        let external = SocketAddr::from((
+                            MOCK_EXTERNAL_IP,
+                            request.internal_port() + PORT_OFFSET,
+                        ));
+                        let mut response = request.granted(external).to_bytes().unwrap();
+                        if refuse.load(Ordering::Relaxed) {
+                            // NOT_AUTHORIZED
+                            response[3] = 2;
+                        }
+                        response
+                    }
+                    // UNSUPP_VERSION, in NAT-PMP's format.
+                    (PCP_VERSION, true) => {
+                        vec![NATPMP_VERSION, request[1] | 0x80, 0, 1, 0, 0, 0, 0]
+                    }
+                    (NATPMP_VERSION, _) if request[1] == 0 => {
+                        NatPmpAddressResponse::new(MOCK_EXTERNAL_IP)
+                            .to_bytes()
+                            .unwrap()
+                    }
+                    (NATPMP_VERSION, _) => {
+                        let (_, request) = NatPmpMapRequest::from_bytes((request, 0)).unwrap();
+                        recorded.lock().unwrap().push(request.lifetime());
+                        let external = request.internal_port() + PORT_OFFSET;
+                        request.granted(external).to_bytes().unwrap()
+                    }
+                    _ => continue,
+                };
+                let _ = socket.send_to(&response, from).await;
+            }
+        });
+        Self {
+            addr,
+            lifetimes,
+            refusing,
+            task,
+        }
+    }
+
+    pub(crate) fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// The lifetime asked for in each map request so far, in seconds.
+    pub(crate) fn lifetimes(&self) -> Vec<u32> {
+        self.lifetimes.lock().unwrap().clone()
+    }
+
+    /// Refuses every PCP request from now on.
+    pub(crate) fn refuse(&self) {
+        self.refusing.store(true, Ordering::Relaxed);
+    }
+}
+
+impl Drop for MockPcpGateway {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// A fake UPnP gateway. Maps ports to the same port outside.
+pub(crate) struct MockUpnpGateway {
+    ssdp_addr: SocketAddr,
+    actions: Arc<Mutex<Vec<String>>>,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl MockUpnpGateway {
+    /// A gateway that refuses leased mappings if `permanent_only`, as some
+    /// older devices do.
+    pub(crate) async fn start(permanent_only: bool) -> Self {
+        let actions: Arc<Mutex<Vec<String>>> = Arc::default();
+        let listener = std::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let http_addr = listener.local_addr().unwrap();
+
+        let recorded = actions.clone();
+        let make_svc = make_service_fn(move |_| {
+            let actions = recorded.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req| {
+                    let actions = actions.clone();
+                    async move {
+                        Ok::<_, Infallible>(Self::respond(&actions, permanent_only, req).await)
+                    }
+                }))
+            }
+        });
+        let server = Server::from_tcp(listener).unwrap().serve(make_svc);
+        let http = tokio::spawn(async move {
+            let _ = server.await;
+        });
+
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let ssdp_addr = socket.local_addr().unwrap();
+        let ssdp = tokio::spawn(async move {
+            let mut buf = [0; 1500];
+            while let Ok((len, from)) = socket.recv_from(&mut buf).await {
+                if !buf[..len].starts_with(b"M-SEARCH") {
+                    continue;
+                }
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\nCACHE-CONTROL: max-age=120\r\n\
+                     ST: urn:schemas-upnp-org:device:InternetGatewayDevice:1\r\n\
+                     Location: http://{http_addr}/rootDesc.xml\r\n\r\n"
+                );
+                let _ = socket.send_to(response.as_bytes(), from).await;
+            }
+        });
+        Self {
+            ssdp_addr,
+            actions,
+            tasks: vec![http, ssdp],
+        }
+    }
+
+    /// Where discovery should search instead of the multicast group.
+    pub(crate) fn ssdp_addr(&self) -> SocketAddr {
+        self.ssdp_addr
+    }
+
+    /// Each SOAP action called so far, with the arguments that matter.
+    pub(crate) fn actions(&self) -> Vec<String> {
+        self.actions.lock().unwrap().clone()
+    }
+
+    async fn respond(
+        actions: &Mutex<Vec<String>>,
+        permanent_only: bool,
+        req: Request<Body>,
+    ) -> Response<Body> {
+        if req.uri().path() == "/rootDesc.xml" {
+            return Response::new(Body::from(DESCRIPTION));
+        }
+        let action = req
+            .headers()
+            .get("SOAPAction")
+            .and_then(|action| action.to_str().ok())
+            .and_then(|action| action.trim_matches('"').split_once('#'))
+            .map(|(_, action)| action.to_string())
+            .unwrap_or_default();
+        let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+        let body = String::from_utf8_lossy(&body);
+        let arg = |name| tag(&body, name).unwrap_or_default();
+        let (recorded, fault) = match action.as_str() {
+            "AddPortMapping" => (
+                format!(
+                    "AddPortMapping {} {} to {}:{} for {}",
+                    arg("NewProtocol"),
+                    arg("NewExternalPort"),
+                    arg("NewInternalClient"),
+                    arg("NewInternalPort"),
+                    arg("NewLeaseDuration")
+                ),
+                permanent_only && arg("NewLeaseDuration") != "0",
+            ),
+            "DeletePortMapping" => (
+                format!(
+                    "DeletePortMapping {} {}",
+                    arg("NewProtocol"),
+                    arg("NewExternalPort")
+                ),
+                false,
+            ),
+            action => (action.to_string(), false),
+        };
+        actions.lock().unwrap().push(recorded);
+
+        if fault {
+            return Response::builder()
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .body(Body::from(
+                    "<s:Envelope><s:Body><s:Fault><detail><UPnPError>\
+                     <errorCode>725</errorCode>\
+                     <errorDescription>OnlyPermanentLeasesSupported</errorDescription>\
+                     </UPnPError></detail></s:Fault></s:Body></s:Envelope>",
+                ))
+                .unwrap();
+        }
+        let result = match action.as_str() {
+            "GetExternalIPAddress" => {
+                format!("<NewExternalIPAddress>{MOCK_EXTERNAL_IP}</NewExternalIPAddress>")
+            }
+            _ => String::new(),
+        };
+        Response::new(Body::from(format!(
+            "<s:Envelope><s:Body><u:{action}Response \
+             xmlns:u=\"urn:schemas-upnp-org:service:WANIPConnection:1\">{result}\
+             </u:{action}Response></s:Body></s:Envelope>"
+        )))
+    }
+}
+
+impl Drop for MockUpnpGateway {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
+
+/// A cut-down router description, with the WAN connection service nested
+/// a couple of devices deep as real ones have it.
+const DESCRIPTION: &str = r#"<?xml version="1.0"?>
+<root xmlns="urn:schemas-upnp-org:device-1-0">
+<device>
+<deviceType>urn:schemas-upnp-org:device:InternetGatewayDevice:1</deviceType>
+<serviceList><service>
+<serviceType>urn:schemas-upnp-org:service:Layer3Forwarding:1</serviceType>
+<controlURL>/ctl/L3F</controlURL>
+</service></serviceList>
+<deviceList><device>
+<deviceType>urn:schemas-upnp-org:device:WANDevice:1</deviceType>
+<deviceList><device>
+<deviceType>urn:schemas-upnp-org:device:WANConnectionDevice:1</deviceType>
+<serviceList><service>
+<serviceType>urn:schemas-upnp-org:service:WANIPConnection:1</serviceType>
+<controlURL>/ctl/IPConn</controlURL>
+</service></serviceList>
+</device></deviceList>
+</device></deviceList>
+</device>
+</root>"#;
@@ -0,0 +1,416 @@
+//! Opens our listen port on the NAT gateway so peers outside can connect
+//! in. Gateways speaking PCP or NAT-PMP are tried first, then any UPnP
+//! Internet Gateway Devices answering on the LAN. Mappings are leased, so
+//! they're renewed halfway through each lease and removed on shutdown.
+
+#[cfg(test)]
+pub(crate) mod mock;
+pub(crate) mod pcp;
+pub(crate) mod upnp;
+
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::Duration;
+
+use log::{debug, info, warn};
+use serde::Deserialize;
+use snafu::prelude::*;
+use tokio::sync::{oneshot, watch};
+use tokio::task::JoinHandle;
+
+use self::pcp::PcpClient;
+use self::upnp::Device;
+use crate::external_ip::{ExternalIp, Voter};
+
+/// How long to wait before trying again when no gateway would map the port.
+const RETRY_INTERVAL: Duration = Duration::from_secs(5 * 60);
+/// Mappings with no expiry are still checked on this often, in case the
+/// gateway rebooted and forgot them.
+const PERMANENT_RENEWAL: Duration = Duration::from_secs(30 * 60);
+
+#[derive(Debug, Snafu)]
+pub(crate) enum PortMapError {
+    #[snafu(display("gateway request failed: {source}"))]
+    Io { source: std::io::Error },
+    #[snafu(display("UPnP request failed: {source}"))]
+    Http { source: reqwest::Error },
+    #[snafu(display("gateway timed out"))]
+    Timeout,
+    #[snafu(display("gateway refused the mapping: {reason}"))]
+    Refused { reason: String },
+    #[snafu(display("malformed gateway response: {message}"))]
+    Malformed { message: String },
+    #[snafu(display("no gateway would map the port"))]
+    NoGateway,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub(crate) struct PortMapConfig {
+    #[serde(default = "default_enabled")]
+    pub(crate) enabled: bool,
+    /// The PCP/NAT-PMP gateway, if not the default route.
+    #[serde(default)]
+    pub(crate) gateway: Option<IpAddr>,
+    /// Seconds each mapping is asked for.
+    #[serde(default = "default_lease")]
+    pub(crate) lease: u64,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+fn default_lease() -> u64 {
+    2 * 60 * 60
+}
+
+impl Default for PortMapConfig {
+    fn default() -> Self {
+        Self {
+            enabled: default_enabled(),
+            gateway: None,
+            lease: default_lease(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Protocol {
+    Tcp,
+    Udp,
+}
+
+impl Protocol {
+    /// The IP protocol number.
+    pub(crate) fn number(&self) -> u8 {
+        match self {
+            Self::Tcp => 6,
+            Self::Udp => 17,
+        }
+    }
+}
+
+/// A port the gateway forwards to us.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Mapping {
+    pub(crate) protocol: Protocol,
+    pub(crate) internal_port: u16,
+    pub(crate) external_port: u16,
+    /// The gateway's public address, if it told us.
+    pub(crate) external_ip: Option<IpAddr>,
+    /// How long until the gateway drops the mapping; zero if never.
+    pub(crate) lifetime: Duration,
+}
+
+#[derive(Debug)]
+pub(crate) enum Gateway {
+    Pcp(PcpClient),
+    Upnp(Device),
+}
+
+impl Gateway {
+    /// Maps `internal_port`, asking for the same port outside.
+    async fn map(
+        &self,
+        protocol: Protocol,
+        internal_port: u16,
+        external_port: u16,
+        lease: Duration,
+    ) -> Result<Mapping, PortMapError> {
+        match self {
+            Self::Pcp(client) => {
+                client
+                    .map(protocol, internal_port, external_port, lease)
+                    .await
+            }
+            Self::Upnp(device) => {
+                device
+                    .map(protocol, internal_port, external_port, lease)
+                    .await
+            }
+        }
+    }
+
+    async fn unmap(&self, mapping: &Mapping) -> Result<(), PortMapError> {
+        match self {
+            Self::Pcp(client) => client
+                .map(
+                    mapping.protocol,
+                    mapping.internal_port,
+                    mapping.external_port,
+                    Duration::ZERO,
+                )
+                .await
+                .map(|_| ()),
+            Self::Upnp(device) => device.unmap(mapping).await,
+        }
+    }
+
+    fn addr(&self) -> IpAddr {
+        match self {
+            Self::Pcp(client) => client.addr().ip(),
+            Self::Upnp(device) => device.addr(),
+        }
+    }
+}
+
+/// The gateways that might map ports for us: the configured or default
+/// one over PCP, then whichever UPnP devices answer discovery.
+pub(crate) async fn gateways(config: &PortMapConfig) -> Vec<Gateway> {
+    let mut gateways = Vec::new();
+    if let Some(gateway) = config.gateway.or_else(|| default_gateway().map(IpAddr::V4)) {
+        gateways.push(Gateway::Pcp(PcpClient::new(SocketAddr::new(
+            gateway,
+            pcp::PORT,
+        ))));
+    }
+    match upnp::discover(upnp::SSDP_GROUP, upnp::DISCOVERY_WAIT).await {
+        Ok(devices) => gateways.extend(devices.into_iter().map(Gateway::Upnp)),
+        Err(e) => debug!("UPnP discovery failed: {e}"),
+    }
+    gateways
+}
+
+/// The IPv4 default route's gateway, from the kernel's routing table.
+fn default_gateway() -> Option<Ipv4Addr> {
+    let routes = fs::read_to_string("/proc/net/route").ok()?;
+    routes.lines().skip(1).find_map(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        match fields[..] {
+            [_, "00000000", gateway, ..] => {
+                // Written as a native-endian number in hex.
+                let gateway = u32::from_str_radix(gateway, 16).ok()?;
+                Some(Ipv4Addr::from(gateway.to_ne_bytes())).filter(|ip| !ip.is_unspecified())
+            }
+            _ => None,
+        }
+    })
+}
+
+/// Keeps our listen port mapped, for both TCP and uTP, on the first
+/// gateway that will map it.
+pub(crate) struct PortMapper {
+    external_port: watch::Receiver<Option<u16>>,
+    stop: oneshot::Sender<()>,
+    task: JoinHandle<()>,
+}
+
+impl PortMapper {
+    /// Maps `port` before returning, so the external port is known for the
+    /// first announce, then keeps the mapping alive in the background.
+    pub(crate) async fn start(
+        gateways: Vec<Gateway>,
+        port: u16,
+        lease: Duration,
+        external_ip: ExternalIp,
+    ) -> Self {
+        let (sender, external_port) = watch::channel(None);
+        let (stop, stopped) = oneshot::channel();
+        let mut state = State {
+            gateways,
+            port,
+            lease,
+            external_ip,
+            external_port: sender,
+            mapped: None,
+        };
+        let wait = state.refresh().await;
+        let task = tokio::spawn(state.run(wait, stopped));
+        Self {
+            external_port,
+            stop,
+            task,
+        }
+    }
+
+    /// The port peers outside the NAT should connect to, if it's mapped.
+    pub(crate) fn external_port(&self) -> Option<u16> {
+        *self.external_port.borrow()
+    }
+
+    /// Stops renewing and removes the mappings from the gateway.
+    pub(crate) async fn shutdown(self) {
+        let _ = self.stop.send(());
+        let _ = self.task.await;
+    }
+}
+
+struct State {
+    gateways: Vec<Gateway>,
+    port: u16,
+    lease: Duration,
+    external_ip: ExternalIp,
+    external_port: watch::Sender<Option<u16>>,
+    /// The gateway holding our mappings, by index, and the mappings.
+    mapped: Option<(usize, Vec<Mapping>)>,
+}
+
+impl State {
+    async fn run(mut self, mut wait: Duration, mut stopped: oneshot::Receiver<()>) {
+        loop {
+            tokio::select! {
+                _ = tokio::time::sleep(wait) => wait = self.refresh().await,
+                _ = &mut stopped => break,
+            }
+        }
+        if let Some((index, mappings)) = self.mapped.take() {
+            for mapping in &mappings {
+                if let Err(e) = self.gateways[index].unmap(mapping).await {
+                    debug!("Couldn't remove {mapping:?}: {e}");
+                }
+            }
+        }
+    }
+
+    /// Maps or renews the port, and says how long until it should be done
+    /// again.
+    async fn refresh(&mut self) -> Duration {
+        // Stick with the gateway that worked, then try the rest in order.
+        let first = self.mapped.as_ref().map_or(0, |(index, _)| *index);
+        let order = (first..self.gateways.len()).chain(0..first);
+        for index in order {
+            match self.map(index).await {
+                Ok(mappings) => {
+                    let external_port = mappings[0].external_port;
+                    if self.external_port.send_replace(Some(external_port)) != Some(external_port) {
+                        info!("Port {} is mapped to {external_port} outside", self.port);
+                    }
+                    for ip in mappings.iter().filter_map(|m| m.external_ip) {
+                        self.external_ip
+                            .vote(Voter::Gateway(self.gateways[index].addr()), ip);
+                    }
+                    let wait = mappings
+                        .iter()
+                        .map(|m| match m.lifetime.is_zero() {
+                            true => PERMANENT_RENEWAL,
+                            false => m.lifetime / 2,
+                        })
+                        .min()
+                        .unwrap_or(RETRY_INTERVAL);
+                    self.mapped = Some((index, mappings));
+                    return wait;
+                }
+                Err(e) => debug!(
+                    "Gateway {} couldn't map port {}: {e}",
+                    self.gateways[index].addr(),
+                    self.port
+                ),
+            }
+        }
+        if !self.gateways.is_empty() {
+            warn!("{}", PortMapError::NoGateway);
+        }
+        self.mapped = None;
+        self.external_port.send_replace(None);
+        RETRY_INTERVAL
+    }
+
+    /// Maps TCP and then UDP on the same external port, so peers can reach
+    /// us over either.
+    async fn map(&self, index: usize) -> Result<Vec<Mapping>, PortMapError> {
+        let gateway = &self.gateways[index];
+        let wanted = match &self.mapped {
+            Some((_, mappings)) => mappings[0].external_port,
+            None => self.port,
+        };
+        let tcp = gateway
+            .map(Protocol::Tcp, self.port, wanted, self.lease)
+            .await?;
+        let udp = gateway
+            .map(Protocol::Udp, self.port, tcp.external_port, self.lease)
+            .await?;
+        if udp.external_port != tcp.external_port {
+            debug!(
+                "Gateway {} mapped UDP to {} rather than {}",
+                gateway.addr(),
+                udp.external_port,
+                tcp.external_port
+            );
+        }
+        Ok(vec![tcp, udp])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::mock::{MockPcpGateway, MockUpnpGateway, MOCK_EXTERNAL_IP};
+    use super::pcp::PcpClient;
+    use super::{upnp, Gateway, PortMapper};
+    use crate::external_ip::{ExternalIp, Voter};
+
+    /// An estimate that a voter on another network already backs the mock
+    /// gateway's answer in, so the gateway's vote settles it.
+    fn external_ip() -> ExternalIp {
+        let external_ip = ExternalIp::default();
+        external_ip.vote(
+            Voter::Node("192.0.2.1".parse().unwrap()),
+            MOCK_EXTERNAL_IP.into(),
+        );
+        external_ip
+    }
+
+    #[tokio::test]
+    async fn maps_renews_and_removes_the_port() {
+        let gateway = MockPcpGateway::start(false).await;
+        let external_ip = external_ip();
+        let mapper = PortMapper::start(
+            vec![Gateway::Pcp(PcpClient::new(gateway.addr()))],
+            6881,
+            Duration::from_secs(1),
+            external_ip.clone(),
+        )
+        .await;
+        assert_eq!(mapper.external_port(), Some(16881));
+        assert_eq!(external_ip.ipv4(), Some(MOCK_EXTERNAL_IP));
+        assert_eq!(gateway.lifetimes(), vec![1, 1]);
+
+        // Renewed halfway through the lease.
+        tokio::time::sleep(Duration::from_millis(700)).await;
+        assert_eq!(gateway.lifetimes(), vec![1, 1, 1, 1]);
+
+        mapper.shutdown().await;
+        assert_eq!(gateway.lifetimes(), vec![1, 1, 1, 1, 0, 0]);
+    }
+
+    #[tokio::test]
+    async fn falls_back_to_upnp() {
+        // Nothing answers PCP here.
+        let silent = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let upnp = MockUpnpGateway::start(true).await;
+        let devices = upnp::discover(upnp.ssdp_addr(), Duration::from_millis(200))
+            .await
+            .unwrap();
+        assert_eq!(devices.len(), 1);
+
+        let mut gateways = vec![Gateway::Pcp(PcpClient::new(silent.local_addr().unwrap()))];
+        gateways.extend(devices.into_iter().map(Gateway::Upnp));
+        let external_ip = external_ip();
+        let mapper = PortMapper::start(
+            gateways,
+            6881,
+            Duration::from_secs(7200),
+            external_ip.clone(),
+        )
+        .await;
+        assert_eq!(mapper.external_port(), Some(6881));
+        assert_eq!(external_ip.ipv4(), Some(MOCK_EXTERNAL_IP));
+
+        mapper.shutdown().await;
+        assert_eq!(
+            upnp.actions(),
+            vec![
+                // Only permanent mappings, so it asks again without a lease.
+                "AddPortMapping TCP 6881 to 127.0.0.1:6881 for 7200",
+                "AddPortMapping TCP 6881 to 127.0.0.1:6881 for 0",
+                "GetExternalIPAddress",
+                "AddPortMapping UDP 6881 to 127.0.0.1:6881 for 7200",
+                "AddPortMapping UDP 6881 to 127.0.0.1:6881 for 0",
+                "GetExternalIPAddress",
+                "DeletePortMapping TCP 6881",
+                "DeletePortMapping UDP 6881",
+            ]
+        );
+    }
+}
@@ -0,0 +1,447 @@
+//! PCP, and NAT-PMP which it grew out of: one UDP request to the gateway
+//! maps a port, and the answer says which external port we got and for how
+//! long. Both share the gateway's port 5351, and a NAT-PMP-only gateway
+//! answers PCP requests with version 0, so we ask in PCP first and fall
+//! back.
+//! See: https://www.rfc-editor.org/rfc/rfc6887 and
+//! https://www.rfc-editor.org/rfc/rfc6886
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use deku::prelude::*;
+use log::debug;
+use snafu::prelude::*;
+use tokio::net::UdpSocket;
+
+use super::{IoSnafu, Mapping, PortMapError, Protocol, RefusedSnafu, TimeoutSnafu};
+
+/// The port gateways listen on.
+pub(crate) const PORT: u16 = 5351;
+pub(crate) const PCP_VERSION: u8 = 2;
+pub(crate) const NATPMP_VERSION: u8 = 0;
+const MAP_OPCODE: u8 = 1;
+/// Set on the opcode of responses.
+const RESPONSE: u8 = 0x80;
+/// The first wait for an answer, doubled on each retry.
+const INITIAL_TIMEOUT: Duration = Duration::from_millis(250);
+const RETRIES: u32 = 3;
+
+#[derive(Debug, Clone, PartialEq, Eq, DekuRead, DekuWrite)]
+#[deku(endian = "big")]
+pub(crate) struct PcpRequest {
+    #[deku(assert_eq = "PCP_VERSION")]
+    version: u8,
+    opcode: u8,
+    reserved: u16,
+    lifetime: u32,
+    /// Our address, IPv4 ones mapped into IPv6.
+    client_ip: [u8; 16],
+    map: MapData,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, DekuRead, DekuWrite)]
+#[deku(endian = "big")]
+pub(crate) struct PcpResponse {
+    #[deku(assert_eq = "PCP_VERSION")]
+    version: u8,
+    opcode: u8,
+    reserved: u8,
+    result: u8,
+    lifetime: u32,
+    epoch: u32,
+    reserved_2: [u8; 12],
+    map: MapData,
+}
+
+/// The MAP opcode's part of requests and responses: what to map, and what
+/// was mapped.
+#[derive(Debug, Clone, PartialEq, Eq, DekuRead, DekuWrite)]
+#[deku(endian = "endian", ctx = "endian: deku::ctx::Endian")]
+pub(crate) struct MapData {
+    nonce: [u8; 12],
+    protocol: u8,
+    reserved: [u8; 3],
+    internal_port: u16,
+    external_port: u16,
+    external_ip: [u8; 16],
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, DekuRead, DekuWrite)]
+#[deku(endian = "big")]
+pub(crate) struct NatPmpMapRequest {
+    #[deku(assert_eq = "NATPMP_VERSION")]
+    version: u8,
+    /// 1 for UDP, 2 for TCP.
+    opcode: u8,
+    reserved: u16,
+    internal_port: u16,
+    external_port: u16,
+    lifetime: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, DekuRead, DekuWrite)]
+#[deku(endian = "big")]
+pub(crate) struct NatPmpMapResponse {
+    #[deku(assert_eq = "NATPMP_VERSION")]
+    version: u8,
+    opcode: u8,
+    result: u16,
+    epoch: u32,
+    internal_port: u16,
+    external_port: u16,
+    lifetime: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, DekuRead, DekuWrite)]
+#[deku(endian = "big")]
+pub(crate) struct NatPmpAddressResponse {
+    #[deku(assert_eq = "NATPMP_VERSION")]
+    version: u8,
+    opcode: u8,
+    result: u16,
+    epoch: u32,
+    ip: [u8; 4],
+}
+
+impl PcpRequest {
+    pub(crate) fn map(
+        client_ip: IpAddr,
+        nonce: [u8; 12],
+        protocol: Protocol,
+        internal_port: u16,
+        external_port: u16,
+        lifetime: Duration,
+    ) -> Self {
+        Self {
+            version: PCP_VERSION,
+            opcode: MAP_OPCODE,
+            reserved: 0,
+            lifetime: lifetime.as_secs().try_into().unwrap_or(u32::MAX),
+            client_ip: mapped(client_ip),
+            map: MapData {
+                nonce,
+                protocol: protocol.number(),
+                reserved: [0; 3],
+                internal_port,
+                external_port,
+                external_ip: [0; 16],
+            },
+        }
+    }
+
+    #[cfg(test)]
+    pub(crate) fn lifetime(&self) -> u32 {
+        self.lifetime
+    }
+
+    #[cfg(test)]
+    pub(crate) fn internal_port(&self) -> u16 {
+        self.map.internal_port
+    }
+
+    /// The answer a gateway gives when it maps `external` for as long as
+    /// was asked.
+    #[cfg(test)]
+    pub(crate) fn granted(&self, external: SocketAddr) -> PcpResponse {
+        PcpResponse {
+            version: PCP_VERSION,
+            opcode: self.opcode | RESPONSE,
+            reserved: 0,
+            result: 0,
+            lifetime: self.lifetime,
+            epoch: 0,
+            reserved_2: [0; 12],
+            map: MapData {
+                external_port: external.port(),
+                external_ip: mapped(external.ip()),
+                ..self.map.clone()
+            },
+        }
+    }
+}
+
+impl NatPmpMapRequest {
+    pub(crate) fn new(
+        protocol: Protocol,
+        internal_port: u16,
+        external_port: u16,
+        lifetime: Duration,
+    ) -> Self {
+        Self {
+            version: NATPMP_VERSION,
+            opcode: match protocol {
+                Protocol::Udp => 1,
+                Protocol::Tcp => 2,
+            },
+            reserved: 0,
+            internal_port,
+            external_port,
+            lifetime: lifetime.as_secs().try_into().unwrap_or(u32::MAX),
+        }
+    }
+
+    #[cfg(test)]
+    pub(crate) fn lifetime(&self) -> u32 {
+        self.lifetime
+    }
+
+    #[cfg(test)]
+    pub(crate) fn internal_port(&self) -> u16 {
+        self.internal_port
+    }
+
+    #[cfg(test)]
+    pub(crate) fn granted(&self, external_port: u16) -> NatPmpMapResponse {
+        NatPmpMapResponse {
+            version: NATPMP_VERSION,
+            opcode: self.opcode | RESPONSE,
+            result: 0,
+            epoch: 0,
+            internal_port: self.internal_port,
+            external_port,
+            lifetime: self.lifetime,
+        }
+    }
+}
+
+impl NatPmpAddressResponse {
+    #[cfg(test)]
+    pub(crate) fn new(ip: Ipv4Addr) -> Self {
+        Self {
+            version: NATPMP_VERSION,
+            opcode: RESPONSE,
+            result: 0,
+            epoch: 0,
+            ip: ip.octets(),
+        }
+    }
+}
+
+/// `ip` as PCP carries addresses, with IPv4 ones mapped into IPv6.
+fn mapped(ip: IpAddr) -> [u8; 16] {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped().octets(),
+        IpAddr::V6(ip) => ip.octets(),
+    }
+}
+
+fn unmapped(bytes: [u8; 16]) -> Option<IpAddr> {
+    let ip = Ipv6Addr::from(bytes);
+    let ip = ip.to_ipv4_mapped().map_or(IpAddr::V6(ip), IpAddr::V4);
+    (!ip.is_unspecified()).then_some(ip)
+}
+
+fn malformed(e: DekuError) -> PortMapError {
+    PortMapError::Malformed {
+        message: e.to_string(),
+    }
+}
+
+/// A gateway spoken to over PCP, or over NAT-PMP once it turns out not to
+/// know PCP.
+#[derive(Debug)]
+pub(crate) struct PcpClient {
+    gateway: SocketAddr,
+    /// Identifies our mappings to the gateway, so renewing or removing one
+    /// is accepted as coming from us.
+    nonce: [u8; 12],
+    natpmp: AtomicBool,
+}
+
+impl PcpClient {
+    pub(crate) fn new(gateway: SocketAddr) -> Self {
+        Self {
+            gateway,
+            nonce: rand::random(),
+            natpmp: AtomicBool::new(false),
+        }
+    }
+
+    pub(crate) fn addr(&self) -> SocketAddr {
+        self.gateway
+    }
+
+    /// Maps `internal_port` for `lifetime`, asking for `external_port` if
+    /// not 0. A zero lifetime removes the mapping.
+    pub(crate) async fn map(
+        &self,
+        protocol: Protocol,
+        internal_port: u16,
+        external_port: u16,
+        lifetime: Duration,
+    ) -> Result<Mapping, PortMapError> {
+        let socket = self.connect().await?;
+        if !self.natpmp.load(Ordering::Relaxed) {
+            let client_ip = socket.local_addr().context(IoSnafu)?.ip();
+            let request = PcpRequest::map(
+                client_ip,
+                self.nonce,
+                protocol,
+                internal_port,
+                external_port,
+                lifetime,
+            );
+            let bytes = request.to_bytes().map_err(malformed)?;
+            let response = transact(&socket, &bytes, |response| {
+                response.first() == Some(&NATPMP_VERSION)
+                    || response.get(1) == Some(&(MAP_OPCODE | RESPONSE))
+                        && response.get(24..36) == Some(&self.nonce[..])
+            })
+            .await?;
+            if response[0] == PCP_VERSION {
+                let (_, response) = PcpResponse::from_bytes((&response, 0)).map_err(malformed)?;
+                ensure!(
+                    response.result == 0,
+                    RefusedSnafu {
+                        reason: format!("PCP result code {}", response.result),
+                    }
+                );
+                return Ok(Mapping {
+                    protocol,
+                    internal_port,
+                    external_port: response.map.external_port,
+                    external_ip: unmapped(response.map.external_ip),
+                    lifetime: Duration::from_secs(response.lifetime.into()),
+                });
+            }
+            debug!("Gateway {} only speaks NAT-PMP", self.gateway);
+            self.natpmp.store(true, Ordering::Relaxed);
+        }
+
+        let request = NatPmpMapRequest::new(protocol, internal_port, external_port, lifetime);
+        let bytes = request.to_bytes().map_err(malformed)?;
+        let response = transact(&socket, &bytes, |response| {
+            response.get(1) == Some(&(request.opcode | RESPONSE))
+        })
+        .await?;
+        let (_, response) = NatPmpMapResponse::from_bytes((&response, 0)).map_err(malformed)?;
+        ensure!(
+            response.result == 0,
+            RefusedSnafu {
+                reason: format!("NAT-PMP result code {}", response.result),
+            }
+        );
+        let external_ip = match lifetime.is_zero() {
+            true => None,
+            false => self.natpmp_address(&socket).await.ok(),
+        };
+        Ok(Mapping {
+            protocol,
+            internal_port,
+            external_port: response.external_port,
+            external_ip,
+            lifetime: Duration::from_secs(response.lifetime.into()),
+        })
+    }
+
+    /// NAT-PMP gives the external address separately from mappings.
+    async fn natpmp_address(&self, socket: &UdpSocket) -> Result<IpAddr, PortMapError> {
+        let response = transact(socket, &[NATPMP_VERSION, 0], |response| {
+            response.get(1) == Some(&RESPONSE)
+        })
+        .await?;
+        let (_, response) = NatPmpAddressResponse::from_bytes((&response, 0)).map_err(malformed)?;
+        ensure!(
+            response.result == 0,
+            RefusedSnafu {
+                reason: format!("NAT-PMP result code {}", response.result),
+            }
+        );
+        Ok(IpAddr::V4(response.ip.into()))
+    }
+
+    async fn connect(&self) -> Result<UdpSocket, PortMapError> {
+        let any = match self.gateway {
+            SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+            SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+        };
+        let socket = UdpSocket::bind(any).await.context(IoSnafu)?;
+        socket.connect(self.gateway).await.context(IoSnafu)?;
+        Ok(socket)
+    }
+}
+
+/// Sends `request` until an answer `matches` it, waiting twice as long
+/// each time.
+async fn transact(
+    socket: &UdpSocket,
+    request: &[u8],
+    matches: impl Fn(&[u8]) -> bool,
+) -> Result<Vec<u8>, PortMapError> {
+    let mut buf = vec![0; 1100];
+    for n in 0..RETRIES {
+        socket.send(request).await.context(IoSnafu)?;
+        let deadline = tokio::time::Instant::now() + INITIAL_TIMEOUT * 2_u32.pow(n);
+        while let Ok(received) = tokio::time::timeout_at(deadline, socket.recv(&mut buf)).await {
+            let response = &buf[..received.context(IoSnafu)?];
+            if matches(response) {
+                return Ok(response.to_vec());
+            }
+        }
+    }
+    TimeoutSnafu.fail()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr};
+    use std::time::Duration;
+
+    use deku::prelude::*;
+
+    use super::{PcpClient, PcpRequest};
+    use crate::portmap::mock::{MockPcpGateway, MOCK_EXTERNAL_IP};
+    use crate::portmap::{PortMapError, Protocol};
+
+    #[test]
+    fn writes_map_requests_as_the_rfc_lays_them_out() {
+        let request = PcpRequest::map(
+            IpAddr::V4(Ipv4Addr::new(192, 168, 1, 2)),
+            [7; 12],
+            Protocol::Tcp,
+            6881,
+            0,
+            Duration::from_secs(7200),
+        );
+        let bytes = request.to_bytes().unwrap();
+        assert_eq!(bytes.len(), 60);
+        assert_eq!(&bytes[..8], &[2, 1, 0, 0, 0, 0, 0x1c, 0x20]);
+        assert_eq!(
+            &bytes[8..24],
+            &Ipv4Addr::new(192, 168, 1, 2).to_ipv6_mapped().octets()
+        );
+        assert_eq!(&bytes[36..42], &[6, 0, 0, 0, 0x1a, 0xe1]);
+        assert_eq!(PcpRequest::from_bytes((&bytes, 0)).unwrap().1, request);
+    }
+
+    #[tokio::test]
+    async fn maps_over_pcp_and_falls_back_to_natpmp() {
+        let lifetime = Duration::from_secs(600);
+        for natpmp in [false, true] {
+            let gateway = MockPcpGateway::start(natpmp).await;
+            let client = PcpClient::new(gateway.addr());
+            for protocol in [Protocol::Tcp, Protocol::Udp] {
+                let mapping = client.map(protocol, 6881, 0, lifetime).await.unwrap();
+                assert_eq!(mapping.protocol, protocol);
+                assert_eq!(mapping.external_port, 16881);
+                assert_eq!(mapping.external_ip, Some(IpAddr::V4(MOCK_EXTERNAL_IP)));
+                assert_eq!(mapping.lifetime, lifetime);
+            }
+            client
+                .map(Protocol::Tcp, 6881, 0, Duration::ZERO)
+                .await
+                .unwrap();
+            assert_eq!(gateway.lifetimes(), vec![600, 600, 0]);
+        }
+
+        let refusing = MockPcpGateway::start(false).await;
+        refusing.refuse();
+        let client = PcpClient::new(refusing.addr());
+        assert!(matches!(
+            client.map(Protocol::Tcp, 6881, 0, lifetime).await,
+            Err(PortMapError::Refused { .. })
+        ));
+    }
+}
@@ -0,0 +1,295 @@
+//! UPnP Internet Gateway Devices: found by an SSDP search multicast on the
+//! LAN, described in XML fetched over HTTP, and asked to map ports through
+//! SOAP calls to their WAN connection service.
+//! See: https://openconnectivity.org/developer/specifications/upnp-resources/upnp/internet-gateway-device-igd-v-2-0/
+
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::time::Duration;
+
+use log::debug;
+use snafu::prelude::*;
+use tokio::net::UdpSocket;
+use url::Url;
+
+use super::{HttpSnafu, IoSnafu, MalformedSnafu, Mapping, PortMapError, Protocol, RefusedSnafu};
+
+pub(crate) const SSDP_GROUP: SocketAddr =
+    SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(239, 255, 255, 250), 1900));
+/// How long devices get to answer a search.
+pub(crate) const DISCOVERY_WAIT: Duration = Duration::from_secs(2);
+const HTTP_TIMEOUT: Duration = Duration::from_secs(5);
+const SEARCH_TARGET: &str = "urn:schemas-upnp-org:device:InternetGatewayDevice:1";
+/// The services that map ports, most preferred first.
+const SERVICES: [&str; 3] = [
+    "urn:schemas-upnp-org:service:WANIPConnection:2",
+    "urn:schemas-upnp-org:service:WANIPConnection:1",
+    "urn:schemas-upnp-org:service:WANPPPConnection:1",
+];
+/// The error AddPortMapping gives when the device only keeps mappings
+/// with no expiry.
+const ONLY_PERMANENT_LEASES: &str = "725";
+
+/// A gateway's port mapping service.
+#[derive(Debug)]
+pub(crate) struct Device {
+    control_url: Url,
+    service: &'static str,
+    /// Our address on the device's network, which mappings point to.
+    local_ip: IpAddr,
+    client: reqwest::Client,
+}
+
+/// Searches for gateways by sending to `ssdp` and describes each that
+/// answers within `wait`.
+pub(crate) async fn discover(
+    ssdp: SocketAddr,
+    wait: Duration,
+) -> Result<Vec<Device>, PortMapError> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))
+        .await
+        .context(IoSnafu)?;
+    let search = format!(
+        "M-SEARCH * HTTP/1.1\r\nHOST: {SSDP_GROUP}\r\nMAN: \"ssdp:discover\"\r\nMX: {}\r\nST: {SEARCH_TARGET}\r\n\r\n",
+        wait.as_secs().max(1)
+    );
+    socket
+        .send_to(search.as_bytes(), ssdp)
+        .await
+        .context(IoSnafu)?;
+
+    let mut locations = Vec::new();
+    let mut buf = [0; 1500];
+    let deadline = tokio::time::Instant::now() + wait;
+    while let Ok(received) = tokio::time::timeout_at(deadline, socket.recv_from(&mut buf)).await {
+        let (len, _) = received.context(IoSnafu)?;
+        let response = String::from_utf8_lossy(&buf[..len]);
+        let location = response.lines().find_map(|line| {
+            let (name, value) = line.split_once(':')?;
+            name.eq_ignore_ascii_case("location")
+                .then(|| value.trim().parse::<Url>().ok())?
+        });
+        if let Some(location) = location.filter(|l| !locations.contains(l)) {
+            locations.push(location);
+        }
+    }
+
+    let client = reqwest::Client::builder()
+        .timeout(HTTP_TIMEOUT)
+        .build()
+        .context(HttpSnafu)?;
+    let mut devices = Vec::new();
+    for location in locations {
+        match Device::describe(client.clone(), &location).await {
+            Ok(device) => devices.push(device),
+            Err(e) => debug!("Couldn't use UPnP device at {location}: {e}"),
+        }
+    }
+    Ok(devices)
+}
+
+impl Device {
+    /// Reads the device description at `location` for a service that maps
+    /// ports.
+    async fn describe(client: reqwest::Client, location: &Url) -> Result<Self, PortMapError> {
+        let description = client
+            .get(location.clone())
+            .send()
+            .await
+            .context(HttpSnafu)?
+            .text()
+            .await
+            .context(HttpSnafu)?;
+        let base = match tag(&description, "URLBase").map(str::parse::<Url>) {
+            Some(Ok(base)) => base,
+            _ => location.clone(),
+        };
+        let (service, control_url) = SERVICES
+            .iter()
+            .find_map(|&wanted| {
+                description.split("<service>").skip(1).find_map(|service| {
+                    (tag(service, "serviceType")? == wanted)
+                        .then(|| Some((wanted, tag(service, "controlURL")?)))?
+                })
+            })
+            .context(MalformedSnafu {
+                message: "no port mapping service",
+            })?;
+        let control_url = base
+            .join(control_url)
+            .map_err(|e| PortMapError::Malformed {
+                message: e.to_string(),
+            })?;
+
+        let host = control_url.socket_addrs(|| None).context(IoSnafu)?;
+        let host = host.first().context(MalformedSnafu {
+            message: "control URL has no address",
+        })?;
+        let probe = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))
+            .await
+            .context(IoSnafu)?;
+        probe.connect(host).await.context(IoSnafu)?;
+        let local_ip = probe.local_addr().context(IoSnafu)?.ip();
+        Ok(Self {
+            control_url,
+            service,
+            local_ip,
+            client,
+        })
+    }
+
+    pub(crate) fn addr(&self) -> IpAddr {
+        match self.control_url.host() {
+            Some(url::Host::Ipv4(ip)) => IpAddr::V4(ip),
+            Some(url::Host::Ipv6(ip)) => IpAddr::V6(ip),
+            _ => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        }
+    }
+
+    /// Maps `external_port`, or `internal_port` if 0, to us for `lease`.
+    /// Devices that refuse leases get a permanent mapping instead.
+    pub(crate) async fn map(
+        &self,
+        protocol: Protocol,
+        internal_port: u16,
+        external_port: u16,
+        lease: Duration,
+    ) -> Result<Mapping, PortMapError> {
+        let external_port = match external_port {
+            0 => internal_port,
+            port => port,
+        };
+        let mut lifetime = lease;
+        let mapped = self
+            .add_port_mapping(protocol, internal_port, external_port, lifetime)
+            .await;
+        match mapped {
+            Err(PortMapError::Refused { reason }) if reason == ONLY_PERMANENT_LEASES => {
+                lifetime = Duration::ZERO;
+                self.add_port_mapping(protocol, internal_port, external_port, lifetime)
+                    .await?;
+            }
+            mapped => mapped?,
+        }
+        let external_ip = self
+            .call("GetExternalIPAddress", &[])
+            .await
+            .ok()
+            .and_then(|response| tag(&response, "NewExternalIPAddress")?.parse().ok());
+        Ok(Mapping {
+            protocol,
+            internal_port,
+            external_port,
+            external_ip,
+            lifetime,
+        })
+    }
+
+    async fn add_port_mapping(
+        &self,
+        protocol: Protocol,
+        internal_port: u16,
+        external_port: u16,
+        lease: Duration,
+    ) -> Result<(), PortMapError> {
+        self.call(
+            "AddPortMapping",
+            &[
+                ("NewRemoteHost", String::new()),
+                ("NewExternalPort", external_port.to_string()),
+                ("NewProtocol", protocol.upnp_name().to_string()),
+                ("NewInternalPort", internal_port.to_string()),
+                ("NewInternalClient", self.local_ip.to_string()),
+                ("NewEnabled", "1".to_string()),
+                ("NewPortMappingDescription", "chitauri".to_string()),
+                ("NewLeaseDuration", lease.as_secs().to_string()),
+            ],
+        )
+        .await
+        .map(|_| ())
+    }
+
+    pub(crate) async fn unmap(&self, mapping: &Mapping) -> Result<(), PortMapError> {
+        self.call(
+            "DeletePortMapping",
+            &[
+                ("NewRemoteHost", String::new()),
+                ("NewExternalPort", mapping.external_port.to_string()),
+                ("NewProtocol", mapping.protocol.upnp_name().to_string()),
+            ],
+        )
+        .await
+        .map(|_| ())
+    }
+
+    /// Calls `action` on the service. A fault is refused with the UPnP
+    /// error code as the reason.
+    async fn call(
+        &self,
+        action: &str,
+        arguments: &[(&str, String)],
+    ) -> Result<String, PortMapError> {
+        let arguments: String = arguments
+            .iter()
+            .map(|(name, value)| format!("<{name}>{value}</{name}>"))
+            .collect();
+        let body = format!(
+            "<?xml version=\"1.0\"?>\r\n\
+             <s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" \
+             s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\">\
+             <s:Body><u:{action} xmlns:u=\"{}\">{arguments}</u:{action}></s:Body></s:Envelope>\r\n",
+            self.service
+        );
+        let response = self
+            .client
+            .post(self.control_url.clone())
+            .header("Content-Type", "text/xml; charset=\"utf-8\"")
+            .header("SOAPAction", format!("\"{}#{action}\"", self.service))
+            .body(body)
+            .send()
+            .await
+            .context(HttpSnafu)?;
+        let status = response.status();
+        let text = response.text().await.context(HttpSnafu)?;
+        ensure!(
+            status.is_success(),
+            RefusedSnafu {
+                reason: tag(&text, "errorCode").unwrap_or(status.as_str()),
+            }
+        );
+        Ok(text)
+    }
+}
+
+impl Protocol {
+    fn upnp_name(&self) -> &'static str {
+        match self {
+            Self::Tcp => "TCP",
+            Self::Udp => "UDP",
+        }
+    }
+}
+
+/// The text of the first `<name>` element in `xml`, with any namespace
+/// prefix on it. Enough for the flat documents gateways send.
+pub(crate) fn tag<'a>(xml: &'a str, name: &str) -> Option<&'a str> {
+    let start = xml
+        .find(&format!("<{name}>"))
+        .or_else(|| xml.find(&format!(":{name}>")))?;
+    let start = start + xml[start..].find('>')? + 1;
+    let end = start + xml[start..].find("</")?;
+    Some(xml[start..end].trim())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::tag;
+
+    #[test]
+    fn reads_tags() {
+        let xml = "<root><URLBase>http://192.168.1.1:5000/</URLBase>\
+                   <s:Body><errorCode>725</errorCode></s:Body></root>";
+        assert_eq!(tag(xml, "URLBase"), Some("http://192.168.1.1:5000/"));
+        assert_eq!(tag(xml, "errorCode"), Some("725"));
+        assert_eq!(tag(xml, "controlURL"), None);
+    }
+}
@@ -0,0 +1,155 @@
+use std::str::FromStr;
+
+use snafu::{whatever, OptionExt, ResultExt, Whatever};
+use url::Url;
+
+use super::InfoHash;
+
+/// A magnet link: an info hash, with perhaps a name and trackers to find
+/// peers through. Peers can also be found without trackers, on the DHT.
+/// See: http://www.bittorrent.org/beps/bep_0009.html#magnet-uri-format
+///
+/// Instead of an info hash, a link may name a public key whose owner
+/// publishes the latest info hash in the DHT, along with the salt it's
+/// published under.
+/// See: http://www.bittorrent.org/beps/bep_0046.html
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Magnet {
+    pub(crate) info_hash: Option<InfoHash>,
+    pub(crate) public_key: Option<[u8; 32]>,
+    pub(crate) salt: Vec<u8>,
+    pub(crate) name: Option<String>,
+    pub(crate) trackers: Vec<Url>,
+}
+
+impl FromStr for Magnet {
+    type Err = Whatever;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let url = Url::parse(s).whatever_context("Magnet link is not a URI")?;
+        if url.scheme() != "magnet" {
+            whatever!("Not a magnet link")
+        }
+        let mut info_hash = None;
+        let mut public_key = None;
+        let mut salt = Vec::new();
+        let mut name = None;
+        let mut trackers = Vec::new();
+        for (key, value) in url.query_pairs() {
+            match &*key {
+                "xt" => {
+                    if let Some(hash) = value.strip_prefix("urn:btih:") {
+                        info_hash = Some(parse_info_hash(hash)?);
+                    }
+                }
+                "xs" => {
+                    if let Some(key) = value.strip_prefix("urn:btpk:") {
+                        public_key = Some(parse_hex(key)?);
+                    }
+                }
+                "s" => salt = parse_hex_vec(&value)?,
+                "dn" => name = Some(value.into_owned()),
+                "tr" => match value.parse() {
+                    Ok(tracker) => trackers.push(tracker),
+                    Err(e) => log::warn!("Ignoring magnet tracker {value}: {e}"),
+                },
+                _ => {}
+            }
+        }
+        if info_hash.is_none() && public_key.is_none() {
+            whatever!("Magnet link has no BitTorrent info hash or public key")
+        }
+        Ok(Self {
+            info_hash,
+            public_key,
+            salt,
+            name,
+            trackers,
+        })
+    }
+}
+
+pub(crate) fn parse_hex<const N: usize>(s: &str) -> Result<[u8; N], Whatever> {
+    parse_hex_vec(s)?
+        .try_into()
+        .ok()
+        .whatever_context(format!("Expected {} hex digits", 2 * N))
+}
+
+fn parse_hex_vec(s: &str) -> Result<Vec<u8>, Whatever> {
+    if !s.len().is_multiple_of(2) || !s.is_ascii() {
+        whatever!("Odd number of hex digits")
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).whatever_context("Bad hex digit"))
+        .collect()
+}
+
+/// Info hashes come as 40 hex digits, or 32 base32 ones.
+fn parse_info_hash(s: &str) -> Result<InfoHash, Whatever> {
+    match s.len() {
+        40 => InfoHash::from_hex(s),
+        32 => {
+            let mut hash = [0; 20];
+            let mut bits = 0_u64;
+            let mut len = 0;
+            let mut out = hash.iter_mut();
+            for c in s.bytes() {
+                let value = match c.to_ascii_uppercase() {
+                    c @ b'A'..=b'Z' => c - b'A',
+                    c @ b'2'..=b'7' => c - b'2' + 26,
+                    _ => whatever!("Info hash must be 32 base32 digits"),
+                };
+                bits = (bits << 5) | u64::from(value);
+                len += 5;
+                if len >= 8 {
+                    len -= 8;
+                    *out.next().unwrap() = (bits >> len) as u8;
+                }
+            }
+            Ok(InfoHash::from(hash))
+        }
+        _ => whatever!("Info hash must be 40 hex or 32 base32 digits"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Magnet;
+    use crate::torrent::InfoHash;
+
+    #[test]
+    fn parses_info_hash_and_public_key_links() {
+        let magnet: Magnet = "magnet:?xt=urn:btih:c9e15763f722f23e98a29decdfae341b98d53056\
+            &dn=Cosmos+Laundromat&tr=udp%3A%2F%2Ftracker.example.org%3A6969&tr=not%20a%20url"
+            .parse()
+            .unwrap();
+        let hash = InfoHash::from_hex("c9e15763f722f23e98a29decdfae341b98d53056").unwrap();
+        assert_eq!(magnet.info_hash, Some(hash.clone()));
+        assert_eq!(magnet.name.as_deref(), Some("Cosmos Laundromat"));
+        assert_eq!(
+            magnet.trackers,
+            vec!["udp://tracker.example.org:6969".parse().unwrap()]
+        );
+
+        let base32: Magnet = "magnet:?xt=urn:btih:ZHQVOY7XELZD5GFCTXWN7LRUDOMNKMCW"
+            .parse()
+            .unwrap();
+        assert_eq!(base32.info_hash, Some(hash));
+
+        let mutable: Magnet = "magnet:?xs=urn:btpk:\
+            8543d3e6115f0f98c944077a4493dcd543e49c739fd998550a1f614ab36ed63e&s=6e616d65"
+            .parse()
+            .unwrap();
+        assert_eq!(mutable.info_hash, None);
+        assert_eq!(mutable.public_key.unwrap()[..2], [0x85, 0x43]);
+        assert_eq!(mutable.salt, b"name");
+        assert!("magnet:?dn=nothing".parse::<Magnet>().is_err());
+        assert!(
+            "http://example.org/?xt=urn:btih:ZHQVOY7XELZD5GFCTXWN7LRUDOMNKMCW"
+                .parse::<Magnet>()
+                .is_err()
+        );
+    }
+}
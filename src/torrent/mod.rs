@@ -13,7 +13,9 @@ use snafu::prelude::*;
 use snafu::{whatever, Whatever};
 use url::Url;
 
-#[derive(PartialEq, Eq, Clone, Deserialize, Serialize, DekuRead, DekuWrite)]
+pub(crate) mod magnet;
+
+#[derive(PartialEq, Eq, Hash, Clone, Deserialize, Serialize, DekuRead, DekuWrite)]
 pub(crate) struct PeerId {
     bytes: [u8; 20],
 }
@@ -26,8 +28,10 @@ impl PeerId {
         Self { bytes }
     }
 
+    #[allow(clippy::inherent_to_string_shadow_display)]
     pub fn to_string(&self) -> String {
-        String::from_utf8(self.bytes.to_vec()).unwrap()
+        // Peers put arbitrary bytes after their client prefix.
+        String::from_utf8_lossy(&self.bytes).into_owned()
     }
 
     pub fn as_bytes(&self) -> &[u8; 20] {
@@ -74,15 +78,32 @@ struct File {
 #[derive(Debug, Serialize, Deserialize)]
 pub(crate) struct Info {
     name: String,
-    #[serde(rename = "piece length")]
+    #[serde(rename = "piece length", deserialize_with = "positive")]
     piece_length: i64,
     pieces: ByteBuf,
     pub(crate) length: Option<i64>,
     files: Option<Vec<File>>,
+    /// BEP 27: peers may only come from the tracker.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    private: Option<i64>,
+}
+
+/// Pieces can't be empty: everything that splits the torrent into pieces
+/// divides by their length.
+fn positive<'de, D>(deserializer: D) -> Result<i64, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    match i64::deserialize(deserializer)? {
+        length if length > 0 => Ok(length),
+        length => Err(serde::de::Error::custom(format!(
+            "piece length must be positive, not {length}"
+        ))),
+    }
 }
 
-#[derive(Clone, PartialEq, Eq, Serialize, Deserialize, DekuRead, DekuWrite)]
-#[deku(endian = "big")]
+#[derive(Clone, PartialEq, Eq, Hash, Serialize, Deserialize, DekuRead, DekuWrite)]
+#[deku(endian = "endian", ctx = "endian: deku::ctx::Endian")]
 pub(crate) struct InfoHash {
     hash: [u8; <<sha1::Sha1Core as OutputSizeUser>::OutputSize as Unsigned>::USIZE],
 }
@@ -92,6 +113,10 @@ impl InfoHash {
         &self.hash
     }
 
+    pub fn as_array(&self) -> &[u8; 20] {
+        &self.hash
+    }
+
     pub fn to_hex_string(&self) -> String {
         self.as_bytes()
             .iter()
@@ -99,6 +124,35 @@ impl InfoHash {
             .collect::<Vec<String>>()
             .join("")
     }
+
+    pub fn from_hex(s: &str) -> Result<Self, Whatever> {
+        if s.len() != 40 || !s.is_ascii() {
+            whatever!("Info hash must be 40 hex digits")
+        }
+        let mut hash = [0; 20];
+        for (i, b) in hash.iter_mut().enumerate() {
+            *b = u8::from_str_radix(&s[2 * i..2 * i + 2], 16)
+                .whatever_context("Info hash must be 40 hex digits")?;
+        }
+        Ok(Self { hash })
+    }
+}
+
+impl From<[u8; 20]> for InfoHash {
+    fn from(hash: [u8; 20]) -> Self {
+        Self { hash }
+    }
+}
+
+impl TryFrom<&[u8]> for InfoHash {
+    type Error = Whatever;
+
+    fn try_from(b: &[u8]) -> Result<Self, Self::Error> {
+        match <[u8; 20]>::try_from(b) {
+            Ok(hash) => Ok(Self { hash }),
+            Err(_) => whatever!("Info hash must be 20 bytes long"),
+        }
+    }
 }
 
 impl fmt::Debug for InfoHash {
@@ -119,6 +173,47 @@ impl Info {
             hash: Sha1::digest(ser::to_bytes(self)?).into(),
         })
     }
+
+    /// The length of the bencoded info dictionary, as BEP 9 serves it.
+    pub(crate) fn metadata_size(&self) -> Result<usize, serde_bencode::Error> {
+        Ok(ser::to_bytes(self)?.len())
+    }
+
+    pub(crate) fn is_private(&self) -> bool {
+        self.private == Some(1)
+    }
+
+    pub(crate) fn piece_length(&self) -> u64 {
+        self.piece_length.max(0) as u64
+    }
+
+    pub(crate) fn piece_count(&self) -> usize {
+        self.pieces.len() / 20
+    }
+
+    /// The SHA-1 hash a piece must have.
+    pub(crate) fn piece_hash(&self, index: usize) -> Option<[u8; 20]> {
+        let hash = self.pieces.get(index * 20..index * 20 + 20)?;
+        hash.try_into().ok()
+    }
+
+    /// The length of each file, in torrent order.
+    pub(crate) fn file_lengths(&self) -> Vec<u64> {
+        match (&self.length, &self.files) {
+            (Some(length), _) => vec![(*length).max(0) as u64],
+            (None, Some(files)) => files.iter().map(|f| f.length.max(0) as u64).collect(),
+            (None, None) => Vec::new(),
+        }
+    }
+
+    /// The length of the single file, or of every file together.
+    pub(crate) fn total_length(&self) -> u64 {
+        match (&self.length, &self.files) {
+            (Some(length), _) => (*length).max(0) as u64,
+            (None, Some(files)) => files.iter().map(|f| f.length.max(0) as u64).sum(),
+            (None, None) => 0,
+        }
+    }
 }
 
 #[derive(Debug, Deserialize, Getters)]
@@ -130,23 +225,45 @@ pub struct Torrent {
     #[serde(default)]
     nodes: Option<Vec<Node>>,
     #[serde(default)]
+    #[getset(get = "pub(crate)")]
     encoding: Option<String>,
     #[serde(default)]
+    #[getset(get = "pub(crate)")]
     httpseeds: Option<Vec<String>>,
     #[serde(default)]
     #[serde(rename = "announce-list")]
     pub(crate) announce_list: Option<AnnounceList>,
     #[serde(default)]
     #[serde(rename = "creation date")]
+    #[getset(get = "pub(crate)")]
     creation_date: Option<i64>,
     #[serde(rename = "comment")]
+    #[getset(get = "pub(crate)")]
     comment: Option<String>,
     #[serde(default)]
     #[serde(rename = "created by")]
+    #[getset(get = "pub(crate)")]
     created_by: Option<String>,
 }
 
 impl Torrent {
+    /// DHT nodes the creator suggests bootstrapping from, as `host:port`.
+    /// See: http://www.bittorrent.org/beps/bep_0005.html#torrent-file-extensions
+    pub(crate) fn nodes(&self) -> Vec<String> {
+        self.nodes
+            .iter()
+            .flatten()
+            .filter_map(|Node(host, port)| {
+                let port = u16::try_from(*port).ok()?;
+                Some(match host.contains(':') {
+                    true => format!("[{host}]:{port}"),
+                    false => format!("{host}:{port}"),
+                })
+            })
+            .collect()
+    }
+
+    #[allow(dead_code)]
     pub(crate) async fn announce_addr(&self) -> Result<impl Iterator<Item = SocketAddr>, Whatever> {
         let url = match self.announce.as_ref() {
             None => whatever!("Torrent had no announce string"),
@@ -189,3 +306,22 @@ impl<'de> Deserialize<'de> for AnnounceList {
         Ok(AnnounceList { list })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::Info;
+
+    #[test]
+    fn rejects_empty_pieces() {
+        let info = |length: i64| {
+            let bencode = format!(
+                "d6:lengthi10e4:name1:a12:piece lengthi{length}e6:pieces20:{}e",
+                "x".repeat(20)
+            );
+            serde_bencode::from_bytes::<Info>(bencode.as_bytes())
+        };
+        assert_eq!(info(16384).unwrap().piece_length(), 16384);
+        assert!(info(0).is_err());
+        assert!(info(-1).is_err());
+    }
+}
@@ -0,0 +1,273 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use getset::{CopyGetters, Getters};
+use sea_orm::sea_query::OnConflict;
+use sea_orm::{ActiveValue, DatabaseConnection, DbErr, EntityTrait};
+use url::Url;
+
+use super::{AnnounceResponse, TrackerError};
+use crate::db::tracker_health;
+
+/// Backoff after the first failure; it doubles with every failure after that.
+const BASE_BACKOFF: i64 = 30;
+const MAX_BACKOFF: i64 = 60 * 60;
+/// A tracker that fails this many times in a row is disabled, and only
+/// retried once a day in case it comes back.
+const DISABLE_AFTER: u32 = 10;
+const DISABLED_RETRY: i64 = 24 * 60 * 60;
+/// A tracker that answers with a `failure reason` is working, but refused
+/// this torrent, so it isn't asked again for a while.
+const REFUSED_RETRY: i64 = MAX_BACKOFF;
+/// Weight given to the newest sample in the latency moving average.
+const LATENCY_WEIGHT: f64 = 0.25;
+
+/// How a single tracker has been behaving. Timestamps are seconds since the
+/// Unix epoch, as returned by [`crate::db::now`].
+#[derive(Debug, Clone, Default, PartialEq, Eq, Getters, CopyGetters)]
+pub(crate) struct TrackerHealth {
+    #[getset(get_copy = "pub(crate)")]
+    last_success: Option<i64>,
+    #[getset(get = "pub(crate)")]
+    last_error: Option<String>,
+    #[getset(get_copy = "pub(crate)")]
+    last_error_at: Option<i64>,
+    #[getset(get_copy = "pub(crate)")]
+    consecutive_failures: u32,
+    #[getset(get_copy = "pub(crate)")]
+    avg_latency: Option<Duration>,
+    #[getset(get_copy = "pub(crate)")]
+    last_peer_count: Option<u32>,
+    /// When we should next announce: the tracker's interval, but no sooner
+    /// than its minimum, after a success, or the end of the backoff after a
+    /// failure.
+    #[getset(get_copy = "pub(crate)")]
+    next_announce: i64,
+}
+
+impl TrackerHealth {
+    pub(crate) fn record_success(
+        &mut self,
+        now: i64,
+        latency: Duration,
+        response: &AnnounceResponse,
+    ) {
+        self.last_success = Some(now);
+        self.consecutive_failures = 0;
+        self.avg_latency = Some(match self.avg_latency {
+            Some(avg) => avg.mul_f64(1.0 - LATENCY_WEIGHT) + latency.mul_f64(LATENCY_WEIGHT),
+            None => latency,
+        });
+        self.last_peer_count = Some(response.peers.len() as u32);
+        let interval = response
+            .interval
+            .max(response.min_interval.unwrap_or_default());
+        self.next_announce = now + i64::from(interval);
+    }
+
+    /// Only errors reaching the tracker count towards its backoff. A
+    /// `failure reason` is about the torrent, not the tracker.
+    pub(crate) fn record_failure(&mut self, now: i64, error: &TrackerError) {
+        self.last_error = Some(error.to_string());
+        self.last_error_at = Some(now);
+        if let TrackerError::Failure { .. } = error {
+            self.next_announce = now + REFUSED_RETRY;
+            return;
+        }
+        self.consecutive_failures = self.consecutive_failures.saturating_add(1);
+        self.next_announce = now + self.backoff();
+    }
+
+    /// Seconds to wait after the current run of failures.
+    fn backoff(&self) -> i64 {
+        if self.is_disabled() {
+            return DISABLED_RETRY;
+        }
+        let doublings = self.consecutive_failures.saturating_sub(1).min(16);
+        (BASE_BACKOFF << doublings).min(MAX_BACKOFF)
+    }
+
+    /// Whether a recent failure means the tracker shouldn't be tried yet.
+    pub(crate) fn is_backing_off(&self, now: i64) -> bool {
+        self.consecutive_failures > 0 && now < self.next_announce
+    }
+
+    pub(crate) fn is_disabled(&self) -> bool {
+        self.consecutive_failures >= DISABLE_AFTER
+    }
+}
+
+impl From<tracker_health::Model> for TrackerHealth {
+    fn from(model: tracker_health::Model) -> Self {
+        Self {
+            last_success: model.last_success,
+            last_error: model.last_error,
+            last_error_at: model.last_error_at,
+            consecutive_failures: model.consecutive_failures.max(0) as u32,
+            avg_latency: model
+                .avg_latency_ms
+                .map(|ms| Duration::from_millis(ms.max(0) as u64)),
+            last_peer_count: model.last_peer_count.map(|n| n.max(0) as u32),
+            next_announce: model.next_announce,
+        }
+    }
+}
+
+/// Keeps [`TrackerHealth`] in the database, keyed by announce URL, so that
+/// backoff and disabled trackers survive restarts.
+#[derive(Clone)]
+pub(crate) struct HealthStore {
+    db: DatabaseConnection,
+}
+
+impl HealthStore {
+    pub(crate) fn new(db: DatabaseConnection) -> Self {
+        Self { db }
+    }
+
+    pub(crate) async fn load(&self) -> Result<HashMap<Url, TrackerHealth>, DbErr> {
+        Ok(tracker_health::Entity::find()
+            .all(&self.db)
+            .await?
+            .into_iter()
+            .filter_map(|model| {
+                let url = model.url.parse().ok()?;
+                Some((url, model.into()))
+            })
+            .collect())
+    }
+
+    pub(crate) async fn save(&self, url: &Url, health: &TrackerHealth) -> Result<(), DbErr> {
+        tracker_health::Entity::insert(tracker_health::ActiveModel {
+            url: ActiveValue::Set(url.to_string()),
+            last_success: ActiveValue::Set(health.last_success),
+            last_error: ActiveValue::Set(health.last_error.clone()),
+            last_error_at: ActiveValue::Set(health.last_error_at),
+            consecutive_failures: ActiveValue::Set(
+                health.consecutive_failures.min(i32::MAX as u32) as i32,
+            ),
+            avg_latency_ms: ActiveValue::Set(health.avg_latency.map(|d| d.as_millis() as i64)),
+            last_peer_count: ActiveValue::Set(
+                health
+                    .last_peer_count
+                    .map(|n| n.min(i32::MAX as u32) as i32),
+            ),
+            next_announce: ActiveValue::Set(health.next_announce),
+        })
+        .on_conflict(
+            OnConflict::column(tracker_health::Column::Url)
+                .update_columns([
+                    tracker_health::Column::LastSuccess,
+                    tracker_health::Column::LastError,
+                    tracker_health::Column::LastErrorAt,
+                    tracker_health::Column::ConsecutiveFailures,
+                    tracker_health::Column::AvgLatencyMs,
+                    tracker_health::Column::LastPeerCount,
+                    tracker_health::Column::NextAnnounce,
+                ])
+                .to_owned(),
+        )
+        .exec(&self.db)
+        .await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{HealthStore, TrackerHealth, DISABLED_RETRY, MAX_BACKOFF, REFUSED_RETRY};
+    use crate::tracker::{AnnounceResponse, TrackerError};
+
+    fn response(interval: u32, peers: usize) -> AnnounceResponse {
+        AnnounceResponse {
+            interval,
+            min_interval: None,
+            seeders: None,
+            leechers: None,
+            peers: vec!["10.0.0.1:6881".parse().unwrap(); peers],
+            external_ip: None,
+        }
+    }
+
+    #[test]
+    fn backoff_doubles_then_disables() {
+        let mut health = TrackerHealth::default();
+        health.record_failure(1000, &TrackerError::Timeout);
+        assert_eq!(health.next_announce(), 1030);
+        assert!(health.is_backing_off(1029));
+        assert!(!health.is_backing_off(1030));
+
+        health.record_failure(1000, &TrackerError::Timeout);
+        assert_eq!(health.next_announce(), 1060);
+        for _ in 2..9 {
+            health.record_failure(1000, &TrackerError::Timeout);
+        }
+        assert_eq!(health.next_announce(), 1000 + MAX_BACKOFF);
+        assert!(!health.is_disabled());
+
+        health.record_failure(1000, &TrackerError::Timeout);
+        assert!(health.is_disabled());
+        assert_eq!(health.next_announce(), 1000 + DISABLED_RETRY);
+        assert_eq!(health.last_error().as_deref(), Some("tracker timed out"));
+    }
+
+    #[test]
+    fn success_resets_failures_and_averages_latency() {
+        let mut health = TrackerHealth::default();
+        health.record_failure(1000, &TrackerError::Timeout);
+        health.record_success(1010, Duration::from_millis(100), &response(1800, 3));
+        assert_eq!(health.consecutive_failures(), 0);
+        assert!(!health.is_backing_off(1010));
+        assert_eq!(health.next_announce(), 2810);
+        assert_eq!(health.last_peer_count(), Some(3));
+
+        health.record_success(2810, Duration::from_millis(500), &response(1800, 0));
+        assert_eq!(health.avg_latency(), Some(Duration::from_millis(200)));
+
+        let hurried = AnnounceResponse {
+            min_interval: Some(600),
+            ..response(60, 0)
+        };
+        health.record_success(3000, Duration::from_millis(200), &hurried);
+        assert_eq!(health.next_announce(), 3600);
+    }
+
+    #[test]
+    fn refusals_dont_count_as_failures() {
+        let mut health = TrackerHealth::default();
+        for _ in 0..20 {
+            let refused = TrackerError::Failure {
+                reason: "unregistered torrent".into(),
+            };
+            health.record_failure(1000, &refused);
+        }
+        assert_eq!(health.consecutive_failures(), 0);
+        assert!(!health.is_backing_off(1000));
+        assert!(!health.is_disabled());
+        assert_eq!(health.next_announce(), 1000 + REFUSED_RETRY);
+        assert_eq!(
+            health.last_error().as_deref(),
+            Some("tracker returned failure: unregistered torrent")
+        );
+    }
+
+    #[tokio::test]
+    async fn store_round_trip() {
+        let db = crate::db::connect("sqlite::memory:").await.unwrap();
+        let store = HealthStore::new(db);
+        let url = "udp://tracker.example:6969/announce".parse().unwrap();
+
+        let mut health = TrackerHealth::default();
+        health.record_success(1000, Duration::from_millis(40), &response(900, 2));
+        health.record_failure(2000, &TrackerError::Timeout);
+        store.save(&url, &health).await.unwrap();
+        health.record_failure(2030, &TrackerError::Timeout);
+        store.save(&url, &health).await.unwrap();
+
+        let loaded = store.load().await.unwrap();
+        assert_eq!(loaded.len(), 1);
+        assert_eq!(loaded[&url], health);
+    }
+}
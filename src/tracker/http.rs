@@ -1,26 +1,44 @@
 use std::borrow::Cow;
-use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::str::FromStr;
+use std::time::Duration;
 
-use log::{debug, info};
+use log::debug;
 use reqwest::IntoUrl;
 use serde::{Deserialize, Serialize};
 use serde_bytes::ByteBuf;
+use snafu::prelude::*;
 use url::Url;
 
-use super::{AnnounceEvent, Tracker};
+use super::{
+    AnnounceEvent, AnnounceResponse, FailureSnafu, HttpSnafu, MalformedSnafu, TimeoutSnafu,
+    Tracker, TrackerError,
+};
+use crate::external_ip::ip_from_bytes;
 use crate::torrent::{InfoHash, PeerId};
 
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
 pub struct HTTPTracker {
     url: Url,
+    client: reqwest::Client,
 }
 
 impl HTTPTracker {
     pub fn new(url: impl IntoUrl) -> Result<Self, reqwest::Error> {
+        Self::with_timeout(url, DEFAULT_TIMEOUT)
+    }
+
+    pub fn with_timeout(url: impl IntoUrl, timeout: Duration) -> Result<Self, reqwest::Error> {
         Ok(Self {
             url: url.into_url()?,
+            client: reqwest::Client::builder().timeout(timeout).build()?,
         })
     }
+
+    pub fn url(&self) -> &Url {
+        &self.url
+    }
 }
 
 impl Tracker for HTTPTracker {
@@ -34,14 +52,14 @@ impl Tracker for HTTPTracker {
         downloaded: u64,
         left: u64,
         event: AnnounceEvent,
-    ) -> Result<Vec<String>, reqwest::Error> {
+    ) -> Result<AnnounceResponse, TrackerError> {
         let mut url = self.url.clone();
         {
             let mut query_pairs = url.query_pairs_mut();
             query_pairs
                 .encoding_override(Some(&iso_8859_1_encode))
                 .append_pair("info_hash", &iso_8859_1_decode(info_hash.as_bytes()))
-                .append_pair("peer_id", &peer_id.to_string())
+                .append_pair("peer_id", &iso_8859_1_decode(peer_id.as_bytes()))
                 .append_pair("port", &port.to_string())
                 .append_pair("uploaded", &uploaded.to_string())
                 .append_pair("downloaded", &downloaded.to_string())
@@ -50,24 +68,47 @@ impl Tracker for HTTPTracker {
                 .append_pair("no_peer_id", "0")
                 .append_pair("numwant", "50");
 
-            if ip.is_some() {
-                query_pairs.append_pair("ip", &ip.unwrap().to_string());
+            if let Some(ip) = ip {
+                query_pairs.append_pair("ip", &ip.to_string());
             }
             if event != AnnounceEvent::Empty {
                 query_pairs.append_pair("event", &event.to_string());
             }
         }
 
-        info!("{}", url);
-        let req = reqwest::get(url).await?;
-        let body = req.bytes().await?;
-        // info!("{}", String::from_utf8_lossy(&body));
-        println!("{:?}", body);
+        debug!("Announcing to {}", url);
+        let body = match self.client.get(url).send().await {
+            Ok(resp) => resp.bytes().await.context(HttpSnafu)?,
+            Err(e) if e.is_timeout() => return TimeoutSnafu.fail(),
+            Err(e) => return Err(e).context(HttpSnafu),
+        };
 
-        let resp = serde_bencode::from_bytes::<HTTPAnnounceResponse>(&body);
-        info!("{:?}", resp);
+        if let Ok(failure) = serde_bencode::from_bytes::<HTTPFailureResponse>(&body) {
+            return FailureSnafu {
+                reason: failure.failure_reason,
+            }
+            .fail();
+        }
+        let resp = serde_bencode::from_bytes::<HTTPAnnounceResponse>(&body).map_err(|e| {
+            MalformedSnafu {
+                message: e.to_string(),
+            }
+            .build()
+        })?;
 
-        Ok(Vec::new())
+        Ok(AnnounceResponse {
+            interval: resp.interval,
+            min_interval: resp.min_interval,
+            seeders: resp.complete,
+            leechers: resp.incomplete,
+            peers: resp
+                .peers
+                .iter()
+                .chain(resp.peers6.iter().flatten())
+                .map(HTTPAnnounceResponsePeer::addr)
+                .collect(),
+            external_ip: resp.external_ip.as_ref().and_then(|ip| ip_from_bytes(ip)),
+        })
     }
 }
 
@@ -76,7 +117,7 @@ fn iso_8859_1_decode(bytes: &[u8]) -> String {
     bytes.iter().map(|&byte| char::from(byte)).collect()
 }
 
-fn iso_8859_1_encode(string: &str) -> Cow<[u8]> {
+fn iso_8859_1_encode(string: &str) -> Cow<'_, [u8]> {
     string
         .chars()
         .map(|c| u8::try_from(u32::from(c)).unwrap())
@@ -91,13 +132,41 @@ pub(crate) struct HTTPAnnounceResponsePeer {
     port: u16,
 }
 
+impl HTTPAnnounceResponsePeer {
+    fn addr(&self) -> SocketAddr {
+        SocketAddr::new(self.ip, self.port)
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Deserialize)]
+struct HTTPFailureResponse {
+    #[serde(rename = "failure reason")]
+    failure_reason: String,
+}
+
 #[derive(PartialEq, Eq, Debug, Deserialize, Serialize)]
 pub(crate) struct HTTPAnnounceResponse {
     interval: u32,
+    #[serde(
+        default,
+        rename = "min interval",
+        skip_serializing_if = "Option::is_none"
+    )]
+    min_interval: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    complete: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    incomplete: Option<u32>,
     #[serde(deserialize_with = "deserialize_peers")]
     peers: Vec<HTTPAnnounceResponsePeer>,
     #[serde(default, deserialize_with = "deserialize_peers6")]
     peers6: Option<Vec<HTTPAnnounceResponsePeer>>,
+    #[serde(
+        default,
+        rename = "external ip",
+        skip_serializing_if = "Option::is_none"
+    )]
+    external_ip: Option<ByteBuf>,
 }
 
 fn deserialize_ipaddr<'de, D>(deserializer: D) -> Result<IpAddr, D::Error>
@@ -136,7 +205,7 @@ where
 fn deserialize_compact_peers(
     bytes: &[u8],
 ) -> Result<Vec<HTTPAnnounceResponsePeer>, serde_bencode::Error> {
-    if bytes.len() % 6 != 0 {
+    if !bytes.len().is_multiple_of(6) {
         return Err(serde_bencode::Error::Custom(format!(
             "invalid compact peer list length: {}",
             bytes.len()
@@ -165,7 +234,7 @@ fn deserialize_peers6<'de, D>(
 where
     D: serde::Deserializer<'de>,
 {
-    match Option::<&'de [u8]>::deserialize(deserializer)?.as_ref() {
+    match Option::<ByteBuf>::deserialize(deserializer)?.as_ref() {
         None => Ok(None),
         Some(bytes) => {
             if bytes.len() % 18 != 0 {
@@ -206,10 +275,31 @@ where
 
 #[cfg(test)]
 mod tests {
-    use std::net::{IpAddr, Ipv4Addr};
+    use std::net::{IpAddr, Ipv4Addr, SocketAddr};
     use std::str::FromStr;
+    use std::time::Duration;
+
+    use super::{HTTPAnnounceResponse, HTTPAnnounceResponsePeer, HTTPTracker};
+    use crate::torrent::{InfoHash, PeerId};
+    use crate::tracker::mock::{MockHttpTracker, Script, MOCK_EXTERNAL_IP};
+    use crate::tracker::{AnnounceEvent, Tracker, TrackerError};
 
-    use super::{HTTPAnnounceResponse, HTTPAnnounceResponsePeer};
+    async fn announce(
+        tracker: &HTTPTracker,
+    ) -> Result<crate::tracker::AnnounceResponse, TrackerError> {
+        tracker
+            .get_peers(
+                InfoHash::from([0xab; 20]),
+                PeerId::new(),
+                None,
+                6881,
+                0,
+                0,
+                100,
+                AnnounceEvent::Started,
+            )
+            .await
+    }
 
     #[test]
     fn deserialize_http_response_non_compact() {
@@ -220,6 +310,9 @@ mod tests {
             resp.unwrap(),
             HTTPAnnounceResponse {
                 interval: 1800,
+                min_interval: None,
+                complete: Some(113),
+                incomplete: Some(3),
                 peers: vec![
                     HTTPAnnounceResponsePeer {
                         id: None,
@@ -238,6 +331,7 @@ mod tests {
                     },
                 ],
                 peers6: None,
+                external_ip: None,
             }
         );
     }
@@ -251,13 +345,82 @@ mod tests {
             resp.unwrap(),
             HTTPAnnounceResponse {
                 interval: 1800,
+                min_interval: None,
+                complete: Some(12),
+                incomplete: Some(1),
                 peers: vec![HTTPAnnounceResponsePeer {
                     id: None,
                     ip: IpAddr::V4(Ipv4Addr::new(185, 125, 190, 59)),
                     port: 6942
                 }],
                 peers6: None,
+                external_ip: None,
             }
         )
     }
+
+    #[tokio::test]
+    async fn announce_returns_peers() {
+        let peers: Vec<SocketAddr> = vec![
+            "10.0.0.1:6881".parse().unwrap(),
+            "[2001:db8::1]:51413".parse().unwrap(),
+        ];
+        let mock = MockHttpTracker::start(vec![Script::Peers(peers.clone())]);
+        let tracker = HTTPTracker::new(mock.url()).unwrap();
+
+        let resp = announce(&tracker).await.unwrap();
+        assert_eq!(resp.peers, peers);
+        assert_eq!(resp.interval, 1800);
+        assert_eq!(resp.external_ip, Some(MOCK_EXTERNAL_IP.into()));
+
+        let query = &mock.announces()[0];
+        assert!(query.contains("info_hash=%AB%AB"), "{query}");
+        assert!(query.contains("event=started"), "{query}");
+    }
+
+    #[tokio::test]
+    async fn announce_reports_failure_reason() {
+        let mock = MockHttpTracker::start(vec![Script::Failure("unregistered torrent".into())]);
+        let tracker = HTTPTracker::new(mock.url()).unwrap();
+
+        match announce(&tracker).await {
+            Err(TrackerError::Failure { reason }) => assert_eq!(reason, "unregistered torrent"),
+            other => panic!("expected a failure, got {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn announce_follows_redirects() {
+        let peer: SocketAddr = "10.0.0.2:6881".parse().unwrap();
+        let mock = MockHttpTracker::start(vec![
+            Script::Redirect("/elsewhere".into()),
+            Script::Peers(vec![peer]),
+        ]);
+        let tracker = HTTPTracker::new(mock.url()).unwrap();
+
+        assert_eq!(announce(&tracker).await.unwrap().peers, vec![peer]);
+        assert_eq!(mock.announces().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn announce_rejects_malformed_response() {
+        let mock = MockHttpTracker::start(vec![Script::Malformed]);
+        let tracker = HTTPTracker::new(mock.url()).unwrap();
+
+        assert!(matches!(
+            announce(&tracker).await,
+            Err(TrackerError::Malformed { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn announce_times_out() {
+        let mock = MockHttpTracker::start(vec![Script::Timeout]);
+        let tracker = HTTPTracker::with_timeout(mock.url(), Duration::from_millis(100)).unwrap();
+
+        assert!(matches!(
+            announce(&tracker).await,
+            Err(TrackerError::Timeout)
+        ));
+    }
 }
@@ -0,0 +1,314 @@
+//! Scriptable fake trackers for tests. Each one listens on an ephemeral
+//! loopback port and answers announces according to a queue of [`Script`]
+//! steps; the last step repeats once the queue runs dry.
+
+use std::collections::VecDeque;
+use std::convert::Infallible;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use deku::prelude::*;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response, Server, StatusCode};
+use serde::Serialize;
+use serde_bytes::ByteBuf;
+use tokio::net::UdpSocket;
+use tokio::task::JoinHandle;
+use url::Url;
+
+use super::udp::{
+    Action, AnnounceRequest, AnnounceResponsePeerV4, AnnounceResponseV4, ConnectRequest,
+    ConnectResponse, ErrorResponse,
+};
+
+/// What the HTTP mock tells clients their address is.
+pub(crate) const MOCK_EXTERNAL_IP: Ipv4Addr = Ipv4Addr::new(203, 0, 113, 7);
+
+#[derive(Debug, Clone)]
+pub(crate) enum Script {
+    /// Answer with these peers.
+    Peers(Vec<SocketAddr>),
+    /// Answer with a tracker failure message.
+    Failure(String),
+    /// Never answer.
+    Timeout,
+    /// HTTP only: redirect to this path on the same tracker.
+    Redirect(String),
+    /// Answer with bytes that don't decode.
+    Malformed,
+    /// UDP only: answer the connect request with the wrong transaction ID.
+    WrongTransactionId,
+}
+
+#[derive(Clone)]
+struct Steps {
+    steps: Arc<Mutex<VecDeque<Script>>>,
+    announces: Arc<Mutex<Vec<Vec<u8>>>>,
+}
+
+impl Steps {
+    fn new(steps: Vec<Script>) -> Self {
+        assert!(!steps.is_empty(), "a mock tracker needs at least one step");
+        Self {
+            steps: Arc::new(Mutex::new(steps.into())),
+            announces: Arc::default(),
+        }
+    }
+
+    fn peek(&self) -> Script {
+        self.steps.lock().unwrap().front().cloned().unwrap()
+    }
+
+    fn next(&self) -> Script {
+        let mut steps = self.steps.lock().unwrap();
+        if steps.len() > 1 {
+            steps.pop_front().unwrap()
+        } else {
+            steps.front().cloned().unwrap()
+        }
+    }
+
+    fn record(&self, request: &[u8]) {
+        self.announces.lock().unwrap().push(request.to_vec());
+    }
+}
+
+/// A fake HTTP tracker serving `/announce` and any redirect targets.
+pub(crate) struct MockHttpTracker {
+    addr: SocketAddr,
+    steps: Steps,
+    task: JoinHandle<()>,
+}
+
+impl MockHttpTracker {
+    pub(crate) fn start(steps: Vec<Script>) -> Self {
+        let steps = Steps::new(steps);
+        let listener = std::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let handler = steps.clone();
+        let make_svc = make_service_fn(move |_| {
+            let steps = handler.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req| {
+                    let steps = steps.clone();
+                    async move { Ok::<_, Infallible>(Self::respond(&steps, req).await) }
+                }))
+            }
+        });
+        let server = Server::from_tcp(listener).unwrap().serve(make_svc);
+        let task = tokio::spawn(async move {
+            let _ = server.await;
+        });
+        Self { addr, steps, task }
+    }
+
+    pub(crate) fn url(&self) -> Url {
+        format!("http://{}/announce", self.addr).parse().unwrap()
+    }
+
+    /// The raw query strings of every announce received so far.
+    pub(crate) fn announces(&self) -> Vec<String> {
+        self.steps
+            .announces
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|q| String::from_utf8_lossy(q).into_owned())
+            .collect()
+    }
+
+    async fn respond(steps: &Steps, req: Request<Body>) -> Response<Body> {
+        let query = req.uri().query().unwrap_or("").to_string();
+        steps.record(query.as_bytes());
+        let body = match steps.next() {
+            Script::Peers(peers) => {
+                serde_bencode::to_bytes(&MockAnnounceReply::new(&peers)).unwrap()
+            }
+            Script::Failure(reason) => serde_bencode::to_bytes(&MockFailureReply {
+                failure_reason: reason,
+            })
+            .unwrap(),
+            Script::Timeout => {
+                // Hold the request open for longer than any test will wait.
+                tokio::time::sleep(Duration::from_secs(3600)).await;
+                Vec::new()
+            }
+            Script::Redirect(path) => {
+                return Response::builder()
+                    .status(StatusCode::FOUND)
+                    .header("Location", format!("{path}?{query}"))
+                    .body(Body::empty())
+                    .unwrap()
+            }
+            Script::Malformed | Script::WrongTransactionId => b"d8:intervalli1800e".to_vec(),
+        };
+        Response::new(Body::from(body))
+    }
+}
+
+impl Drop for MockHttpTracker {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+#[derive(Serialize)]
+struct MockFailureReply {
+    #[serde(rename = "failure reason")]
+    failure_reason: String,
+}
+
+#[derive(Serialize)]
+struct MockAnnounceReply {
+    interval: u32,
+    complete: u32,
+    incomplete: u32,
+    peers: ByteBuf,
+    peers6: ByteBuf,
+    #[serde(rename = "external ip")]
+    external_ip: ByteBuf,
+}
+
+impl MockAnnounceReply {
+    fn new(peers: &[SocketAddr]) -> Self {
+        let mut v4 = Vec::new();
+        let mut v6 = Vec::new();
+        for peer in peers {
+            let out = match peer.ip() {
+                IpAddr::V4(ip) => {
+                    v4.extend_from_slice(&ip.octets());
+                    &mut v4
+                }
+                IpAddr::V6(ip) => {
+                    v6.extend_from_slice(&ip.octets());
+                    &mut v6
+                }
+            };
+            out.extend_from_slice(&peer.port().to_be_bytes());
+        }
+        Self {
+            interval: 1800,
+            complete: 0,
+            incomplete: peers.len() as u32,
+            peers: ByteBuf::from(v4),
+            peers6: ByteBuf::from(v6),
+            external_ip: ByteBuf::from(MOCK_EXTERNAL_IP.octets().to_vec()),
+        }
+    }
+}
+
+/// A fake UDP tracker. Connect requests are answered honestly unless the
+/// current step misbehaves at that stage (`Timeout`, `Malformed` and
+/// `WrongTransactionId`); every other step is consumed by the announce.
+pub(crate) struct MockUdpTracker {
+    addr: SocketAddr,
+    steps: Steps,
+    task: JoinHandle<()>,
+}
+
+const MOCK_CONNECTION_ID: u64 = 0x1234_5678;
+
+impl MockUdpTracker {
+    pub(crate) async fn start(steps: Vec<Script>) -> Self {
+        let steps = Steps::new(steps);
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let addr = socket.local_addr().unwrap();
+
+        let handler = steps.clone();
+        let task = tokio::spawn(async move {
+            let mut buf = [0_u8; 2048];
+            loop {
+                let Ok((len, from)) = socket.recv_from(&mut buf).await else {
+                    return;
+                };
+                if let Some(reply) = Self::respond(&handler, &buf[..len]) {
+                    let _ = socket.send_to(&reply, from).await;
+                }
+            }
+        });
+        Self { addr, steps, task }
+    }
+
+    pub(crate) fn url(&self) -> Url {
+        format!("udp://{}/announce", self.addr).parse().unwrap()
+    }
+
+    /// The raw announce packets received so far.
+    pub(crate) fn announces(&self) -> Vec<Vec<u8>> {
+        self.steps.announces.lock().unwrap().clone()
+    }
+
+    fn respond(steps: &Steps, packet: &[u8]) -> Option<Vec<u8>> {
+        match Action::of_request(packet)? {
+            Action::Connect => {
+                let (_, req) = ConnectRequest::from_bytes((packet, 0)).ok()?;
+                match steps.peek() {
+                    Script::Timeout => {
+                        steps.next();
+                        None
+                    }
+                    Script::Malformed => {
+                        steps.next();
+                        let mut reply = req.transaction_id().to_be_bytes().to_vec();
+                        reply.splice(0..0, (Action::Connect as u32).to_be_bytes());
+                        Some(reply)
+                    }
+                    Script::WrongTransactionId => {
+                        steps.next();
+                        ConnectResponse::new(
+                            req.transaction_id().wrapping_add(1),
+                            MOCK_CONNECTION_ID,
+                        )
+                        .to_bytes()
+                        .ok()
+                    }
+                    _ => ConnectResponse::new(req.transaction_id(), MOCK_CONNECTION_ID)
+                        .to_bytes()
+                        .ok(),
+                }
+            }
+            Action::Announce => {
+                let (_, req) = AnnounceRequest::from_bytes((packet, 0)).ok()?;
+                if req.connection_id() != MOCK_CONNECTION_ID {
+                    return None;
+                }
+                steps.record(packet);
+                match steps.next() {
+                    Script::Peers(peers) => AnnounceResponseV4::new(
+                        req.transaction_id(),
+                        1800,
+                        peers.len() as u32,
+                        0,
+                        peers
+                            .iter()
+                            .filter_map(|p| match p.ip() {
+                                IpAddr::V4(ip) => {
+                                    Some(AnnounceResponsePeerV4::new(&ip.octets(), p.port()))
+                                }
+                                IpAddr::V6(_) => None,
+                            })
+                            .collect(),
+                    )
+                    .to_bytes()
+                    .ok(),
+                    Script::Failure(reason) => ErrorResponse::new(req.transaction_id(), &reason)
+                        .to_bytes()
+                        .ok(),
+                    Script::Redirect(_) => ErrorResponse::new(req.transaction_id(), "redirect")
+                        .to_bytes()
+                        .ok(),
+                    Script::Timeout | Script::Malformed | Script::WrongTransactionId => None,
+                }
+            }
+            Action::Scrape | Action::Error => None,
+        }
+    }
+}
+
+impl Drop for MockUdpTracker {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
@@ -1,16 +1,99 @@
+mod health;
 mod http;
+#[cfg(test)]
+pub(crate) mod mock;
+pub(crate) mod server;
+mod tier;
 mod udp;
 
 use std::fmt;
-use std::net::IpAddr;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
 
 use deku::prelude::*;
 pub use http::HTTPTracker;
 use serde::{Deserialize, Serialize};
+use snafu::prelude::*;
+pub(crate) use tier::TierManager;
+pub(crate) use udp::UDPTracker;
+use url::Url;
 
 use crate::torrent::{InfoHash, PeerId};
 
+#[derive(Debug, Snafu)]
+pub(crate) enum TrackerError {
+    #[snafu(display("HTTP request failed: {source}"))]
+    Http { source: reqwest::Error },
+    #[snafu(display("UDP request failed: {source}"))]
+    Io { source: std::io::Error },
+    #[snafu(display("tracker returned failure: {reason}"))]
+    Failure { reason: String },
+    #[snafu(display("malformed tracker response: {message}"))]
+    Malformed { message: String },
+    #[snafu(display("tracker timed out"))]
+    Timeout,
+    #[snafu(display("unsupported tracker URL {url}"))]
+    UnsupportedUrl { url: Url },
+    #[snafu(display("no trackers to announce to"))]
+    NoTrackers,
+    #[snafu(display("every tracker is backing off after failures"))]
+    BackingOff,
+}
+
+/// What an announce tells us, independent of the tracker protocol.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct AnnounceResponse {
+    pub(crate) interval: u32,
+    pub(crate) min_interval: Option<u32>,
+    pub(crate) seeders: Option<u32>,
+    pub(crate) leechers: Option<u32>,
+    pub(crate) peers: Vec<SocketAddr>,
+    /// Our address as the tracker sees it (BEP 24).
+    pub(crate) external_ip: Option<IpAddr>,
+}
+
 pub(crate) trait Tracker {
+    #[allow(clippy::too_many_arguments)]
+    async fn get_peers(
+        &self,
+        info_hash: InfoHash,
+        peer_id: PeerId,
+        ip: Option<IpAddr>,
+        port: u16,
+        uploaded: u64,
+        downloaded: u64,
+        left: u64,
+        event: AnnounceEvent,
+    ) -> Result<AnnounceResponse, TrackerError>;
+}
+
+/// Any tracker we know how to talk to, picked by URL scheme. Async trait
+/// methods can't be called through `dyn Tracker`, so this stands in for it.
+pub(crate) enum AnyTracker {
+    Http(HTTPTracker),
+    Udp(UDPTracker),
+}
+
+impl AnyTracker {
+    pub(crate) fn from_url(url: &Url) -> Result<Self, TrackerError> {
+        match url.scheme() {
+            "http" | "https" => Ok(AnyTracker::Http(
+                HTTPTracker::new(url.clone()).context(HttpSnafu)?,
+            )),
+            "udp" => Ok(AnyTracker::Udp(UDPTracker::new(url.clone())?)),
+            _ => UnsupportedUrlSnafu { url: url.clone() }.fail(),
+        }
+    }
+
+    pub(crate) fn url(&self) -> &Url {
+        match self {
+            AnyTracker::Http(t) => t.url(),
+            AnyTracker::Udp(t) => t.url(),
+        }
+    }
+}
+
+impl Tracker for AnyTracker {
     async fn get_peers(
         &self,
         info_hash: InfoHash,
@@ -21,7 +104,22 @@ pub(crate) trait Tracker {
         downloaded: u64,
         left: u64,
         event: AnnounceEvent,
-    ) -> Result<Vec<String>, reqwest::Error>;
+    ) -> Result<AnnounceResponse, TrackerError> {
+        match self {
+            AnyTracker::Http(t) => {
+                t.get_peers(
+                    info_hash, peer_id, ip, port, uploaded, downloaded, left, event,
+                )
+                .await
+            }
+            AnyTracker::Udp(t) => {
+                t.get_peers(
+                    info_hash, peer_id, ip, port, uploaded, downloaded, left, event,
+                )
+                .await
+            }
+        }
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, DekuRead, DekuWrite, Deserialize, Serialize)]
@@ -45,3 +143,17 @@ impl fmt::Display for AnnounceEvent {
         write!(f, "{}", s)
     }
 }
+
+impl FromStr for AnnounceEvent {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "" | "empty" => Ok(AnnounceEvent::Empty),
+            "completed" => Ok(AnnounceEvent::Completed),
+            "started" => Ok(AnnounceEvent::Started),
+            "stopped" => Ok(AnnounceEvent::Stopped),
+            _ => Err(format!("unknown announce event `{s}`")),
+        }
+    }
+}
@@ -0,0 +1,290 @@
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::Arc;
+
+use hyper::server::conn::AddrStream;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response, Server, StatusCode};
+use log::{debug, warn};
+use percent_encoding::percent_decode_str;
+use serde::Serialize;
+use serde_bytes::ByteBuf;
+use snafu::prelude::*;
+
+use super::swarm::{canonical, Announce, SwarmError, SwarmPeer};
+use super::{HttpSnafu, ServerError, TrackerServer};
+use crate::external_ip::ip_to_bytes;
+use crate::torrent::{InfoHash, PeerId};
+use crate::tracker::AnnounceEvent;
+
+pub(super) async fn serve(
+    server: Arc<TrackerServer>,
+    listener: std::net::TcpListener,
+) -> Result<(), ServerError> {
+    let make_svc = make_service_fn(move |conn: &AddrStream| {
+        let server = server.clone();
+        let remote = conn.remote_addr();
+        async move { Ok::<_, Infallible>(service_fn(move |req| handle(server.clone(), remote, req))) }
+    });
+
+    Server::from_tcp(listener)
+        .context(HttpSnafu)?
+        .serve(make_svc)
+        .await
+        .context(HttpSnafu)
+}
+
+async fn handle(
+    server: Arc<TrackerServer>,
+    remote: SocketAddr,
+    req: Request<Body>,
+) -> Result<Response<Body>, Infallible> {
+    let query = Query::decode(req.uri().query().unwrap_or(""));
+    let body = match req.uri().path() {
+        "/announce" => announce(&server, remote, &query).await,
+        "/scrape" => scrape(&server, &query).await,
+        _ => {
+            return Ok(Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body(Body::empty())
+                .unwrap())
+        }
+    };
+
+    let body = body.unwrap_or_else(|reason| {
+        debug!("Rejecting request from {remote}: {reason}");
+        serde_bencode::to_bytes(&FailureReply {
+            failure_reason: reason,
+        })
+        .unwrap_or_default()
+    });
+    Ok(Response::builder()
+        .header("Content-Type", "text/plain")
+        .body(Body::from(body))
+        .unwrap())
+}
+
+async fn announce(
+    server: &TrackerServer,
+    remote: SocketAddr,
+    query: &Query,
+) -> Result<Vec<u8>, String> {
+    let info_hash = query.info_hash()?;
+    let peer_id = query
+        .get("peer_id")
+        .and_then(|b| PeerId::try_from(b).ok())
+        .ok_or("missing or malformed peer_id")?;
+    let port = query.parse::<u16>("port")?.ok_or("missing port")?;
+    let left = query.parse::<u64>("left")?.ok_or("missing left")?;
+    let event = query
+        .parse::<AnnounceEvent>("event")?
+        .unwrap_or(AnnounceEvent::Empty);
+    let compact = query.parse::<u8>("compact")? != Some(0);
+    let no_peer_id = query.parse::<u8>("no_peer_id")?.is_some_and(|c| c != 0);
+
+    let outcome = server
+        .registry
+        .announce(Announce {
+            info_hash,
+            peer_id,
+            addr: SocketAddr::new(canonical(remote.ip()), port),
+            left,
+            event,
+            num_want: TrackerServer::num_want(query.parse::<i64>("numwant")?),
+            family: None,
+        })
+        .await
+        .map_err(|e| match e {
+            SwarmError::NotAllowed { .. } => e.to_string(),
+            SwarmError::Database { .. } => {
+                warn!("{e}");
+                "internal tracker error".to_string()
+            }
+        })?;
+
+    let (peers, peers6) = if compact {
+        let (v4, v6) = compact_peers(&outcome.peers);
+        (ReplyPeers::Compact(v4), (!v6.is_empty()).then_some(v6))
+    } else {
+        let peers = outcome
+            .peers
+            .iter()
+            .map(|p| ReplyPeer {
+                peer_id: (!no_peer_id).then(|| ByteBuf::from(p.peer_id.as_bytes().to_vec())),
+                ip: p.addr.ip().to_string(),
+                port: p.addr.port(),
+            })
+            .collect();
+        (ReplyPeers::Full(peers), None)
+    };
+
+    serde_bencode::to_bytes(&AnnounceReply {
+        interval: server.interval,
+        min_interval: server.min_interval,
+        complete: outcome.seeders,
+        incomplete: outcome.leechers,
+        peers,
+        peers6,
+        external_ip: ByteBuf::from(ip_to_bytes(&canonical(remote.ip()))),
+    })
+    .map_err(|e| e.to_string())
+}
+
+async fn scrape(server: &TrackerServer, query: &Query) -> Result<Vec<u8>, String> {
+    let info_hashes = query
+        .get_all("info_hash")
+        .map(InfoHash::try_from)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| e.to_string())?;
+    let stats = server.registry.scrape(&info_hashes).await;
+
+    let files = info_hashes
+        .iter()
+        .zip(stats)
+        .map(|(h, s)| {
+            (
+                ByteBuf::from(h.as_bytes().to_vec()),
+                ScrapeFile {
+                    complete: s.seeders,
+                    downloaded: s.completed,
+                    incomplete: s.leechers,
+                },
+            )
+        })
+        .collect();
+    serde_bencode::to_bytes(&ScrapeReply { files }).map_err(|e| e.to_string())
+}
+
+/// Packs peers into the BEP 23 (IPv4) and BEP 7 (IPv6) compact formats.
+fn compact_peers(peers: &[SwarmPeer]) -> (ByteBuf, ByteBuf) {
+    let mut v4 = Vec::new();
+    let mut v6 = Vec::new();
+    for peer in peers {
+        match peer.addr.ip() {
+            IpAddr::V4(ip) => {
+                v4.extend_from_slice(&ip.octets());
+                v4.extend_from_slice(&peer.addr.port().to_be_bytes());
+            }
+            IpAddr::V6(ip) => {
+                v6.extend_from_slice(&ip.octets());
+                v6.extend_from_slice(&peer.addr.port().to_be_bytes());
+            }
+        }
+    }
+    (ByteBuf::from(v4), ByteBuf::from(v6))
+}
+
+/// A decoded query string. Values are kept as raw bytes since `info_hash` and
+/// `peer_id` are binary.
+struct Query(Vec<(String, Vec<u8>)>);
+
+impl Query {
+    fn decode(query: &str) -> Self {
+        let decode = |s: &str| percent_decode_str(&s.replace('+', " ")).collect::<Vec<u8>>();
+        Query(
+            query
+                .split('&')
+                .filter(|pair| !pair.is_empty())
+                .map(|pair| {
+                    let (k, v) = pair.split_once('=').unwrap_or((pair, ""));
+                    (String::from_utf8_lossy(&decode(k)).into_owned(), decode(v))
+                })
+                .collect(),
+        )
+    }
+
+    fn get<'a>(&'a self, key: &'a str) -> Option<&'a [u8]> {
+        self.get_all(key).next()
+    }
+
+    fn get_all<'a>(&'a self, key: &'a str) -> impl Iterator<Item = &'a [u8]> {
+        self.0
+            .iter()
+            .filter(move |(k, _)| k == key)
+            .map(|(_, v)| v.as_slice())
+    }
+
+    fn parse<T: FromStr>(&self, key: &str) -> Result<Option<T>, String> {
+        self.get(key)
+            .map(|v| {
+                std::str::from_utf8(v)
+                    .ok()
+                    .and_then(|s| s.parse().ok())
+                    .ok_or_else(|| format!("malformed {key}"))
+            })
+            .transpose()
+    }
+
+    fn info_hash(&self) -> Result<InfoHash, String> {
+        self.get("info_hash")
+            .and_then(|b| InfoHash::try_from(b).ok())
+            .ok_or_else(|| "missing or malformed info_hash".to_string())
+    }
+}
+
+#[derive(Serialize)]
+struct FailureReply {
+    #[serde(rename = "failure reason")]
+    failure_reason: String,
+}
+
+#[derive(Serialize)]
+struct AnnounceReply {
+    interval: u32,
+    #[serde(rename = "min interval", skip_serializing_if = "Option::is_none")]
+    min_interval: Option<u32>,
+    complete: u32,
+    incomplete: u32,
+    peers: ReplyPeers,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    peers6: Option<ByteBuf>,
+    #[serde(rename = "external ip")]
+    external_ip: ByteBuf,
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+enum ReplyPeers {
+    Compact(ByteBuf),
+    Full(Vec<ReplyPeer>),
+}
+
+#[derive(Serialize)]
+struct ReplyPeer {
+    #[serde(rename = "peer id", skip_serializing_if = "Option::is_none")]
+    peer_id: Option<ByteBuf>,
+    ip: String,
+    port: u16,
+}
+
+#[derive(Serialize)]
+struct ScrapeReply {
+    files: BTreeMap<ByteBuf, ScrapeFile>,
+}
+
+#[derive(Serialize)]
+struct ScrapeFile {
+    complete: u32,
+    downloaded: u32,
+    incomplete: u32,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Query;
+
+    #[test]
+    fn query_keeps_binary_values() {
+        let query = Query::decode("info_hash=%00%FF%2B+a&left=10&info_hash=b");
+        assert_eq!(
+            query.get("info_hash"),
+            Some(&[0x00, 0xff, b'+', b' ', b'a'][..])
+        );
+        assert_eq!(query.get_all("info_hash").count(), 2);
+        assert_eq!(query.parse::<u64>("left"), Ok(Some(10)));
+        assert_eq!(query.parse::<u64>("right"), Ok(None));
+        assert!(query.parse::<u64>("info_hash").is_err());
+    }
+}
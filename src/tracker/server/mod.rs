@@ -0,0 +1,152 @@
+//! A built-in BitTorrent tracker, serving both the HTTP (BEP 3, BEP 23) and
+//! UDP (BEP 15) protocols from a single shared set of swarms.
+
+mod http;
+mod swarm;
+mod udp;
+
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::{info, warn};
+use serde::Deserialize;
+use snafu::prelude::*;
+use snafu::Whatever;
+use tokio::task::JoinSet;
+
+use self::swarm::SwarmRegistry;
+use crate::db;
+use crate::torrent::InfoHash;
+
+/// Errors that stop one of the tracker's front ends.
+#[derive(Debug, Snafu)]
+pub(crate) enum ServerError {
+    #[snafu(display("HTTP tracker failed: {source}"))]
+    Http { source: hyper::Error },
+    #[snafu(display("UDP tracker socket failed: {source}"))]
+    Udp { source: std::io::Error },
+}
+
+/// Peers asking for more than this many peers get this many.
+const MAX_NUMWANT: usize = 200;
+const DEFAULT_NUMWANT: usize = 50;
+
+#[derive(Debug, Clone, Deserialize)]
+pub(crate) struct ServerConfig {
+    /// Address to serve HTTP announces and scrapes on.
+    #[serde(default)]
+    http: Option<SocketAddr>,
+    /// Address to serve UDP announces and scrapes on.
+    #[serde(default)]
+    udp: Option<SocketAddr>,
+    #[serde(default = "default_interval")]
+    interval: u32,
+    #[serde(default)]
+    min_interval: Option<u32>,
+    /// Seconds without an announce before a peer is dropped. Defaults to
+    /// twice the announce interval.
+    #[serde(default)]
+    peer_timeout: Option<u64>,
+    #[serde(default = "default_database")]
+    database: String,
+    /// Hex info hashes to serve. Every torrent is served when unset.
+    #[serde(default)]
+    allowlist: Option<Vec<String>>,
+}
+
+fn default_interval() -> u32 {
+    1800
+}
+
+fn default_database() -> String {
+    "sqlite://tracker.db?mode=rwc".to_string()
+}
+
+impl ServerConfig {
+    fn peer_timeout(&self) -> Duration {
+        Duration::from_secs(self.peer_timeout.unwrap_or(2 * u64::from(self.interval)))
+    }
+}
+
+/// State shared by the HTTP and UDP front ends.
+pub(crate) struct TrackerServer {
+    registry: SwarmRegistry,
+    interval: u32,
+    min_interval: Option<u32>,
+}
+
+impl TrackerServer {
+    fn num_want(requested: Option<i64>) -> usize {
+        match requested {
+            Some(n) if n >= 0 => (n as usize).min(MAX_NUMWANT),
+            _ => DEFAULT_NUMWANT,
+        }
+    }
+}
+
+pub(crate) async fn run(config: ServerConfig) -> Result<(), Whatever> {
+    if config.http.is_none() && config.udp.is_none() {
+        whatever!("Tracker needs at least one of `http` or `udp` to listen on")
+    }
+    if config.peer_timeout().is_zero() {
+        whatever!("Tracker `peer_timeout`, or `interval` when it's unset, must be positive")
+    }
+
+    let allowlist = config
+        .allowlist
+        .as_ref()
+        .map(|list| {
+            list.iter()
+                .map(|h| InfoHash::from_hex(h))
+                .collect::<Result<HashSet<_>, _>>()
+        })
+        .transpose()
+        .whatever_context("Invalid info hash in tracker allowlist")?;
+
+    let db = db::connect(&config.database)
+        .await
+        .with_whatever_context(|_| format!("Couldn't open database {}", config.database))?;
+    let registry = SwarmRegistry::load(db, allowlist, config.peer_timeout())
+        .await
+        .whatever_context("Couldn't load swarms")?;
+    let server = Arc::new(TrackerServer {
+        registry,
+        interval: config.interval,
+        min_interval: config.min_interval,
+    });
+
+    let mut tasks = JoinSet::new();
+    if let Some(addr) = config.http {
+        let listener =
+            std::net::TcpListener::bind(addr).whatever_context("Couldn't bind HTTP tracker")?;
+        info!("Serving HTTP tracker on {addr}");
+        tasks.spawn(http::serve(server.clone(), listener));
+    }
+    if let Some(addr) = config.udp {
+        let socket = tokio::net::UdpSocket::bind(addr)
+            .await
+            .whatever_context("Couldn't bind UDP tracker")?;
+        info!("Serving UDP tracker on {addr}");
+        tasks.spawn(udp::serve(server.clone(), socket));
+    }
+
+    tasks.spawn(expire_peers(server.clone(), config.peer_timeout() / 2));
+
+    while let Some(res) = tasks.join_next().await {
+        res.whatever_context("Tracker task panicked")?
+            .whatever_context("Tracker stopped")?;
+    }
+    Ok(())
+}
+
+async fn expire_peers(server: Arc<TrackerServer>, period: Duration) -> Result<(), ServerError> {
+    let mut ticker = tokio::time::interval(period);
+    loop {
+        ticker.tick().await;
+        if let Err(e) = server.registry.expire().await {
+            warn!("Couldn't expire peers: {e}");
+        }
+    }
+}
@@ -0,0 +1,513 @@
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::{debug, info, warn};
+use rand::seq::IteratorRandom;
+use sea_orm::sea_query::OnConflict;
+use sea_orm::{ActiveValue, ColumnTrait, DatabaseConnection, DbErr, EntityTrait, QueryFilter};
+use snafu::prelude::*;
+use tokio::sync::Mutex;
+
+use crate::db::{now, swarm, swarm_peer};
+use crate::torrent::{InfoHash, PeerId};
+use crate::tracker::AnnounceEvent;
+
+#[derive(Debug, Snafu)]
+pub(crate) enum SwarmError {
+    #[snafu(display("info hash {info_hash} is not on the allowlist"))]
+    NotAllowed { info_hash: InfoHash },
+    #[snafu(display("tracker database error: {source}"))]
+    Database { source: DbErr },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum IpFamily {
+    V4,
+    V6,
+}
+
+impl IpFamily {
+    pub(crate) fn of(ip: &IpAddr) -> Self {
+        match ip {
+            IpAddr::V4(_) => IpFamily::V4,
+            IpAddr::V6(_) => IpFamily::V6,
+        }
+    }
+}
+
+/// IPv4 clients reaching a dual-stack listener show up as mapped addresses.
+pub(crate) fn canonical(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+        IpAddr::V4(_) => ip,
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct SwarmPeer {
+    pub(crate) peer_id: PeerId,
+    pub(crate) addr: SocketAddr,
+    left: u64,
+    last_seen: i64,
+}
+
+impl SwarmPeer {
+    fn is_seed(&self) -> bool {
+        self.left == 0
+    }
+}
+
+#[derive(Debug, Default)]
+struct Swarm {
+    peers: HashMap<PeerId, SwarmPeer>,
+    downloaded: u64,
+    /// Held from updating memory until the database has caught up, so the
+    /// swarm's writes land in the order they were made.
+    writes: Arc<Mutex<()>>,
+}
+
+impl Swarm {
+    fn stats(&self) -> ScrapeStats {
+        let seeders = self.peers.values().filter(|p| p.is_seed()).count();
+        ScrapeStats {
+            seeders: seeders as u32,
+            completed: self.downloaded as u32,
+            leechers: (self.peers.len() - seeders) as u32,
+        }
+    }
+}
+
+/// A single announce, already decoded from either HTTP or UDP.
+#[derive(Debug)]
+pub(crate) struct Announce {
+    pub(crate) info_hash: InfoHash,
+    pub(crate) peer_id: PeerId,
+    pub(crate) addr: SocketAddr,
+    pub(crate) left: u64,
+    pub(crate) event: AnnounceEvent,
+    pub(crate) num_want: usize,
+    /// Only return peers of this family, for transports that can't carry both.
+    pub(crate) family: Option<IpFamily>,
+}
+
+#[derive(Debug)]
+pub(crate) struct AnnounceOutcome {
+    pub(crate) seeders: u32,
+    pub(crate) leechers: u32,
+    pub(crate) peers: Vec<SwarmPeer>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct ScrapeStats {
+    pub(crate) seeders: u32,
+    pub(crate) completed: u32,
+    pub(crate) leechers: u32,
+}
+
+/// Every swarm the tracker knows about. Kept in memory and written through to
+/// the database so a restarted tracker doesn't start out empty.
+pub(crate) struct SwarmRegistry {
+    swarms: Mutex<HashMap<InfoHash, Swarm>>,
+    allowlist: Option<HashSet<InfoHash>>,
+    peer_timeout: Duration,
+    db: DatabaseConnection,
+}
+
+impl SwarmRegistry {
+    pub(crate) async fn load(
+        db: DatabaseConnection,
+        allowlist: Option<HashSet<InfoHash>>,
+        peer_timeout: Duration,
+    ) -> Result<Self, SwarmError> {
+        let mut swarms = HashMap::<InfoHash, Swarm>::new();
+        for row in swarm::Entity::find()
+            .all(&db)
+            .await
+            .context(DatabaseSnafu)?
+        {
+            match InfoHash::try_from(row.info_hash.as_slice()) {
+                Ok(info_hash) => {
+                    swarms.entry(info_hash).or_default().downloaded = row.downloaded as u64
+                }
+                Err(e) => warn!("Skipping stored swarm: {e}"),
+            }
+        }
+
+        let cutoff = now() - peer_timeout.as_secs() as i64;
+        for row in swarm_peer::Entity::find()
+            .filter(swarm_peer::Column::LastSeen.gte(cutoff))
+            .all(&db)
+            .await
+            .context(DatabaseSnafu)?
+        {
+            let (Ok(info_hash), Ok(peer_id), Ok(ip)) = (
+                InfoHash::try_from(row.info_hash.as_slice()),
+                PeerId::try_from(row.peer_id.as_slice()),
+                row.ip.parse::<IpAddr>(),
+            ) else {
+                warn!("Skipping malformed stored peer {:?}", row);
+                continue;
+            };
+            swarms.entry(info_hash).or_default().peers.insert(
+                peer_id.clone(),
+                SwarmPeer {
+                    peer_id,
+                    addr: SocketAddr::new(ip, row.port as u16),
+                    left: row.left as u64,
+                    last_seen: row.last_seen,
+                },
+            );
+        }
+        info!("Loaded {} swarms from the tracker database", swarms.len());
+
+        Ok(Self {
+            swarms: Mutex::new(swarms),
+            allowlist,
+            peer_timeout,
+            db,
+        })
+    }
+
+    pub(crate) async fn announce(&self, req: Announce) -> Result<AnnounceOutcome, SwarmError> {
+        if let Some(allowlist) = &self.allowlist {
+            ensure!(
+                allowlist.contains(&req.info_hash),
+                NotAllowedSnafu {
+                    info_hash: req.info_hash.clone()
+                }
+            );
+        }
+
+        // The swarms are only locked to update memory and pick the reply,
+        // so a slow database doesn't hold up announces for other torrents.
+        // Announces to one torrent wait their turn, so a stop can't reach
+        // the database after the start that followed it.
+        let writes = {
+            let mut swarms = self.swarms.lock().await;
+            swarms
+                .entry(req.info_hash.clone())
+                .or_default()
+                .writes
+                .clone()
+        };
+        let _writing = writes.lock().await;
+        let peer = (req.event != AnnounceEvent::Stopped).then(|| SwarmPeer {
+            peer_id: req.peer_id.clone(),
+            addr: req.addr,
+            left: req.left,
+            last_seen: now(),
+        });
+        let (outcome, downloaded) = {
+            let mut swarms = self.swarms.lock().await;
+            let swarm = swarms.entry(req.info_hash.clone()).or_default();
+            match &peer {
+                Some(peer) => swarm.peers.insert(req.peer_id.clone(), peer.clone()),
+                None => swarm.peers.remove(&req.peer_id),
+            };
+            if req.event == AnnounceEvent::Completed {
+                swarm.downloaded += 1;
+            }
+
+            // Seeds have no use for other seeds.
+            let seeding = req.left == 0;
+            let peers = swarm
+                .peers
+                .values()
+                .filter(|p| p.peer_id != req.peer_id)
+                .filter(|p| !(seeding && p.is_seed()))
+                .filter(|p| req.family.is_none() || req.family == Some(IpFamily::of(&p.addr.ip())))
+                .cloned()
+                .choose_multiple(&mut rand::thread_rng(), req.num_want);
+
+            let stats = swarm.stats();
+            let outcome = AnnounceOutcome {
+                seeders: stats.seeders,
+                leechers: stats.leechers,
+                peers,
+            };
+            (outcome, swarm.downloaded)
+        };
+
+        match &peer {
+            Some(peer) => self.store_peer(&req.info_hash, peer).await?,
+            None => {
+                swarm_peer::Entity::delete_by_id((
+                    req.info_hash.as_bytes().to_vec(),
+                    req.peer_id.as_bytes().to_vec(),
+                ))
+                .exec(&self.db)
+                .await
+                .context(DatabaseSnafu)?;
+            }
+        }
+        if req.event == AnnounceEvent::Completed {
+            swarm::Entity::insert(swarm::ActiveModel {
+                info_hash: ActiveValue::Set(req.info_hash.as_bytes().to_vec()),
+                downloaded: ActiveValue::Set(downloaded as i64),
+            })
+            .on_conflict(
+                OnConflict::column(swarm::Column::InfoHash)
+                    .update_column(swarm::Column::Downloaded)
+                    .to_owned(),
+            )
+            .exec(&self.db)
+            .await
+            .context(DatabaseSnafu)?;
+        }
+        Ok(outcome)
+    }
+
+    async fn store_peer(&self, info_hash: &InfoHash, peer: &SwarmPeer) -> Result<(), SwarmError> {
+        swarm_peer::Entity::insert(swarm_peer::ActiveModel {
+            info_hash: ActiveValue::Set(info_hash.as_bytes().to_vec()),
+            peer_id: ActiveValue::Set(peer.peer_id.as_bytes().to_vec()),
+            ip: ActiveValue::Set(peer.addr.ip().to_string()),
+            port: ActiveValue::Set(peer.addr.port().into()),
+            left: ActiveValue::Set(peer.left as i64),
+            last_seen: ActiveValue::Set(peer.last_seen),
+        })
+        .on_conflict(
+            OnConflict::columns([swarm_peer::Column::InfoHash, swarm_peer::Column::PeerId])
+                .update_columns([
+                    swarm_peer::Column::Ip,
+                    swarm_peer::Column::Port,
+                    swarm_peer::Column::Left,
+                    swarm_peer::Column::LastSeen,
+                ])
+                .to_owned(),
+        )
+        .exec(&self.db)
+        .await
+        .context(DatabaseSnafu)?;
+        Ok(())
+    }
+
+    pub(crate) async fn scrape(&self, info_hashes: &[InfoHash]) -> Vec<ScrapeStats> {
+        let swarms = self.swarms.lock().await;
+        info_hashes
+            .iter()
+            .map(|h| swarms.get(h).map(Swarm::stats).unwrap_or_default())
+            .collect()
+    }
+
+    /// Drops peers that haven't announced within the peer timeout.
+    pub(crate) async fn expire(&self) -> Result<(), SwarmError> {
+        let cutoff = now() - self.peer_timeout.as_secs() as i64;
+        let mut swarms = self.swarms.lock().await;
+        let mut expired = 0;
+        for swarm in swarms.values_mut() {
+            let before = swarm.peers.len();
+            swarm.peers.retain(|_, p| p.last_seen >= cutoff);
+            expired += before - swarm.peers.len();
+        }
+        // Swarms with announces underway are kept, so those announces stay
+        // in order with the ones after them.
+        swarms.retain(|_, s| {
+            !s.peers.is_empty() || s.downloaded > 0 || Arc::strong_count(&s.writes) > 1
+        });
+        drop(swarms);
+
+        swarm_peer::Entity::delete_many()
+            .filter(swarm_peer::Column::LastSeen.lt(cutoff))
+            .exec(&self.db)
+            .await
+            .context(DatabaseSnafu)?;
+        debug!("Expired {expired} peers");
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::net::SocketAddr;
+    use std::time::Duration;
+
+    use sea_orm::DatabaseConnection;
+
+    use super::{Announce, IpFamily, ScrapeStats, SwarmError, SwarmRegistry};
+    use crate::db::now;
+    use crate::torrent::{InfoHash, PeerId};
+    use crate::tracker::AnnounceEvent;
+
+    const HOUR: Duration = Duration::from_secs(3600);
+
+    fn announce(n: u8, left: u64, event: AnnounceEvent) -> Announce {
+        Announce {
+            info_hash: InfoHash::from([1; 20]),
+            peer_id: PeerId::try_from(&[n; 20][..]).unwrap(),
+            addr: SocketAddr::from(([10, 0, 0, n], 6881)),
+            left,
+            event,
+            num_want: 50,
+            family: None,
+        }
+    }
+
+    async fn registry(db: &DatabaseConnection) -> SwarmRegistry {
+        SwarmRegistry::load(db.clone(), None, HOUR).await.unwrap()
+    }
+
+    async fn stats(registry: &SwarmRegistry) -> ScrapeStats {
+        registry.scrape(&[InfoHash::from([1; 20])]).await[0]
+    }
+
+    #[tokio::test]
+    async fn announces_and_scrapes() {
+        let db = crate::db::connect("sqlite::memory:").await.unwrap();
+        let registry = registry(&db).await;
+
+        let first = registry
+            .announce(announce(1, 100, AnnounceEvent::Started))
+            .await
+            .unwrap();
+        assert!(first.peers.is_empty());
+        assert_eq!((first.seeders, first.leechers), (0, 1));
+
+        let seed = registry
+            .announce(announce(2, 0, AnnounceEvent::Completed))
+            .await
+            .unwrap();
+        assert_eq!(seed.peers.len(), 1);
+        assert_eq!(seed.peers[0].addr, SocketAddr::from(([10, 0, 0, 1], 6881)));
+        // Seeds aren't handed to other seeds.
+        let other_seed = registry
+            .announce(announce(3, 0, AnnounceEvent::Started))
+            .await
+            .unwrap();
+        assert_eq!(other_seed.peers.len(), 1);
+        let v6_only = registry
+            .announce(Announce {
+                family: Some(IpFamily::V6),
+                ..announce(1, 100, AnnounceEvent::Empty)
+            })
+            .await
+            .unwrap();
+        assert!(v6_only.peers.is_empty());
+        assert_eq!(
+            stats(&registry).await,
+            ScrapeStats {
+                seeders: 2,
+                completed: 1,
+                leechers: 1,
+            }
+        );
+
+        registry
+            .announce(announce(1, 100, AnnounceEvent::Stopped))
+            .await
+            .unwrap();
+        assert_eq!(stats(&registry).await.leechers, 0);
+        let unknown = registry.scrape(&[InfoHash::from([2; 20])]).await;
+        assert_eq!(unknown, vec![ScrapeStats::default()]);
+    }
+
+    #[tokio::test]
+    async fn expires_silent_peers() {
+        let db = crate::db::connect("sqlite::memory:").await.unwrap();
+        let registry = registry(&db).await;
+        registry
+            .announce(announce(1, 100, AnnounceEvent::Started))
+            .await
+            .unwrap();
+        let stale = registry
+            .announce(announce(2, 100, AnnounceEvent::Started))
+            .await
+            .unwrap();
+        assert_eq!(stale.peers.len(), 1);
+
+        // Peer 2 last announced long ago, in memory and on disk.
+        let old = announce(2, 100, AnnounceEvent::Empty);
+        let peer = {
+            let mut swarms = registry.swarms.lock().await;
+            let peer = swarms
+                .get_mut(&old.info_hash)
+                .unwrap()
+                .peers
+                .get_mut(&old.peer_id)
+                .unwrap();
+            peer.last_seen = now() - 2 * HOUR.as_secs() as i64;
+            peer.clone()
+        };
+        registry.store_peer(&old.info_hash, &peer).await.unwrap();
+
+        registry.expire().await.unwrap();
+        assert_eq!(stats(&registry).await.leechers, 1);
+        assert_eq!(stats(&self::registry(&db).await).await.leechers, 1);
+    }
+
+    #[tokio::test]
+    async fn only_serves_the_allowlist() {
+        let db = crate::db::connect("sqlite::memory:").await.unwrap();
+        let allowlist = HashSet::from([InfoHash::from([2; 20])]);
+        let registry = SwarmRegistry::load(db, Some(allowlist), HOUR)
+            .await
+            .unwrap();
+
+        let refused = registry
+            .announce(announce(1, 100, AnnounceEvent::Started))
+            .await;
+        assert!(matches!(refused, Err(SwarmError::NotAllowed { .. })));
+        let allowed = Announce {
+            info_hash: InfoHash::from([2; 20]),
+            ..announce(1, 100, AnnounceEvent::Started)
+        };
+        assert!(registry.announce(allowed).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn survives_a_restart() {
+        let db = crate::db::connect("sqlite::memory:").await.unwrap();
+        let registry = registry(&db).await;
+        for (n, left, event) in [
+            (1, 100, AnnounceEvent::Started),
+            (2, 0, AnnounceEvent::Completed),
+            (3, 100, AnnounceEvent::Started),
+            (3, 100, AnnounceEvent::Stopped),
+        ] {
+            registry.announce(announce(n, left, event)).await.unwrap();
+        }
+        let before = stats(&registry).await;
+        drop(registry);
+
+        let restarted = self::registry(&db).await;
+        assert_eq!(stats(&restarted).await, before);
+        let reply = restarted
+            .announce(announce(4, 100, AnnounceEvent::Started))
+            .await
+            .unwrap();
+        let mut addrs: Vec<_> = reply.peers.iter().map(|p| p.addr).collect();
+        addrs.sort();
+        assert_eq!(
+            addrs,
+            vec![
+                SocketAddr::from(([10, 0, 0, 1], 6881)),
+                SocketAddr::from(([10, 0, 0, 2], 6881)),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn stores_concurrent_announces_in_order() {
+        let db = crate::db::connect("sqlite::memory:").await.unwrap();
+        let registry = registry(&db).await;
+        futures::future::join_all((1..=20).map(|n| {
+            let event = match n % 2 {
+                0 => AnnounceEvent::Stopped,
+                _ => AnnounceEvent::Completed,
+            };
+            registry.announce(announce(n / 2 + 1, 0, event))
+        }))
+        .await
+        .into_iter()
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+        let before = stats(&registry).await;
+        assert_eq!(before.completed, 10);
+        drop(registry);
+
+        assert_eq!(stats(&self::registry(&db).await).await, before);
+    }
+}
@@ -0,0 +1,293 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use deku::prelude::*;
+use log::{debug, warn};
+use snafu::prelude::*;
+use tokio::net::UdpSocket;
+
+use super::swarm::{canonical, Announce, IpFamily, SwarmError, SwarmPeer};
+use super::{ServerError, TrackerServer, UdpSnafu};
+use crate::torrent::{InfoHash, PeerId};
+use crate::tracker::udp::{
+    Action, AnnounceRequest, AnnounceResponsePeerV4, AnnounceResponsePeerV6, AnnounceResponseV4,
+    AnnounceResponseV6, ConnectRequest, ConnectResponse, ErrorResponse, ScrapeRequest,
+    ScrapeResponse, ScrapeResponseFile,
+};
+
+/// BEP 15 caps a scrape at about 74 info hashes per packet.
+const MAX_SCRAPE: usize = 74;
+
+/// Connection IDs are valid for this many seconds, plus however far into the
+/// current window the connect happened.
+const CONNECTION_ID_WINDOW: u64 = 120;
+
+/// Hands out connection IDs without keeping any per-client state: an ID is a
+/// keyed hash of the client address and the current time window.
+struct ConnectionIds {
+    key: RandomState,
+}
+
+impl ConnectionIds {
+    fn window() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs()
+            / CONNECTION_ID_WINDOW
+    }
+
+    fn issue(&self, addr: &SocketAddr) -> u64 {
+        self.id_for(addr, Self::window())
+    }
+
+    fn is_valid(&self, addr: &SocketAddr, id: u64) -> bool {
+        let window = Self::window();
+        id == self.id_for(addr, window) || id == self.id_for(addr, window.wrapping_sub(1))
+    }
+
+    fn id_for(&self, addr: &SocketAddr, window: u64) -> u64 {
+        let mut hasher = self.key.build_hasher();
+        addr.hash(&mut hasher);
+        window.hash(&mut hasher);
+        hasher.finish()
+    }
+}
+
+pub(super) async fn serve(
+    server: Arc<TrackerServer>,
+    socket: UdpSocket,
+) -> Result<(), ServerError> {
+    let socket = Arc::new(socket);
+    let ids = Arc::new(ConnectionIds {
+        key: RandomState::new(),
+    });
+    let mut buf = [0_u8; 2048];
+    loop {
+        let (len, from) = socket.recv_from(&mut buf).await.context(UdpSnafu)?;
+        let packet = buf[..len].to_vec();
+        let (server, socket, ids) = (server.clone(), socket.clone(), ids.clone());
+        tokio::spawn(async move {
+            if let Some(reply) = handle(&server, &ids, from, &packet).await {
+                if let Err(e) = socket.send_to(&reply, from).await {
+                    debug!("Couldn't reply to {from}: {e}");
+                }
+            }
+        });
+    }
+}
+
+/// Returns the datagram to send back, if any. Malformed packets are dropped
+/// silently, as are requests with a stale connection ID.
+async fn handle(
+    server: &TrackerServer,
+    ids: &ConnectionIds,
+    from: SocketAddr,
+    packet: &[u8],
+) -> Option<Vec<u8>> {
+    match Action::of_request(packet)? {
+        Action::Connect => {
+            let (_, req) = ConnectRequest::from_bytes((packet, 0)).ok()?;
+            ConnectResponse::new(req.transaction_id(), ids.issue(&from))
+                .to_bytes()
+                .ok()
+        }
+        Action::Announce => {
+            let (_, req) = AnnounceRequest::from_bytes((packet, 0)).ok()?;
+            if !ids.is_valid(&from, req.connection_id()) {
+                return None;
+            }
+            announce(server, from, req).await
+        }
+        Action::Scrape => {
+            let (_, req) = ScrapeRequest::from_bytes((packet, 0)).ok()?;
+            if !ids.is_valid(&from, req.connection_id()) {
+                return None;
+            }
+            let info_hashes = &req.info_hashes()[..req.info_hashes().len().min(MAX_SCRAPE)];
+            let files = server
+                .registry
+                .scrape(info_hashes)
+                .await
+                .into_iter()
+                .map(|s| ScrapeResponseFile::new(s.seeders, s.completed, s.leechers))
+                .collect();
+            ScrapeResponse::new(req.transaction_id(), files)
+                .to_bytes()
+                .ok()
+        }
+        Action::Error => None,
+    }
+}
+
+async fn announce(
+    server: &TrackerServer,
+    from: SocketAddr,
+    req: AnnounceRequest,
+) -> Option<Vec<u8>> {
+    let ip = canonical(from.ip());
+    let family = IpFamily::of(&ip);
+    let outcome = server
+        .registry
+        .announce(Announce {
+            info_hash: InfoHash::from(*req.info_hash()),
+            peer_id: PeerId::try_from(&req.peer_id()[..]).ok()?,
+            addr: SocketAddr::new(ip, req.port()),
+            left: req.left(),
+            event: req.event().clone(),
+            num_want: TrackerServer::num_want(Some(req.num_want().into())),
+            family: Some(family),
+        })
+        .await;
+
+    let outcome = match outcome {
+        Ok(outcome) => outcome,
+        Err(e) => {
+            let message = match e {
+                SwarmError::NotAllowed { .. } => e.to_string(),
+                SwarmError::Database { .. } => {
+                    warn!("{e}");
+                    "internal tracker error".to_string()
+                }
+            };
+            return ErrorResponse::new(req.transaction_id(), &message)
+                .to_bytes()
+                .ok();
+        }
+    };
+
+    let interval = server.interval;
+    match family {
+        IpFamily::V4 => AnnounceResponseV4::new(
+            req.transaction_id(),
+            interval,
+            outcome.leechers,
+            outcome.seeders,
+            outcome.peers.iter().filter_map(peer_v4).collect(),
+        )
+        .to_bytes()
+        .ok(),
+        IpFamily::V6 => AnnounceResponseV6::new(
+            req.transaction_id(),
+            interval,
+            outcome.leechers,
+            outcome.seeders,
+            outcome.peers.iter().filter_map(peer_v6).collect(),
+        )
+        .to_bytes()
+        .ok(),
+    }
+}
+
+fn peer_v4(peer: &SwarmPeer) -> Option<AnnounceResponsePeerV4> {
+    match peer.addr.ip() {
+        IpAddr::V4(ip) => Some(AnnounceResponsePeerV4::new(&ip.octets(), peer.addr.port())),
+        IpAddr::V6(_) => None,
+    }
+}
+
+fn peer_v6(peer: &SwarmPeer) -> Option<AnnounceResponsePeerV6> {
+    match peer.addr.ip() {
+        IpAddr::V6(ip) => Some(AnnounceResponsePeerV6::new(&ip.octets(), peer.addr.port())),
+        IpAddr::V4(_) => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::hash_map::RandomState;
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use deku::prelude::*;
+    use tokio::net::UdpSocket;
+
+    use super::{handle, serve, ConnectionIds};
+    use crate::torrent::{InfoHash, PeerId};
+    use crate::tracker::server::swarm::SwarmRegistry;
+    use crate::tracker::server::TrackerServer;
+    use crate::tracker::udp::{AnnounceRequest, UDPTracker};
+    use crate::tracker::{AnnounceEvent, Tracker};
+
+    async fn server() -> Arc<TrackerServer> {
+        let db = crate::db::connect("sqlite::memory:").await.unwrap();
+        let registry = SwarmRegistry::load(db, None, Duration::from_secs(3600))
+            .await
+            .unwrap();
+        Arc::new(TrackerServer {
+            registry,
+            interval: 900,
+            min_interval: None,
+        })
+    }
+
+    #[test]
+    fn connection_ids_expire() {
+        let ids = ConnectionIds {
+            key: RandomState::new(),
+        };
+        let addr = SocketAddr::from(([10, 0, 0, 1], 6881));
+        let window = ConnectionIds::window();
+        assert!(ids.is_valid(&addr, ids.issue(&addr)));
+        assert!(ids.is_valid(&addr, ids.id_for(&addr, window - 1)));
+        assert!(!ids.is_valid(&addr, ids.id_for(&addr, window - 2)));
+        let elsewhere = SocketAddr::from(([10, 0, 0, 2], 6881));
+        assert!(!ids.is_valid(&elsewhere, ids.issue(&addr)));
+    }
+
+    #[tokio::test]
+    async fn drops_announces_without_a_connection_id() {
+        let server = server().await;
+        let ids = ConnectionIds {
+            key: RandomState::new(),
+        };
+        let from = SocketAddr::from(([10, 0, 0, 1], 6881));
+        let announce = AnnounceRequest::new(
+            ids.issue(&from) ^ 1,
+            7,
+            &[1; 20],
+            &[2; 20],
+            0,
+            100,
+            0,
+            AnnounceEvent::Started,
+            None,
+            0,
+            None,
+            6881,
+        );
+        let packet = announce.to_bytes().unwrap();
+        assert_eq!(handle(&server, &ids, from, &packet).await, None);
+    }
+
+    #[tokio::test]
+    async fn connects_and_announces() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("udp://{}/announce", socket.local_addr().unwrap());
+        tokio::spawn(serve(server().await, socket));
+        let tracker = UDPTracker::new(url.parse().unwrap()).unwrap();
+        let announce = |port| {
+            tracker.get_peers(
+                InfoHash::from([1; 20]),
+                PeerId::new(),
+                None,
+                port,
+                0,
+                0,
+                100,
+                AnnounceEvent::Started,
+            )
+        };
+
+        let first = announce(6881).await.unwrap();
+        assert!(first.peers.is_empty());
+        assert_eq!(first.interval, 900);
+        let second = announce(6882).await.unwrap();
+        assert_eq!(second.peers, vec![SocketAddr::from(([127, 0, 0, 1], 6881))]);
+        assert_eq!((second.leechers, second.seeders), (Some(2), Some(0)));
+    }
+}
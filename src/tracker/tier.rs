@@ -0,0 +1,458 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::Instant;
+
+use log::{debug, info, warn};
+use rand::seq::SliceRandom;
+use sea_orm::DatabaseConnection;
+use url::Url;
+
+use super::health::{HealthStore, TrackerHealth};
+use super::{AnnounceEvent, AnnounceResponse, AnyTracker, Tracker, TrackerError};
+use crate::db;
+use crate::external_ip::{ExternalIp, Voter};
+use crate::net::UdpMux;
+use crate::torrent::{InfoHash, PeerId, Torrent};
+
+/// Announces to a torrent's trackers in the order BEP 12 prescribes: tiers
+/// are tried in turn, trackers within a tier are shuffled once, and a tracker
+/// that answers is moved to the front of its tier.
+/// Trackers that have been failing are skipped until their backoff expires.
+/// See: http://www.bittorrent.org/beps/bep_0012.html
+pub(crate) struct TierManager {
+    tiers: Vec<Vec<AnyTracker>>,
+    health: HashMap<Url, TrackerHealth>,
+    store: Option<HealthStore>,
+    external_ip: ExternalIp,
+    /// The tracker that answered our last announce, if any did.
+    answered: Option<Url>,
+}
+
+impl TierManager {
+    pub(crate) fn new(mut tiers: Vec<Vec<AnyTracker>>) -> Self {
+        let mut rng = rand::thread_rng();
+        for tier in tiers.iter_mut() {
+            tier.shuffle(&mut rng);
+        }
+        tiers.retain(|tier| !tier.is_empty());
+        Self {
+            tiers,
+            health: HashMap::new(),
+            store: None,
+            external_ip: ExternalIp::default(),
+            answered: None,
+        }
+    }
+
+    /// Shares the session's external address estimate, which trackers vote
+    /// into and which fills in the `ip` we announce when none is given.
+    pub(crate) fn with_external_ip(mut self, external_ip: ExternalIp) -> Self {
+        self.external_ip = external_ip;
+        self
+    }
+
+    /// Announces to UDP trackers from `sockets`, the ones we listen on.
+    pub(crate) fn with_udp_sockets(mut self, sockets: &[UdpMux]) -> Self {
+        self.tiers = self
+            .tiers
+            .into_iter()
+            .map(|tier| {
+                tier.into_iter()
+                    .map(|tracker| match tracker {
+                        AnyTracker::Udp(tracker) => AnyTracker::Udp(tracker.with_sockets(sockets)),
+                        tracker => tracker,
+                    })
+                    .collect()
+            })
+            .collect();
+        self
+    }
+
+    /// Loads tracker health from the database and keeps it up to date there.
+    pub(crate) async fn with_database(mut self, db: DatabaseConnection) -> Self {
+        let store = HealthStore::new(db);
+        match store.load().await {
+            Ok(health) => self.health = health,
+            Err(e) => warn!("Couldn't load tracker health: {e}"),
+        }
+        self.store = Some(store);
+        self
+    }
+
+    /// Uses `announce-list` when present and falls back to `announce`.
+    /// Trackers with URLs we can't use are skipped.
+    pub(crate) fn from_torrent(torrent: &Torrent) -> Self {
+        let urls = match (&torrent.announce_list, &torrent.announce) {
+            (Some(list), _) => list.list.clone(),
+            (None, Some(announce)) => match announce.parse() {
+                Ok(url) => vec![vec![url]],
+                Err(e) => {
+                    warn!("Ignoring announce URL {announce}: {e}");
+                    vec![]
+                }
+            },
+            (None, None) => vec![],
+        };
+
+        Self::from_urls(&urls)
+    }
+
+    /// One tier per list of URLs, skipping ones we can't use.
+    pub(crate) fn from_urls(urls: &[Vec<Url>]) -> Self {
+        Self::new(
+            urls.iter()
+                .map(|tier| {
+                    tier.iter()
+                        .filter_map(|url| match AnyTracker::from_url(url) {
+                            Ok(tracker) => Some(tracker),
+                            Err(e) => {
+                                warn!("Ignoring tracker: {e}");
+                                None
+                            }
+                        })
+                        .collect()
+                })
+                .collect(),
+        )
+    }
+
+    pub(crate) fn tiers(&self) -> &[Vec<AnyTracker>] {
+        &self.tiers
+    }
+
+    #[cfg(test)]
+    pub(crate) fn health(&self, url: &Url) -> Option<&TrackerHealth> {
+        self.health.get(url)
+    }
+
+    /// When we should announce again, in seconds since the Unix epoch: once
+    /// the interval of the tracker that last answered is up, or else when the
+    /// first backoff ends. None when there are no trackers at all.
+    pub(crate) fn next_announce(&self) -> Option<i64> {
+        if let Some(health) = self.answered.as_ref().and_then(|url| self.health.get(url)) {
+            return Some(health.next_announce());
+        }
+        self.tiers
+            .iter()
+            .flatten()
+            .map(|tracker| {
+                self.health
+                    .get(tracker.url())
+                    .map_or(0, TrackerHealth::next_announce)
+            })
+            .min()
+    }
+
+    /// Announces to the first tracker that answers, returning the last error
+    /// if none do. Trackers that are backing off are not tried at all.
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) async fn announce(
+        &mut self,
+        info_hash: InfoHash,
+        peer_id: PeerId,
+        ip: Option<IpAddr>,
+        port: u16,
+        uploaded: u64,
+        downloaded: u64,
+        left: u64,
+        event: AnnounceEvent,
+    ) -> Result<AnnounceResponse, TrackerError> {
+        let ip = ip.or_else(|| self.external_ip.ipv4().map(IpAddr::V4));
+        self.answered = None;
+        let mut last_error = TrackerError::NoTrackers;
+        for tier in self.tiers.iter_mut() {
+            for i in 0..tier.len() {
+                let url = tier[i].url().clone();
+                let health = self.health.entry(url.clone()).or_default();
+                if health.is_backing_off(db::now()) {
+                    debug!(
+                        "Skipping {url}, backing off after {} failures",
+                        health.consecutive_failures()
+                    );
+                    if matches!(last_error, TrackerError::NoTrackers) {
+                        last_error = TrackerError::BackingOff;
+                    }
+                    continue;
+                }
+
+                let started = Instant::now();
+                let result = tier[i]
+                    .get_peers(
+                        info_hash.clone(),
+                        peer_id.clone(),
+                        ip,
+                        port,
+                        uploaded,
+                        downloaded,
+                        left,
+                        event.clone(),
+                    )
+                    .await;
+                match result {
+                    Ok(resp) => {
+                        info!("{url} returned {} peers", resp.peers.len());
+                        if let Some(addr) = resp.external_ip {
+                            self.external_ip.vote(Voter::Tracker(url.to_string()), addr);
+                        }
+                        health.record_success(db::now(), started.elapsed(), &resp);
+                        let health = health.clone();
+                        save_health(self.store.as_ref(), &url, &health).await;
+                        let tracker = tier.remove(i);
+                        tier.insert(0, tracker);
+                        self.answered = Some(url);
+                        return Ok(resp);
+                    }
+                    Err(e) => {
+                        warn!("Announce to {url} failed: {e}");
+                        health.record_failure(db::now(), &e);
+                        if health.is_disabled() {
+                            warn!(
+                                "Disabling {url} after {} failures",
+                                health.consecutive_failures()
+                            );
+                        }
+                        let health = health.clone();
+                        save_health(self.store.as_ref(), &url, &health).await;
+                        last_error = e;
+                    }
+                }
+            }
+        }
+        Err(last_error)
+    }
+}
+
+async fn save_health(store: Option<&HealthStore>, url: &Url, health: &TrackerHealth) {
+    if let Some(store) = store {
+        if let Err(e) = store.save(url, health).await {
+            warn!("Couldn't save health of {url}: {e}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::time::Duration;
+
+    use super::TierManager;
+    use crate::external_ip::{ExternalIp, Voter};
+    use crate::torrent::{InfoHash, PeerId};
+    use crate::tracker::mock::{MockHttpTracker, MockUdpTracker, Script, MOCK_EXTERNAL_IP};
+    use crate::tracker::{AnnounceEvent, AnyTracker, HTTPTracker, UDPTracker};
+
+    #[tokio::test]
+    async fn falls_through_tiers_and_promotes_working_tracker() {
+        let peer: SocketAddr = "10.0.0.3:6881".parse().unwrap();
+        let failing = MockHttpTracker::start(vec![Script::Failure("down".into())]);
+        let timing_out = MockUdpTracker::start(vec![Script::Timeout]).await;
+        let working = MockUdpTracker::start(vec![Script::Peers(vec![peer])]).await;
+
+        let mut manager = TierManager::new(vec![
+            vec![AnyTracker::Http(HTTPTracker::new(failing.url()).unwrap())],
+            vec![
+                AnyTracker::Udp(
+                    UDPTracker::new(timing_out.url())
+                        .unwrap()
+                        .with_timeouts(Duration::from_millis(50), 0),
+                ),
+                AnyTracker::Udp(
+                    UDPTracker::new(working.url())
+                        .unwrap()
+                        .with_timeouts(Duration::from_millis(50), 0),
+                ),
+            ],
+        ]);
+
+        let resp = manager
+            .announce(
+                InfoHash::from([1; 20]),
+                PeerId::new(),
+                None,
+                6881,
+                0,
+                0,
+                100,
+                AnnounceEvent::Started,
+            )
+            .await
+            .unwrap();
+        assert_eq!(resp.peers, vec![peer]);
+        assert_eq!(manager.tiers()[1][0].url(), &working.url());
+    }
+
+    #[tokio::test]
+    async fn trackers_vote_for_external_ip() {
+        let tracker = MockHttpTracker::start(vec![Script::Peers(vec![])]);
+        let external_ip = ExternalIp::default();
+        // A voter on another network already agrees, so the tracker's
+        // `external ip` settles our address.
+        external_ip.vote(
+            Voter::Node("192.0.2.1".parse().unwrap()),
+            MOCK_EXTERNAL_IP.into(),
+        );
+        let mut manager = TierManager::new(vec![vec![AnyTracker::Http(
+            HTTPTracker::new(tracker.url()).unwrap(),
+        )]])
+        .with_external_ip(external_ip.clone());
+
+        for _ in 0..2 {
+            manager
+                .announce(
+                    InfoHash::from([1; 20]),
+                    PeerId::new(),
+                    None,
+                    6881,
+                    0,
+                    0,
+                    100,
+                    AnnounceEvent::Empty,
+                )
+                .await
+                .unwrap();
+        }
+        assert_eq!(external_ip.ipv4(), Some(MOCK_EXTERNAL_IP));
+        // The second announce tells the tracker what the first one learned.
+        assert!(tracker.announces()[1].contains("ip=203.0.113.7"));
+    }
+
+    #[tokio::test]
+    async fn returns_last_error_when_every_tracker_fails() {
+        let failing = MockHttpTracker::start(vec![Script::Failure("down".into())]);
+        let mut manager = TierManager::new(vec![vec![AnyTracker::Http(
+            HTTPTracker::new(failing.url()).unwrap(),
+        )]]);
+
+        let err = manager
+            .announce(
+                InfoHash::from([1; 20]),
+                PeerId::new(),
+                None,
+                6881,
+                0,
+                0,
+                100,
+                AnnounceEvent::Started,
+            )
+            .await
+            .unwrap_err();
+        assert_eq!(err.to_string(), "tracker returned failure: down");
+    }
+
+    #[tokio::test]
+    async fn reannounces_when_the_answering_tracker_asks() {
+        let failing = MockHttpTracker::start(vec![Script::Malformed]);
+        let working = MockHttpTracker::start(vec![Script::Peers(vec![])]);
+        let mut manager = TierManager::new(vec![
+            vec![AnyTracker::Http(HTTPTracker::new(failing.url()).unwrap())],
+            vec![AnyTracker::Http(HTTPTracker::new(working.url()).unwrap())],
+        ]);
+        assert_eq!(manager.next_announce(), Some(0));
+
+        manager
+            .announce(
+                InfoHash::from([1; 20]),
+                PeerId::new(),
+                None,
+                6881,
+                0,
+                0,
+                100,
+                AnnounceEvent::Started,
+            )
+            .await
+            .unwrap();
+        // The working tracker's interval wins over the failing one's backoff.
+        let now = crate::db::now();
+        let next = manager.next_announce().unwrap();
+        assert!((now + 1799..=now + 1800).contains(&next));
+    }
+
+    #[tokio::test]
+    async fn reannounces_when_the_backoff_ends() {
+        let failing = MockHttpTracker::start(vec![Script::Malformed]);
+        let mut manager = TierManager::new(vec![vec![AnyTracker::Http(
+            HTTPTracker::new(failing.url()).unwrap(),
+        )]]);
+
+        let _ = manager
+            .announce(
+                InfoHash::from([1; 20]),
+                PeerId::new(),
+                None,
+                6881,
+                0,
+                0,
+                100,
+                AnnounceEvent::Started,
+            )
+            .await;
+        let now = crate::db::now();
+        let next = manager.next_announce().unwrap();
+        assert!((now + 29..=now + 30).contains(&next));
+        assert_eq!(TierManager::new(vec![]).next_announce(), None);
+    }
+
+    #[tokio::test]
+    async fn skips_failing_tracker_while_backing_off() {
+        let failing = MockHttpTracker::start(vec![Script::Malformed]);
+        let db = crate::db::connect("sqlite::memory:").await.unwrap();
+        let mut manager = TierManager::new(vec![vec![AnyTracker::Http(
+            HTTPTracker::new(failing.url()).unwrap(),
+        )]])
+        .with_database(db.clone())
+        .await;
+
+        for _ in 0..2 {
+            let _ = manager
+                .announce(
+                    InfoHash::from([1; 20]),
+                    PeerId::new(),
+                    None,
+                    6881,
+                    0,
+                    0,
+                    100,
+                    AnnounceEvent::Started,
+                )
+                .await;
+        }
+        assert_eq!(failing.announces().len(), 1);
+        let health = manager.health(&failing.url()).unwrap();
+        assert_eq!(health.consecutive_failures(), 1);
+
+        let reloaded = TierManager::new(vec![]).with_database(db).await;
+        assert_eq!(reloaded.health(&failing.url()), Some(health));
+    }
+
+    #[tokio::test]
+    async fn refusals_leave_the_tracker_healthy() {
+        let refusing = MockHttpTracker::start(vec![Script::Failure("unregistered torrent".into())]);
+        let mut manager = TierManager::new(vec![vec![AnyTracker::Http(
+            HTTPTracker::new(refusing.url()).unwrap(),
+        )]]);
+
+        for _ in 0..2 {
+            let _ = manager
+                .announce(
+                    InfoHash::from([1; 20]),
+                    PeerId::new(),
+                    None,
+                    6881,
+                    0,
+                    0,
+                    100,
+                    AnnounceEvent::Started,
+                )
+                .await;
+        }
+        // It isn't skipped as if it were down, but isn't due again for an
+        // hour either.
+        assert_eq!(refusing.announces().len(), 2);
+        let health = manager.health(&refusing.url()).unwrap();
+        assert_eq!(health.consecutive_failures(), 0);
+        let now = crate::db::now();
+        let next = manager.next_announce().unwrap();
+        assert!((now + 3599..=now + 3600).contains(&next));
+    }
+}
@@ -1,25 +1,64 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+
+use deku::bitvec::{BitSlice, Msb0};
+use deku::ctx::Endian;
 use deku::prelude::*;
 use derive_builder::Builder;
+use getset::{CopyGetters, Getters};
+use log::debug;
+use snafu::prelude::*;
+use url::{Host, Position, Url};
 
-use super::AnnounceEvent;
-use crate::torrent::InfoHash;
+use super::{
+    AnnounceEvent, AnnounceResponse, FailureSnafu, IoSnafu, MalformedSnafu, TimeoutSnafu, Tracker,
+    TrackerError, UnsupportedUrlSnafu,
+};
+use crate::deku_ext::read_to_end;
+use crate::net::UdpMux;
+use crate::torrent::{InfoHash, PeerId};
 
-const BITTORRENT_UDP_MAGIC: u64 = 0x41727101980;
+pub(crate) const BITTORRENT_UDP_MAGIC: u64 = 0x41727101980;
 
-#[derive(Debug, Clone, PartialEq, Eq, DekuRead, DekuWrite, Builder)]
+#[derive(Debug, Clone, PartialEq, Eq, DekuRead, DekuWrite, Builder, CopyGetters)]
 #[deku(endian = "big")]
 pub(crate) struct ConnectRequest {
     #[deku(assert_eq = "BITTORRENT_UDP_MAGIC")]
     protocol_id: u64,
     #[deku(assert_eq = "Action::Connect as u32")]
     action: u32,
+    #[getset(get_copy = "pub(crate)")]
     transaction_id: u32,
 }
 
-enum Action {
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Action {
     Connect = 0,
     Announce = 1,
     Scrape = 2,
+    Error = 3,
+}
+
+impl Action {
+    /// Peeks at the action of a raw datagram. Requests and responses both
+    /// carry the action as a big-endian `u32`, but at different offsets.
+    pub(crate) fn of_request(bytes: &[u8]) -> Option<Self> {
+        Self::from_u32(u32::from_be_bytes(bytes.get(8..12)?.try_into().ok()?))
+    }
+
+    pub(crate) fn of_response(bytes: &[u8]) -> Option<Self> {
+        Self::from_u32(u32::from_be_bytes(bytes.get(0..4)?.try_into().ok()?))
+    }
+
+    fn from_u32(action: u32) -> Option<Self> {
+        match action {
+            0 => Some(Action::Connect),
+            1 => Some(Action::Announce),
+            2 => Some(Action::Scrape),
+            3 => Some(Action::Error),
+            _ => None,
+        }
+    }
 }
 
 impl ConnectRequest {
@@ -32,32 +71,58 @@ impl ConnectRequest {
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, DekuRead, DekuWrite)]
+#[derive(Debug, Clone, PartialEq, Eq, DekuRead, DekuWrite, CopyGetters)]
 #[deku(endian = "big")]
 pub(crate) struct ConnectResponse {
     #[deku(assert_eq = "Action::Connect as u32")]
     action: u32,
+    #[getset(get_copy = "pub(crate)")]
     transaction_id: u32,
     pub connection_id: u64,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, DekuRead, DekuWrite, Builder)]
+impl ConnectResponse {
+    pub(crate) fn new(transaction_id: u32, connection_id: u64) -> Self {
+        Self {
+            action: Action::Connect as u32,
+            transaction_id,
+            connection_id,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, DekuRead, DekuWrite, Builder, Getters, CopyGetters)]
 #[deku(endian = "big")]
 pub(crate) struct AnnounceRequest {
+    #[getset(get_copy = "pub(crate)")]
     connection_id: u64,
     #[deku(assert_eq = "Action::Announce as u32")]
     action: u32,
+    #[getset(get_copy = "pub(crate)")]
     transaction_id: u32,
+    #[getset(get = "pub(crate)")]
     info_hash: [u8; 20],
+    #[getset(get = "pub(crate)")]
     peer_id: [u8; 20],
+    #[getset(get_copy = "pub(crate)")]
     downloaded: u64,
+    #[getset(get_copy = "pub(crate)")]
     left: u64,
+    #[getset(get_copy = "pub(crate)")]
     uploaded: u64,
+    #[getset(get = "pub(crate)")]
     event: AnnounceEvent,
+    #[getset(get = "pub(crate)")]
     ip: [u8; 4],
+    #[getset(get_copy = "pub(crate)")]
     key: u32,
+    #[getset(get_copy = "pub(crate)")]
     num_want: i32,
+    #[getset(get_copy = "pub(crate)")]
     port: u16,
+    #[deku(reader = "read_options(deku::rest)")]
+    #[getset(get = "pub(crate)")]
+    options: Vec<AnnounceOption>,
 }
 
 impl AnnounceRequest {
@@ -90,8 +155,83 @@ impl AnnounceRequest {
             key,
             num_want: num_want.unwrap_or(-1),
             port,
+            options: Vec::new(),
         }
     }
+
+    /// Appends the path and query of the announce URL as BEP 41 `URLData`
+    /// options, split into as many chunks as it takes.
+    pub(crate) fn with_url_data(mut self, url_data: &[u8]) -> Self {
+        self.options
+            .extend(
+                url_data
+                    .chunks(u8::MAX as usize)
+                    .map(|chunk| AnnounceOption::UrlData {
+                        length: chunk.len() as u8,
+                        data: chunk.to_vec(),
+                    }),
+            );
+        self
+    }
+
+    /// The `URLData` options, concatenated back into the path and query.
+    #[cfg(test)]
+    pub(crate) fn url_data(&self) -> Vec<u8> {
+        self.options
+            .iter()
+            .filter_map(|option| match option {
+                AnnounceOption::UrlData { data, .. } => Some(data.as_slice()),
+                _ => None,
+            })
+            .flatten()
+            .copied()
+            .collect()
+    }
+}
+
+/// Options trailing an announce request.
+/// See: http://www.bittorrent.org/beps/bep_0041.html
+#[derive(Debug, Clone, PartialEq, Eq, DekuRead, DekuWrite)]
+#[deku(type = "u8", endian = "endian", ctx = "endian: deku::ctx::Endian")]
+pub(crate) enum AnnounceOption {
+    #[deku(id = "0")]
+    EndOfOptions,
+    #[deku(id = "1")]
+    Nop,
+    #[deku(id = "2")]
+    UrlData {
+        length: u8,
+        #[deku(count = "length")]
+        data: Vec<u8>,
+    },
+    /// Options we don't understand still carry a length, so they can be
+    /// skipped.
+    #[deku(id_pat = "_")]
+    Unknown {
+        kind: u8,
+        length: u8,
+        #[deku(count = "length")]
+        data: Vec<u8>,
+    },
+}
+
+/// Reads options until `EndOfOptions` or the end of the packet, whichever
+/// comes first. Anything after `EndOfOptions` is ignored.
+fn read_options(
+    mut rest: &BitSlice<u8, Msb0>,
+) -> Result<(&BitSlice<u8, Msb0>, Vec<AnnounceOption>), DekuError> {
+    let mut options = Vec::new();
+    while !rest.is_empty() {
+        let (new_rest, option) = AnnounceOption::read(rest, Endian::Big)?;
+        let done = option == AnnounceOption::EndOfOptions;
+        options.push(option);
+        rest = if done {
+            &new_rest[new_rest.len()..]
+        } else {
+            new_rest
+        };
+    }
+    Ok((rest, options))
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, DekuRead, DekuWrite)]
@@ -105,57 +245,601 @@ impl AnnounceResponsePeerV4 {
     pub(crate) fn new(ip: &[u8; 4], port: u16) -> Self {
         Self { ip: *ip, port }
     }
+
+    pub(crate) fn addr(&self) -> SocketAddr {
+        SocketAddr::new(Ipv4Addr::from(self.ip).into(), self.port)
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, DekuRead, DekuWrite)]
+#[deku(endian = "endian", ctx = "endian: deku::ctx::Endian")]
+pub(crate) struct AnnounceResponsePeerV6 {
+    ip: [u8; 16],
+    port: u16,
+}
+
+impl AnnounceResponsePeerV6 {
+    pub(crate) fn new(ip: &[u8; 16], port: u16) -> Self {
+        Self { ip: *ip, port }
+    }
+
+    pub(crate) fn addr(&self) -> SocketAddr {
+        SocketAddr::new(Ipv6Addr::from(self.ip).into(), self.port)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, DekuRead, DekuWrite, Getters, CopyGetters)]
 #[deku(endian = "big")]
 pub(crate) struct AnnounceResponseV4 {
     #[deku(assert_eq = "Action::Announce as u32")]
     action: u32,
+    #[getset(get_copy = "pub(crate)")]
     transaction_id: u32,
+    #[getset(get_copy = "pub(crate)")]
     interval: u32,
+    #[getset(get_copy = "pub(crate)")]
     leechers: u32,
+    #[getset(get_copy = "pub(crate)")]
     seeders: u32,
-    #[deku(until = "|_| true")]
+    #[deku(reader = "read_to_end(deku::rest, Endian::Big)")]
+    #[getset(get = "pub(crate)")]
     peers: Vec<AnnounceResponsePeerV4>,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, DekuRead, DekuWrite, Builder)]
+impl AnnounceResponseV4 {
+    pub(crate) fn new(
+        transaction_id: u32,
+        interval: u32,
+        leechers: u32,
+        seeders: u32,
+        peers: Vec<AnnounceResponsePeerV4>,
+    ) -> Self {
+        Self {
+            action: Action::Announce as u32,
+            transaction_id,
+            interval,
+            leechers,
+            seeders,
+            peers,
+        }
+    }
+}
+
+/// Announce responses to requests that arrived over IPv6 carry 18-byte peers.
+/// See: http://www.bittorrent.org/beps/bep_0015.html
+#[derive(Debug, Clone, PartialEq, Eq, DekuRead, DekuWrite, Getters, CopyGetters)]
+#[deku(endian = "big")]
+pub(crate) struct AnnounceResponseV6 {
+    #[deku(assert_eq = "Action::Announce as u32")]
+    action: u32,
+    #[getset(get_copy = "pub(crate)")]
+    transaction_id: u32,
+    #[getset(get_copy = "pub(crate)")]
+    interval: u32,
+    #[getset(get_copy = "pub(crate)")]
+    leechers: u32,
+    #[getset(get_copy = "pub(crate)")]
+    seeders: u32,
+    #[deku(reader = "read_to_end(deku::rest, Endian::Big)")]
+    #[getset(get = "pub(crate)")]
+    peers: Vec<AnnounceResponsePeerV6>,
+}
+
+impl AnnounceResponseV6 {
+    pub(crate) fn new(
+        transaction_id: u32,
+        interval: u32,
+        leechers: u32,
+        seeders: u32,
+        peers: Vec<AnnounceResponsePeerV6>,
+    ) -> Self {
+        Self {
+            action: Action::Announce as u32,
+            transaction_id,
+            interval,
+            leechers,
+            seeders,
+            peers,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, DekuRead, DekuWrite, Builder, Getters, CopyGetters)]
 #[deku(endian = "big")]
 pub(crate) struct ScrapeRequest {
+    #[getset(get_copy = "pub(crate)")]
     connection_id: u64,
     #[deku(assert_eq = "Action::Scrape as u32")]
     action: u32,
+    #[getset(get_copy = "pub(crate)")]
     transaction_id: u32,
-    #[deku(until = "|_| true")]
+    #[deku(reader = "read_to_end(deku::rest, Endian::Big)")]
+    #[getset(get = "pub(crate)")]
     info_hashes: Vec<InfoHash>,
 }
 
 impl ScrapeRequest {
+    #[cfg(test)]
     pub(crate) fn new(connection_id: u64, transaction_id: u32, info_hashes: Vec<InfoHash>) -> Self {
         Self {
             connection_id,
-            action: 2,
+            action: Action::Scrape as u32,
             transaction_id,
             info_hashes,
         }
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, DekuRead, DekuWrite, Builder)]
+#[derive(Debug, Clone, PartialEq, Eq, DekuRead, DekuWrite, Builder, CopyGetters)]
 #[deku(endian = "endian", ctx = "endian: deku::ctx::Endian")]
+#[getset(get_copy = "pub(crate)")]
 pub(crate) struct ScrapeResponseFile {
     seeders: u32,
     completed: u32,
     leechers: u32,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, DekuRead, DekuWrite)]
+impl ScrapeResponseFile {
+    pub(crate) fn new(seeders: u32, completed: u32, leechers: u32) -> Self {
+        Self {
+            seeders,
+            completed,
+            leechers,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, DekuRead, DekuWrite, Getters, CopyGetters)]
 #[deku(endian = "big")]
 pub(crate) struct ScrapeResponse {
     #[deku(assert_eq = "Action::Scrape as u32")]
     action: u32,
+    #[getset(get_copy = "pub(crate)")]
     transaction_id: u32,
-    #[deku(until = "|_| true")]
+    #[deku(reader = "read_to_end(deku::rest, Endian::Big)")]
+    #[getset(get = "pub(crate)")]
     files: Vec<ScrapeResponseFile>,
 }
+
+impl ScrapeResponse {
+    pub(crate) fn new(transaction_id: u32, files: Vec<ScrapeResponseFile>) -> Self {
+        Self {
+            action: Action::Scrape as u32,
+            transaction_id,
+            files,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, DekuRead, DekuWrite, Getters, CopyGetters)]
+#[deku(endian = "big")]
+pub(crate) struct ErrorResponse {
+    #[deku(assert_eq = "Action::Error as u32")]
+    action: u32,
+    #[getset(get_copy = "pub(crate)")]
+    transaction_id: u32,
+    #[deku(reader = "read_to_end(deku::rest, Endian::Big)")]
+    message: Vec<u8>,
+}
+
+impl ErrorResponse {
+    pub(crate) fn new(transaction_id: u32, message: &str) -> Self {
+        Self {
+            action: Action::Error as u32,
+            transaction_id,
+            message: message.as_bytes().to_vec(),
+        }
+    }
+
+    pub(crate) fn message(&self) -> String {
+        String::from_utf8_lossy(&self.message).into_owned()
+    }
+}
+
+/// BEP 15 retransmits after 15 * 2^n seconds. It goes on to n = 8, which
+/// leaves a dead tracker holding up its tier for hours, so we stop after
+/// n = 2: under two minutes a request before the next tracker gets a turn.
+const DEFAULT_BASE_TIMEOUT: Duration = Duration::from_secs(15);
+const DEFAULT_RETRIES: u32 = 2;
+
+/// A client for BEP 15 UDP trackers.
+pub(crate) struct UDPTracker {
+    url: Url,
+    /// Sent with every announce so the tracker can recognise us across IP
+    /// changes.
+    key: u32,
+    base_timeout: Duration,
+    retries: u32,
+    /// Shared sockets to announce from, so responses come back to the port
+    /// we listen on. Without one for the tracker's family, each announce
+    /// binds its own.
+    sockets: Vec<UdpMux>,
+}
+
+fn malformed(e: DekuError) -> TrackerError {
+    MalformedSnafu {
+        message: e.to_string(),
+    }
+    .build()
+}
+
+impl UDPTracker {
+    pub(crate) fn new(url: Url) -> Result<Self, TrackerError> {
+        ensure!(
+            url.host().is_some() && url.port().is_some(),
+            UnsupportedUrlSnafu { url }
+        );
+        Ok(Self {
+            url,
+            key: rand::random(),
+            base_timeout: DEFAULT_BASE_TIMEOUT,
+            retries: DEFAULT_RETRIES,
+            sockets: Vec::new(),
+        })
+    }
+
+    #[cfg(test)]
+    pub(crate) fn with_timeouts(mut self, base_timeout: Duration, retries: u32) -> Self {
+        self.base_timeout = base_timeout;
+        self.retries = retries;
+        self
+    }
+
+    pub(crate) fn with_sockets(mut self, sockets: &[UdpMux]) -> Self {
+        self.sockets = sockets.to_vec();
+        self
+    }
+
+    pub(crate) fn url(&self) -> &Url {
+        &self.url
+    }
+
+    /// Private trackers identify us by the path and query of their announce
+    /// URL, which UDP only carries as BEP 41 options.
+    fn url_data(&self) -> &str {
+        match &self.url[Position::BeforePath..] {
+            "/" => "",
+            data => data,
+        }
+    }
+
+    async fn resolve(&self) -> Result<SocketAddr, TrackerError> {
+        // `new` checked that both are present.
+        let port = self.url.port().unwrap_or_default();
+        let ip = match self.url.host() {
+            Some(Host::Ipv4(ip)) => IpAddr::V4(ip),
+            Some(Host::Ipv6(ip)) => IpAddr::V6(ip),
+            Some(Host::Domain(host)) => {
+                return tokio::net::lookup_host((host, port))
+                    .await
+                    .context(IoSnafu)?
+                    .next()
+                    .context(MalformedSnafu {
+                        message: format!("{host} has no addresses"),
+                    })
+            }
+            None => {
+                return UnsupportedUrlSnafu {
+                    url: self.url.clone(),
+                }
+                .fail()
+            }
+        };
+        Ok(SocketAddr::new(ip, port))
+    }
+
+    /// Sends `request` to `addr` and waits for the response carrying
+    /// `transaction_id`, retransmitting with exponential backoff.
+    async fn transact(
+        &self,
+        socket: &UdpMux,
+        addr: SocketAddr,
+        request: &[u8],
+        transaction_id: u32,
+    ) -> Result<Vec<u8>, TrackerError> {
+        let mut transaction = socket.transaction(addr, transaction_id);
+        for n in 0..=self.retries {
+            socket.send_to(request, addr).await.context(IoSnafu)?;
+            let timeout = self.base_timeout * 2_u32.pow(n);
+            let Ok(packet) = tokio::time::timeout(timeout, transaction.response()).await else {
+                debug!("Retrying {} after {timeout:?}", self.url);
+                continue;
+            };
+            if Action::of_response(&packet) == Some(Action::Error) {
+                let (_, error) = ErrorResponse::from_bytes((&packet, 0)).map_err(malformed)?;
+                return FailureSnafu {
+                    reason: error.message(),
+                }
+                .fail();
+            }
+            return Ok(packet);
+        }
+        TimeoutSnafu.fail()
+    }
+}
+
+impl Tracker for UDPTracker {
+    async fn get_peers(
+        &self,
+        info_hash: InfoHash,
+        peer_id: PeerId,
+        ip: Option<IpAddr>,
+        port: u16,
+        uploaded: u64,
+        downloaded: u64,
+        left: u64,
+        event: AnnounceEvent,
+    ) -> Result<AnnounceResponse, TrackerError> {
+        let addr = self.resolve().await?;
+        let shared = self.sockets.iter().find(|s| s.is_ipv4() == addr.is_ipv4());
+        let socket = match shared {
+            Some(socket) => socket.clone(),
+            None => {
+                let local = match addr {
+                    SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+                    SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+                };
+                UdpMux::bind(local).await.context(IoSnafu)?
+            }
+        };
+
+        let transaction_id = rand::random();
+        let request = ConnectRequest::new(transaction_id)
+            .to_bytes()
+            .map_err(malformed)?;
+        let packet = self
+            .transact(&socket, addr, &request, transaction_id)
+            .await?;
+        let (_, connect) = ConnectResponse::from_bytes((&packet, 0)).map_err(malformed)?;
+
+        let transaction_id = rand::random();
+        let ip = match ip {
+            Some(IpAddr::V4(ip)) => Some(ip.octets()),
+            _ => None,
+        };
+        let request = AnnounceRequest::new(
+            connect.connection_id,
+            transaction_id,
+            info_hash.as_array(),
+            peer_id.as_bytes(),
+            downloaded,
+            left,
+            uploaded,
+            event,
+            ip.as_ref(),
+            self.key,
+            Some(50),
+            port,
+        )
+        .with_url_data(self.url_data().as_bytes())
+        .to_bytes()
+        .map_err(malformed)?;
+        let packet = self
+            .transact(&socket, addr, &request, transaction_id)
+            .await?;
+
+        let (interval, leechers, seeders, peers) = match addr {
+            SocketAddr::V4(_) => {
+                let (_, resp) = AnnounceResponseV4::from_bytes((&packet, 0)).map_err(malformed)?;
+                let peers = resp.peers().iter().map(|p| p.addr()).collect();
+                (resp.interval(), resp.leechers(), resp.seeders(), peers)
+            }
+            SocketAddr::V6(_) => {
+                let (_, resp) = AnnounceResponseV6::from_bytes((&packet, 0)).map_err(malformed)?;
+                let peers = resp.peers().iter().map(|p| p.addr()).collect();
+                (resp.interval(), resp.leechers(), resp.seeders(), peers)
+            }
+        };
+        Ok(AnnounceResponse {
+            interval,
+            min_interval: None,
+            seeders: Some(seeders),
+            leechers: Some(leechers),
+            peers,
+            external_ip: None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::time::Duration;
+
+    use deku::prelude::*;
+
+    use super::{
+        AnnounceOption, AnnounceRequest, AnnounceResponsePeerV4, AnnounceResponseV4, ErrorResponse,
+        ScrapeRequest, ScrapeResponse, ScrapeResponseFile, UDPTracker,
+    };
+    use crate::net::UdpMux;
+    use crate::torrent::{InfoHash, PeerId};
+    use crate::tracker::mock::{MockUdpTracker, Script};
+    use crate::tracker::{AnnounceEvent, AnnounceResponse, Tracker, TrackerError};
+
+    async fn announce(mock: &MockUdpTracker) -> Result<AnnounceResponse, TrackerError> {
+        announce_from(mock, &[]).await
+    }
+
+    async fn announce_from(
+        mock: &MockUdpTracker,
+        sockets: &[UdpMux],
+    ) -> Result<AnnounceResponse, TrackerError> {
+        UDPTracker::new(mock.url())
+            .unwrap()
+            .with_timeouts(Duration::from_millis(50), 1)
+            .with_sockets(sockets)
+            .get_peers(
+                InfoHash::from([0xab; 20]),
+                PeerId::new(),
+                None,
+                6881,
+                0,
+                0,
+                100,
+                AnnounceEvent::Started,
+            )
+            .await
+    }
+
+    #[test]
+    fn announce_response_reads_every_peer() {
+        let resp = AnnounceResponseV4::new(
+            7,
+            1800,
+            1,
+            2,
+            vec![
+                AnnounceResponsePeerV4::new(&[10, 0, 0, 1], 6881),
+                AnnounceResponsePeerV4::new(&[10, 0, 0, 2], 6882),
+            ],
+        );
+        let bytes = resp.to_bytes().unwrap();
+        assert_eq!(bytes.len(), 20 + 2 * 6);
+        let (_, parsed) = AnnounceResponseV4::from_bytes((&bytes, 0)).unwrap();
+        assert_eq!(parsed, resp);
+    }
+
+    #[test]
+    fn scrape_round_trip() {
+        let req = ScrapeRequest::new(1, 2, vec![InfoHash::from([1; 20]), InfoHash::from([2; 20])]);
+        let (_, parsed) = ScrapeRequest::from_bytes((&req.to_bytes().unwrap(), 0)).unwrap();
+        assert_eq!(parsed, req);
+
+        let resp = ScrapeResponse::new(2, vec![ScrapeResponseFile::new(3, 4, 5)]);
+        let (_, parsed) = ScrapeResponse::from_bytes((&resp.to_bytes().unwrap(), 0)).unwrap();
+        assert_eq!(parsed, resp);
+    }
+
+    #[test]
+    fn error_response_message() {
+        let resp = ErrorResponse::new(9, "unregistered torrent");
+        let (_, parsed) = ErrorResponse::from_bytes((&resp.to_bytes().unwrap(), 0)).unwrap();
+        assert_eq!(parsed.message(), "unregistered torrent");
+    }
+
+    #[tokio::test]
+    async fn client_announces() {
+        let peers: Vec<SocketAddr> = vec!["10.0.0.1:6881".parse().unwrap()];
+        let mock = MockUdpTracker::start(vec![Script::Peers(peers.clone())]).await;
+
+        let resp = announce(&mock).await.unwrap();
+        assert_eq!(resp.peers, peers);
+        assert_eq!(resp.interval, 1800);
+
+        let (_, req) = AnnounceRequest::from_bytes((&mock.announces()[0], 0)).unwrap();
+        assert_eq!(req.info_hash(), &[0xab; 20]);
+        assert_eq!(req.port(), 6881);
+        assert_eq!(req.event(), &AnnounceEvent::Started);
+        assert_eq!(req.url_data(), b"/announce");
+    }
+
+    #[tokio::test]
+    async fn client_announces_from_a_shared_socket() {
+        let peers: Vec<SocketAddr> = vec!["10.0.0.1:6881".parse().unwrap()];
+        let mock = MockUdpTracker::start(vec![
+            Script::WrongTransactionId,
+            Script::Peers(peers.clone()),
+        ])
+        .await;
+        let socket = UdpMux::bind("127.0.0.1:0".parse().unwrap()).await.unwrap();
+        let mut dht = socket.dht();
+
+        let resp = announce_from(&mock, &[socket]).await.unwrap();
+        assert_eq!(resp.peers, peers);
+        assert!(dht.try_recv().is_err());
+    }
+
+    #[test]
+    fn url_data_is_split_into_options() {
+        let url_data = format!("/announce?passkey={}", "k".repeat(300));
+        let req = AnnounceRequest::new(
+            1,
+            2,
+            &[3; 20],
+            &[4; 20],
+            0,
+            0,
+            0,
+            AnnounceEvent::Empty,
+            None,
+            5,
+            None,
+            6881,
+        )
+        .with_url_data(url_data.as_bytes());
+        let bytes = req.to_bytes().unwrap();
+        assert_eq!(bytes.len(), 98 + 2 + 255 + 2 + (url_data.len() - 255));
+
+        let (_, parsed) = AnnounceRequest::from_bytes((&bytes, 0)).unwrap();
+        assert_eq!(parsed.options().len(), 2);
+        assert_eq!(parsed.url_data(), url_data.as_bytes());
+    }
+
+    #[test]
+    fn options_stop_at_end_of_options() {
+        let mut bytes = AnnounceRequest::new(
+            1,
+            2,
+            &[3; 20],
+            &[4; 20],
+            0,
+            0,
+            0,
+            AnnounceEvent::Empty,
+            None,
+            5,
+            None,
+            6881,
+        )
+        .to_bytes()
+        .unwrap();
+        bytes.extend_from_slice(&[1, 2, 2, b'/', b'a', 7, 1, b'x', 0, 0xff, 0xff]);
+
+        let (_, parsed) = AnnounceRequest::from_bytes((&bytes, 0)).unwrap();
+        assert_eq!(parsed.url_data(), b"/a");
+        assert_eq!(parsed.options().last(), Some(&AnnounceOption::EndOfOptions));
+    }
+
+    #[tokio::test]
+    async fn client_reports_error_response() {
+        let mock = MockUdpTracker::start(vec![Script::Failure("banned".into())]).await;
+
+        match announce(&mock).await {
+            Err(TrackerError::Failure { reason }) => assert_eq!(reason, "banned"),
+            other => panic!("expected a failure, got {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn client_retransmits_after_timeout() {
+        let peers: Vec<SocketAddr> = vec!["10.0.0.1:6881".parse().unwrap()];
+        let mock = MockUdpTracker::start(vec![Script::Timeout, Script::Peers(peers.clone())]).await;
+
+        assert_eq!(announce(&mock).await.unwrap().peers, peers);
+    }
+
+    #[tokio::test]
+    async fn client_ignores_wrong_transaction_id() {
+        let peers: Vec<SocketAddr> = vec!["10.0.0.1:6881".parse().unwrap()];
+        let mock = MockUdpTracker::start(vec![
+            Script::WrongTransactionId,
+            Script::Peers(peers.clone()),
+        ])
+        .await;
+        assert_eq!(announce(&mock).await.unwrap().peers, peers);
+
+        let mock = MockUdpTracker::start(vec![Script::WrongTransactionId]).await;
+        assert!(matches!(announce(&mock).await, Err(TrackerError::Timeout)));
+    }
+
+    #[tokio::test]
+    async fn client_rejects_malformed_response() {
+        let mock = MockUdpTracker::start(vec![Script::Malformed]).await;
+
+        assert!(matches!(
+            announce(&mock).await,
+            Err(TrackerError::Malformed { .. })
+        ));
+    }
+}
@@ -0,0 +1,618 @@
+//! One uTP connection as a state machine. The socket feeds it packets and
+//! clock ticks and sends whatever it queues; streams read and write through
+//! it.
+
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::task::{Context, Poll, Waker};
+use std::time::Duration;
+
+use tokio::io::ReadBuf;
+use tokio::time::Instant;
+
+use super::ledbat::Ledbat;
+use super::packet::{Header, Packet, PacketType};
+
+/// Payload per packet, which with the IP, UDP and uTP headers fits in the
+/// MTU of most links.
+pub(crate) const MAX_PAYLOAD: usize = 1380;
+/// Bytes written but not yet acknowledged that a connection holds.
+const SEND_BUFFER: usize = 1024 * 1024;
+/// Bytes received but not yet read, as advertised in our window.
+const RECV_BUFFER: usize = 1024 * 1024;
+/// How far past the next expected packet we keep packets that arrive early.
+const REORDER_WINDOW: u16 = 1024;
+/// Selective ACK bits we send, covering the packets after the next expected.
+const MAX_SACK_BYTES: usize = 16;
+const INITIAL_RTO: Duration = Duration::from_secs(1);
+const MIN_RTO: Duration = Duration::from_millis(500);
+const MAX_RTO: Duration = Duration::from_secs(30);
+/// Times the SYN is sent before we give up on the peer.
+const SYN_ATTEMPTS: u32 = 2;
+/// Timeouts in a row that close an established connection.
+const MAX_TIMEOUTS: u32 = 8;
+/// Duplicate ACKs, or packets selectively ACKed past the oldest unacked,
+/// that count it as lost.
+const LOSS_THRESHOLD: usize = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    SynSent,
+    Connected,
+    Failed(io::ErrorKind),
+}
+
+/// A packet sent and not yet acknowledged.
+#[derive(Debug)]
+struct Sent {
+    kind: PacketType,
+    seq_nr: u16,
+    payload: Vec<u8>,
+    sent_at: Instant,
+    transmissions: u32,
+    /// The peer has it, but hasn't everything before it.
+    sacked: bool,
+    resend: bool,
+}
+
+#[derive(Debug)]
+pub(crate) struct Connection {
+    state: State,
+    send_id: u16,
+    /// The next sequence number to send.
+    seq_nr: u16,
+    /// The last sequence number received in order.
+    ack_nr: u16,
+    epoch: Instant,
+    unsent: VecDeque<u8>,
+    in_flight: VecDeque<Sent>,
+    received: VecDeque<u8>,
+    out_of_order: HashMap<u16, Packet>,
+    fin_received: bool,
+    closing: bool,
+    fin_sent: bool,
+    /// The stream is gone, so the connection ends once our FIN is through.
+    detached: bool,
+    /// The peer dialed us while we dialed it, and its connection, receiving
+    /// on this ID, is the one kept.
+    superseded_by: Option<u16>,
+    peer_window: usize,
+    ledbat: Ledbat,
+    /// Smoothed round trip time and its variance.
+    rtt: Option<(Duration, Duration)>,
+    rto: Duration,
+    timeout_at: Option<Instant>,
+    timeouts: u32,
+    duplicate_acks: usize,
+    /// How long the peer's last packet took, echoed back for its LEDBAT.
+    reply_micros: u32,
+    ack_due: bool,
+    outbox: Vec<Packet>,
+    read_waker: Option<Waker>,
+    write_waker: Option<Waker>,
+}
+
+impl Connection {
+    fn new(state: State, send_id: u16, seq_nr: u16, ack_nr: u16, now: Instant) -> Self {
+        Self {
+            state,
+            send_id,
+            seq_nr,
+            ack_nr,
+            epoch: now,
+            unsent: VecDeque::new(),
+            in_flight: VecDeque::new(),
+            received: VecDeque::new(),
+            out_of_order: HashMap::new(),
+            fin_received: false,
+            closing: false,
+            fin_sent: false,
+            detached: false,
+            superseded_by: None,
+            peer_window: RECV_BUFFER,
+            ledbat: Ledbat::default(),
+            rtt: None,
+            rto: INITIAL_RTO,
+            timeout_at: None,
+            timeouts: 0,
+            duplicate_acks: 0,
+            reply_micros: 0,
+            ack_due: false,
+            outbox: Vec::new(),
+            read_waker: None,
+            write_waker: None,
+        }
+    }
+
+    /// Starts a connection that receives packets addressed to `recv_id`,
+    /// queuing the SYN.
+    pub(crate) fn connect(recv_id: u16, now: Instant) -> Self {
+        let mut connection = Self::new(State::SynSent, recv_id.wrapping_add(1), 1, 0, now);
+        connection.push(PacketType::Syn, Vec::new(), now);
+        connection
+    }
+
+    /// Answers `syn`. The connection receives packets addressed to one past
+    /// the SYN's connection ID, and sends to the SYN's.
+    pub(crate) fn accept(syn: &Header, now: Instant) -> Self {
+        let mut connection = Self::new(
+            State::Connected,
+            syn.connection_id,
+            rand::random(),
+            syn.seq_nr,
+            now,
+        );
+        connection.peer_window = syn.wnd_size as usize;
+        connection.ack_due = true;
+        connection
+    }
+
+    pub(crate) fn send_id(&self) -> u16 {
+        self.send_id
+    }
+
+    /// Whether our SYN is still unanswered.
+    pub(crate) fn is_connecting(&self) -> bool {
+        self.state == State::SynSent
+    }
+
+    /// Gives up dialing in favour of the connection the peer dialed, which
+    /// receives on `recv_id`.
+    pub(crate) fn supersede(&mut self, recv_id: u16) {
+        self.superseded_by = Some(recv_id);
+        self.fail(io::ErrorKind::ConnectionAborted);
+    }
+
+    pub(crate) fn superseded_by(&self) -> Option<u16> {
+        self.superseded_by
+    }
+
+    /// Whether the socket can forget the connection.
+    pub(crate) fn is_finished(&self) -> bool {
+        self.detached
+            && match self.state {
+                State::Failed(_) => true,
+                _ => self.fin_sent && self.in_flight.is_empty(),
+            }
+    }
+
+    pub(crate) fn take_outbox(&mut self) -> Vec<Packet> {
+        std::mem::take(&mut self.outbox)
+    }
+
+    pub(crate) fn on_packet(&mut self, packet: Packet, now: Instant) {
+        if matches!(self.state, State::Failed(_)) {
+            return;
+        }
+        let header = &packet.header;
+        match header.kind {
+            PacketType::Reset => return self.fail(io::ErrorKind::ConnectionReset),
+            // A SYN again: our answer to it was lost.
+            PacketType::Syn => {
+                self.ack_due = true;
+                return;
+            }
+            _ => {}
+        }
+        if self.state == State::SynSent {
+            if header.kind != PacketType::State {
+                return;
+            }
+            self.state = State::Connected;
+            self.ack_nr = header.seq_nr.wrapping_sub(1);
+        }
+        self.reply_micros = self.micros(now).wrapping_sub(header.timestamp);
+        self.peer_window = header.wnd_size as usize;
+        self.on_ack(&packet, now);
+        if matches!(header.kind, PacketType::Data | PacketType::Fin) {
+            self.on_data(packet);
+        }
+        self.wake();
+    }
+
+    fn on_ack(&mut self, packet: &Packet, now: Instant) {
+        let header = &packet.header;
+        let first = self.seq_nr.wrapping_sub(self.in_flight.len() as u16);
+        let acked = header.ack_nr.wrapping_sub(first).wrapping_add(1) as usize;
+        let mut bytes_acked = 0;
+        if (1..=self.in_flight.len()).contains(&acked) {
+            for sent in self.in_flight.drain(..acked).collect::<Vec<_>>() {
+                if !sent.sacked {
+                    bytes_acked += sent.payload.len();
+                }
+                // Karn's rule: a resent packet's ACK could be for either copy.
+                if sent.transmissions == 1 {
+                    self.sample_rtt(now.duration_since(sent.sent_at));
+                }
+            }
+            self.duplicate_acks = 0;
+            self.timeouts = 0;
+            self.timeout_at = (!self.in_flight.is_empty()).then(|| now + self.rto);
+        } else if acked == 0 && header.kind == PacketType::State && !self.in_flight.is_empty() {
+            self.duplicate_acks += 1;
+        }
+
+        if let Some(mask) = &packet.selective_ack {
+            for sent in self.in_flight.iter_mut().filter(|s| !s.sacked) {
+                let bit = sent.seq_nr.wrapping_sub(header.ack_nr).wrapping_sub(2) as usize;
+                if bit < mask.len() * 8 && mask[bit / 8] & (1 << (bit % 8)) != 0 {
+                    sent.sacked = true;
+                    sent.resend = false;
+                    bytes_acked += sent.payload.len();
+                }
+            }
+        }
+        let sacked = self.in_flight.iter().filter(|s| s.sacked).count();
+        if let Some(oldest) = self.in_flight.front_mut() {
+            let lost = self.duplicate_acks >= LOSS_THRESHOLD || sacked >= LOSS_THRESHOLD;
+            // Each packet is fast retransmitted once; after that it's up to
+            // the timeout.
+            if lost && !oldest.sacked && oldest.transmissions == 1 {
+                oldest.resend = true;
+                self.duplicate_acks = 0;
+                self.ledbat.on_loss();
+            }
+        }
+        self.ledbat
+            .on_ack(header.timestamp_difference, bytes_acked, now);
+    }
+
+    fn on_data(&mut self, packet: Packet) {
+        self.ack_due = true;
+        let ahead = packet.header.seq_nr.wrapping_sub(self.ack_nr);
+        if self.fin_received || ahead == 0 || ahead > REORDER_WINDOW {
+            return;
+        }
+        if ahead > 1 {
+            self.out_of_order.insert(packet.header.seq_nr, packet);
+            return;
+        }
+        let mut next = Some(packet);
+        while let Some(packet) = next {
+            self.ack_nr = packet.header.seq_nr;
+            if packet.header.kind == PacketType::Fin {
+                self.fin_received = true;
+                self.out_of_order.clear();
+                return;
+            }
+            self.received.extend(packet.payload);
+            next = self.out_of_order.remove(&self.ack_nr.wrapping_add(1));
+        }
+    }
+
+    fn sample_rtt(&mut self, sample: Duration) {
+        let (rtt, variance) = match self.rtt {
+            None => (sample, sample / 2),
+            Some((rtt, variance)) => (
+                (rtt * 7 + sample) / 8,
+                (variance * 3 + rtt.abs_diff(sample)) / 4,
+            ),
+        };
+        self.rtt = Some((rtt, variance));
+        self.rto = (rtt + variance * 4).clamp(MIN_RTO, MAX_RTO);
+    }
+
+    /// Handles the retransmission timeout, to be called regularly.
+    pub(crate) fn tick(&mut self, now: Instant) {
+        match self.timeout_at {
+            Some(at) if now >= at => {}
+            _ => return,
+        }
+        self.timeouts += 1;
+        let limit = match self.state {
+            State::SynSent => SYN_ATTEMPTS,
+            _ => MAX_TIMEOUTS,
+        };
+        if self.timeouts >= limit {
+            return self.fail(io::ErrorKind::TimedOut);
+        }
+        self.rto = (self.rto * 2).min(MAX_RTO);
+        self.timeout_at = Some(now + self.rto);
+        self.duplicate_acks = 0;
+        self.ledbat.on_timeout();
+        for sent in self.in_flight.iter_mut().filter(|s| !s.sacked) {
+            sent.resend = true;
+        }
+    }
+
+    /// Queues what the windows allow: retransmissions, then new data, then
+    /// our FIN, and an ACK if nothing else carries one.
+    pub(crate) fn fill(&mut self, now: Instant) {
+        if matches!(self.state, State::Failed(_)) {
+            return;
+        }
+        let window = self.ledbat.window().min(self.peer_window);
+        let mut in_flight: usize = self
+            .in_flight
+            .iter()
+            .filter(|s| !s.sacked && !s.resend)
+            .map(|s| s.payload.len())
+            .sum();
+        // Whatever the windows say, one packet may always be out, so a
+        // closed window gets probed.
+        let fits = |in_flight: usize, len: usize| in_flight == 0 || in_flight + len <= window;
+
+        for i in 0..self.in_flight.len() {
+            if !self.in_flight[i].resend {
+                continue;
+            }
+            let len = self.in_flight[i].payload.len();
+            if !fits(in_flight, len) {
+                break;
+            }
+            self.transmit(i, now);
+            in_flight += len;
+        }
+        if self.state == State::SynSent {
+            return;
+        }
+        while !self.unsent.is_empty() && self.in_flight.len() < REORDER_WINDOW as usize / 2 {
+            let len = self.unsent.len().min(MAX_PAYLOAD);
+            if !fits(in_flight, len) {
+                break;
+            }
+            let payload = self.unsent.drain(..len).collect();
+            self.push(PacketType::Data, payload, now);
+            in_flight += len;
+        }
+        if self.closing && !self.fin_sent && self.unsent.is_empty() {
+            self.fin_sent = true;
+            self.push(PacketType::Fin, Vec::new(), now);
+        }
+        if self.ack_due {
+            let ack = self.packet(PacketType::State, self.seq_nr, Vec::new(), now);
+            self.outbox.push(ack);
+            self.ack_due = false;
+        }
+    }
+
+    fn push(&mut self, kind: PacketType, payload: Vec<u8>, now: Instant) {
+        self.in_flight.push_back(Sent {
+            kind,
+            seq_nr: self.seq_nr,
+            payload,
+            sent_at: now,
+            transmissions: 0,
+            sacked: false,
+            resend: false,
+        });
+        self.seq_nr = self.seq_nr.wrapping_add(1);
+        self.timeout_at.get_or_insert(now + self.rto);
+        self.transmit(self.in_flight.len() - 1, now);
+    }
+
+    fn transmit(&mut self, index: usize, now: Instant) {
+        let sent = &self.in_flight[index];
+        let packet = self.packet(sent.kind, sent.seq_nr, sent.payload.clone(), now);
+        self.outbox.push(packet);
+        let sent = &mut self.in_flight[index];
+        sent.sent_at = now;
+        sent.transmissions += 1;
+        sent.resend = false;
+        // It carries our latest ACK.
+        self.ack_due = false;
+    }
+
+    fn packet(&self, kind: PacketType, seq_nr: u16, payload: Vec<u8>, now: Instant) -> Packet {
+        // Only the SYN goes to the ID the peer will send to.
+        let connection_id = match kind {
+            PacketType::Syn => self.send_id.wrapping_sub(1),
+            _ => self.send_id,
+        };
+        let mut header = Header::new(kind, connection_id, seq_nr, self.ack_nr);
+        header.timestamp = self.micros(now);
+        header.timestamp_difference = self.reply_micros;
+        header.wnd_size = RECV_BUFFER.saturating_sub(self.received.len()) as u32;
+        Packet {
+            header,
+            selective_ack: self.selective_ack(),
+            payload,
+        }
+    }
+
+    /// Bit `i` is set for each packet `ack_nr + 2 + i` held out of order.
+    fn selective_ack(&self) -> Option<Vec<u8>> {
+        if self.out_of_order.is_empty() {
+            return None;
+        }
+        let mut mask = vec![0; 4];
+        for seq_nr in self.out_of_order.keys() {
+            let bit = seq_nr.wrapping_sub(self.ack_nr).wrapping_sub(2) as usize;
+            let byte = bit / 8;
+            if byte >= MAX_SACK_BYTES {
+                continue;
+            }
+            if byte >= mask.len() {
+                // The extension's length is a multiple of 4.
+                mask.resize((byte / 4 + 1) * 4, 0);
+            }
+            mask[byte] |= 1 << (bit % 8);
+        }
+        Some(mask)
+    }
+
+    fn micros(&self, now: Instant) -> u32 {
+        now.duration_since(self.epoch).as_micros() as u32
+    }
+
+    fn fail(&mut self, kind: io::ErrorKind) {
+        self.state = State::Failed(kind);
+        self.in_flight.clear();
+        self.timeout_at = None;
+        self.wake();
+    }
+
+    fn wake(&mut self) {
+        for waker in [self.read_waker.take(), self.write_waker.take()]
+            .into_iter()
+            .flatten()
+        {
+            waker.wake();
+        }
+    }
+
+    pub(crate) fn poll_connected(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.state {
+            State::SynSent => {
+                self.write_waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+            State::Connected => Poll::Ready(Ok(())),
+            State::Failed(kind) => Poll::Ready(Err(kind.into())),
+        }
+    }
+
+    pub(crate) fn poll_read(
+        &mut self,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if !self.received.is_empty() {
+            // Tell a peer held up by our full window that there's room again.
+            if RECV_BUFFER - self.received.len() < MAX_PAYLOAD {
+                self.ack_due = true;
+            }
+            let len = buf.remaining().min(self.received.len());
+            let bytes: Vec<u8> = self.received.drain(..len).collect();
+            buf.put_slice(&bytes);
+            return Poll::Ready(Ok(()));
+        }
+        match self.state {
+            State::Failed(kind) => Poll::Ready(Err(kind.into())),
+            _ if self.fin_received => Poll::Ready(Ok(())),
+            _ => {
+                self.read_waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+
+    pub(crate) fn poll_write(
+        &mut self,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        if let State::Failed(kind) = self.state {
+            return Poll::Ready(Err(kind.into()));
+        }
+        if self.closing {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+        let buffered = self.unsent.len()
+            + self
+                .in_flight
+                .iter()
+                .map(|s| s.payload.len())
+                .sum::<usize>();
+        let room = SEND_BUFFER.saturating_sub(buffered).min(buf.len());
+        if room == 0 {
+            self.write_waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        self.unsent.extend(&buf[..room]);
+        Poll::Ready(Ok(room))
+    }
+
+    /// Sends our FIN once everything written has gone out.
+    pub(crate) fn close(&mut self) {
+        self.closing = true;
+    }
+
+    pub(crate) fn detach(&mut self) {
+        self.closing = true;
+        self.detached = true;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::task::{Context, Poll};
+
+    use futures::task::noop_waker_ref;
+    use tokio::io::ReadBuf;
+    use tokio::time::Instant;
+
+    use super::{Connection, State, MAX_PAYLOAD};
+    use crate::utp::packet::{Packet, PacketType};
+
+    /// Delivers everything `from` has queued to `to`, except packets
+    /// `lose` picks.
+    fn deliver(
+        from: &mut Connection,
+        to: &mut Connection,
+        now: Instant,
+        mut lose: impl FnMut(&Packet) -> bool,
+    ) {
+        from.fill(now);
+        for packet in from.take_outbox() {
+            if !lose(&packet) {
+                to.on_packet(Packet::parse(&packet.to_bytes()).unwrap(), now);
+            }
+        }
+    }
+
+    fn read_all(connection: &mut Connection) -> Vec<u8> {
+        let mut cx = Context::from_waker(noop_waker_ref());
+        let mut bytes = vec![0; 64 * 1024];
+        let mut buf = ReadBuf::new(&mut bytes);
+        let _ = connection.poll_read(&mut cx, &mut buf);
+        buf.filled().to_vec()
+    }
+
+    #[test]
+    fn recovers_lost_packets_from_selective_acks() {
+        let mut cx = Context::from_waker(noop_waker_ref());
+        let now = Instant::now();
+        let mut a = Connection::connect(7, now);
+        let syn = a.take_outbox().remove(0);
+        assert_eq!(syn.header.kind, PacketType::Syn);
+        assert_eq!(syn.header.connection_id, 7);
+        let mut b = Connection::accept(&syn.header, now);
+        assert_eq!(b.send_id(), 7);
+        deliver(&mut b, &mut a, now, |_| false);
+        assert_eq!(a.state, State::Connected);
+
+        let data: Vec<u8> = (0..4 * MAX_PAYLOAD).map(|i| i as u8).collect();
+        assert!(matches!(
+            a.poll_write(&mut cx, &data),
+            Poll::Ready(Ok(n)) if n == data.len()
+        ));
+        // The first data packet is lost; the rest arrive and are ACKed
+        // selectively.
+        let mut first = true;
+        deliver(&mut a, &mut b, now, |packet| {
+            std::mem::take(&mut first) && packet.header.kind == PacketType::Data
+        });
+        assert!(read_all(&mut b).is_empty());
+        assert_eq!(b.out_of_order.len(), 3);
+        b.fill(now);
+        let ack = b.take_outbox().pop().unwrap();
+        assert_eq!(ack.selective_ack, Some(vec![0b111, 0, 0, 0]));
+        a.on_packet(ack, now);
+
+        // Three packets past it got through, so it's resent without waiting
+        // for the timeout.
+        deliver(&mut a, &mut b, now, |_| false);
+        assert_eq!(read_all(&mut b), data);
+        deliver(&mut b, &mut a, now, |_| false);
+        assert!(a.in_flight.is_empty());
+
+        a.detach();
+        deliver(&mut a, &mut b, now, |_| false);
+        assert!(b.fin_received);
+        deliver(&mut b, &mut a, now, |_| false);
+        assert!(a.is_finished());
+    }
+
+    #[test]
+    fn gives_up_after_unanswered_syns() {
+        let now = Instant::now();
+        let mut a = Connection::connect(7, now);
+        a.take_outbox();
+        a.tick(now + super::INITIAL_RTO);
+        a.fill(now + super::INITIAL_RTO);
+        assert_eq!(a.take_outbox()[0].header.kind, PacketType::Syn);
+        a.tick(now + super::INITIAL_RTO * 3);
+        assert_eq!(a.state, State::Failed(std::io::ErrorKind::TimedOut));
+    }
+}
@@ -0,0 +1,142 @@
+//! LEDBAT congestion control: the window grows while the one-way delay stays
+//! under [`TARGET`] above the lowest delay seen, and shrinks as queues build,
+//! so uTP gives way to TCP and interactive traffic on the same link.
+//! See: https://datatracker.ietf.org/doc/html/rfc6817
+
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+use super::connection::MAX_PAYLOAD;
+
+/// The queuing delay we aim to add, in microseconds.
+const TARGET: f64 = 100_000.0;
+/// The most the window grows by in a round trip, at zero queuing delay.
+const MAX_WINDOW_INCREASE: f64 = 3000.0;
+const MIN_WINDOW: usize = 2 * MAX_PAYLOAD;
+const INITIAL_WINDOW: usize = 4 * MAX_PAYLOAD;
+const MAX_WINDOW: usize = 1024 * 1024;
+/// Base delay is the lowest of the minima of this many recent minutes, so it
+/// follows route changes.
+const BASE_HISTORY: usize = 2;
+const BASE_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug)]
+pub(crate) struct Ledbat {
+    window: usize,
+    /// The lowest delay seen in each recent interval, newest last.
+    base_delays: VecDeque<u32>,
+    interval_start: Option<Instant>,
+}
+
+impl Default for Ledbat {
+    fn default() -> Self {
+        Self {
+            window: INITIAL_WINDOW,
+            base_delays: VecDeque::new(),
+            interval_start: None,
+        }
+    }
+}
+
+impl Ledbat {
+    /// Bytes we may have in flight.
+    pub(crate) fn window(&self) -> usize {
+        self.window
+    }
+
+    /// Takes an ACK for `bytes_acked` bytes that says our packets took
+    /// `delay` microseconds to arrive, as the peer's clock tells it.
+    pub(crate) fn on_ack(&mut self, delay: u32, bytes_acked: usize, now: Instant) {
+        // A difference of 0 means the peer hasn't measured one yet.
+        if delay == 0 || bytes_acked == 0 {
+            return;
+        }
+        self.record(delay, now);
+        let base = self.base_delays.iter().min().copied().unwrap_or(delay);
+        let queuing = f64::from(delay.wrapping_sub(base));
+        let off_target = (TARGET - queuing) / TARGET;
+        let change = off_target * MAX_WINDOW_INCREASE * bytes_acked as f64 / self.window as f64;
+        self.window =
+            (self.window as f64 + change).clamp(MIN_WINDOW as f64, MAX_WINDOW as f64) as usize;
+    }
+
+    /// A packet was lost but others are getting through.
+    pub(crate) fn on_loss(&mut self) {
+        self.window = (self.window / 2).max(MIN_WINDOW);
+    }
+
+    /// Nothing was acknowledged for a whole retransmission timeout.
+    pub(crate) fn on_timeout(&mut self) {
+        self.window = MIN_WINDOW;
+    }
+
+    fn record(&mut self, delay: u32, now: Instant) {
+        match self.interval_start {
+            Some(start) if now.duration_since(start) < BASE_INTERVAL => {
+                let current = self
+                    .base_delays
+                    .back_mut()
+                    .expect("an interval has started");
+                *current = (*current).min(delay);
+            }
+            _ => {
+                self.interval_start = Some(now);
+                self.base_delays.push_back(delay);
+                if self.base_delays.len() > BASE_HISTORY {
+                    self.base_delays.pop_front();
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::time::Instant;
+
+    use super::{Ledbat, BASE_INTERVAL, INITIAL_WINDOW, MIN_WINDOW};
+
+    #[test]
+    fn grows_below_target_and_backs_off_above() {
+        let now = Instant::now();
+        let mut ledbat = Ledbat::default();
+        ledbat.on_ack(20_000, 1000, now);
+        for _ in 0..50 {
+            ledbat.on_ack(30_000, 1000, now);
+        }
+        let grown = ledbat.window();
+        assert!(grown > INITIAL_WINDOW);
+
+        // 250ms of queuing, well over the target.
+        for _ in 0..50 {
+            ledbat.on_ack(270_000, 1000, now);
+        }
+        assert!(ledbat.window() < grown);
+        // Unmeasured delays change nothing.
+        let window = ledbat.window();
+        ledbat.on_ack(0, 1000, now);
+        assert_eq!(ledbat.window(), window);
+
+        ledbat.on_loss();
+        assert_eq!(ledbat.window(), (window / 2).max(MIN_WINDOW));
+        ledbat.on_timeout();
+        assert_eq!(ledbat.window(), MIN_WINDOW);
+    }
+
+    #[test]
+    fn base_delay_forgets_old_minutes() {
+        let now = Instant::now();
+        let mut ledbat = Ledbat::default();
+        ledbat.on_ack(10_000, 1000, now);
+        ledbat.on_ack(500_000, 1000, now + BASE_INTERVAL);
+        assert_eq!(ledbat.base_delays, [10_000, 500_000]);
+        // After the route changed, the new delay becomes the base.
+        ledbat.on_ack(500_000, 1000, now + BASE_INTERVAL * 2);
+        assert_eq!(ledbat.base_delays, [500_000, 500_000]);
+        let window = ledbat.window();
+        ledbat.on_ack(500_000, 1000, now + BASE_INTERVAL * 2);
+        assert!(ledbat.window() > window);
+    }
+}
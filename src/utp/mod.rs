@@ -0,0 +1,395 @@
+//! uTP, the Micro Transport Protocol: reliable, ordered streams over UDP
+//! whose LEDBAT congestion control gives way to other traffic. A
+//! [`UtpSocket`] carries every connection on one UDP port, and each
+//! connection is a [`UtpStream`] that reads and writes like a TCP stream.
+//! See: http://www.bittorrent.org/beps/bep_0029.html
+
+mod connection;
+mod ledbat;
+mod packet;
+
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use log::debug;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+
+use self::connection::Connection;
+use self::packet::{Header, Packet, PacketType};
+use crate::net::UdpMux;
+
+/// How often connections check their retransmission timers.
+const TICK_INTERVAL: Duration = Duration::from_millis(100);
+/// Accepted connections waiting for [`UtpSocket::accept`].
+const ACCEPT_BACKLOG: usize = 32;
+
+/// A connection is known by the peer's address and the ID its packets to
+/// us carry.
+type Key = (SocketAddr, u16);
+
+struct Shared {
+    /// Sends don't wait for the reactor to find the socket writable.
+    udp: UdpMux,
+    connections: Mutex<HashMap<Key, Connection>>,
+    incoming: mpsc::Sender<UtpStream>,
+}
+
+impl Shared {
+    /// Sends without waiting: a datagram the socket has no room for is as
+    /// good as lost, and will be resent like one.
+    fn send(&self, to: SocketAddr, packets: Vec<Packet>) {
+        for packet in packets {
+            if let Err(e) = self.udp.send_now(&packet.to_bytes(), to) {
+                debug!("Couldn't send uTP packet to {to}: {e}");
+            }
+        }
+    }
+
+    /// Runs `f` on `key`'s connection, sends what that queued, and forgets
+    /// the connection if it's finished.
+    fn with<T>(&self, key: &Key, f: impl FnOnce(&mut Connection) -> T) -> Option<T> {
+        let now = Instant::now();
+        let mut connections = self.connections.lock().unwrap();
+        let connection = connections.get_mut(key)?;
+        let result = f(connection);
+        connection.fill(now);
+        self.send(key.0, connection.take_outbox());
+        if connection.is_finished() {
+            connections.remove(key);
+        }
+        Some(result)
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct UtpSocket {
+    shared: Arc<Shared>,
+    accepted: Arc<tokio::sync::Mutex<mpsc::Receiver<UtpStream>>>,
+}
+
+impl UtpSocket {
+    #[cfg(test)]
+    pub(crate) async fn bind(addr: SocketAddr) -> io::Result<Self> {
+        Ok(Self::on(&UdpMux::bind(addr).await?))
+    }
+
+    /// Carries connections over `udp`, alongside whatever else shares it.
+    pub(crate) fn on(udp: &UdpMux) -> Self {
+        let (incoming, accepted) = mpsc::channel(ACCEPT_BACKLOG);
+        let shared = Arc::new(Shared {
+            udp: udp.clone(),
+            connections: Mutex::default(),
+            incoming,
+        });
+        tokio::spawn(tick(shared.clone()));
+        let socket = Self {
+            shared,
+            accepted: Arc::new(tokio::sync::Mutex::new(accepted)),
+        };
+        udp.set_utp(socket.clone());
+        socket
+    }
+
+    pub(crate) fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.shared.udp.local_addr()
+    }
+
+    /// Takes a datagram that arrived on the socket. Ones that aren't uTP
+    /// are ignored.
+    pub(crate) fn handle(&self, from: SocketAddr, bytes: &[u8]) {
+        let Some(packet) = Packet::parse(bytes) else {
+            return;
+        };
+        let now = Instant::now();
+        let header = packet.header.clone();
+        let mut connections = self.shared.connections.lock().unwrap();
+        let key = match header.kind {
+            // Resets may carry either of the connection's IDs.
+            PacketType::Reset => connections
+                .iter()
+                .find(|((addr, id), connection)| {
+                    *addr == from
+                        && (*id == header.connection_id
+                            || connection.send_id() == header.connection_id)
+                })
+                .map(|(key, _)| *key),
+            PacketType::Syn => Some((from, header.connection_id.wrapping_add(1))),
+            _ => Some((from, header.connection_id)),
+        }
+        .filter(|key| connections.contains_key(key));
+
+        if let Some(key) = key {
+            let connection = connections.get_mut(&key).unwrap();
+            connection.on_packet(packet, now);
+            connection.fill(now);
+            self.shared.send(from, connection.take_outbox());
+            if connection.is_finished() {
+                connections.remove(&key);
+            }
+            return;
+        }
+        match header.kind {
+            PacketType::Syn => {
+                let key = (from, header.connection_id.wrapping_add(1));
+                // Dialing each other at once, as holepunching peers do, would
+                // leave two connections. Both ends keep the one dialed from
+                // the lower connection ID: if that's ours the peer gives way
+                // when our SYN gets through, and if not our dialer takes
+                // theirs over. On the rare tie neither gives way, and the
+                // dial fails.
+                let dialing = connections
+                    .iter()
+                    .find(|((addr, _), connection)| *addr == from && connection.is_connecting())
+                    .map(|(key, _)| *key);
+                if let Some(ours) = dialing {
+                    if ours.1 <= header.connection_id {
+                        return;
+                    }
+                    let mut connection = Connection::accept(&header, now);
+                    connection.fill(now);
+                    self.shared.send(from, connection.take_outbox());
+                    connections.insert(key, connection);
+                    if let Some(dialing) = connections.get_mut(&ours) {
+                        dialing.supersede(key.1);
+                    }
+                    return;
+                }
+                let Ok(permit) = self.shared.incoming.try_reserve() else {
+                    debug!("Refusing uTP connection from {from}: backlog full");
+                    return self.shared.send(from, vec![reset(&header)]);
+                };
+                let mut connection = Connection::accept(&header, now);
+                connection.fill(now);
+                self.shared.send(from, connection.take_outbox());
+                connections.insert(key, connection);
+                permit.send(UtpStream {
+                    shared: self.shared.clone(),
+                    key,
+                });
+            }
+            PacketType::Reset => {}
+            _ => self.shared.send(from, vec![reset(&header)]),
+        }
+    }
+
+    /// Dials `addr`. If it dials us at the same time, we end up with the
+    /// one connection it does.
+    pub(crate) async fn connect(&self, addr: SocketAddr) -> io::Result<UtpStream> {
+        let now = Instant::now();
+        let key = {
+            let mut connections = self.shared.connections.lock().unwrap();
+            let key = std::iter::repeat_with(|| (addr, rand::random()))
+                .find(|key| !connections.contains_key(key))
+                .expect("some connection ID is free");
+            let mut connection = Connection::connect(key.1, now);
+            self.shared.send(addr, connection.take_outbox());
+            connections.insert(key, connection);
+            key
+        };
+        // Dropped if connecting fails, which closes the connection.
+        let mut stream = UtpStream {
+            shared: self.shared.clone(),
+            key,
+        };
+        let superseded_by = std::future::poll_fn(|cx| {
+            stream.poll_with(|c| match c.superseded_by() {
+                Some(recv_id) => Poll::Ready(Ok(Some(recv_id))),
+                None => c.poll_connected(cx).map_ok(|()| None),
+            })
+        })
+        .await?;
+        if let Some(recv_id) = superseded_by {
+            // Replacing the stream lets the abandoned connection go.
+            stream = UtpStream {
+                shared: self.shared.clone(),
+                key: (addr, recv_id),
+            };
+        }
+        Ok(stream)
+    }
+
+    /// Dials `addr` for a holepunch the peer asked for, then hands over the
+    /// connection as if the peer had dialed us, so the peer speaks first.
+    pub(crate) async fn holepunch(&self, addr: SocketAddr) -> io::Result<()> {
+        let stream = self.connect(addr).await?;
+        self.shared
+            .incoming
+            .send(stream)
+            .await
+            .map_err(|_| io::ErrorKind::BrokenPipe.into())
+    }
+
+    /// The next peer to connect to us.
+    pub(crate) async fn accept(&self) -> UtpStream {
+        self.accepted
+            .lock()
+            .await
+            .recv()
+            .await
+            .expect("the socket holds a sender")
+    }
+}
+
+/// Refuses a packet for a connection we don't have.
+fn reset(header: &Header) -> Packet {
+    Packet {
+        header: Header::new(
+            PacketType::Reset,
+            header.connection_id,
+            rand::random(),
+            header.seq_nr,
+        ),
+        selective_ack: None,
+        payload: Vec::new(),
+    }
+}
+
+async fn tick(shared: Arc<Shared>) {
+    let mut interval = tokio::time::interval(TICK_INTERVAL);
+    loop {
+        interval.tick().await;
+        let now = Instant::now();
+        let mut connections = shared.connections.lock().unwrap();
+        connections.retain(|(addr, _), connection| {
+            connection.tick(now);
+            connection.fill(now);
+            shared.send(*addr, connection.take_outbox());
+            !connection.is_finished()
+        });
+    }
+}
+
+/// A uTP connection. Dropping it closes the connection once everything
+/// written has been delivered.
+pub(crate) struct UtpStream {
+    shared: Arc<Shared>,
+    key: Key,
+}
+
+impl UtpStream {
+    pub(crate) fn peer_addr(&self) -> SocketAddr {
+        self.key.0
+    }
+
+    fn poll_with<T>(
+        &self,
+        f: impl FnOnce(&mut Connection) -> Poll<io::Result<T>>,
+    ) -> Poll<io::Result<T>> {
+        self.shared
+            .with(&self.key, f)
+            .expect("connections outlive their streams")
+    }
+}
+
+impl AsyncRead for UtpStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        self.poll_with(|c| c.poll_read(cx, buf))
+    }
+}
+
+impl AsyncWrite for UtpStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.poll_with(|c| c.poll_write(cx, buf))
+    }
+
+    /// Writes go out as soon as the windows allow, so there's nothing to
+    /// flush.
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.poll_with(|c| {
+            c.close();
+            Poll::Ready(Ok(()))
+        })
+    }
+}
+
+impl Drop for UtpStream {
+    fn drop(&mut self) {
+        self.shared.with(&self.key, Connection::detach);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::UtpSocket;
+
+    #[tokio::test]
+    async fn transfers_both_ways_and_closes() {
+        let server = UtpSocket::bind("127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap();
+        let client = UtpSocket::bind("127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap();
+        let data: Vec<u8> = (0..512 * 1024).map(|i| (i % 251) as u8).collect();
+
+        let mut ours = client.connect(server.local_addr().unwrap()).await.unwrap();
+        let mut theirs = server.accept().await;
+        assert_eq!(theirs.peer_addr(), client.local_addr().unwrap());
+
+        let sent = data.clone();
+        let writer = tokio::spawn(async move {
+            ours.write_all(&sent).await.unwrap();
+            ours.shutdown().await.unwrap();
+            let mut reply = Vec::new();
+            ours.read_to_end(&mut reply).await.unwrap();
+            reply
+        });
+        let mut received = Vec::new();
+        theirs.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, data);
+        theirs.write_all(b"thanks").await.unwrap();
+        drop(theirs);
+        assert_eq!(writer.await.unwrap(), b"thanks");
+    }
+
+    #[tokio::test]
+    async fn simultaneous_connects_make_one_connection() {
+        let a = UtpSocket::bind("127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap();
+        let b = UtpSocket::bind("127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap();
+        let (a_addr, b_addr) = (a.local_addr().unwrap(), b.local_addr().unwrap());
+
+        let (ours, theirs) = tokio::join!(a.connect(b_addr), b.connect(a_addr));
+        let (mut ours, mut theirs) = (ours.unwrap(), theirs.unwrap());
+        ours.write_all(b"ping").await.unwrap();
+        let mut buf = [0; 4];
+        theirs.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+        assert_eq!(a.shared.connections.lock().unwrap().len(), 1);
+        assert_eq!(b.shared.connections.lock().unwrap().len(), 1);
+
+        // Holepunching as the side that didn't ask, the peer speaks first.
+        let (ours, theirs) = tokio::join!(a.connect(b_addr), b.holepunch(a_addr));
+        let mut ours = ours.unwrap();
+        theirs.unwrap();
+        let mut theirs = b.accept().await;
+        assert_eq!(theirs.peer_addr(), a_addr);
+        ours.write_all(b"pong").await.unwrap();
+        theirs.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"pong");
+    }
+}
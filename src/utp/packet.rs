@@ -0,0 +1,149 @@
+//! The uTP packet format: a 20 byte header, a chain of extensions, then the
+//! payload.
+
+use deku::prelude::*;
+
+const VERSION: u8 = 1;
+/// The extension carrying a selective ACK bitmask.
+const SELECTIVE_ACK: u8 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, DekuRead, DekuWrite)]
+#[deku(
+    type = "u8",
+    bits = "4",
+    endian = "endian",
+    ctx = "endian: deku::ctx::Endian"
+)]
+pub(crate) enum PacketType {
+    #[deku(id = "0")]
+    Data,
+    #[deku(id = "1")]
+    Fin,
+    /// A bare ACK.
+    #[deku(id = "2")]
+    State,
+    #[deku(id = "3")]
+    Reset,
+    #[deku(id = "4")]
+    Syn,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, DekuRead, DekuWrite)]
+#[deku(endian = "big")]
+pub(crate) struct Header {
+    pub(crate) kind: PacketType,
+    #[deku(bits = "4", assert_eq = "VERSION")]
+    version: u8,
+    extension: u8,
+    pub(crate) connection_id: u16,
+    /// When the packet was sent, in microseconds on the sender's clock.
+    pub(crate) timestamp: u32,
+    /// How long the last packet the sender got took to arrive, as far as the
+    /// two clocks tell.
+    pub(crate) timestamp_difference: u32,
+    /// Bytes the sender still has room to receive.
+    pub(crate) wnd_size: u32,
+    pub(crate) seq_nr: u16,
+    pub(crate) ack_nr: u16,
+}
+
+impl Header {
+    pub(crate) fn new(kind: PacketType, connection_id: u16, seq_nr: u16, ack_nr: u16) -> Self {
+        Self {
+            kind,
+            version: VERSION,
+            extension: 0,
+            connection_id,
+            timestamp: 0,
+            timestamp_difference: 0,
+            wnd_size: 0,
+            seq_nr,
+            ack_nr,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Packet {
+    pub(crate) header: Header,
+    /// Bit `i` set means packet `ack_nr + 2 + i` has arrived.
+    pub(crate) selective_ack: Option<Vec<u8>>,
+    pub(crate) payload: Vec<u8>,
+}
+
+impl Packet {
+    /// Reads a datagram, skipping extensions we don't know. Anything that
+    /// isn't a uTP packet gives None.
+    pub(crate) fn parse(bytes: &[u8]) -> Option<Self> {
+        let ((rest, _), mut header) = Header::from_bytes((bytes, 0)).ok()?;
+        let mut selective_ack = None;
+        let mut rest = rest;
+        // The chain is kept as `selective_ack`, and rebuilt from it.
+        let mut extension = std::mem::take(&mut header.extension);
+        while extension != 0 {
+            let [next, len, ref tail @ ..] = *rest else {
+                return None;
+            };
+            let data = tail.get(..len as usize)?;
+            if extension == SELECTIVE_ACK {
+                selective_ack = Some(data.to_vec());
+            }
+            extension = next;
+            rest = &tail[len as usize..];
+        }
+        Some(Self {
+            header,
+            selective_ack,
+            payload: rest.to_vec(),
+        })
+    }
+
+    pub(crate) fn to_bytes(&self) -> Vec<u8> {
+        let mut header = self.header.clone();
+        header.extension = match self.selective_ack {
+            Some(_) => SELECTIVE_ACK,
+            None => 0,
+        };
+        let mut bytes = header.to_bytes().expect("header always encodes");
+        if let Some(mask) = &self.selective_ack {
+            bytes.extend([0, mask.len() as u8]);
+            bytes.extend(mask);
+        }
+        bytes.extend(&self.payload);
+        bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Header, Packet, PacketType};
+
+    #[test]
+    fn round_trips_with_extensions() {
+        let mut header = Header::new(PacketType::Data, 0x1234, 7, 5);
+        header.timestamp = 1;
+        header.wnd_size = 65536;
+        let packet = Packet {
+            header,
+            selective_ack: Some(vec![0b101, 0, 0, 0]),
+            payload: b"hello".to_vec(),
+        };
+        let bytes = packet.to_bytes();
+        assert_eq!(&bytes[..4], &[0x01, 1, 0x12, 0x34]);
+        assert_eq!(bytes.len(), 20 + 2 + 4 + 5);
+        assert_eq!(Packet::parse(&bytes), Some(packet));
+
+        // An unknown extension is skipped.
+        let mut bytes = Packet {
+            header: Header::new(PacketType::Syn, 1, 1, 0),
+            selective_ack: None,
+            payload: Vec::new(),
+        }
+        .to_bytes();
+        bytes[1] = 9;
+        bytes.extend([0, 2, 0xaa, 0xbb]);
+        assert_eq!(Packet::parse(&bytes).unwrap().header.kind, PacketType::Syn);
+        // So are other protocols sharing the socket.
+        assert_eq!(Packet::parse(b"d1:ad2:id20:"), None);
+    }
+}